@@ -0,0 +1,95 @@
+use std::fmt::Debug;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// 2020-01-01T00:00:00Z. A wall clock reading earlier than this is almost
+/// certainly wrong (unset RTC, broken NTP) and would corrupt expiry math.
+const PLAUSIBLE_EPOCH_FLOOR: u64 = 1_577_836_800;
+
+/// Source of the current unix time for all `created_at`/expiry math, so it
+/// can be swapped for a controllable clock in tests.
+pub trait Clock: Debug + Send + Sync {
+    /// Current unix time in seconds
+    fn now(&self) -> u64;
+}
+
+#[derive(Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs()
+    }
+}
+
+/// Whether a clock reading looks like a sane current time.
+pub fn is_plausible(now: u64) -> bool {
+    now >= PLAUSIBLE_EPOCH_FLOOR
+}
+
+/// Log a warning at startup if the system clock looks implausible.
+pub fn check_clock(clock: &dyn Clock) {
+    let now = clock.now();
+    if !is_plausible(now) {
+        tracing::warn!(
+            "System clock reads {} (before 2020); expiry and embargo times will be wrong",
+            now
+        );
+    }
+}
+
+#[cfg(test)]
+pub use mock::MockClock;
+
+#[cfg(test)]
+mod mock {
+    use super::Clock;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    /// Manually controlled clock for tests.
+    #[derive(Debug)]
+    pub struct MockClock(AtomicU64);
+
+    impl MockClock {
+        pub fn new(now: u64) -> Self {
+            Self(AtomicU64::new(now))
+        }
+
+        pub fn advance(&self, seconds: u64) {
+            self.0.fetch_add(seconds, Ordering::SeqCst);
+        }
+    }
+
+    impl Clock for MockClock {
+        fn now(&self) -> u64 {
+            self.0.load(Ordering::SeqCst)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_system_clock_is_plausible() {
+        assert!(is_plausible(SystemClock.now()));
+    }
+
+    #[test]
+    fn test_clock_before_2020_is_implausible() {
+        assert!(!is_plausible(0));
+        assert!(!is_plausible(PLAUSIBLE_EPOCH_FLOOR - 1));
+        assert!(is_plausible(PLAUSIBLE_EPOCH_FLOOR));
+    }
+
+    #[test]
+    fn test_mock_clock_advances() {
+        let clock = MockClock::new(1706900000);
+        assert_eq!(clock.now(), 1706900000);
+        clock.advance(60);
+        assert_eq!(clock.now(), 1706900060);
+    }
+}
@@ -0,0 +1,1121 @@
+use crate::db::{SecretStorage, StorageFormat};
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// How paths with a trailing slash are routed (`TRAILING_SLASH`).
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum TrailingSlash {
+    /// `/v1/secrets/` is handled as `/v1/secrets`
+    #[default]
+    Merge,
+    /// Only the exact route matches; `/v1/secrets/` is a 404
+    Strict,
+}
+
+impl FromStr for TrailingSlash {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "merge" => Ok(Self::Merge),
+            "strict" => Ok(Self::Strict),
+            _ => Err(()),
+        }
+    }
+}
+
+/// What happens to requests that arrived over plain HTTP (`FORCE_HTTPS`).
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ForceHttps {
+    /// Served like any other request
+    #[default]
+    Off,
+    /// GET and HEAD are redirected to https; anything else is rejected
+    Redirect,
+    /// Rejected with 400
+    Reject,
+}
+
+impl FromStr for ForceHttps {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "off" | "" => Ok(Self::Off),
+            "redirect" => Ok(Self::Redirect),
+            "reject" => Ok(Self::Reject),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Whether `?peek=true` is honored (`DISABLE_PEEK`), for deployments that
+/// want strict burn-only reads.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum DisablePeek {
+    /// Peeks work, subject to per-secret `noPeek`
+    #[default]
+    Off,
+    /// Peeks are refused with 400 `PEEK_DISABLED`
+    Reject,
+    /// The flag is ignored and every read burns
+    Burn,
+}
+
+impl FromStr for DisablePeek {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "0" | "false" | "no" | "off" | "" => Ok(Self::Off),
+            "1" | "true" | "yes" | "reject" => Ok(Self::Reject),
+            "burn" => Ok(Self::Burn),
+            _ => Err(()),
+        }
+    }
+}
+
+/// What happens when a file's `contentType` contradicts the extension of its
+/// `originalFilename` (`CHECK_EXTENSION_CONTENT_TYPE`).
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ExtensionContentTypeCheck {
+    #[default]
+    Off,
+    /// The mismatch is logged and the file stored anyway
+    Warn,
+    /// The file is refused with 400 `CONTENT_TYPE_MISMATCH`
+    Strict,
+}
+
+impl FromStr for ExtensionContentTypeCheck {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "0" | "false" | "no" | "off" | "" => Ok(Self::Off),
+            "warn" => Ok(Self::Warn),
+            "1" | "true" | "yes" | "strict" => Ok(Self::Strict),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Key casing of JSON response bodies (`RESPONSE_CASE`).
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ResponseCase {
+    /// `secretId`, as the models serialize
+    #[default]
+    Camel,
+    /// `secret_id`
+    Snake,
+}
+
+impl FromStr for ResponseCase {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "camel" => Ok(Self::Camel),
+            "snake" => Ok(Self::Snake),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Alphabet uploaded file data is re-encoded to (`BASE64_ALPHABET`).
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Base64Alphabet {
+    /// Stored exactly as sent
+    #[default]
+    Verbatim,
+    /// RFC 4648 standard alphabet (`+`, `/`) with padding
+    Standard,
+    /// RFC 4648 url-safe alphabet (`-`, `_`) without padding
+    UrlSafe,
+}
+
+impl FromStr for Base64Alphabet {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "verbatim" => Ok(Self::Verbatim),
+            "standard" => Ok(Self::Standard),
+            "url-safe" | "urlsafe" => Ok(Self::UrlSafe),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Maximum expiration by payload size (`SIZE_TTL_CURVE`): breakpoints of
+/// `min_size_bytes:max_expiration_seconds`, e.g. `1048576:604800` caps files
+/// over 1MB at 7 days.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SizeTtlCurve(Vec<(usize, u64)>);
+
+impl SizeTtlCurve {
+    pub fn new(breakpoints: Vec<(usize, u64)>) -> Self {
+        Self(breakpoints)
+    }
+
+    /// The tightest cap among breakpoints `size_bytes` exceeds, if any.
+    pub fn max_expiration(&self, size_bytes: usize) -> Option<u64> {
+        self.0
+            .iter()
+            .filter(|(min_size, _)| size_bytes > *min_size)
+            .map(|(_, max_expiration)| *max_expiration)
+            .min()
+    }
+}
+
+impl FromStr for SizeTtlCurve {
+    type Err = ();
+
+    /// Comma-separated `size:seconds` pairs; any malformed pair rejects the
+    /// whole curve.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .map(str::trim)
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| {
+                let (size, seconds) = pair.split_once(':').ok_or(())?;
+                Ok((
+                    size.trim().parse().map_err(|_| ())?,
+                    seconds.trim().parse().map_err(|_| ())?,
+                ))
+            })
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+}
+
+/// Sizes stored secret payloads are padded up to (`PAD_PAYLOAD_BUCKETS`), in
+/// bytes of the stored base64 text, e.g. `1024,4096,16384`. Payloads past the
+/// largest bucket are padded to a multiple of it. Empty = no padding.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PadBuckets(Vec<usize>);
+
+impl PadBuckets {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// The length a payload of `len` bytes is stored at.
+    pub fn padded_len(&self, len: usize) -> usize {
+        let Some(&largest) = self.0.last() else {
+            return len;
+        };
+        match self.0.iter().find(|&&bucket| bucket >= len) {
+            Some(&bucket) => bucket,
+            None => len.div_ceil(largest) * largest,
+        }
+    }
+}
+
+impl FromStr for PadBuckets {
+    type Err = ();
+
+    /// Comma-separated sizes, each above 0; any malformed one rejects the list.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut buckets = s
+            .split(',')
+            .map(str::trim)
+            .filter(|size| !size.is_empty())
+            .map(|size| match size.parse() {
+                Ok(0) | Err(_) => Err(()),
+                Ok(size) => Ok(size),
+            })
+            .collect::<Result<Vec<usize>, _>>()?;
+        buckets.sort_unstable();
+        buckets.dedup();
+        Ok(Self(buckets))
+    }
+}
+
+/// Policy for one secret kind in `SECRET_KINDS`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SecretKind {
+    pub name: String,
+    /// Expiration applied when the request doesn't set one
+    pub default_expiration: Option<u64>,
+    /// Longest encrypted payload (characters) accepted for this kind
+    pub max_payload_length: Option<usize>,
+}
+
+/// Secret kinds callers may tag secrets with (`SECRET_KINDS`): comma-separated
+/// `kind[:default_expiration_seconds[:max_payload_length]]`, e.g.
+/// `password:3600:4096,note:86400,apikey`. Empty fields fall back to the
+/// global behaviour. With no kinds configured any kind is accepted.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SecretKinds(Vec<SecretKind>);
+
+impl SecretKinds {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&SecretKind> {
+        self.0.iter().find(|kind| kind.name == name)
+    }
+}
+
+impl FromStr for SecretKinds {
+    type Err = ();
+
+    /// Any malformed entry rejects the whole list.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        fn optional<T: FromStr>(field: Option<&str>) -> Result<Option<T>, ()> {
+            match field.map(str::trim) {
+                None | Some("") => Ok(None),
+                Some(value) => value.parse().map(Some).map_err(|_| ()),
+            }
+        }
+
+        s.split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(|entry| {
+                let mut fields = entry.split(':');
+                let name = fields.next().unwrap_or_default().trim();
+                if name.is_empty() {
+                    return Err(());
+                }
+                let kind = SecretKind {
+                    name: name.to_string(),
+                    default_expiration: optional(fields.next())?,
+                    max_payload_length: optional(fields.next())?,
+                };
+                match fields.next() {
+                    Some(_) => Err(()),
+                    None => Ok(kind),
+                }
+            })
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+}
+
+/// Limits for callers presenting one API key, on top of the global ones.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PolicyProfile {
+    pub min_expiration: Option<u64>,
+    pub max_expiration: Option<u64>,
+    /// Longest encrypted payload (characters) accepted
+    pub max_payload_length: Option<usize>,
+    /// File content types accepted; empty = any
+    pub content_types: Vec<String>,
+}
+
+/// Policy profiles by API key (`POLICY_PROFILES`): comma-separated
+/// `key[:min_expiration[:max_expiration[:max_payload_length[:type|type]]]]`,
+/// e.g. `intranet:60:3600,partner-*::604800:1048576:image/png|image/jpeg`.
+/// A key ending in `*` matches keys by prefix. Empty fields add no limit.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PolicyProfiles(Vec<(String, PolicyProfile)>);
+
+impl PolicyProfiles {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// The profile for `api_key`: an exact match, else the first matching
+    /// prefix. Anonymous and unknown callers get the default profile.
+    pub fn for_key(&self, api_key: Option<&str>) -> PolicyProfile {
+        let Some(api_key) = api_key else {
+            return PolicyProfile::default();
+        };
+        self.0
+            .iter()
+            .find(|(key, _)| key == api_key)
+            .or_else(|| {
+                self.0.iter().find(|(key, _)| {
+                    key.strip_suffix('*')
+                        .is_some_and(|prefix| api_key.starts_with(prefix))
+                })
+            })
+            .map(|(_, profile)| profile.clone())
+            .unwrap_or_default()
+    }
+}
+
+impl FromStr for PolicyProfiles {
+    type Err = ();
+
+    /// Any malformed entry rejects the whole list.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        fn optional<T: FromStr>(field: Option<&str>) -> Result<Option<T>, ()> {
+            match field.map(str::trim) {
+                None | Some("") => Ok(None),
+                Some(value) => value.parse().map(Some).map_err(|_| ()),
+            }
+        }
+
+        s.split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(|entry| {
+                let mut fields = entry.split(':');
+                let key = fields.next().unwrap_or_default().trim();
+                if key.is_empty() || key == "*" {
+                    return Err(());
+                }
+                let profile = PolicyProfile {
+                    min_expiration: optional(fields.next())?,
+                    max_expiration: optional(fields.next())?,
+                    max_payload_length: optional(fields.next())?,
+                    content_types: fields
+                        .next()
+                        .unwrap_or_default()
+                        .split('|')
+                        .map(|content_type| content_type.trim().to_ascii_lowercase())
+                        .filter(|content_type| !content_type.is_empty())
+                        .collect(),
+                };
+                match fields.next() {
+                    Some(_) => Err(()),
+                    None => Ok((key.to_string(), profile)),
+                }
+            })
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+}
+
+/// Keys accepted in `X-Api-Key` on create endpoints (`API_KEYS`), as
+/// comma-separated `label:key` pairs, e.g. `web:k1,ci:k2`. A label may appear
+/// more than once, so a consumer's old and new key can overlap during
+/// rotation. The label is what gets logged, never the key.
+#[derive(Clone, Default, PartialEq)]
+pub struct ApiKeys(Vec<(String, String)>);
+
+impl ApiKeys {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// `(label, key)` pairs, in configuration order.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0
+            .iter()
+            .map(|(label, key)| (label.as_str(), key.as_str()))
+    }
+}
+
+/// Only the labels: the config is logged at startup.
+impl fmt::Debug for ApiKeys {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list()
+            .entries(self.0.iter().map(|(label, _)| label))
+            .finish()
+    }
+}
+
+impl FromStr for ApiKeys {
+    type Err = ();
+
+    /// Any malformed entry rejects the whole list.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(|entry| match entry.split_once(':') {
+                Some((label, key)) if !label.trim().is_empty() && !key.trim().is_empty() => {
+                    Ok((label.trim().to_string(), key.trim().to_string()))
+                }
+                _ => Err(()),
+            })
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+}
+
+/// Runtime configuration, loaded once from environment variables at startup.
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// Replica for read-only lookups (peeks, admin scans); None = primary only
+    pub redis_replica_url: Option<String>,
+    /// Retry a replica miss on the primary, to paper over replication lag
+    pub replica_fallback: bool,
+    /// Namespace prepended to every Redis key (empty = none)
+    pub redis_key_prefix: String,
+    /// Lowercase substrings generated ids are regenerated to avoid
+    pub id_blocklist: Vec<String>,
+    pub max_file_size_bytes: usize,
+    /// Largest decoded `encryptedThumbnail` accepted with a file, separate from the file limit
+    pub max_thumbnail_bytes: usize,
+    /// Re-encode uploaded file data to one base64 alphabet before storing it
+    pub base64_alphabet: Base64Alphabet,
+    /// Body limit for routes taking small JSON bodies (secrets, and GETs);
+    /// 0 = the upload limit, as for every route
+    pub max_json_body_bytes: usize,
+    /// Lower expiration ceilings for larger files and bundles
+    pub size_ttl_curve: SizeTtlCurve,
+    /// Sizes secret payloads are padded up to before storing (empty = off)
+    pub pad_payload_buckets: PadBuckets,
+    /// Minimum wall-clock time for GET secret/file responses (None = disabled)
+    pub min_response_time: Option<Duration>,
+    /// Longest a request may take before a 408 (None = unlimited)
+    pub request_timeout: Option<Duration>,
+    /// Overrides `request_timeout` for the secret endpoints
+    pub secret_timeout: Option<Duration>,
+    /// Overrides `request_timeout` for the file endpoints, which move far more data
+    pub file_timeout: Option<Duration>,
+    /// How long a reader confirmation counts towards a multi-confirmation secret
+    pub confirmation_window_seconds: u64,
+    /// Most `requiredConfirmations` a secret may ask for
+    pub max_required_confirmations: u32,
+    /// How long after a peek a `bindViewContext` secret can be burned with its token
+    pub view_context_seconds: u64,
+    /// Bearer token for `/v1/admin/*` (None = admin routes always reject)
+    pub admin_api_key: Option<String>,
+    /// Let `PUT /v1/admin/secrets/:id?overwrite=true` replace an existing secret
+    pub allow_import_overwrite: bool,
+    /// Let admin callers have stored files decrypted server-side with a key
+    /// they supply. Not end-to-end: the server sees the key and plaintext.
+    pub allow_server_decrypt: bool,
+    /// Signs management links returned at creation (None = no links issued)
+    pub management_signing_key: Option<String>,
+    /// Key being rotated out: links it signed still verify, new ones use the current key
+    pub management_signing_key_previous: Option<String>,
+    /// Use the proxy-appended X-Forwarded-For entry as the client IP
+    pub trust_proxy_headers: bool,
+    pub force_https: ForceHttps,
+    /// Simultaneous in-flight file uploads allowed per client IP (0 = unlimited)
+    pub max_concurrent_uploads_per_ip: usize,
+    /// Seconds a chunked upload has to be completed before its chunks are dropped
+    pub upload_session_seconds: u64,
+    /// Seconds a secret create's `Idempotency-Key` is replayed for (0 = keys are ignored)
+    pub idempotency_ttl_seconds: u64,
+    /// Answer an identical secret create arriving shortly after another with
+    /// the first one's id instead of storing it twice
+    pub dedup_creates: bool,
+    /// Simultaneous in-flight peeks allowed per secret or file id (0 = unlimited)
+    pub max_concurrent_peeks_per_id: u64,
+    /// Reads kept in each secret's access log, newest first (0 = no access log)
+    pub max_access_log_entries: u64,
+    /// How long a burned secret's metadata stays peekable (0 = not kept)
+    pub burn_grace_seconds: u64,
+    /// Most ids one POST /v1/secrets/exists may check
+    pub max_exists_ids: usize,
+    /// Requests per second the whole service accepts before shedding with 429 (0 = unlimited)
+    pub global_rps: u32,
+    /// Requests the global budget can absorb at once (0 = one second of `global_rps`)
+    pub global_burst: u32,
+    /// Random spread applied to every 429's `Retry-After`, as a fraction of
+    /// the base wait (0 = exact), so throttled clients don't retry in lockstep
+    pub retry_after_jitter: f64,
+    /// Accept expirations of exactly the minimum and maximum (false = strictly between)
+    pub expiration_bounds_inclusive: bool,
+    /// Round requested expirations up to a multiple of this many seconds (0 = disabled)
+    pub expiration_granularity_seconds: u64,
+    /// Furthest `availableAt` may be from now, in seconds (0 = only bounded by expiry)
+    pub max_embargo_seconds: u64,
+    /// Take the current time from Redis `TIME`, both for creation stamps and the
+    /// read checks against them, so instances share one clock
+    pub use_redis_time: bool,
+    /// Lowest client-reported zxcvbn `passwordStrength` accepted (0 = no check)
+    pub min_password_strength: u8,
+    /// How long a `durable` write waits for a replica acknowledgment
+    pub durable_wait_timeout: Duration,
+    /// Reject encrypted payloads shorter than this many characters (0 = disabled)
+    pub min_payload_length: usize,
+    /// Reject create requests whose Origin/Referer isn't in `allowed_origins`
+    pub enforce_origin: bool,
+    /// Answer burn reads of a `maxReads` secret whose reads ran out with 409, not 404
+    pub reads_exhausted_conflict: bool,
+    /// Publish secret lifecycle events and serve them at `/v1/secrets/:id/events`
+    pub secret_events: bool,
+    /// Open event subscriptions allowed per secret id in this process (0 = unlimited)
+    pub max_subscribers_per_id: usize,
+    /// Open event subscriptions allowed in this process (0 = unlimited)
+    pub max_subscribers: usize,
+    /// Redis Stream that lifecycle events are also appended to (None = disabled)
+    pub events_stream: Option<String>,
+    /// Approximate number of entries the events stream is trimmed to
+    pub events_stream_max_len: u64,
+    /// Re-store legacy plain-string secrets as JSON records when they're peeked
+    pub migrate_legacy_on_peek: bool,
+    /// Answer peeks at `noPeek` secrets with the same 404 as a missing id
+    pub hide_nopeek_existence: bool,
+    pub disable_peek: DisablePeek,
+    /// Accept legacy `sp-` secret ids (minted before ids were base58)
+    pub legacy_secret_ids: bool,
+    /// Explain in 404/410 bodies why a secret wasn't found. Never in production:
+    /// it tells anyone probing ids what became of them
+    pub diagnostic_errors: bool,
+    /// Reject creates without a non-empty User-Agent header
+    pub require_user_agent: bool,
+    /// Reject creates whose `Content-Type` isn't exactly `application/json`
+    pub strict_content_type: bool,
+    /// Origins (`scheme://host[:port]`) allowed to create secrets and files
+    pub allowed_origins: Vec<String>,
+    /// Include a random `nonce` in create responses
+    pub response_nonce: bool,
+    /// Include the SHA-256 of the received payload in create responses
+    pub response_checksum: bool,
+    /// Wrap JSON responses as `{ data, error }` for clients that need a fixed shape
+    pub envelope_responses: bool,
+    pub response_case: ResponseCase,
+    /// Also accept `application/x-www-form-urlencoded` secret create bodies
+    pub accept_form_bodies: bool,
+    pub trailing_slash: TrailingSlash,
+    /// Serve the HTML status page at `GET /status`
+    pub enable_status_page: bool,
+    /// Front-end URL that `GET /` redirects to instead of the service descriptor
+    pub root_redirect: Option<String>,
+    /// Base URL share links are built from (`<base>/<id>`), e.g. for QR codes
+    pub public_base_url: Option<String>,
+    /// Most `tags` one secret may carry
+    pub max_tags: usize,
+    /// Longest single tag, in bytes
+    pub max_tag_length: usize,
+    /// Longest `originalFilename` a file or bundle part may have
+    pub max_filename_length: usize,
+    /// If non-empty, only files with these extensions (lowercase, no dot) are accepted
+    pub allowed_extensions: Vec<String>,
+    /// Extensions (lowercase, no dot) that are always rejected
+    pub blocked_extensions: Vec<String>,
+    /// Whether file content types are checked against filename extensions
+    pub check_extension_content_type: ExtensionContentTypeCheck,
+    /// Requests with more headers than this are rejected with 431 (0 = unlimited)
+    pub max_header_count: usize,
+    /// Requests whose header names and values total more bytes are rejected with 431 (0 = unlimited)
+    pub max_header_bytes: usize,
+    /// Maximum nesting depth of a secret request body (including `metadata`)
+    pub max_json_depth: usize,
+    /// Reject secret `metadata` that isn't a JSON object
+    pub strict_metadata_object: bool,
+    /// Reject `metadata: {}` instead of storing it as no metadata
+    pub reject_empty_metadata: bool,
+    /// Reject ciphertext too short for the cipher its `crypto` envelope declares
+    pub validate_ciphertext_length: bool,
+    /// Reject secret creates without a `shareReason`
+    pub require_share_reason: bool,
+    /// Reject secrets whose metadata lacks a client-computed `mac` over the ciphertext
+    pub require_payload_mac: bool,
+    /// Log each secret create (with its `shareReason`) to the `audit` target
+    pub audit_secret_creates: bool,
+    /// Metadata keys whose values are written as `[redacted]` in logs and audit records
+    pub redact_metadata_keys: Vec<String>,
+    /// Top-level metadata keys copied into the create audit entry; none by default
+    pub audit_metadata_keys: Vec<String>,
+    /// Allowed secret kinds and their default expiration and size limits
+    pub secret_kinds: SecretKinds,
+    /// Per-API-key expiration, size and content type limits (`X-Api-Key`)
+    pub policy_profiles: PolicyProfiles,
+    /// When set, create endpoints require one of these keys in `X-Api-Key`
+    pub api_keys: ApiKeys,
+    /// Add each create's stored bytes to its API key's total in Redis
+    pub usage_by_api_key: bool,
+    /// Metadata keys shown on peek; empty = all metadata is peek-visible
+    pub peek_metadata_allowlist: Vec<String>,
+    /// Peeks leave out metadata over this many bytes of JSON (0 = no limit)
+    pub max_peek_metadata_bytes: usize,
+    /// Largest stored value a read will load; bigger ones are refused (0 = unlimited)
+    pub max_stored_value_bytes: u64,
+    /// Largest stored secret or file a download will send; bigger ones get 502 (0 = unlimited)
+    pub max_download_bytes: u64,
+    /// Gzip file records before storing them
+    pub compress_storage: bool,
+    /// Most files one bundle may hold (0 = unlimited)
+    pub max_bundle_parts: usize,
+    /// Seconds after creation no extension or sliding peek may keep a secret
+    /// past (0 = unlimited)
+    pub max_total_lifetime_seconds: u64,
+    /// Encoding of new secret and file records; reads accept either
+    pub storage_format: StorageFormat,
+    /// Keep file data in blobs shared by content hash, referenceable by new files
+    pub dedup_files: bool,
+    /// Files up to this many decoded bytes are stored as `sps-` secrets (0 = off)
+    pub unify_small_files: usize,
+    /// Plain secrets over this many decoded bytes are stored as `spf-` files (0 = off)
+    pub unify_large_secrets: usize,
+    /// Most a compressed record may inflate to on read (0 = unlimited)
+    pub max_decompressed_bytes: u64,
+    /// Maximum number of unread files stored at once (0 = unlimited)
+    pub max_active_files: u64,
+    /// Send `X-Capacity-Remaining` on file creates while `max_active_files` is set
+    pub capacity_header: bool,
+    /// How long a file staged for ranged download stays readable (0 = staging disabled)
+    pub staged_download_seconds: u64,
+    /// How often the janitor recounts keys and resets counters (None = disabled)
+    pub janitor_interval: Option<Duration>,
+    /// How often secrets past their `maxAgeSeconds` are deleted early (None = disabled)
+    pub max_age_sweep_interval: Option<Duration>,
+    /// Percentage of Redis `maxmemory` above which `/readyz` reports degraded
+    pub redis_memory_high_water_percent: u8,
+    /// Refuse to start if Redis may evict keys before their TTL
+    pub strict_eviction_check: bool,
+    /// Store secrets as hashes whose payload expires before their metadata (Redis 7.4+)
+    pub hash_storage: bool,
+    /// How long a hash-stored secret's metadata outlives its payload, at most
+    /// (`TOMBSTONE_TTL_SECONDS`, formerly `HASH_TOMBSTONE_SECONDS`)
+    pub hash_tombstone_seconds: u64,
+    /// Gzip responses for clients sending `Accept-Encoding: gzip`
+    pub compress_responses: bool,
+    /// Responses smaller than this are never compressed
+    pub compression_min_bytes: u16,
+    /// Connections served at once; further ones wait to be accepted (0 = unlimited)
+    pub max_connections: usize,
+    /// Set TCP_NODELAY on accepted connections
+    pub tcp_nodelay: bool,
+    /// Idle time before TCP keepalive probes (None = disabled)
+    pub tcp_keepalive: Option<Duration>,
+    /// How long shutdown waits for background tasks before cancelling them
+    pub shutdown_grace: Duration,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            redis_replica_url: None,
+            replica_fallback: true,
+            redis_key_prefix: String::new(),
+            id_blocklist: Vec::new(),
+            max_file_size_bytes: 2 * 1024 * 1024,
+            max_thumbnail_bytes: 64 * 1024,
+            base64_alphabet: Base64Alphabet::default(),
+            max_json_body_bytes: 0,
+            size_ttl_curve: SizeTtlCurve::default(),
+            pad_payload_buckets: PadBuckets::default(),
+            min_response_time: None,
+            request_timeout: None,
+            secret_timeout: None,
+            file_timeout: None,
+            confirmation_window_seconds: 600,
+            max_required_confirmations: 10,
+            view_context_seconds: 300,
+            admin_api_key: None,
+            allow_import_overwrite: false,
+            allow_server_decrypt: false,
+            management_signing_key: None,
+            management_signing_key_previous: None,
+            trust_proxy_headers: false,
+            force_https: ForceHttps::default(),
+            max_concurrent_uploads_per_ip: 2,
+            upload_session_seconds: 900,
+            idempotency_ttl_seconds: 0,
+            dedup_creates: false,
+            max_concurrent_peeks_per_id: 0,
+            max_access_log_entries: 0,
+            burn_grace_seconds: 0,
+            max_exists_ids: 100,
+            global_rps: 0,
+            global_burst: 0,
+            retry_after_jitter: 0.25,
+            expiration_bounds_inclusive: true,
+            expiration_granularity_seconds: 0,
+            max_embargo_seconds: 0,
+            use_redis_time: false,
+            min_password_strength: 0,
+            durable_wait_timeout: Duration::from_secs(1),
+            min_payload_length: 0,
+            enforce_origin: false,
+            reads_exhausted_conflict: false,
+            secret_events: false,
+            max_subscribers_per_id: 8,
+            max_subscribers: 1000,
+            events_stream: None,
+            events_stream_max_len: 10000,
+            migrate_legacy_on_peek: false,
+            hide_nopeek_existence: false,
+            disable_peek: DisablePeek::default(),
+            legacy_secret_ids: true,
+            diagnostic_errors: false,
+            require_user_agent: false,
+            strict_content_type: false,
+            allowed_origins: Vec::new(),
+            response_nonce: false,
+            response_checksum: false,
+            envelope_responses: false,
+            response_case: ResponseCase::default(),
+            accept_form_bodies: false,
+            trailing_slash: TrailingSlash::default(),
+            enable_status_page: false,
+            root_redirect: None,
+            public_base_url: None,
+            max_tags: 10,
+            max_tag_length: 32,
+            max_filename_length: 255,
+            allowed_extensions: Vec::new(),
+            blocked_extensions: Vec::new(),
+            check_extension_content_type: ExtensionContentTypeCheck::default(),
+            max_header_count: 0,
+            max_header_bytes: 0,
+            max_json_depth: 32,
+            strict_metadata_object: false,
+            reject_empty_metadata: false,
+            validate_ciphertext_length: true,
+            require_share_reason: false,
+            require_payload_mac: false,
+            audit_secret_creates: false,
+            redact_metadata_keys: Vec::new(),
+            audit_metadata_keys: Vec::new(),
+            secret_kinds: SecretKinds::default(),
+            policy_profiles: PolicyProfiles::default(),
+            api_keys: ApiKeys::default(),
+            usage_by_api_key: false,
+            peek_metadata_allowlist: Vec::new(),
+            max_peek_metadata_bytes: 0,
+            max_stored_value_bytes: 64 * 1024 * 1024,
+            max_download_bytes: 0,
+            compress_storage: false,
+            max_bundle_parts: 100,
+            max_total_lifetime_seconds: 0,
+            storage_format: StorageFormat::Json,
+            dedup_files: false,
+            unify_small_files: 0,
+            unify_large_secrets: 0,
+            max_decompressed_bytes: 64 * 1024 * 1024,
+            max_active_files: 0,
+            capacity_header: false,
+            staged_download_seconds: 0,
+            janitor_interval: None,
+            max_age_sweep_interval: None,
+            redis_memory_high_water_percent: 90,
+            strict_eviction_check: false,
+            hash_storage: false,
+            hash_tombstone_seconds: 86400,
+            compress_responses: false,
+            compression_min_bytes: 1024,
+            max_connections: 0,
+            tcp_nodelay: true,
+            tcp_keepalive: None,
+            shutdown_grace: Duration::from_secs(10),
+        }
+    }
+}
+
+impl Config {
+    pub fn from_env() -> Self {
+        let defaults = Self::default();
+
+        // Configurable max file size (MB) - default 2MB
+        let max_file_size_mb: usize = env_parse("MAX_FILE_SIZE_MB", 2);
+
+        let min_response_ms: u64 = env_parse("MIN_RESPONSE_MS", 0);
+        let timeout_ms = |name| {
+            let ms: u64 = env_parse(name, 0);
+            (ms > 0).then_some(Duration::from_millis(ms))
+        };
+        let durable_wait_ms: u64 = env_parse(
+            "DURABLE_WAIT_MS",
+            defaults.durable_wait_timeout.as_millis() as u64,
+        );
+
+        let janitor_secs: u64 = env_parse("JANITOR_INTERVAL_SECONDS", 0);
+        let max_age_sweep_secs: u64 = env_parse("MAX_AGE_SWEEP_SECONDS", 0);
+        let tcp_keepalive_secs: u64 = env_parse("TCP_KEEPALIVE_SECONDS", 0);
+        let shutdown_grace_secs: u64 =
+            env_parse("SHUTDOWN_GRACE_SECONDS", defaults.shutdown_grace.as_secs());
+
+        Self {
+            redis_replica_url: env_string("REDIS_REPLICA_URL"),
+            replica_fallback: env_flag_or("REDIS_REPLICA_FALLBACK", defaults.replica_fallback),
+            redis_key_prefix: env_string("REDIS_KEY_PREFIX").unwrap_or_default(),
+            id_blocklist: env_list("ID_BLOCKLIST")
+                .into_iter()
+                .map(|word| word.to_lowercase())
+                .collect(),
+            max_file_size_bytes: max_file_size_mb * 1024 * 1024,
+            max_thumbnail_bytes: env_parse("MAX_THUMBNAIL_BYTES", defaults.max_thumbnail_bytes),
+            base64_alphabet: env_parse("BASE64_ALPHABET", defaults.base64_alphabet),
+            max_json_body_bytes: env_parse("MAX_JSON_BODY_BYTES", defaults.max_json_body_bytes),
+            size_ttl_curve: env_parse("SIZE_TTL_CURVE", SizeTtlCurve::default()),
+            pad_payload_buckets: env_parse("PAD_PAYLOAD_BUCKETS", PadBuckets::default()),
+            min_response_time: (min_response_ms > 0)
+                .then_some(Duration::from_millis(min_response_ms)),
+            request_timeout: timeout_ms("REQUEST_TIMEOUT_MS"),
+            secret_timeout: timeout_ms("SECRET_TIMEOUT_MS"),
+            file_timeout: timeout_ms("FILE_TIMEOUT_MS"),
+            confirmation_window_seconds: env_parse(
+                "CONFIRMATION_WINDOW_SECONDS",
+                defaults.confirmation_window_seconds,
+            ),
+            max_required_confirmations: env_parse(
+                "MAX_REQUIRED_CONFIRMATIONS",
+                defaults.max_required_confirmations,
+            ),
+            view_context_seconds: env_parse("VIEW_CONTEXT_SECONDS", defaults.view_context_seconds),
+            admin_api_key: env_string("ADMIN_API_KEY"),
+            allow_import_overwrite: env_flag("ALLOW_IMPORT_OVERWRITE"),
+            allow_server_decrypt: env_flag("ALLOW_SERVER_DECRYPT"),
+            management_signing_key: env_string("MANAGEMENT_SIGNING_KEY"),
+            management_signing_key_previous: env_string("MANAGEMENT_SIGNING_KEY_PREVIOUS"),
+            trust_proxy_headers: env_flag("TRUST_PROXY_HEADERS"),
+            force_https: env_parse("FORCE_HTTPS", defaults.force_https),
+            max_concurrent_uploads_per_ip: env_parse(
+                "MAX_CONCURRENT_UPLOADS_PER_IP",
+                defaults.max_concurrent_uploads_per_ip,
+            ),
+            upload_session_seconds: env_parse(
+                "UPLOAD_SESSION_SECONDS",
+                defaults.upload_session_seconds,
+            ),
+            idempotency_ttl_seconds: env_parse(
+                "IDEMPOTENCY_TTL_SECONDS",
+                defaults.idempotency_ttl_seconds,
+            ),
+            dedup_creates: env_flag("DEDUP_CREATES"),
+            max_concurrent_peeks_per_id: env_parse(
+                "MAX_CONCURRENT_PEEKS_PER_ID",
+                defaults.max_concurrent_peeks_per_id,
+            ),
+            max_access_log_entries: env_parse(
+                "MAX_ACCESS_LOG_ENTRIES",
+                defaults.max_access_log_entries,
+            ),
+            burn_grace_seconds: env_parse("BURN_GRACE_SECONDS", defaults.burn_grace_seconds),
+            max_exists_ids: env_parse("MAX_EXISTS_IDS", defaults.max_exists_ids),
+            global_rps: env_parse("GLOBAL_RPS", defaults.global_rps),
+            global_burst: env_parse("GLOBAL_BURST", defaults.global_burst),
+            retry_after_jitter: env_parse("RETRY_AFTER_JITTER", defaults.retry_after_jitter)
+                .clamp(0.0, 1.0),
+            expiration_bounds_inclusive: env_flag_or(
+                "EXPIRATION_BOUNDS_INCLUSIVE",
+                defaults.expiration_bounds_inclusive,
+            ),
+            expiration_granularity_seconds: env_parse(
+                "EXPIRATION_GRANULARITY_SECONDS",
+                defaults.expiration_granularity_seconds,
+            ),
+            max_embargo_seconds: env_parse("MAX_EMBARGO_SECONDS", defaults.max_embargo_seconds),
+            use_redis_time: env_flag("USE_REDIS_TIME"),
+            min_password_strength: env_parse(
+                "MIN_PASSWORD_STRENGTH",
+                defaults.min_password_strength,
+            ),
+            durable_wait_timeout: Duration::from_millis(durable_wait_ms),
+            min_payload_length: env_parse("MIN_PAYLOAD_LENGTH", defaults.min_payload_length),
+            enforce_origin: env_flag("ENFORCE_ORIGIN"),
+            reads_exhausted_conflict: env_flag("READS_EXHAUSTED_CONFLICT"),
+            secret_events: env_flag("SECRET_EVENTS"),
+            max_subscribers_per_id: env_parse(
+                "MAX_SUBSCRIBERS_PER_ID",
+                defaults.max_subscribers_per_id,
+            ),
+            max_subscribers: env_parse("MAX_SUBSCRIBERS", defaults.max_subscribers),
+            events_stream: env_string("EVENTS_STREAM"),
+            events_stream_max_len: env_parse(
+                "EVENTS_STREAM_MAXLEN",
+                defaults.events_stream_max_len,
+            ),
+            migrate_legacy_on_peek: env_flag("MIGRATE_LEGACY_ON_PEEK"),
+            hide_nopeek_existence: env_flag("HIDE_NOPEEK_EXISTENCE"),
+            disable_peek: env_parse("DISABLE_PEEK", defaults.disable_peek),
+            legacy_secret_ids: env_flag_or("LEGACY_SECRET_IDS", defaults.legacy_secret_ids),
+            diagnostic_errors: env_flag("DIAGNOSTIC_ERRORS"),
+            require_user_agent: env_flag("REQUIRE_USER_AGENT"),
+            strict_content_type: env_flag("STRICT_CONTENT_TYPE"),
+            allowed_origins: env_list("ALLOWED_ORIGINS")
+                .into_iter()
+                .map(|origin| origin.trim_end_matches('/').to_string())
+                .collect(),
+            response_nonce: env_flag("RESPONSE_NONCE"),
+            response_checksum: env_flag("RESPONSE_CHECKSUM"),
+            envelope_responses: env_flag("ENVELOPE_RESPONSES"),
+            response_case: env_parse("RESPONSE_CASE", defaults.response_case),
+            accept_form_bodies: env_flag("ACCEPT_FORM_BODIES"),
+            trailing_slash: env_parse("TRAILING_SLASH", defaults.trailing_slash),
+            enable_status_page: env_flag("ENABLE_STATUS_PAGE"),
+            root_redirect: env_string("ROOT_REDIRECT"),
+            public_base_url: env_string("PUBLIC_BASE_URL")
+                .map(|url| url.trim_end_matches('/').to_string()),
+            max_tags: env_parse("MAX_TAGS", defaults.max_tags),
+            max_tag_length: env_parse("MAX_TAG_LENGTH", defaults.max_tag_length),
+            max_filename_length: env_parse("MAX_FILENAME_LENGTH", defaults.max_filename_length),
+            allowed_extensions: env_extensions("ALLOWED_EXTENSIONS"),
+            blocked_extensions: env_extensions("BLOCKED_EXTENSIONS"),
+            check_extension_content_type: env_parse(
+                "CHECK_EXTENSION_CONTENT_TYPE",
+                defaults.check_extension_content_type,
+            ),
+            max_header_count: env_parse("MAX_HEADER_COUNT", defaults.max_header_count),
+            max_header_bytes: env_parse("MAX_HEADER_BYTES", defaults.max_header_bytes),
+            max_json_depth: env_parse("MAX_JSON_DEPTH", defaults.max_json_depth),
+            strict_metadata_object: env_flag("STRICT_METADATA_OBJECT"),
+            reject_empty_metadata: env_flag("REJECT_EMPTY_METADATA"),
+            validate_ciphertext_length: env_flag_or(
+                "VALIDATE_CIPHERTEXT_LENGTH",
+                defaults.validate_ciphertext_length,
+            ),
+            require_share_reason: env_flag("REQUIRE_SHARE_REASON"),
+            require_payload_mac: env_flag("REQUIRE_PAYLOAD_MAC"),
+            audit_secret_creates: env_flag("AUDIT_SECRET_CREATES"),
+            redact_metadata_keys: env_list("REDACT_METADATA_KEYS"),
+            audit_metadata_keys: env_list("AUDIT_METADATA_KEYS"),
+            secret_kinds: env_parse("SECRET_KINDS", SecretKinds::default()),
+            policy_profiles: env_parse("POLICY_PROFILES", PolicyProfiles::default()),
+            api_keys: env_parse("API_KEYS", ApiKeys::default()),
+            usage_by_api_key: env_flag("USAGE_BY_API_KEY"),
+            peek_metadata_allowlist: env_list("PEEK_METADATA_ALLOWLIST"),
+            max_peek_metadata_bytes: env_parse(
+                "MAX_PEEK_METADATA_BYTES",
+                defaults.max_peek_metadata_bytes,
+            ),
+            max_stored_value_bytes: env_parse(
+                "MAX_STORED_VALUE_BYTES",
+                defaults.max_stored_value_bytes,
+            ),
+            max_download_bytes: env_parse("MAX_DOWNLOAD_BYTES", defaults.max_download_bytes),
+            compress_storage: env_flag("COMPRESS_STORAGE"),
+            max_bundle_parts: env_parse("MAX_BUNDLE_PARTS", defaults.max_bundle_parts),
+            max_total_lifetime_seconds: env_parse(
+                "MAX_TOTAL_LIFETIME_SECONDS",
+                defaults.max_total_lifetime_seconds,
+            ),
+            storage_format: env_parse("STORAGE_FORMAT", defaults.storage_format),
+            dedup_files: env_flag("DEDUP_FILES"),
+            unify_small_files: env_parse("UNIFY_SMALL_FILES", defaults.unify_small_files),
+            unify_large_secrets: env_parse("UNIFY_LARGE_SECRETS", defaults.unify_large_secrets),
+            max_decompressed_bytes: env_parse(
+                "MAX_DECOMPRESSED_BYTES",
+                defaults.max_decompressed_bytes,
+            ),
+            max_active_files: env_parse("MAX_ACTIVE_FILES", defaults.max_active_files),
+            capacity_header: env_flag("CAPACITY_HEADER"),
+            staged_download_seconds: env_parse(
+                "STAGED_DOWNLOAD_SECONDS",
+                defaults.staged_download_seconds,
+            ),
+            janitor_interval: (janitor_secs > 0).then_some(Duration::from_secs(janitor_secs)),
+            max_age_sweep_interval: (max_age_sweep_secs > 0)
+                .then_some(Duration::from_secs(max_age_sweep_secs)),
+            redis_memory_high_water_percent: env_parse(
+                "REDIS_MEMORY_HIGH_WATER_PERCENT",
+                defaults.redis_memory_high_water_percent,
+            ),
+            strict_eviction_check: env_flag("STRICT_EVICTION_CHECK"),
+            hash_storage: env_flag("HASH_STORAGE"),
+            hash_tombstone_seconds: env_parse(
+                "TOMBSTONE_TTL_SECONDS",
+                env_parse("HASH_TOMBSTONE_SECONDS", defaults.hash_tombstone_seconds),
+            ),
+            compress_responses: env_flag("COMPRESS_RESPONSES"),
+            compression_min_bytes: env_parse(
+                "COMPRESSION_MIN_BYTES",
+                defaults.compression_min_bytes,
+            ),
+            max_connections: env_parse("MAX_CONNECTIONS", defaults.max_connections),
+            tcp_nodelay: env_flag_or("TCP_NODELAY", defaults.tcp_nodelay),
+            tcp_keepalive: (tcp_keepalive_secs > 0)
+                .then_some(Duration::from_secs(tcp_keepalive_secs)),
+            shutdown_grace: Duration::from_secs(shutdown_grace_secs),
+        }
+    }
+
+    /// Body limit for upload routes. Generous on purpose (2x the file size
+    /// for base64 and JSON overhead, at least 10MB): handlers enforce the
+    /// real file size limit with a proper error.
+    pub fn upload_body_limit(&self) -> usize {
+        std::cmp::max(10 * 1024 * 1024, self.max_file_size_bytes * 2)
+    }
+
+    /// Body limit for every other route: `MAX_JSON_BODY_BYTES` when set,
+    /// otherwise the upload limit.
+    pub fn json_body_limit(&self) -> usize {
+        match self.max_json_body_bytes {
+            0 => self.upload_body_limit(),
+            max => max,
+        }
+    }
+
+    /// Keys a management link may be signed with, current first. Empty when
+    /// management links are disabled, even if a previous key is still set.
+    pub fn management_keys(&self) -> Vec<&str> {
+        match &self.management_signing_key {
+            Some(current) => std::iter::once(current.as_str())
+                .chain(self.management_signing_key_previous.as_deref())
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Optional client-facing features and whether this deployment has them
+    /// on, for `GET /v1/features`. Only on/off, never the configured values.
+    /// `emergencyMaxTtl` is added by the handler, as it lives in Redis.
+    pub fn features(&self) -> BTreeMap<&'static str, bool> {
+        BTreeMap::from([
+            ("accessLog", self.max_access_log_entries > 0),
+            ("apiKeys", !self.api_keys.is_empty()),
+            ("burnGrace", self.burn_grace_seconds > 0),
+            ("fileRefs", self.dedup_files),
+            ("formBodies", self.accept_form_bodies),
+            ("managementLinks", self.management_signing_key.is_some()),
+            ("passwordStrength", self.min_password_strength > 0),
+            ("peek", self.disable_peek == DisablePeek::Off),
+            ("qrCodes", self.public_base_url.is_some()),
+            ("secretEvents", self.secret_events),
+            ("secretKinds", !self.secret_kinds.is_empty()),
+            ("stagedDownloads", self.staged_download_seconds > 0),
+            ("statusPage", self.enable_status_page),
+            (
+                "unifiedStorage",
+                self.unify_small_files > 0 || self.unify_large_secrets > 0,
+            ),
+        ])
+    }
+
+    pub fn secret_storage(&self) -> SecretStorage {
+        if self.hash_storage {
+            SecretStorage::Hash {
+                tombstone: self.hash_tombstone_seconds,
+            }
+        } else {
+            SecretStorage::String {
+                format: self.storage_format,
+            }
+        }
+    }
+}
+
+fn env_parse<T: FromStr>(key: &str, default: T) -> T {
+    env::var(key)
+        .ok()
+        .and_then(|v| v.trim().parse().ok())
+        .unwrap_or(default)
+}
+
+fn env_string(key: &str) -> Option<String> {
+    env::var(key)
+        .ok()
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+}
+
+/// Comma-separated list, with entries trimmed and empty entries dropped.
+fn env_list(key: &str) -> Vec<String> {
+    env_string(key)
+        .map(|v| {
+            v.split(',')
+                .map(|item| item.trim().to_string())
+                .filter(|item| !item.is_empty())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// File extensions normalized to lowercase without a leading dot.
+fn env_extensions(key: &str) -> Vec<String> {
+    env_list(key)
+        .into_iter()
+        .map(|ext| ext.trim_start_matches('.').to_ascii_lowercase())
+        .collect()
+}
+
+fn env_flag(key: &str) -> bool {
+    env_flag_or(key, false)
+}
+
+/// A flag that may default to on: "0", "false" or "no" turn it off, and
+/// anything unrecognized keeps the default.
+fn env_flag_or(key: &str, default: bool) -> bool {
+    match env::var(key).as_deref().map(str::trim) {
+        Ok("1" | "true" | "TRUE" | "yes") => true,
+        Ok("0" | "false" | "FALSE" | "no") => false,
+        _ => default,
+    }
+}
@@ -1,120 +1,2617 @@
-use crate::models::{FileMetadata, StoredFile, StoredSecret};
-use redis::{AsyncCommands, Client};
-use std::time::{SystemTime, UNIX_EPOCH};
-use uuid::Uuid;
+use crate::models::{
+    BundlePart, BurnedSecretPeek, CryptoParams, SecretReplaceRequest, StoredBundle, StoredFile,
+    StoredSecret,
+};
+use redis::{aio::MultiplexedConnection, AsyncCommands, Client, FromRedisValue, ToRedisArgs};
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::time::Duration;
+
+mod compress;
+mod format;
+mod hash;
+mod keys;
+
+pub use format::StorageFormat;
+
+pub use keys::{
+    secret_id_bytes, secret_id_from_bytes, Keys, ACCESS_DROPPED_SUFFIX, ACCESS_LOG_SUFFIX,
+    ACTIVE_PEEKS_SUFFIX, BURNED_SUFFIX, CHUNKS_SUFFIX, CONFIRMATIONS_SUFFIX, CONFIRM_TOKENS_SUFFIX,
+    PEEKS_SUFFIX, READS_SUFFIX, STAGED_SUFFIX, THUMBNAIL_SUFFIX, VIEW_CONTEXT_SUFFIX,
+};
+use keys::{BUNDLE_ID_PREFIX, FILE_ID_PREFIX, SECRET_ID_PREFIX, UPLOAD_ID_PREFIX};
+
+/// Outcome of a read that may be refused without consuming the secret.
+#[derive(Debug)]
+pub enum SecretRead<T> {
+    Found(T),
+    Refused(Refusal),
+}
+
+/// A secret released by a burn read.
+#[derive(Debug)]
+pub struct BurnedSecret {
+    pub encrypted_secret: String,
+    /// 0 for legacy plain-string secrets
+    pub created_at: u64,
+    pub metadata: Option<serde_json::Value>,
+    /// Unix timestamp of the GETDEL that removed the secret
+    pub burned_at: u64,
+    /// Number of peeks before the burn (0 for secrets that predate peek counting)
+    pub peek_count: u64,
+    /// Burn reads left for a `maxReads` secret; at 0 this read deleted it
+    pub reads_remaining: Option<u32>,
+    pub crypto: Option<CryptoParams>,
+    pub kind: Option<String>,
+    /// The secret refused peeks, so nothing about it may outlive the burn
+    pub no_peek: bool,
+}
+
+/// Why a read failed.
+#[derive(Debug)]
+pub enum ReadError {
+    Redis(redis::RedisError),
+    /// The stored value is over `MAX_STORED_VALUE_BYTES`; it was left unread
+    TooLarge(u64),
+    /// The stored value inflates past `MAX_DECOMPRESSED_BYTES`
+    DecompressionLimit(u64),
+}
+
+impl From<redis::RedisError> for ReadError {
+    fn from(e: redis::RedisError) -> Self {
+        ReadError::Redis(e)
+    }
+}
+
+impl From<compress::DecompressError> for ReadError {
+    fn from(e: compress::DecompressError) -> Self {
+        match e {
+            compress::DecompressError::TooLarge(limit) => ReadError::DecompressionLimit(limit),
+            compress::DecompressError::Corrupt(e) => ReadError::Redis(redis::RedisError::from((
+                redis::ErrorKind::TypeError,
+                "Decompression error",
+                e,
+            ))),
+        }
+    }
+}
+
+impl std::fmt::Display for ReadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReadError::Redis(e) => write!(f, "{}", e),
+            ReadError::TooLarge(bytes) => write!(f, "stored value is {} bytes", bytes),
+            ReadError::DecompressionLimit(bytes) => {
+                write!(f, "stored value inflates past {} bytes", bytes)
+            }
+        }
+    }
+}
+
+/// Where read-only lookups go when a replica is configured
+/// (`REDIS_REPLICA_URL`). Replication is asynchronous, so with `fallback` a
+/// miss on the replica is retried on the primary: an entry created moments
+/// ago may not have reached the replica yet.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Replica<'a> {
+    pub client: Option<&'a Client>,
+    pub fallback: bool,
+}
+
+/// What a secret read needs besides the secret's id: the request's context
+/// and the limits it's held to. Shared by `get_secret` and `peek_secret`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ReadOptions<'a> {
+    /// Unix time the read happens at, for embargoes and read windows
+    pub now: u64,
+    /// Checked against the secret's `allowedCidrs`
+    pub client_ip: Option<IpAddr>,
+    /// Largest stored value the read will load (0 = unlimited)
+    pub max_value_bytes: u64,
+    /// How long a reader confirmation counts (burns only)
+    pub confirmation_window: u64,
+    /// Token the reader presented for a `bindViewContext` secret (burns only)
+    pub view_context: Option<&'a str>,
+    /// Where the read may be served from (peeks only: burns take the primary)
+    pub replica: Replica<'a>,
+    /// Re-store a legacy plain-string secret as a record in this format when
+    /// it's peeked (`MIGRATE_LEGACY_ON_PEEK`; peeks only)
+    pub migrate_legacy: Option<StorageFormat>,
+}
+
+/// Why a secret was not released. Apart from `ReadsExhausted`, which is about
+/// a secret already gone, the stored value is left untouched.
+#[derive(Debug)]
+pub enum Refusal {
+    /// The reader's IP is outside the secret's `allowedCidrs`
+    IpNotAllowed,
+    /// The secret is embargoed until the given unix timestamp
+    NotYetAvailable(u64),
+    /// The secret's `maxAgeSeconds` read window closed at the given unix timestamp
+    PastMaxAge(u64),
+    /// The secret needs more distinct reader confirmations before it can be burned
+    ConfirmationsPending { required: u32, collected: u64 },
+    /// Every read slot of a `maxReads` secret was taken (as opposed to an id
+    /// that never existed)
+    ReadsExhausted { max_reads: u32 },
+    /// A peek at a `noPeek` secret
+    PeekNotAllowed,
+    /// A burn of a `bindViewContext` secret without the token issued to the
+    /// client that peeked it, or after that token's window
+    ViewContextMismatch,
+}
+
+/// Outcome of an in-place payload replace.
+#[derive(Debug, PartialEq)]
+pub enum Replace {
+    Replaced,
+    /// The secret doesn't exist (never did, expired or burned)
+    NotFound,
+    /// A reader has already confirmed the secret, or taken one of its
+    /// `maxReads` reads, so it has been seen
+    AlreadyRead,
+    /// The record changed after the caller's `If-Unmodified-Since`; carries
+    /// its last-modified time
+    Modified(u64),
+}
+
+/// Outcome of a management-link TTL reset.
+#[derive(Debug, PartialEq)]
+pub enum Extend {
+    Extended,
+    /// The secret doesn't exist (never did, expired or burned)
+    NotFound,
+    /// The record changed after the caller's `If-Unmodified-Since`; carries
+    /// its last-modified time
+    Modified(u64),
+}
+
+/// Outcome of storing one chunk of a chunked upload.
+#[derive(Debug, PartialEq)]
+pub enum ChunkWrite {
+    /// Stored; the upload's chunks now total this many characters
+    Stored(u64),
+    /// The session doesn't exist (never did, abandoned or completed)
+    NotFound,
+    /// The chunk would take the upload past its size limit, so it wasn't stored
+    TooLarge,
+}
+
+/// Outcome of completing a chunked upload.
+#[derive(Debug, PartialEq)]
+pub enum UploadTake {
+    /// The session record and its chunks joined in order; both keys are gone
+    Complete { session: String, data: String },
+    /// The session doesn't exist (never did, abandoned or completed)
+    NotFound,
+    /// Some of the declared chunks are missing, or extra ones were sent;
+    /// the session is left as it was
+    Incomplete,
+}
+
+/// How secrets are laid out in Redis.
+#[derive(Clone, Copy, Debug)]
+pub enum SecretStorage {
+    /// One string per secret, holding the record encoded as `format`
+    String { format: StorageFormat },
+    /// A hash whose payload field expires `tombstone` seconds before the
+    /// metadata field, which a burn also cuts down to `tombstone` seconds
+    /// (see `hash`); needs Redis 7.4+ for HEXPIRE
+    Hash { tombstone: u64 },
+}
+
+/// File records are plain strings too, as far as the read helpers shared with
+/// secrets are concerned. Those never look at the format: reads detect it.
+const FILE_LAYOUT: SecretStorage = SecretStorage::String {
+    format: StorageFormat::Json,
+};
+
+/// Why a write failed.
+#[derive(Debug)]
+pub enum StoreError {
+    Redis(redis::RedisError),
+    /// No replica acknowledged a durable write in time; the write was rolled back
+    NotDurable,
+    /// A file referenced a deduplicated blob (`contentHash`) that's gone
+    BlobNotFound,
+}
+
+impl From<redis::RedisError> for StoreError {
+    fn from(e: redis::RedisError) -> Self {
+        StoreError::Redis(e)
+    }
+}
+
+impl std::fmt::Display for StoreError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StoreError::Redis(e) => write!(f, "{}", e),
+            StoreError::NotDurable => write!(f, "no replica acknowledged the write"),
+            StoreError::BlobNotFound => write!(f, "the referenced blob no longer exists"),
+        }
+    }
+}
 
 pub async fn get_redis_client(redis_url: &str) -> Result<Client, redis::RedisError> {
     Client::open(redis_url)
 }
 
-fn generate_short_id() -> String {
-    let uuid = Uuid::new_v4();
-    bs58::encode(uuid.as_bytes()).into_string()
+/// PING Redis and read `INFO memory`, for readiness probes.
+pub async fn memory_info(client: &Client) -> Result<String, redis::RedisError> {
+    let mut conn = client.get_multiplexed_async_connection().await?;
+    let _: String = redis::cmd("PING").query_async(&mut conn).await?;
+    redis::cmd("INFO")
+        .arg("memory")
+        .query_async(&mut conn)
+        .await
+}
+
+/// `INFO server`, for the Redis version.
+pub async fn server_info(client: &Client) -> Result<String, redis::RedisError> {
+    let mut conn = client.get_multiplexed_async_connection().await?;
+    redis::cmd("INFO")
+        .arg("server")
+        .query_async(&mut conn)
+        .await
+}
+
+/// The Redis server's clock, in whole Unix seconds.
+pub async fn server_time(client: &Client) -> Result<u64, redis::RedisError> {
+    let mut conn = client.get_multiplexed_async_connection().await?;
+    let (seconds, _micros): (u64, u64) = redis::cmd("TIME").query_async(&mut conn).await?;
+    Ok(seconds)
+}
+
+/// The server's `maxmemory-policy`, or None if CONFIG GET returned nothing.
+pub async fn maxmemory_policy(client: &Client) -> Result<Option<String>, redis::RedisError> {
+    let mut conn = client.get_multiplexed_async_connection().await?;
+    let reply: Vec<String> = redis::cmd("CONFIG")
+        .arg("GET")
+        .arg("maxmemory-policy")
+        .query_async(&mut conn)
+        .await?;
+    Ok(reply.into_iter().nth(1))
+}
+
+/// The server's `notify-keyspace-events` flags, or None if CONFIG GET
+/// returned nothing.
+pub async fn keyspace_events(client: &Client) -> Result<Option<String>, redis::RedisError> {
+    let mut conn = client.get_multiplexed_async_connection().await?;
+    let reply: Vec<String> = redis::cmd("CONFIG")
+        .arg("GET")
+        .arg("notify-keyspace-events")
+        .query_async(&mut conn)
+        .await?;
+    Ok(reply.into_iter().nth(1))
+}
+
+/// Ids regenerated before giving up on a write that keeps colliding.
+const MAX_ID_ATTEMPTS: usize = 3;
+
+/// SET NX EX: replies OK, or nil if the key is taken.
+fn set_new(key: &str, value: impl ToRedisArgs, expiration: u64) -> redis::Cmd {
+    let mut cmd = redis::cmd("SET");
+    cmd.arg(key).arg(value).arg("NX").arg("EX").arg(expiration);
+    cmd
+}
+
+/// Store an entry under a freshly generated `<prefix>-<id>` id, keyed by
+/// `key_for(id)`. `write(key)` must only create the key if it is free (like
+/// SET NX), so an id collision regenerates the id instead of clobbering an
+/// existing entry. With `durable_wait`, also waits that long for a replica to
+/// acknowledge the write.
+async fn store_new(
+    conn: &mut MultiplexedConnection,
+    keys: &Keys,
+    prefix: &str,
+    key_for: impl Fn(&str) -> String,
+    write: impl Fn(&str) -> redis::Cmd,
+    durable_wait: Option<Duration>,
+) -> Result<String, StoreError> {
+    for _ in 0..MAX_ID_ATTEMPTS {
+        let id = keys.new_id(prefix);
+        let key = key_for(&id);
+        let set: Option<String> = write(&key).query_async(conn).await?;
+        if set.is_none() {
+            tracing::warn!("Id collision on {}-, regenerating", prefix);
+            continue;
+        }
+
+        if let Some(timeout) = durable_wait {
+            if let Err(e) = wait_replicated(conn, timeout).await {
+                let _: () = conn.del(&key).await?;
+                return Err(e);
+            }
+        }
+
+        return Ok(id);
+    }
+
+    Err(StoreError::Redis(redis::RedisError::from((
+        redis::ErrorKind::ClientError,
+        "Id collision",
+        format!("no free {}- id after {} attempts", prefix, MAX_ID_ATTEMPTS),
+    ))))
+}
+
+/// Wait up to `timeout` for a replica to acknowledge the writes made on
+/// `conn` so far.
+async fn wait_replicated(
+    conn: &mut MultiplexedConnection,
+    timeout: Duration,
+) -> Result<(), StoreError> {
+    let acked: i64 = redis::cmd("WAIT")
+        .arg(1)
+        .arg(timeout.as_millis() as u64)
+        .query_async(conn)
+        .await?;
+    check_replicated(acked)
+}
+
+/// Interpret a WAIT reply: a durable write needs at least one replica ack.
+fn check_replicated(acked: i64) -> Result<(), StoreError> {
+    if acked < 1 {
+        return Err(StoreError::NotDurable);
+    }
+    Ok(())
+}
+
+/// Burns a `peekBurn` secret as part of its first peek: GETDEL the record and
+/// drop its peek counter in one step, so concurrent peeks can't both get it.
+const PEEK_BURN_SCRIPT: &str = r#"
+local value = redis.call('GETDEL', KEYS[1])
+if value then
+    redis.call('DEL', KEYS[2])
+end
+return value
+"#;
+
+/// Prepends ARGV[1] to the access log at KEYS[1], trimming it to the newest
+/// ARGV[2] entries and adding the number trimmed to KEYS[2]. Both keys live
+/// at least ARGV[3] seconds; an existing longer TTL is kept. Replies the
+/// total dropped so far.
+const RECORD_ACCESS_SCRIPT: &str = r#"
+local len = redis.call('LPUSH', KEYS[1], ARGV[1])
+local max = tonumber(ARGV[2])
+if len > max then
+    redis.call('LTRIM', KEYS[1], 0, max - 1)
+    redis.call('INCRBY', KEYS[2], len - max)
+end
+local ttl = tonumber(ARGV[3])
+if redis.call('TTL', KEYS[1]) < ttl then
+    redis.call('EXPIRE', KEYS[1], ttl)
+end
+if redis.call('EXISTS', KEYS[2]) == 1 then
+    redis.call('EXPIRE', KEYS[2], redis.call('TTL', KEYS[1]))
+end
+return tonumber(redis.call('GET', KEYS[2]) or '0')
+"#;
+
+/// Binds the burn of secret KEYS[1] to a view context kept in KEYS[2].
+/// Unbound, it takes the fresh token ARGV[1]; once bound, only a peek
+/// presenting the bound token (ARGV[2]) renews it. Either way the token is
+/// good until ARGV[3] (unix seconds), and the binding expires with the
+/// secret. Replies the token, or nil if the secret is gone or bound to
+/// another context.
+const BIND_VIEW_CONTEXT_SCRIPT: &str = r#"
+local ttl = redis.call('TTL', KEYS[1])
+if ttl == -2 then
+    return false
+end
+local bound = redis.call('HGET', KEYS[2], 'token')
+if bound and bound ~= ARGV[2] then
+    return false
+end
+local token = bound or ARGV[1]
+redis.call('HSET', KEYS[2], 'token', token, 'until', ARGV[3])
+if ttl > 0 then
+    redis.call('EXPIRE', KEYS[2], ttl)
+end
+return token
+"#;
+
+/// Stores chunk ARGV[2] at index ARGV[1] of the upload session KEYS[1] in
+/// the hash KEYS[2], unless the chunks would then total over ARGV[3]
+/// characters. The hash expires with the session, so an abandoned upload's
+/// chunks go when it does. Replies the new total, -1 without a session or -2
+/// over the limit.
+const UPLOAD_CHUNK_SCRIPT: &str = r#"
+local ttl = redis.call('PTTL', KEYS[1])
+if ttl < 0 then
+    return -1
+end
+local total = tonumber(redis.call('HGET', KEYS[2], 'bytes') or '0')
+total = total - redis.call('HSTRLEN', KEYS[2], ARGV[1]) + string.len(ARGV[2])
+if total > tonumber(ARGV[3]) then
+    return -2
+end
+redis.call('HSET', KEYS[2], ARGV[1], ARGV[2], 'bytes', total)
+redis.call('PEXPIRE', KEYS[2], ttl)
+return total
+"#;
+
+/// Takes the upload session KEYS[1] with chunks 0..ARGV[1] from KEYS[2],
+/// deleting both, but only when exactly those chunks are present. Replies
+/// nil without a session, {session} when incomplete (nothing is deleted),
+/// or {session, joined chunks}.
+const TAKE_UPLOAD_SCRIPT: &str = r#"
+local session = redis.call('GET', KEYS[1])
+if not session then
+    return false
+end
+local count = tonumber(ARGV[1])
+if redis.call('HLEN', KEYS[2]) ~= count + 1 then
+    return {session}
+end
+local chunks = {}
+for i = 0, count - 1 do
+    local chunk = redis.call('HGET', KEYS[2], tostring(i))
+    if not chunk then
+        return {session}
+    end
+    chunks[#chunks + 1] = chunk
+end
+redis.call('DEL', KEYS[1], KEYS[2])
+return {session, table.concat(chunks)}
+"#;
+
+/// Deletes the chunk hash KEYS[2] if its session KEYS[1] is gone. Re-checked
+/// here because SCAN may have missed a session created mid-sweep.
+const SWEEP_CHUNKS_SCRIPT: &str = r#"
+if redis.call('EXISTS', KEYS[1]) == 1 then
+    return 0
+end
+return redis.call('DEL', KEYS[2])
+"#;
+
+/// Rewrites a legacy plain-string secret (KEYS[1]) as the record in ARGV[2],
+/// keeping its TTL, but only while it still holds exactly the plain payload
+/// in ARGV[1]. A concurrent burn, a JSON record or a hash is left alone.
+const MIGRATE_LEGACY_SCRIPT: &str = r#"
+if redis.call('TYPE', KEYS[1]).ok ~= 'string' then
+    return 0
+end
+if redis.call('GET', KEYS[1]) ~= ARGV[1] then
+    return 0
+end
+redis.call('SET', KEYS[1], ARGV[2], 'KEEPTTL')
+return 1
+"#;
+
+/// Adds secret id ARGV[1] to the owner set KEYS[1], which then lives at
+/// least ARGV[2] seconds: as long as the longest-lived secret in it.
+const RECORD_OWNED_SCRIPT: &str = r#"
+redis.call('SADD', KEYS[1], ARGV[1])
+if redis.call('TTL', KEYS[1]) < tonumber(ARGV[2]) then
+    redis.call('EXPIRE', KEYS[1], ARGV[2])
+end
+"#;
+
+/// Claims the create dedup record KEYS[1] for ARGV[1] seconds with a
+/// pending marker if there is none; otherwise replies its {id, response},
+/// both nil while another create holds the claim.
+const CLAIM_DEDUP_SCRIPT: &str = r#"
+if redis.call('EXISTS', KEYS[1]) == 0 then
+    redis.call('HSET', KEYS[1], 'pending', 1)
+    redis.call('EXPIRE', KEYS[1], ARGV[1])
+    return false
+end
+return redis.call('HMGET', KEYS[1], 'id', 'response')
+"#;
+
+/// Replaces the create dedup record KEYS[1] with a fresh claim for ARGV[2]
+/// seconds if it still records the gone secret ARGV[1]. Replies 1 if
+/// claimed, 0 if another create got there first.
+const RECLAIM_DEDUP_SCRIPT: &str = r#"
+if redis.call('HGET', KEYS[1], 'id') ~= ARGV[1] then
+    return 0
+end
+redis.call('DEL', KEYS[1])
+redis.call('HSET', KEYS[1], 'pending', 1)
+redis.call('EXPIRE', KEYS[1], ARGV[2])
+return 1
+"#;
+
+/// Deletes the create dedup record KEYS[1] unless a response was recorded.
+const RELEASE_DEDUP_SCRIPT: &str = r#"
+if redis.call('HEXISTS', KEYS[1], 'id') == 0 then
+    redis.call('DEL', KEYS[1])
+end
+"#;
+
+/// Takes one burn read of a `maxReads` secret (KEYS[1]) out of ARGV[1],
+/// counting reads at KEYS[2]. The counter gets the secret's remaining TTL on
+/// the first read and outlives the last one, which deletes the record, so
+/// readers that lose the race can be told the slots ran out. Replies
+/// {status, record, reads taken}: 1 = read taken, 2 = slots already
+/// exhausted, 0 = no such secret.
+const TAKE_READ_SCRIPT: &str = r#"
+local value = redis.call('GET', KEYS[1])
+if not value then
+    local taken = redis.call('GET', KEYS[2])
+    if taken then
+        return {2, false, tonumber(taken)}
+    end
+    return {0, false, 0}
+end
+local taken = redis.call('INCR', KEYS[2])
+if taken == 1 then
+    redis.call('EXPIRE', KEYS[2], math.max(redis.call('TTL', KEYS[1]), 1))
+end
+if taken >= tonumber(ARGV[1]) then
+    redis.call('DEL', KEYS[1])
+end
+return {1, value, taken}
+"#;
+
+/// Records a confirmation for the token in ARGV[1], which must have been
+/// issued by a peek into the hash at KEYS[1]. The reader it was issued to
+/// goes into the sorted set at KEYS[2] scored ARGV[2], which then expires
+/// with the secret after ARGV[3] seconds. Replies the confirmations scored
+/// from ARGV[4] on, or -1 for a token that was never issued.
+const CONFIRM_SCRIPT: &str = r#"
+local reader = redis.call('HGET', KEYS[1], ARGV[1])
+if not reader then
+    return -1
+end
+redis.call('ZADD', KEYS[2], ARGV[2], reader)
+redis.call('EXPIRE', KEYS[2], ARGV[3])
+return redis.call('ZCOUNT', KEYS[2], ARGV[4], '+inf')
+"#;
+
+/// Burns a string-stored multi-confirmation secret (KEYS[1]) only while the
+/// sorted set at KEYS[2] holds at least ARGV[2] confirmations scored from
+/// ARGV[1] on, so none can lapse between the check and the take. The
+/// confirmations and issued tokens (KEYS[3]) go with it. Replies {1 if the
+/// threshold was met, confirmations counted, record}.
+const TAKE_CONFIRMED_SCRIPT: &str = r#"
+local collected = redis.call('ZCOUNT', KEYS[2], ARGV[1], '+inf')
+if collected < tonumber(ARGV[2]) then
+    return {0, collected, false}
+end
+redis.call('DEL', KEYS[2], KEYS[3])
+return {1, collected, redis.call('GETDEL', KEYS[1])}
+"#;
+
+/// Overwrites a string-stored secret (KEYS[1]) with ARGV[1], keeping its TTL,
+/// unless it's gone (0), has been seen (2): reader confirmations at KEYS[2],
+/// or burn reads of a `maxReads` secret counted at KEYS[3], or, given ARGV[2],
+/// is no longer that record (3). A string-stored record may be bincode or
+/// gzipped, which Lua can't read, so `If-Unmodified-Since` is judged on the
+/// record before the call and the script only checks it's still the one.
+const REPLACE_SCRIPT: &str = r#"
+if redis.call('EXISTS', KEYS[1]) == 0 then
+    return 0
+end
+if redis.call('EXISTS', KEYS[2], KEYS[3]) > 0 then
+    return 2
+end
+if ARGV[2] and redis.call('GET', KEYS[1]) ~= ARGV[2] then
+    return 3
+end
+redis.call('SET', KEYS[1], ARGV[1], 'KEEPTTL')
+return 1
+"#;
+
+/// Resets a string-stored secret's (KEYS[1]) TTL to ARGV[1] seconds, unless
+/// it's gone (0) or, given ARGV[2], is no longer that record (3), as in
+/// `REPLACE_SCRIPT`.
+const EXTEND_SCRIPT: &str = r#"
+if redis.call('EXISTS', KEYS[1]) == 0 then
+    return 0
+end
+if ARGV[2] and redis.call('GET', KEYS[1]) ~= ARGV[2] then
+    return 3
+end
+redis.call('EXPIRE', KEYS[1], ARGV[1])
+return 1
+"#;
+
+/// Adds file ARGV[1], which expires in ARGV[2] seconds, to the references
+/// (KEYS[2]) of the deduplicated file blob at KEYS[1]. Both keys then live as
+/// long as the longest-lived file pointing at the blob. ARGV[3] is the data
+/// to create a missing blob with; without it, a missing blob replies 0.
+const BLOB_REF_SCRIPT: &str = r#"
+if redis.call('EXISTS', KEYS[1]) == 0 then
+    if not ARGV[3] then
+        return 0
+    end
+    redis.call('HSET', KEYS[1], 'data', ARGV[3])
+end
+local now = tonumber(redis.call('TIME')[1])
+redis.call('ZADD', KEYS[2], now + tonumber(ARGV[2]), ARGV[1])
+local last = redis.call('ZRANGE', KEYS[2], -1, -1, 'WITHSCORES')[2]
+redis.call('EXPIREAT', KEYS[1], last)
+redis.call('EXPIREAT', KEYS[2], last)
+return 1
+"#;
+
+/// Drops file ARGV[1]'s reference to the blob at KEYS[1] (references at
+/// KEYS[2]) and returns the blob's data, nil if the blob is gone. References
+/// of files that have expired since are dropped too, as they never release
+/// their own; the blob goes with the last reference, or otherwise lives only
+/// as long as the files still pointing at it.
+const BLOB_RELEASE_SCRIPT: &str = r#"
+local data = redis.call('HGET', KEYS[1], 'data')
+redis.call('ZREM', KEYS[2], ARGV[1])
+redis.call('ZREMRANGEBYSCORE', KEYS[2], '-inf', '(' .. redis.call('TIME')[1])
+local last = redis.call('ZRANGE', KEYS[2], -1, -1, 'WITHSCORES')[2]
+if last then
+    redis.call('EXPIREAT', KEYS[1], last)
+    redis.call('EXPIREAT', KEYS[2], last)
+else
+    redis.call('DEL', KEYS[1], KEYS[2])
+end
+return data
+"#;
+
+/// Count a peek on the counter at `key`, which shares the secret's remaining TTL.
+async fn record_peek(
+    conn: &mut MultiplexedConnection,
+    key: &str,
+    ttl: i64,
+) -> Result<(), redis::RedisError> {
+    if ttl <= 0 {
+        return Ok(());
+    }
+
+    redis::pipe()
+        .atomic()
+        .incr(key, 1)
+        .ignore()
+        .expire(key, ttl)
+        .ignore()
+        .query_async(conn)
+        .await
+}
+
+fn serialization_error(e: serde_json::Error) -> redis::RedisError {
+    redis::RedisError::from((
+        redis::ErrorKind::TypeError,
+        "Serialization error",
+        e.to_string(),
+    ))
+}
+
+/// A newly stored secret or file.
+#[derive(Debug)]
+pub struct Stored {
+    pub id: String,
+    /// The record's TTL read back once stored: its readable lifetime, which
+    /// a hash tombstone outlives
+    pub ttl_seconds: u64,
+}
+
+pub async fn store_secret(
+    client: &Client,
+    keys: &Keys,
+    storage: SecretStorage,
+    stored: &StoredSecret,
+    expiration: u64,
+    durable_wait: Option<Duration>,
+) -> Result<Stored, StoreError> {
+    let mut conn = client.get_multiplexed_async_connection().await?;
+
+    let id = match storage {
+        SecretStorage::String { format } => {
+            let value = format::encode_secret(format, stored);
+            store_new(
+                &mut conn,
+                keys,
+                SECRET_ID_PREFIX,
+                |id| keys.secret(id),
+                |key| set_new(key, &value, expiration),
+                durable_wait,
+            )
+            .await
+        }
+        SecretStorage::Hash { tombstone } => {
+            let (meta, payload) = hash::split(stored).map_err(serialization_error)?;
+            store_new(
+                &mut conn,
+                keys,
+                SECRET_ID_PREFIX,
+                |id| keys.secret(id),
+                |key| hash::create(key, &meta, payload, expiration, tombstone),
+                durable_wait,
+            )
+            .await
+        }
+    }?;
+    let ttl = secret_ttl(&mut conn, storage, &keys.secret(&id)).await?;
+    Ok(Stored {
+        id,
+        ttl_seconds: ttl.max(0) as u64,
+    })
+}
+
+/// The secret record as stored (see `format`), or a legacy plain string.
+async fn read_secret<T: FromRedisValue + From<String>>(
+    conn: &mut MultiplexedConnection,
+    storage: SecretStorage,
+    key: &str,
+) -> Result<Option<T>, redis::RedisError> {
+    match storage {
+        SecretStorage::String { .. } => conn.get(key).await,
+        SecretStorage::Hash { .. } => Ok(hash::read(conn, key).await?.map(T::from)),
+    }
+}
+
+/// Remove the secret's payload and return the record, like `read_secret`.
+async fn take_secret(
+    conn: &mut MultiplexedConnection,
+    storage: SecretStorage,
+    key: &str,
+) -> Result<Option<Vec<u8>>, redis::RedisError> {
+    match storage {
+        SecretStorage::String { .. } => redis::cmd("GETDEL").arg(key).query_async(conn).await,
+        SecretStorage::Hash { tombstone } => Ok(hash::take(conn, key, tombstone)
+            .await?
+            .map(String::into_bytes)),
+    }
+}
+
+/// `take_secret` for a multi-confirmation secret, atomically refused while
+/// fewer than `required` confirmations are scored from `window_start` on.
+/// Replies whether it was met, the confirmations counted and the record.
+async fn take_confirmed(
+    conn: &mut MultiplexedConnection,
+    storage: SecretStorage,
+    keys: &Keys,
+    id: &str,
+    window_start: u64,
+    required: u32,
+) -> Result<(bool, u64, Option<Vec<u8>>), redis::RedisError> {
+    let key = keys.secret(id);
+    match storage {
+        SecretStorage::String { .. } => {
+            let (met, collected, value): (i64, u64, Option<Vec<u8>>) =
+                redis::Script::new(TAKE_CONFIRMED_SCRIPT)
+                    .key(&key)
+                    .key(keys.confirmations(id))
+                    .key(keys.confirm_tokens(id))
+                    .arg(window_start)
+                    .arg(required)
+                    .invoke_async(conn)
+                    .await?;
+            Ok((met == 1, collected, value))
+        }
+        SecretStorage::Hash { tombstone } => {
+            let (met, collected, record) = hash::take_confirmed(
+                conn,
+                &key,
+                &keys.confirmations(id),
+                &keys.confirm_tokens(id),
+                window_start,
+                required,
+                tombstone,
+            )
+            .await?;
+            Ok((met, collected, record.map(String::into_bytes)))
+        }
+    }
+}
+
+/// A secret record, or None for a legacy plain-string secret. A binary
+/// record that doesn't decode fails the read instead of passing for one.
+fn decode_record(value: &[u8]) -> Result<Option<StoredSecret>, redis::RedisError> {
+    format::decode_secret(value).map_err(|e| {
+        redis::RedisError::from((redis::ErrorKind::TypeError, "Deserialization error", e))
+    })
+}
+
+/// A record read by `read_secret`, with a legacy plain-string secret as a
+/// record holding just its payload.
+fn secret_record(value: Vec<u8>) -> Result<StoredSecret, redis::RedisError> {
+    Ok(decode_record(&value)?.unwrap_or_else(|| StoredSecret {
+        encrypted_secret: String::from_utf8_lossy(&value).into_owned(),
+        ..Default::default()
+    }))
+}
+
+/// Remaining TTL of the secret's payload.
+async fn secret_ttl(
+    conn: &mut MultiplexedConnection,
+    storage: SecretStorage,
+    key: &str,
+) -> Result<i64, redis::RedisError> {
+    match storage {
+        SecretStorage::String { .. } => conn.ttl(key).await,
+        SecretStorage::Hash { .. } => hash::payload_ttl(conn, key).await,
+    }
+}
+
+/// Refuse values over `max_bytes` (0 = no cap) before loading them. Entries
+/// are written by this service within its own limits, so this only trips on
+/// values stored by hand, which could otherwise exhaust memory on parse.
+async fn check_value_len(
+    conn: &mut MultiplexedConnection,
+    storage: SecretStorage,
+    key: &str,
+    max_bytes: u64,
+) -> Result<(), ReadError> {
+    if max_bytes == 0 {
+        return Ok(());
+    }
+    within_value_cap(value_len(conn, storage, key).await?, max_bytes)
+}
+
+/// Bytes of the value stored at `key`, 0 when there is none.
+async fn value_len(
+    conn: &mut MultiplexedConnection,
+    storage: SecretStorage,
+    key: &str,
+) -> Result<u64, redis::RedisError> {
+    match storage {
+        SecretStorage::String { .. } => conn.strlen(key).await,
+        SecretStorage::Hash { .. } => hash::payload_len(conn, key).await,
+    }
+}
+
+/// Bytes of a secret's stored value (0 when there is none), for checking a
+/// download against `MAX_DOWNLOAD_BYTES` before reading it.
+pub async fn secret_len(
+    client: &Client,
+    keys: &Keys,
+    storage: SecretStorage,
+    id: &str,
+) -> Result<u64, redis::RedisError> {
+    let mut conn = client.get_multiplexed_async_connection().await?;
+    value_len(&mut conn, storage, &keys.secret(id)).await
+}
+
+/// Bytes of a file's stored record, like `secret_len`, plus the shared
+/// blob's data for a deduplicated file, whose record leaves the data out
+/// (`DEDUP_FILES`). Finding the blob means decoding the record, so one
+/// already over `max_bytes` is reported as it is, unread.
+pub async fn file_len(
+    client: &Client,
+    keys: &Keys,
+    id: &str,
+    max_bytes: u64,
+    max_decompressed_bytes: u64,
+) -> Result<u64, redis::RedisError> {
+    let mut conn = client.get_multiplexed_async_connection().await?;
+    let key = keys.file(id);
+    let len = value_len(&mut conn, FILE_LAYOUT, &key).await?;
+    if len == 0 || len > max_bytes {
+        return Ok(len);
+    }
+    let Some(value): Option<Vec<u8>> = conn.get(&key).await? else {
+        return Ok(0);
+    };
+    // Whatever doesn't decode here fails the read itself just the same
+    let Some(hash) = compress::decode(value, max_decompressed_bytes)
+        .ok()
+        .and_then(|record| format::decode_file(&record).ok())
+        .and_then(|stored| stored.content_hash)
+    else {
+        return Ok(len);
+    };
+    let blob_len: u64 = redis::cmd("HSTRLEN")
+        .arg(keys.blob(&hash))
+        .arg("data")
+        .query_async(&mut conn)
+        .await?;
+    Ok(len + blob_len)
+}
+
+/// Bytes of a bundle's stored record, like `secret_len`.
+pub async fn bundle_len(client: &Client, keys: &Keys, id: &str) -> Result<u64, redis::RedisError> {
+    let mut conn = client.get_multiplexed_async_connection().await?;
+    conn.strlen(keys.bundle(id)).await
+}
+
+fn within_value_cap(len: u64, max_bytes: u64) -> Result<(), ReadError> {
+    if len > max_bytes {
+        return Err(ReadError::TooLarge(len));
+    }
+    Ok(())
+}
+
+/// A record and its remaining TTL, without taking it.
+async fn read_with_ttl<T: FromRedisValue + From<String>>(
+    conn: &mut MultiplexedConnection,
+    storage: SecretStorage,
+    key: &str,
+    max_bytes: u64,
+) -> Result<Option<(T, i64)>, ReadError> {
+    check_value_len(conn, storage, key, max_bytes).await?;
+    let Some(value) = read_secret(conn, storage, key).await? else {
+        return Ok(None);
+    };
+    let ttl = secret_ttl(conn, storage, key).await?;
+    Ok(Some((value, ttl)))
+}
+
+/// `read_with_ttl`, served by the replica when there is one. `conn` is the
+/// primary, used only for the fallback.
+async fn read_routed<T: FromRedisValue + From<String>>(
+    conn: &mut MultiplexedConnection,
+    replica: Replica<'_>,
+    storage: SecretStorage,
+    key: &str,
+    max_bytes: u64,
+) -> Result<Option<(T, i64)>, ReadError> {
+    if let Some(client) = replica.client {
+        let mut replica_conn = client.get_multiplexed_async_connection().await?;
+        let read = read_with_ttl(&mut replica_conn, storage, key, max_bytes).await?;
+        if read.is_some() || !replica.fallback {
+            return Ok(read);
+        }
+    }
+    read_with_ttl(conn, storage, key, max_bytes).await
+}
+
+/// Reset the TTL of the secret's payload to `ttl` seconds.
+async fn expire_secret(
+    conn: &mut MultiplexedConnection,
+    storage: SecretStorage,
+    key: &str,
+    ttl: i64,
+) -> Result<(), redis::RedisError> {
+    match storage {
+        SecretStorage::String { .. } => conn.expire(key, ttl).await,
+        SecretStorage::Hash { tombstone } => hash::expire(conn, key, ttl, tombstone).await,
+    }
+}
+
+pub async fn get_secret(
+    client: &Client,
+    keys: &Keys,
+    storage: SecretStorage,
+    id: &str,
+    options: ReadOptions<'_>,
+) -> Result<Option<SecretRead<BurnedSecret>>, ReadError> {
+    let ReadOptions {
+        now,
+        client_ip,
+        max_value_bytes,
+        confirmation_window,
+        view_context,
+        ..
+    } = options;
+    let mut conn = client.get_multiplexed_async_connection().await?;
+
+    let key = keys.secret(id);
+
+    check_value_len(&mut conn, storage, &key, max_value_bytes).await?;
+    // Check the release gates with a plain read first so a refused read doesn't burn the secret
+    let current: Option<Vec<u8>> = read_secret(&mut conn, storage, &key).await?;
+    let Some(current) = current else {
+        // The read counter outlives a `maxReads` secret's last read
+        let taken: Option<u32> = conn.get(keys.reads(id)).await?;
+        return Ok(
+            taken.map(|max_reads| SecretRead::Refused(Refusal::ReadsExhausted { max_reads }))
+        );
+    };
+    let mut required_confirmations = None;
+    let mut max_reads = None;
+    let mut bound_view = false;
+    if let Some(stored) = decode_record(&current)? {
+        if !stored.allows_ip(client_ip) {
+            return Ok(Some(SecretRead::Refused(Refusal::IpNotAllowed)));
+        }
+        if !stored.is_available(now) {
+            return Ok(Some(SecretRead::Refused(Refusal::NotYetAvailable(
+                stored.available_at.unwrap_or_default(),
+            ))));
+        }
+        if stored.is_past_max_age(now) {
+            return Ok(Some(SecretRead::Refused(Refusal::PastMaxAge(
+                stored.readable_until().unwrap_or_default(),
+            ))));
+        }
+        if let Some(required) = stored.required_confirmations {
+            // Only confirmations collected within the window count towards the threshold
+            let collected: u64 = conn
+                .zcount(
+                    keys.confirmations(id),
+                    now.saturating_sub(confirmation_window),
+                    "+inf",
+                )
+                .await?;
+            if !stored.confirmations_met(collected) {
+                return Ok(Some(SecretRead::Refused(Refusal::ConfirmationsPending {
+                    required,
+                    collected,
+                })));
+            }
+            required_confirmations = Some(required);
+        }
+        if stored.bind_view_context {
+            // Only a peek starts the binding, so a direct burn is let through
+            let (token, valid_until): (Option<String>, Option<u64>) = redis::cmd("HMGET")
+                .arg(keys.view_context(id))
+                .arg("token")
+                .arg("until")
+                .query_async(&mut conn)
+                .await?;
+            if let Some(token) = token {
+                if view_context != Some(token.as_str()) || now > valid_until.unwrap_or(0) {
+                    return Ok(Some(SecretRead::Refused(Refusal::ViewContextMismatch)));
+                }
+                bound_view = true;
+            }
+        }
+        max_reads = stored.max_reads;
+    }
+
+    let (result, reads_remaining) = match max_reads {
+        // Only string storage allows `maxReads` (checked at create)
+        Some(max_reads) => {
+            let (status, value, taken): (i64, Option<Vec<u8>>, u32) =
+                redis::Script::new(TAKE_READ_SCRIPT)
+                    .key(&key)
+                    .key(keys.reads(id))
+                    .arg(max_reads)
+                    .invoke_async(&mut conn)
+                    .await?;
+            match status {
+                1 => (value, Some(max_reads.saturating_sub(taken))),
+                // Another reader took the last slot since the gate check
+                2 => {
+                    return Ok(Some(SecretRead::Refused(Refusal::ReadsExhausted {
+                        max_reads: taken,
+                    })))
+                }
+                _ => (None, None),
+            }
+        }
+        None => match required_confirmations {
+            // Re-checked as part of the take, so a confirmation lapsing since
+            // the gate check can't let the burn through
+            Some(required) => {
+                let window_start = now.saturating_sub(confirmation_window);
+                let (met, collected, value) =
+                    take_confirmed(&mut conn, storage, keys, id, window_start, required).await?;
+                if !met {
+                    return Ok(Some(SecretRead::Refused(Refusal::ConfirmationsPending {
+                        required,
+                        collected,
+                    })));
+                }
+                (value, None)
+            }
+            None => (take_secret(&mut conn, storage, &key).await?, None),
+        },
+    };
+    // Earlier reads of a `maxReads` secret leave it in place
+    let final_read = reads_remaining.is_none_or(|remaining| remaining == 0);
+
+    if bound_view && final_read {
+        let _: () = conn.del(keys.view_context(id)).await?;
+    }
+
+    match result {
+        Some(value) => {
+            let peek_count: Option<u64> = if final_read {
+                redis::cmd("GETDEL")
+                    .arg(keys.peeks(id))
+                    .query_async(&mut conn)
+                    .await?
+            } else {
+                conn.get(keys.peeks(id)).await?
+            };
+
+            let mut stored = secret_record(value)?;
+            stored.unpad();
+            Ok(Some(SecretRead::Found(BurnedSecret {
+                encrypted_secret: stored.encrypted_secret,
+                created_at: stored.created_at,
+                metadata: stored.metadata,
+                burned_at: now,
+                peek_count: peek_count.unwrap_or(0),
+                reads_remaining,
+                crypto: stored.crypto,
+                kind: stored.kind,
+                no_peek: stored.no_peek,
+            })))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Peek at a secret without burning it. Returns (StoredSecret, ttl_seconds).
+/// For legacy secrets (plain string), returns created_at=0 and metadata=None.
+/// `peekBurn` secrets are the exception: the first peek burns them and
+/// reports a TTL of 0.
+pub async fn peek_secret(
+    client: &Client,
+    keys: &Keys,
+    storage: SecretStorage,
+    id: &str,
+    options: ReadOptions<'_>,
+) -> Result<Option<SecretRead<(StoredSecret, i64)>>, ReadError> {
+    let ReadOptions {
+        now,
+        client_ip,
+        max_value_bytes,
+        replica,
+        migrate_legacy,
+        ..
+    } = options;
+    let mut conn = client.get_multiplexed_async_connection().await?;
+
+    let key = keys.secret(id);
+
+    // Read without taking, to preserve the secret. Only the read may go to a
+    // replica; burns, TTL extensions and peek counts below hit the primary.
+    let result: Option<(Vec<u8>, i64)> =
+        read_routed(&mut conn, replica, storage, &key, max_value_bytes).await?;
+
+    match result {
+        Some((value, ttl)) => {
+            if let Some(mut stored) = decode_record(&value)? {
+                stored.unpad();
+                // Checked first so no other refusal gives a hidden `noPeek`
+                // secret away
+                if stored.no_peek {
+                    return Ok(Some(SecretRead::Refused(Refusal::PeekNotAllowed)));
+                }
+                if !stored.allows_ip(client_ip) {
+                    return Ok(Some(SecretRead::Refused(Refusal::IpNotAllowed)));
+                }
+                if !stored.is_available(now) {
+                    return Ok(Some(SecretRead::Refused(Refusal::NotYetAvailable(
+                        stored.available_at.unwrap_or_default(),
+                    ))));
+                }
+
+                if stored.peek_burn {
+                    // A peek-burn releases the payload, so the read window applies
+                    if stored.is_past_max_age(now) {
+                        return Ok(Some(SecretRead::Refused(Refusal::PastMaxAge(
+                            stored.readable_until().unwrap_or_default(),
+                        ))));
+                    }
+                    let burned: Option<Vec<u8>> = match storage {
+                        SecretStorage::String { .. } => {
+                            redis::Script::new(PEEK_BURN_SCRIPT)
+                                .key(&key)
+                                .key(keys.peeks(id))
+                                .invoke_async(&mut conn)
+                                .await?
+                        }
+                        SecretStorage::Hash { .. } => {
+                            let burned = take_secret(&mut conn, storage, &key).await?;
+                            let _: () = conn.del(keys.peeks(id)).await?;
+                            burned
+                        }
+                    };
+                    // Another reader got there first
+                    let Some(burned) = burned else {
+                        return Ok(None);
+                    };
+                    let mut stored = decode_record(&burned)?.unwrap_or(stored);
+                    stored.unpad();
+                    return Ok(Some(SecretRead::Found((stored, 0))));
+                }
+
+                // Sliding secrets stay alive while they're being peeked
+                let ttl = match stored.sliding_ttl(now) {
+                    Some(extended) if extended as i64 > ttl => {
+                        let extended = extended as i64;
+                        expire_secret(&mut conn, storage, &key, extended).await?;
+                        if stored.required_confirmations.is_some() {
+                            let _: () = redis::pipe()
+                                .expire(keys.confirmations(id), extended)
+                                .ignore()
+                                .expire(keys.confirm_tokens(id), extended)
+                                .ignore()
+                                .query_async(&mut conn)
+                                .await?;
+                        }
+                        extended
+                    }
+                    _ => ttl,
+                };
+                record_peek(&mut conn, &keys.peeks(id), ttl).await?;
+                Ok(Some(SecretRead::Found((stored, ttl))))
+            } else {
+                // Legacy format: plain string, with created_at=0 and no metadata
+                record_peek(&mut conn, &keys.peeks(id), ttl).await?;
+                let stored = secret_record(value)?;
+                if let Some(format) = migrate_legacy {
+                    // Once rewritten it decodes as a record, so this runs
+                    // once per secret rather than on every peek
+                    if let Err(e) = migrate_legacy_secret(&mut conn, &key, &stored, format).await {
+                        tracing::warn!("Failed to migrate legacy secret: {}", e);
+                    }
+                }
+                Ok(Some(SecretRead::Found((stored, ttl))))
+            }
+        }
+        None => Ok(None),
+    }
+}
+
+/// Append `entry` to a secret's access log, keeping the newest `max_entries`
+/// and counting the rest as dropped. The log lives for at least `ttl`
+/// seconds. Returns the total dropped so far.
+pub async fn record_access(
+    client: &Client,
+    keys: &Keys,
+    id: &str,
+    entry: &str,
+    max_entries: u64,
+    ttl: u64,
+) -> Result<u64, redis::RedisError> {
+    let mut conn = client.get_multiplexed_async_connection().await?;
+    redis::Script::new(RECORD_ACCESS_SCRIPT)
+        .key(keys.access_log(id))
+        .key(keys.access_dropped(id))
+        .arg(entry)
+        .arg(max_entries)
+        .arg(ttl.max(1))
+        .invoke_async(&mut conn)
+        .await
+}
+
+/// A secret's access log entries, newest first, and how many older ones
+/// were trimmed.
+pub async fn access_log(
+    client: &Client,
+    keys: &Keys,
+    id: &str,
+) -> Result<(Vec<String>, u64), redis::RedisError> {
+    let mut conn = client.get_multiplexed_async_connection().await?;
+    let (entries, dropped): (Vec<String>, Option<u64>) = redis::pipe()
+        .lrange(keys.access_log(id), 0, -1)
+        .get(keys.access_dropped(id))
+        .query_async(&mut conn)
+        .await?;
+    Ok((entries, dropped.unwrap_or(0)))
+}
+
+/// Keep what a peek may show of a just-burned secret for `grace` seconds
+/// (`BURN_GRACE_SECONDS`).
+pub async fn record_burn(
+    client: &Client,
+    keys: &Keys,
+    id: &str,
+    record: &BurnedSecretPeek,
+    grace: u64,
+) -> Result<(), redis::RedisError> {
+    let mut conn = client.get_multiplexed_async_connection().await?;
+    let json = serde_json::to_string(record).expect("burn record serializes");
+    conn.set_ex(keys.burned(id), json, grace).await
+}
+
+/// The burn record of a secret burned within the grace window, if any.
+pub async fn burned_secret(
+    client: &Client,
+    keys: &Keys,
+    id: &str,
+) -> Result<Option<BurnedSecretPeek>, redis::RedisError> {
+    let mut conn = client.get_multiplexed_async_connection().await?;
+    let json: Option<String> = conn.get(keys.burned(id)).await?;
+    Ok(json.and_then(|json| serde_json::from_str(&json).ok()))
+}
+
+/// Re-store a legacy plain-string secret (`stored`, as `secret_record`
+/// reads it) as a record encoded as `format`, leaving its payload and TTL
+/// unchanged. Its creation time is unknown, so it stays 0. Returns whether it
+/// was rewritten.
+async fn migrate_legacy_secret(
+    conn: &mut MultiplexedConnection,
+    key: &str,
+    stored: &StoredSecret,
+    format: StorageFormat,
+) -> Result<bool, redis::RedisError> {
+    let migrated: i64 = redis::Script::new(MIGRATE_LEGACY_SCRIPT)
+        .key(key)
+        .arg(&stored.encrypted_secret)
+        .arg(format::encode_secret(format, stored))
+        .invoke_async(conn)
+        .await?;
+    Ok(migrated == 1)
+}
+
+/// The remaining TTL of a secret's payload, or None if it doesn't exist.
+/// Reads nothing else and changes nothing, so it's safe for monitoring.
+pub async fn touch_secret(
+    client: &Client,
+    keys: &Keys,
+    storage: SecretStorage,
+    id: &str,
+    replica: Replica<'_>,
+) -> Result<Option<i64>, redis::RedisError> {
+    let key = keys.secret(id);
+
+    if let Some(replica_client) = replica.client {
+        let mut conn = replica_client.get_multiplexed_async_connection().await?;
+        let ttl = secret_ttl(&mut conn, storage, &key).await?;
+        if ttl != -2 || !replica.fallback {
+            return Ok((ttl != -2).then_some(ttl));
+        }
+    }
+
+    let mut conn = client.get_multiplexed_async_connection().await?;
+    let ttl = secret_ttl(&mut conn, storage, &key).await?;
+    Ok((ttl != -2).then_some(ttl))
+}
+
+/// Remaining TTL of each secret in one round trip, with TTL's -2 for ids
+/// that don't exist. Never reads the payloads.
+pub async fn secret_ttls(
+    client: &Client,
+    keys: &Keys,
+    storage: SecretStorage,
+    ids: &[&str],
+) -> Result<Vec<i64>, redis::RedisError> {
+    let mut conn = client.get_multiplexed_async_connection().await?;
+    let mut pipe = redis::pipe();
+    for id in ids {
+        let key = keys.secret(id);
+        match storage {
+            SecretStorage::String { .. } => pipe.ttl(key),
+            SecretStorage::Hash { .. } => pipe.add_command(hash::payload_ttl_cmd(&key)),
+        };
+    }
+    match storage {
+        SecretStorage::String { .. } => pipe.query_async(&mut conn).await,
+        SecretStorage::Hash { .. } => {
+            let ttls: Vec<Vec<i64>> = pipe.query_async(&mut conn).await?;
+            Ok(ttls
+                .into_iter()
+                .map(|ttl| ttl.first().copied().unwrap_or(-2))
+                .collect())
+        }
+    }
+}
+
+/// Which of these secrets are `noPeek`, so existence checks can hide them
+/// as their peeks do (`HIDE_NOPEEK_EXISTENCE`). The flag is in the record, so
+/// each one is loaded; ids that are gone count as not `noPeek`.
+pub async fn no_peek_flags(
+    client: &Client,
+    keys: &Keys,
+    storage: SecretStorage,
+    ids: &[&str],
+) -> Result<Vec<bool>, redis::RedisError> {
+    let mut conn = client.get_multiplexed_async_connection().await?;
+    let mut flags = Vec::with_capacity(ids.len());
+    for id in ids {
+        let record: Option<Vec<u8>> = read_secret(&mut conn, storage, &keys.secret(id)).await?;
+        let no_peek = match record {
+            Some(record) => secret_record(record)?.no_peek,
+            None => false,
+        };
+        flags.push(no_peek);
+    }
+    Ok(flags)
+}
+
+/// The secret's stored record as kept, ciphertext included (less any
+/// padding), without burning it or counting a peek. Legacy plain-string
+/// secrets come back as a record holding just the ciphertext.
+pub async fn raw_secret(
+    client: &Client,
+    keys: &Keys,
+    storage: SecretStorage,
+    id: &str,
+    max_value_bytes: u64,
+) -> Result<Option<StoredSecret>, ReadError> {
+    let mut conn = client.get_multiplexed_async_connection().await?;
+    let read: Option<(Vec<u8>, i64)> =
+        read_with_ttl(&mut conn, storage, &keys.secret(id), max_value_bytes).await?;
+
+    read.map(|(value, _)| {
+        let mut stored = secret_record(value)?;
+        stored.unpad();
+        Ok(stored)
+    })
+    .transpose()
+}
+
+/// One SCAN step over every secret from `cursor`, examining about `count`
+/// keys, for the janitor's sweeps: the secrets found with their remaining
+/// TTLs, and the cursor to continue from (0 once the scan is complete). A
+/// step can find none while the scan goes on. Side keys are skipped, and so
+/// are secrets gone or too large to read by the time they're looked at.
+pub async fn list_secrets(
+    client: &Client,
+    keys: &Keys,
+    storage: SecretStorage,
+    cursor: u64,
+    count: usize,
+    max_value_bytes: u64,
+) -> Result<(u64, Vec<(String, StoredSecret, i64)>), ReadError> {
+    let mut conn = client.get_multiplexed_async_connection().await?;
+    let (next, found): (u64, Vec<String>) = redis::cmd("SCAN")
+        .arg(cursor)
+        .arg("MATCH")
+        .arg(keys.secret_scan_pattern())
+        .arg("COUNT")
+        .arg(count)
+        .query_async(&mut conn)
+        .await?;
+
+    let mut secrets = Vec::new();
+    for key in found {
+        let id = keys.strip(&key);
+        if id.contains(':') {
+            continue;
+        }
+        let read: Option<(Vec<u8>, i64)> =
+            match read_with_ttl(&mut conn, storage, &key, max_value_bytes).await {
+                Ok(read) => read,
+                Err(ReadError::TooLarge(_) | ReadError::DecompressionLimit(_)) => continue,
+                Err(e) => return Err(e),
+            };
+        if let Some((value, ttl)) = read {
+            let mut stored = secret_record(value)?;
+            stored.unpad();
+            secrets.push((id.to_string(), stored, ttl));
+        }
+    }
+    Ok((next, secrets))
+}
+
+/// A secret as listed to its owner: what picks it out, never its payload or
+/// metadata.
+#[derive(Debug, PartialEq)]
+pub struct ListedSecret {
+    pub id: String,
+    pub created_at: u64,
+    pub tags: Vec<String>,
+    pub ttl: i64,
+}
+
+/// Remember that the API key labelled `label` created secret `id`, which
+/// expires in `ttl` seconds, so the key can list its secrets.
+pub async fn record_owned_secret(
+    client: &Client,
+    keys: &Keys,
+    label: &str,
+    id: &str,
+    ttl: u64,
+) -> Result<(), redis::RedisError> {
+    let mut conn = client.get_multiplexed_async_connection().await?;
+    redis::Script::new(RECORD_OWNED_SCRIPT)
+        .key(keys.owned_secrets(label))
+        .arg(id)
+        .arg(ttl.max(1))
+        .invoke_async(&mut conn)
+        .await
+}
+
+/// One SSCAN step over the secrets the API key labelled `label` created,
+/// from `cursor`, examining about `count` ids: the live ones, and the cursor
+/// to continue from (0 once the listing is complete). Ids whose secret is
+/// gone are dropped from the set; secrets too large to read are skipped.
+pub async fn list_owned_secrets(
+    client: &Client,
+    keys: &Keys,
+    storage: SecretStorage,
+    label: &str,
+    cursor: u64,
+    count: usize,
+    max_value_bytes: u64,
+) -> Result<(u64, Vec<ListedSecret>), ReadError> {
+    let mut conn = client.get_multiplexed_async_connection().await?;
+    let owned = keys.owned_secrets(label);
+    let (next, ids): (u64, Vec<String>) = redis::cmd("SSCAN")
+        .arg(&owned)
+        .arg(cursor)
+        .arg("COUNT")
+        .arg(count)
+        .query_async(&mut conn)
+        .await?;
+
+    let mut secrets = Vec::new();
+    for id in ids {
+        let read: Option<(Vec<u8>, i64)> =
+            match read_with_ttl(&mut conn, storage, &keys.secret(&id), max_value_bytes).await {
+                Ok(read) => read,
+                Err(ReadError::TooLarge(_) | ReadError::DecompressionLimit(_)) => continue,
+                Err(e) => return Err(e),
+            };
+        match read {
+            Some((value, ttl)) => {
+                let stored = secret_record(value)?;
+                secrets.push(ListedSecret {
+                    id,
+                    created_at: stored.created_at,
+                    tags: stored.tags,
+                    ttl,
+                });
+            }
+            None => {
+                let _: () = conn.srem(&owned, &id).await?;
+            }
+        }
+    }
+    Ok((next, secrets))
+}
+
+/// TTL of a secret's key, for explaining a read that found nothing (-2 when
+/// the key doesn't exist).
+pub async fn secret_key_ttl(
+    client: &Client,
+    keys: &Keys,
+    id: &str,
+) -> Result<i64, redis::RedisError> {
+    let mut conn = client.get_multiplexed_async_connection().await?;
+    conn.ttl(keys.secret(id)).await
+}
+
+/// Bind the burn of a `bindViewContext` secret to the client peeking it,
+/// until `valid_until`. Returns the token that client must present: `fresh`
+/// for the first peek, the bound one again for a peek presenting it, or
+/// None when another client holds the binding (or the secret is gone).
+pub async fn bind_view_context(
+    client: &Client,
+    keys: &Keys,
+    id: &str,
+    fresh: &str,
+    presented: Option<&str>,
+    valid_until: u64,
+) -> Result<Option<String>, redis::RedisError> {
+    let mut conn = client.get_multiplexed_async_connection().await?;
+    redis::Script::new(BIND_VIEW_CONTEXT_SCRIPT)
+        .key(keys.secret(id))
+        .key(keys.view_context(id))
+        .arg(fresh)
+        .arg(presented.unwrap_or_default())
+        .arg(valid_until)
+        .invoke_async(&mut conn)
+        .await
+}
+
+/// The create response recorded under an idempotency record key, with the
+/// hash of the request it answered, if it was recorded at or after
+/// `not_before`. Older records are no longer replayed even while Redis still
+/// holds them.
+pub async fn idempotent_response(
+    client: &Client,
+    keys: &Keys,
+    key: &str,
+    not_before: u64,
+) -> Result<Option<(String, String)>, redis::RedisError> {
+    let mut conn = client.get_multiplexed_async_connection().await?;
+    let (created_at, request_hash, response): (Option<u64>, Option<String>, Option<String>) = conn
+        .hget(
+            keys.idempotency(key),
+            &["created_at", "request_hash", "response"],
+        )
+        .await?;
+
+    Ok(match (created_at, request_hash, response) {
+        (Some(created_at), Some(request_hash), Some(response)) if created_at >= not_before => {
+            Some((request_hash, response))
+        }
+        _ => None,
+    })
+}
+
+/// Record the response to replay for an idempotency record key, and the
+/// hash of the request it answered, for `ttl` seconds, replacing any expired
+/// record under the same key.
+pub async fn record_idempotent_response(
+    client: &Client,
+    keys: &Keys,
+    key: &str,
+    created_at: u64,
+    request_hash: &str,
+    response: &str,
+    ttl: u64,
+) -> Result<(), redis::RedisError> {
+    let mut conn = client.get_multiplexed_async_connection().await?;
+    let key = keys.idempotency(key);
+    redis::pipe()
+        .atomic()
+        .hset_multiple(
+            &key,
+            &[
+                ("created_at", created_at.to_string()),
+                ("request_hash", request_hash.to_string()),
+                ("response", response.to_string()),
+            ],
+        )
+        .ignore()
+        .expire(&key, ttl as i64)
+        .ignore()
+        .query_async(&mut conn)
+        .await
+}
+
+/// Outcome of `claim_deduplicated_create`.
+#[derive(Debug, PartialEq)]
+pub enum DedupClaim {
+    /// Nothing recorded for the hash; it is now held for this create, which
+    /// must record or release it
+    Claimed,
+    /// The response of an earlier create whose secret is still stored
+    Recorded(String),
+    /// An identical create holds the hash and hasn't recorded its response
+    Pending,
+}
+
+/// Claim a secret create's content hash (`DEDUP_CREATES`) for `window`
+/// seconds, or return what an earlier create recorded under it. The claim is
+/// taken atomically, so of two identical creates in flight only one stores.
+/// A recorded create whose secret was already read is replaced by the
+/// claim, so a resubmit of it gets a fresh secret.
+pub async fn claim_deduplicated_create(
+    client: &Client,
+    keys: &Keys,
+    storage: SecretStorage,
+    hash: &str,
+    window: u64,
+) -> Result<DedupClaim, redis::RedisError> {
+    let mut conn = client.get_multiplexed_async_connection().await?;
+    let key = keys.create_dedup(hash);
+    let recorded: Option<(Option<String>, Option<String>)> = redis::Script::new(CLAIM_DEDUP_SCRIPT)
+        .key(&key)
+        .arg(window)
+        .invoke_async(&mut conn)
+        .await?;
+    let Some(recorded) = recorded else {
+        return Ok(DedupClaim::Claimed);
+    };
+    let (Some(id), Some(response)) = recorded else {
+        return Ok(DedupClaim::Pending);
+    };
+    let secret_key = keys.secret(&id);
+    let stored = match storage {
+        SecretStorage::String { .. } => conn.exists(&secret_key).await?,
+        // A burned secret's hash stays behind as a tombstone, so only its
+        // payload says the secret is unread. A large secret kept as a file
+        // (`UNIFY_LARGE_SECRETS`) is a plain string either way.
+        SecretStorage::Hash { .. } => {
+            let kind: String = redis::cmd("TYPE")
+                .arg(&secret_key)
+                .query_async(&mut conn)
+                .await?;
+            match kind.as_str() {
+                "hash" => hash::payload_exists(&mut conn, &secret_key).await?,
+                "none" => false,
+                _ => true,
+            }
+        }
+    };
+    if stored {
+        return Ok(DedupClaim::Recorded(response));
+    }
+    let claimed: i64 = redis::Script::new(RECLAIM_DEDUP_SCRIPT)
+        .key(&key)
+        .arg(&id)
+        .arg(window)
+        .invoke_async(&mut conn)
+        .await?;
+    Ok(if claimed == 1 {
+        DedupClaim::Claimed
+    } else {
+        DedupClaim::Pending
+    })
+}
+
+/// Record the response to answer resubmits of a claimed secret content hash
+/// with, for `window` seconds.
+pub async fn record_deduplicated_create(
+    client: &Client,
+    keys: &Keys,
+    hash: &str,
+    id: &str,
+    response: &str,
+    window: u64,
+) -> Result<(), redis::RedisError> {
+    let mut conn = client.get_multiplexed_async_connection().await?;
+    let key = keys.create_dedup(hash);
+    redis::pipe()
+        .atomic()
+        .hset_multiple(&key, &[("id", id), ("response", response)])
+        .ignore()
+        .hdel(&key, "pending")
+        .ignore()
+        .expire(&key, window as i64)
+        .ignore()
+        .query_async(&mut conn)
+        .await
+}
+
+/// Give up the claim on a secret content hash whose create stored nothing,
+/// so a retry isn't told an identical create is in progress.
+pub async fn release_deduplicated_create(
+    client: &Client,
+    keys: &Keys,
+    hash: &str,
+) -> Result<(), redis::RedisError> {
+    let mut conn = client.get_multiplexed_async_connection().await?;
+    redis::Script::new(RELEASE_DEDUP_SCRIPT)
+        .key(keys.create_dedup(hash))
+        .invoke_async(&mut conn)
+        .await
+}
+
+/// Add `bytes` to an API key's stored-bytes total.
+pub async fn record_usage(
+    client: &Client,
+    keys: &Keys,
+    label: &str,
+    bytes: u64,
+) -> Result<(), redis::RedisError> {
+    let mut conn = client.get_multiplexed_async_connection().await?;
+    conn.hincr(keys.usage(), label, bytes).await
+}
+
+/// Stored-bytes totals by API key label.
+pub async fn usage(
+    client: &Client,
+    keys: &Keys,
+) -> Result<BTreeMap<String, u64>, redis::RedisError> {
+    let mut conn = client.get_multiplexed_async_connection().await?;
+    conn.hgetall(keys.usage()).await
+}
+
+/// The emergency cap on new entries' TTLs, if an operator has set one.
+pub async fn emergency_max_ttl(
+    client: &Client,
+    keys: &Keys,
+) -> Result<Option<u64>, redis::RedisError> {
+    let mut conn = client.get_multiplexed_async_connection().await?;
+    conn.get(keys.emergency_max_ttl()).await
+}
+
+/// Set (Some) or clear (None) the emergency cap on new entries' TTLs.
+pub async fn set_emergency_max_ttl(
+    client: &Client,
+    keys: &Keys,
+    seconds: Option<u64>,
+) -> Result<(), redis::RedisError> {
+    let mut conn = client.get_multiplexed_async_connection().await?;
+    match seconds {
+        Some(seconds) => conn.set(keys.emergency_max_ttl(), seconds).await,
+        None => conn.del(keys.emergency_max_ttl()).await,
+    }
+}
+
+/// Delete a secret along with its confirmations and peek and read counters,
+/// for its management link. Returns whether the secret still existed.
+pub async fn delete_secret(
+    client: &Client,
+    keys: &Keys,
+    id: &str,
+) -> Result<bool, redis::RedisError> {
+    let mut conn = client.get_multiplexed_async_connection().await?;
+
+    let (deleted,): (i64,) = redis::pipe()
+        .atomic()
+        .del(keys.secret(id))
+        .del(&[
+            keys.confirmations(id),
+            keys.confirm_tokens(id),
+            keys.peeks(id),
+            keys.reads(id),
+            keys.view_context(id),
+        ])
+        .ignore()
+        .query_async(&mut conn)
+        .await?;
+
+    Ok(deleted > 0)
+}
+
+/// Outcome of `import_secret`.
+#[derive(Debug, PartialEq)]
+pub enum Import {
+    Created,
+    /// An existing secret was overwritten
+    Replaced,
+    /// The id is taken and overwriting wasn't asked for; nothing was written
+    Exists,
+}
+
+/// Store a secret migrated from another instance under its existing id,
+/// expiring after `expiration` seconds. The key must be free unless
+/// `overwrite`, which replaces the record along with its confirmations,
+/// counters and view context in one transaction, with the new TTL.
+pub async fn import_secret(
+    client: &Client,
+    keys: &Keys,
+    storage: SecretStorage,
+    id: &str,
+    stored: &StoredSecret,
+    expiration: u64,
+    overwrite: bool,
+) -> Result<Import, redis::RedisError> {
+    let mut conn = client.get_multiplexed_async_connection().await?;
+    let key = keys.secret(id);
+
+    let (meta, payload) = hash::split(stored).map_err(serialization_error)?;
+    // Only creates a free key, like `store_new`'s writes
+    let create = match storage {
+        SecretStorage::String { format } => {
+            set_new(&key, format::encode_secret(format, stored), expiration)
+        }
+        SecretStorage::Hash { tombstone } => {
+            hash::create(&key, &meta, payload, expiration, tombstone)
+        }
+    };
+
+    if !overwrite {
+        let created: Option<String> = create.query_async(&mut conn).await?;
+        return Ok(if created.is_some() {
+            Import::Created
+        } else {
+            Import::Exists
+        });
+    }
+
+    let (existed,): (i64,) = redis::pipe()
+        .atomic()
+        .exists(&key)
+        .del(&[
+            key.clone(),
+            keys.confirmations(id),
+            keys.confirm_tokens(id),
+            keys.peeks(id),
+            keys.reads(id),
+            keys.view_context(id),
+        ])
+        .ignore()
+        .add_command(create)
+        .ignore()
+        .query_async(&mut conn)
+        .await?;
+    Ok(if existed > 0 {
+        Import::Replaced
+    } else {
+        Import::Created
+    })
+}
+
+/// Publish a lifecycle event name on a secret's events channel.
+pub async fn publish_secret_event(
+    client: &Client,
+    keys: &Keys,
+    id: &str,
+    event: &str,
+) -> Result<(), redis::RedisError> {
+    let mut conn = client.get_multiplexed_async_connection().await?;
+    conn.publish(keys.events(id), event).await
+}
+
+/// One entry appended to the events stream.
+#[derive(Clone, Copy, Debug)]
+pub struct StreamEvent<'a> {
+    pub id: &'a str,
+    /// The secret's kind, when it has one
+    pub kind: Option<&'a str>,
+    /// Lifecycle event name (`created`, `burned`, ...)
+    pub event: &'a str,
+    /// Unix timestamp the event happened at
+    pub at: u64,
+}
+
+/// Append a lifecycle event to the `stream` of all secrets' events,
+/// trimming it to about `max_len` entries.
+pub async fn append_secret_event(
+    client: &Client,
+    keys: &Keys,
+    stream: &str,
+    max_len: u64,
+    event: StreamEvent<'_>,
+) -> Result<(), redis::RedisError> {
+    let mut conn = client.get_multiplexed_async_connection().await?;
+    let mut cmd = redis::cmd("XADD");
+    cmd.arg(keys.events_stream(stream))
+        .arg("MAXLEN")
+        .arg("~")
+        .arg(max_len)
+        .arg("*")
+        .arg("id")
+        .arg(event.id)
+        .arg("type")
+        .arg(event.event)
+        .arg("timestamp")
+        .arg(event.at);
+    if let Some(kind) = event.kind {
+        cmd.arg("kind").arg(kind);
+    }
+    let _: String = cmd.query_async(&mut conn).await?;
+    Ok(())
+}
+
+/// Keyspace notification channel for a secret's payload key. Redis only
+/// publishes on it when `notify-keyspace-events` includes `K`.
+pub fn secret_keyspace_channel(client: &Client, keys: &Keys, id: &str) -> String {
+    format!(
+        "__keyspace@{}__:{}",
+        client.get_connection_info().redis.db,
+        keys.secret(id)
+    )
+}
+
+/// A dedicated pub/sub connection subscribed to a secret's events channel
+/// and to keyspace notifications for its payload key.
+pub async fn subscribe_secret_events(
+    client: &Client,
+    keys: &Keys,
+    id: &str,
+) -> Result<redis::aio::PubSub, redis::RedisError> {
+    let mut pubsub = client.get_async_pubsub().await?;
+    pubsub.subscribe(keys.events(id)).await?;
+    pubsub
+        .subscribe(secret_keyspace_channel(client, keys, id))
+        .await?;
+    Ok(pubsub)
+}
+
+/// Reset a secret's TTL to `ttl` seconds from now, side keys included, for
+/// its management link. With `unmodified_since`, a record replaced after
+/// that time is left alone.
+pub async fn extend_secret(
+    client: &Client,
+    keys: &Keys,
+    storage: SecretStorage,
+    id: &str,
+    ttl: u64,
+    unmodified_since: Option<u64>,
+) -> Result<Extend, redis::RedisError> {
+    let mut conn = client.get_multiplexed_async_connection().await?;
+
+    let key = keys.secret(id);
+    loop {
+        let judged = match unmodified_since {
+            Some(since) => match judge_unmodified(&mut conn, storage, &key, since).await? {
+                Judged::Unmodified(current) => Some(current),
+                Judged::Modified(last_modified) => return Ok(Extend::Modified(last_modified)),
+                Judged::NotFound => return Ok(Extend::NotFound),
+            },
+            None => None,
+        };
+        let extended: i64 = match storage {
+            SecretStorage::String { .. } => {
+                redis::Script::new(EXTEND_SCRIPT)
+                    .key(&key)
+                    .arg(ttl)
+                    .arg(&judged)
+                    .invoke_async(&mut conn)
+                    .await?
+            }
+            SecretStorage::Hash { tombstone } => {
+                hash::extend(&mut conn, &key, ttl, tombstone, unmodified_since).await?
+            }
+        };
+        match extended {
+            1 => break,
+            // Changed between the read and the script: judge it again
+            3 => continue,
+            _ => return Ok(Extend::NotFound),
+        }
+    }
+
+    // EXPIRE is a no-op on side keys that don't exist yet
+    let _: () = redis::pipe()
+        .expire(keys.confirmations(id), ttl as i64)
+        .ignore()
+        .expire(keys.confirm_tokens(id), ttl as i64)
+        .ignore()
+        .expire(keys.peeks(id), ttl as i64)
+        .ignore()
+        .expire(keys.reads(id), ttl as i64)
+        .ignore()
+        .query_async(&mut conn)
+        .await?;
+
+    Ok(Extend::Extended)
+}
+
+/// A record judged against `If-Unmodified-Since`.
+enum Judged {
+    /// Not modified since; carries the record as read, for the script to
+    /// check it's still the one
+    Unmodified(Vec<u8>),
+    Modified(u64),
+    NotFound,
+}
+
+async fn judge_unmodified(
+    conn: &mut MultiplexedConnection,
+    storage: SecretStorage,
+    key: &str,
+    since: u64,
+) -> Result<Judged, redis::RedisError> {
+    let Some(current): Option<Vec<u8>> = read_secret(conn, storage, key).await? else {
+        return Ok(Judged::NotFound);
+    };
+    let last_modified = decode_record(&current)?.unwrap_or_default().last_modified();
+    Ok(if last_modified > since {
+        Judged::Modified(last_modified)
+    } else {
+        Judged::Unmodified(current)
+    })
+}
+
+/// Outcome of `confirm_secret`.
+#[derive(Debug, PartialEq)]
+pub enum Confirm {
+    /// Recorded; confirmations now within the window
+    Counted(u64),
+    /// The token wasn't issued by a peek of this secret
+    UnknownToken,
+    /// The secret no longer exists
+    Gone,
+}
+
+/// Hand a reader peeking a multi-confirmation secret a confirmation token,
+/// kept with the secret's remaining `ttl`. `reader` is who it was issued to:
+/// confirmations count distinct readers, however many tokens each peeks for.
+pub async fn issue_confirmation_token(
+    client: &Client,
+    keys: &Keys,
+    id: &str,
+    token: &str,
+    reader: &str,
+    ttl: i64,
+) -> Result<(), redis::RedisError> {
+    let mut conn = client.get_multiplexed_async_connection().await?;
+    let key = keys.confirm_tokens(id);
+    redis::pipe()
+        .atomic()
+        .hset(&key, token, reader)
+        .ignore()
+        .expire(&key, ttl.max(1))
+        .ignore()
+        .query_async(&mut conn)
+        .await
+}
+
+/// Record a reader confirmation for a multi-confirmation secret, from a
+/// token `issue_confirmation_token` handed out. Confirmations are kept in a
+/// sorted set (reader -> timestamp) sharing the secret's TTL, so repeated
+/// confirmations from the same reader only refresh its timestamp.
+pub async fn confirm_secret(
+    client: &Client,
+    keys: &Keys,
+    storage: SecretStorage,
+    id: &str,
+    token: &str,
+    now: u64,
+    confirmation_window: u64,
+) -> Result<Confirm, redis::RedisError> {
+    let mut conn = client.get_multiplexed_async_connection().await?;
+
+    let ttl = secret_ttl(&mut conn, storage, &keys.secret(id)).await?;
+    if ttl <= 0 {
+        return Ok(Confirm::Gone);
+    }
+
+    let collected: i64 = redis::Script::new(CONFIRM_SCRIPT)
+        .key(keys.confirm_tokens(id))
+        .key(keys.confirmations(id))
+        .arg(token)
+        .arg(now)
+        .arg(ttl)
+        .arg(now.saturating_sub(confirmation_window))
+        .invoke_async(&mut conn)
+        .await?;
+
+    Ok(match u64::try_from(collected) {
+        Ok(collected) => Confirm::Counted(collected),
+        Err(_) => Confirm::UnknownToken,
+    })
+}
+
+/// Swap a secret's payload and metadata in place, keeping its id, remaining
+/// TTL and every other field, and stamp it as updated at `now`. The script
+/// makes sure the secret wasn't burned in between, which would otherwise
+/// resurrect it. With `unmodified_since`, a record replaced after that time
+/// is left alone, checked again by the script itself so a concurrent replace
+/// can't slip in between.
+pub async fn replace_secret(
+    client: &Client,
+    keys: &Keys,
+    storage: SecretStorage,
+    id: &str,
+    replacement: SecretReplaceRequest,
+    now: u64,
+    unmodified_since: Option<u64>,
+) -> Result<Replace, redis::RedisError> {
+    let mut conn = client.get_multiplexed_async_connection().await?;
+
+    let key = keys.secret(id);
+    loop {
+        let current: Vec<u8> = match unmodified_since {
+            Some(since) => match judge_unmodified(&mut conn, storage, &key, since).await? {
+                Judged::Unmodified(current) => current,
+                Judged::Modified(last_modified) => return Ok(Replace::Modified(last_modified)),
+                Judged::NotFound => return Ok(Replace::NotFound),
+            },
+            None => match read_secret(&mut conn, storage, &key).await? {
+                Some(current) => current,
+                None => return Ok(Replace::NotFound),
+            },
+        };
+        // Legacy plain-string secrets are upgraded to a record, as peek shows them
+        let mut stored = decode_record(&current)?.unwrap_or_default();
+        stored.encrypted_secret = replacement.encrypted_secret.clone();
+        stored.payload_length = replacement.payload_length;
+        stored.metadata = replacement.metadata.clone();
+        stored.updated_at = Some(now);
+
+        let replaced: i64 = match storage {
+            SecretStorage::String { format } => {
+                redis::Script::new(REPLACE_SCRIPT)
+                    .key(&key)
+                    .key(keys.confirmations(id))
+                    .key(keys.reads(id))
+                    .arg(format::encode_secret(format, &stored))
+                    .arg(unmodified_since.map(|_| &current))
+                    .invoke_async(&mut conn)
+                    .await?
+            }
+            SecretStorage::Hash { .. } => {
+                let (meta, payload) = hash::split(&stored).map_err(serialization_error)?;
+                hash::replace(
+                    &mut conn,
+                    &key,
+                    &keys.confirmations(id),
+                    &meta,
+                    payload,
+                    unmodified_since,
+                )
+                .await?
+            }
+        };
+
+        return Ok(match replaced {
+            1 => Replace::Replaced,
+            2 => Replace::AlreadyRead,
+            // Changed between the read and the script: judge it again
+            3 => continue,
+            _ => Replace::NotFound,
+        });
+    }
+}
+
+/// Store a file record encoded as `format`, gzipped when `compress`
+/// (`COMPRESS_STORAGE`). With a
+/// `content_hash` the data lives in the shared blob for that hash instead
+/// (`DEDUP_FILES`): created from `encrypted_data`, or when that's empty,
+/// referenced only if it still exists (`BlobNotFound` otherwise).
+pub async fn store_file(
+    client: &Client,
+    keys: &Keys,
+    stored_file: &StoredFile,
+    expiration: u64,
+    durable_wait: Option<Duration>,
+    compress: bool,
+    format: StorageFormat,
+) -> Result<Stored, StoreError> {
+    let mut conn = client.get_multiplexed_async_connection().await?;
+
+    let mut record = stored_file.clone();
+    // Kept at its own key, so a preview doesn't load the whole file
+    let thumbnail = record
+        .encrypted_thumbnail
+        .take()
+        .zip(record.thumbnail_iv.take());
+    let content_hash = stored_file.content_hash.as_deref();
+    if content_hash.is_some() {
+        record.encrypted_data.clear();
+    }
+    let value = format::encode_file(format, &record);
+    let value = if compress {
+        compress::compress(&value)
+    } else {
+        value
+    };
+
+    // A deduplicated file waits for the replica once its blob is referenced too
+    let id = store_new(
+        &mut conn,
+        keys,
+        FILE_ID_PREFIX,
+        |id| keys.file(id),
+        |key| set_new(key, &value, expiration),
+        durable_wait.filter(|_| content_hash.is_none()),
+    )
+    .await?;
+    if let Some(hash) = content_hash {
+        let data = &stored_file.encrypted_data;
+        let referenced: i64 = redis::Script::new(BLOB_REF_SCRIPT)
+            .key(keys.blob(hash))
+            .key(keys.blob_refs(hash))
+            .arg(&id)
+            .arg(expiration)
+            .arg((!data.is_empty()).then_some(data))
+            .invoke_async(&mut conn)
+            .await?;
+        if referenced == 0 {
+            let _: () = conn.del(keys.file(&id)).await?;
+            return Err(StoreError::BlobNotFound);
+        }
+        if let Some(timeout) = durable_wait {
+            if let Err(e) = wait_replicated(&mut conn, timeout).await {
+                let _: Option<String> = release_blob(&mut conn, keys, hash, &id).await?;
+                let _: () = conn.del(keys.file(&id)).await?;
+                return Err(e);
+            }
+        }
+    }
+    if let Some((data, iv)) = thumbnail {
+        let key = keys.thumbnail(&id);
+        let _: () = redis::pipe()
+            .atomic()
+            .hset_multiple(&key, &[("data", data), ("iv", iv)])
+            .ignore()
+            .expire(&key, expiration as i64)
+            .ignore()
+            .query_async(&mut conn)
+            .await?;
+    }
+    let ttl: i64 = conn.ttl(keys.file(&id)).await?;
+    Ok(Stored {
+        id,
+        ttl_seconds: ttl.max(0) as u64,
+    })
+}
+
+/// Take a slot under the active file cap, returning how many slots are left
+/// after it. Returns None (without taking a slot) when `max` files are
+/// already active.
+/// Expired files don't decrement the counter, so the janitor periodically recounts it.
+pub async fn reserve_file_slot(
+    client: &Client,
+    keys: &Keys,
+    max: u64,
+) -> Result<Option<u64>, redis::RedisError> {
+    let mut conn = client.get_multiplexed_async_connection().await?;
+    let key = keys.active_files();
+
+    // Signed: releases racing a janitor recount can briefly push the counter below zero
+    let active: i64 = conn.incr(&key, 1).await?;
+    if active > max as i64 {
+        let _: () = conn.decr(&key, 1).await?;
+        return Ok(None);
+    }
+
+    Ok(Some(max - active.max(0) as u64))
 }
 
-fn current_timestamp() -> u64 {
-    SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap_or_default()
-        .as_secs()
+/// Give back a slot taken by `reserve_file_slot` (file burned or never stored).
+pub async fn release_file_slot(client: &Client, keys: &Keys) -> Result<(), redis::RedisError> {
+    let mut conn = client.get_multiplexed_async_connection().await?;
+    let _: () = conn.decr(keys.active_files(), 1).await?;
+    Ok(())
 }
 
-pub async fn store_secret(
+/// How long a peek slot counter outlives its last change, so slots leaked by
+/// a crash mid-peek free themselves.
+const PEEK_SLOT_TTL_SECONDS: i64 = 10;
+
+/// Take one of `max` simultaneous peek slots for `id`, or false if all are
+/// in use.
+pub async fn acquire_peek_slot(
     client: &Client,
-    secret: String,
-    expiration: u64,
-    metadata: Option<serde_json::Value>,
-) -> Result<String, redis::RedisError> {
+    keys: &Keys,
+    id: &str,
+    max: u64,
+) -> Result<bool, redis::RedisError> {
     let mut conn = client.get_multiplexed_async_connection().await?;
-    let id = format!("sps-{}", generate_short_id());
+    let key = keys.active_peeks(id);
 
-    let stored = StoredSecret {
-        encrypted_secret: secret,
-        created_at: current_timestamp(),
-        metadata,
-    };
+    let (active,): (i64,) = redis::pipe()
+        .atomic()
+        .incr(&key, 1)
+        .expire(&key, PEEK_SLOT_TTL_SECONDS)
+        .ignore()
+        .query_async(&mut conn)
+        .await?;
+    if active > max as i64 {
+        let _: () = conn.decr(&key, 1).await?;
+        return Ok(false);
+    }
 
-    let json_val = serde_json::to_string(&stored).map_err(|e| {
-        redis::RedisError::from((
-            redis::ErrorKind::TypeError,
-            "Serialization error",
-            e.to_string(),
-        ))
-    })?;
+    Ok(true)
+}
 
-    let _: () = conn.set_ex(&id, json_val, expiration).await?;
+/// Give back a slot taken by `acquire_peek_slot`. The TTL is refreshed so a
+/// release after the counter expired doesn't leave a stray key behind.
+pub async fn release_peek_slot(
+    client: &Client,
+    keys: &Keys,
+    id: &str,
+) -> Result<(), redis::RedisError> {
+    let mut conn = client.get_multiplexed_async_connection().await?;
+    let key = keys.active_peeks(id);
+    redis::pipe()
+        .atomic()
+        .decr(&key, 1)
+        .ignore()
+        .expire(&key, PEEK_SLOT_TTL_SECONDS)
+        .ignore()
+        .query_async(&mut conn)
+        .await
+}
 
-    Ok(id)
+/// The active file counter, for `reconcile_active_files` to correct later.
+pub async fn active_files(client: &Client, keys: &Keys) -> Result<i64, redis::RedisError> {
+    let mut conn = client.get_multiplexed_async_connection().await?;
+    let active: Option<i64> = conn.get(keys.active_files()).await?;
+    Ok(active.unwrap_or(0))
 }
 
-pub async fn get_secret(client: &Client, id: &str) -> Result<Option<String>, redis::RedisError> {
+/// Correct the active file counter by a recount's drift from `snapshot`, its
+/// value when the recount started. Applied as one INCRBY rather than
+/// overwriting the counter, so slots taken and released while the SCAN ran
+/// are kept. Returns the corrected value.
+pub async fn reconcile_active_files(
+    client: &Client,
+    keys: &Keys,
+    snapshot: i64,
+    recount: u64,
+) -> Result<i64, redis::RedisError> {
     let mut conn = client.get_multiplexed_async_connection().await?;
+    conn.incr(keys.active_files(), recount as i64 - snapshot)
+        .await
+}
 
-    let result: Option<String> = redis::cmd("GETDEL").arg(id).query_async(&mut conn).await?;
+/// Visit every key matching `pattern`, one SCAN batch at a time with `pause`
+/// between batches so a full sweep doesn't monopolize Redis.
+pub async fn scan_keys(
+    client: &Client,
+    pattern: &str,
+    pause: Duration,
+    mut visit: impl FnMut(String),
+) -> Result<(), redis::RedisError> {
+    let mut conn = client.get_multiplexed_async_connection().await?;
 
-    match result {
-        Some(json_str) => {
-            // Try to parse as StoredSecret (new format)
-            if let Ok(stored) = serde_json::from_str::<StoredSecret>(&json_str) {
-                Ok(Some(stored.encrypted_secret))
-            } else {
-                // Legacy format: plain string
-                Ok(Some(json_str))
+    let mut cursor: u64 = 0;
+    loop {
+        let (next, keys): (u64, Vec<String>) = redis::cmd("SCAN")
+            .arg(cursor)
+            .arg("MATCH")
+            .arg(pattern)
+            .arg("COUNT")
+            .arg(500)
+            .query_async(&mut conn)
+            .await?;
+        keys.into_iter().for_each(&mut visit);
+        if next == 0 {
+            return Ok(());
+        }
+        cursor = next;
+        tokio::time::sleep(pause).await;
+    }
+}
+
+/// Like `scan_keys`, but also reads each key's TTL (pipelined per batch).
+/// Keys that expire between the SCAN and the TTL report -2.
+pub async fn scan_ttls(
+    client: &Client,
+    pattern: &str,
+    pause: Duration,
+    mut visit: impl FnMut(String, i64),
+) -> Result<(), redis::RedisError> {
+    let mut conn = client.get_multiplexed_async_connection().await?;
+
+    let mut cursor: u64 = 0;
+    loop {
+        let (next, keys): (u64, Vec<String>) = redis::cmd("SCAN")
+            .arg(cursor)
+            .arg("MATCH")
+            .arg(pattern)
+            .arg("COUNT")
+            .arg(500)
+            .query_async(&mut conn)
+            .await?;
+        if !keys.is_empty() {
+            let mut pipe = redis::pipe();
+            for key in &keys {
+                pipe.ttl(key);
             }
+            let ttls: Vec<i64> = pipe.query_async(&mut conn).await?;
+            keys.into_iter()
+                .zip(ttls)
+                .for_each(|(key, ttl)| visit(key, ttl));
         }
-        None => Ok(None),
+        if next == 0 {
+            return Ok(());
+        }
+        cursor = next;
+        tokio::time::sleep(pause).await;
     }
 }
 
-/// Peek at a secret without burning it. Returns (StoredSecret, ttl_seconds).
-/// For legacy secrets (plain string), returns created_at=0 and metadata=None.
-pub async fn peek_secret(
+/// Burn a file. A compressed record that inflates past `max_decompressed_bytes`
+/// is an error, though the GETDEL has already removed it.
+pub async fn get_file(
     client: &Client,
+    keys: &Keys,
     id: &str,
-) -> Result<Option<(StoredSecret, i64)>, redis::RedisError> {
+    max_value_bytes: u64,
+    max_decompressed_bytes: u64,
+) -> Result<Option<StoredFile>, ReadError> {
     let mut conn = client.get_multiplexed_async_connection().await?;
+    let key = keys.file(id);
 
-    // Use GET (not GETDEL) to preserve the secret
-    let result: Option<String> = conn.get(id).await?;
+    check_value_len(&mut conn, FILE_LAYOUT, &key, max_value_bytes).await?;
+    let (result,): (Option<Vec<u8>>,) = redis::pipe()
+        .atomic()
+        .cmd("GETDEL")
+        .arg(&key)
+        .del(keys.thumbnail(id))
+        .ignore()
+        .query_async(&mut conn)
+        .await?;
 
-    match result {
-        Some(json_str) => {
-            // Get TTL
-            let ttl: i64 = conn.ttl(id).await?;
+    if let Some(value) = result {
+        let record = compress::decode(value, max_decompressed_bytes)?;
+        let mut stored_file = format::decode_file(&record).map_err(|e| {
+            redis::RedisError::from((redis::ErrorKind::TypeError, "Deserialization error", e))
+        })?;
+        if let Some(hash) = stored_file.content_hash.take() {
+            let Some(data) = release_blob(&mut conn, keys, &hash, id).await? else {
+                return Ok(None);
+            };
+            stored_file.encrypted_data = data;
+        }
+        return Ok(Some(stored_file));
+    }
 
-            // Try to parse as StoredSecret (new format)
-            if let Ok(stored) = serde_json::from_str::<StoredSecret>(&json_str) {
-                Ok(Some((stored, ttl)))
-            } else {
-                // Legacy format: plain string - create a synthetic StoredSecret
-                let legacy_stored = StoredSecret {
-                    encrypted_secret: json_str,
-                    created_at: 0,
-                    metadata: None,
-                };
-                Ok(Some((legacy_stored, ttl)))
-            }
+    Ok(None)
+}
+
+/// Drop file `id`'s reference to the blob for `hash`, returning the blob's
+/// data (see `BLOB_RELEASE_SCRIPT`).
+async fn release_blob(
+    conn: &mut MultiplexedConnection,
+    keys: &Keys,
+    hash: &str,
+    id: &str,
+) -> Result<Option<String>, redis::RedisError> {
+    redis::Script::new(BLOB_RELEASE_SCRIPT)
+        .key(keys.blob(hash))
+        .key(keys.blob_refs(hash))
+        .arg(id)
+        .invoke_async(conn)
+        .await
+}
+
+/// Burn a file like `get_file`, keeping its data at the file's staged key
+/// under `token` for `ttl` seconds so it can be downloaded in ranges
+/// (`STAGED_DOWNLOAD_SECONDS`).
+pub async fn stage_file(
+    client: &Client,
+    keys: &Keys,
+    id: &str,
+    token: &str,
+    ttl: u64,
+    max_value_bytes: u64,
+    max_decompressed_bytes: u64,
+) -> Result<Option<StoredFile>, ReadError> {
+    let Some(stored) = get_file(client, keys, id, max_value_bytes, max_decompressed_bytes).await?
+    else {
+        return Ok(None);
+    };
+    let mut conn = client.get_multiplexed_async_connection().await?;
+    let _: () = conn
+        .set_ex(keys.staged(id, token), &stored.encrypted_data, ttl)
+        .await?;
+    Ok(Some(stored))
+}
+
+/// Length of a staged file's data, or None when nothing is staged for `id`.
+pub async fn staged_len(
+    client: &Client,
+    keys: &Keys,
+    id: &str,
+    token: &str,
+) -> Result<Option<u64>, redis::RedisError> {
+    let mut conn = client.get_multiplexed_async_connection().await?;
+    let key = keys.staged(id, token);
+    let (exists, len): (bool, u64) = redis::pipe()
+        .exists(&key)
+        .strlen(&key)
+        .query_async(&mut conn)
+        .await?;
+    Ok(exists.then_some(len))
+}
+
+/// Bytes `first..=last` of a staged file's data. Empty if it expired since
+/// its length was read.
+pub async fn staged_range(
+    client: &Client,
+    keys: &Keys,
+    id: &str,
+    token: &str,
+    first: u64,
+    last: u64,
+) -> Result<Vec<u8>, redis::RedisError> {
+    let mut conn = client.get_multiplexed_async_connection().await?;
+    conn.getrange(keys.staged(id, token), first as isize, last as isize)
+        .await
+}
+
+/// Drop a staged file's data. Returns whether anything was staged.
+pub async fn delete_staged(
+    client: &Client,
+    keys: &Keys,
+    id: &str,
+    token: &str,
+) -> Result<bool, redis::RedisError> {
+    let mut conn = client.get_multiplexed_async_connection().await?;
+    let deleted: u64 = conn.del(keys.staged(id, token)).await?;
+    Ok(deleted > 0)
+}
+
+/// The data of a deduplicated file blob, without taking a reference.
+pub async fn blob_data(
+    client: &Client,
+    keys: &Keys,
+    hash: &str,
+) -> Result<Option<String>, redis::RedisError> {
+    let mut conn = client.get_multiplexed_async_connection().await?;
+    conn.hget(keys.blob(hash), "data").await
+}
+
+/// A file's encrypted thumbnail and its IV, read without loading the file.
+pub async fn file_thumbnail(
+    client: &Client,
+    keys: &Keys,
+    id: &str,
+    replica: Replica<'_>,
+) -> Result<Option<(String, String)>, redis::RedisError> {
+    let key = keys.thumbnail(id);
+    if let Some(client) = replica.client {
+        let mut conn = client.get_multiplexed_async_connection().await?;
+        let (data, iv): (Option<String>, Option<String>) = conn.hget(&key, &["data", "iv"]).await?;
+        if data.is_some() || !replica.fallback {
+            return Ok(data.zip(iv));
+        }
+    }
+    let mut conn = client.get_multiplexed_async_connection().await?;
+    let (data, iv): (Option<String>, Option<String>) = conn.hget(&key, &["data", "iv"]).await?;
+    Ok(data.zip(iv))
+}
+
+/// Peek at a file without burning it. Returns (StoredFile, ttl_seconds).
+/// For legacy files without created_at, returns created_at=0. A deduplicated
+/// file's `encrypted_data` is left empty: see `blob_data`.
+pub async fn peek_file(
+    client: &Client,
+    keys: &Keys,
+    id: &str,
+    replica: Replica<'_>,
+    max_value_bytes: u64,
+    max_decompressed_bytes: u64,
+) -> Result<Option<(StoredFile, i64)>, ReadError> {
+    let mut conn = client.get_multiplexed_async_connection().await?;
+    let key = keys.file(id);
+
+    // Use GET (not GETDEL) to preserve the file; files are plain strings
+    let result: Option<(Vec<u8>, i64)> =
+        read_routed(&mut conn, replica, FILE_LAYOUT, &key, max_value_bytes).await?;
+
+    match result {
+        Some((value, ttl)) => {
+            let record = compress::decode(value, max_decompressed_bytes)?;
+            let stored = format::decode_file(&record).map_err(|e| {
+                redis::RedisError::from((redis::ErrorKind::TypeError, "Deserialization error", e))
+            })?;
+            Ok(Some((stored, ttl)))
         }
         None => Ok(None),
     }
 }
 
-pub async fn store_file(
+pub async fn store_bundle(
     client: &Client,
-    metadata: FileMetadata,
-    encrypted_data: String,
+    keys: &Keys,
+    files: Vec<BundlePart>,
+    created_at: u64,
     expiration: u64,
-) -> Result<String, redis::RedisError> {
+) -> Result<String, StoreError> {
     let mut conn = client.get_multiplexed_async_connection().await?;
-    let id = format!("spf-{}", generate_short_id());
 
-    let stored_file = StoredFile {
-        metadata,
-        encrypted_data,
-        created_at: current_timestamp(),
-    };
+    let stored_bundle = StoredBundle { files, created_at };
 
-    let json_val = serde_json::to_string(&stored_file).map_err(|e| {
+    let json_val = serde_json::to_string(&stored_bundle).map_err(|e| {
         redis::RedisError::from((
             redis::ErrorKind::TypeError,
             "Serialization error",
@@ -122,55 +2619,1088 @@ pub async fn store_file(
         ))
     })?;
 
-    let _: () = conn.set_ex(&id, json_val, expiration).await?;
+    store_new(
+        &mut conn,
+        keys,
+        BUNDLE_ID_PREFIX,
+        |id| keys.bundle(id),
+        |key| set_new(key, &json_val, expiration),
+        None,
+    )
+    .await
+}
+
+/// Open a chunked upload session holding `session` (the upload's metadata as
+/// JSON). It and any chunks are dropped unless completed within `window`
+/// seconds.
+pub async fn start_upload(
+    client: &Client,
+    keys: &Keys,
+    session: &str,
+    window: u64,
+) -> Result<String, StoreError> {
+    let mut conn = client.get_multiplexed_async_connection().await?;
+
+    store_new(
+        &mut conn,
+        keys,
+        UPLOAD_ID_PREFIX,
+        |id| keys.upload(id),
+        |key| set_new(key, session, window),
+        None,
+    )
+    .await
+}
+
+/// Store (or overwrite) chunk `index` of an upload, keeping the chunks to at
+/// most `max_bytes` characters in total.
+pub async fn put_upload_chunk(
+    client: &Client,
+    keys: &Keys,
+    id: &str,
+    index: u32,
+    data: &str,
+    max_bytes: u64,
+) -> Result<ChunkWrite, redis::RedisError> {
+    let mut conn = client.get_multiplexed_async_connection().await?;
+    let total: i64 = redis::Script::new(UPLOAD_CHUNK_SCRIPT)
+        .key(keys.upload(id))
+        .key(keys.upload_chunks(id))
+        .arg(index)
+        .arg(data)
+        .arg(max_bytes)
+        .invoke_async(&mut conn)
+        .await?;
+
+    Ok(match total {
+        -1 => ChunkWrite::NotFound,
+        -2 => ChunkWrite::TooLarge,
+        total => ChunkWrite::Stored(total as u64),
+    })
+}
+
+/// Complete an upload of `chunk_count` chunks, removing its session and
+/// chunks so nothing is left for the janitor.
+pub async fn take_upload(
+    client: &Client,
+    keys: &Keys,
+    id: &str,
+    chunk_count: u32,
+) -> Result<UploadTake, redis::RedisError> {
+    let mut conn = client.get_multiplexed_async_connection().await?;
+    let taken: Option<Vec<String>> = redis::Script::new(TAKE_UPLOAD_SCRIPT)
+        .key(keys.upload(id))
+        .key(keys.upload_chunks(id))
+        .arg(chunk_count)
+        .invoke_async(&mut conn)
+        .await?;
+
+    Ok(match taken {
+        None => UploadTake::NotFound,
+        Some(mut taken) if taken.len() == 2 => {
+            let data = taken.pop().unwrap_or_default();
+            let session = taken.pop().unwrap_or_default();
+            UploadTake::Complete { session, data }
+        }
+        Some(_) => UploadTake::Incomplete,
+    })
+}
 
-    Ok(id)
+/// Delete the chunks of uploads (by id) whose session no longer exists.
+/// Returns how many chunk hashes were removed.
+pub async fn sweep_upload_chunks(
+    client: &Client,
+    keys: &Keys,
+    ids: &[String],
+) -> Result<u64, redis::RedisError> {
+    let mut conn = client.get_multiplexed_async_connection().await?;
+    let script = redis::Script::new(SWEEP_CHUNKS_SCRIPT);
+    let mut removed = 0;
+    for id in ids {
+        let deleted: u64 = script
+            .key(keys.upload(id))
+            .key(keys.upload_chunks(id))
+            .invoke_async(&mut conn)
+            .await?;
+        removed += deleted;
+    }
+    Ok(removed)
 }
 
-pub async fn get_file(client: &Client, id: &str) -> Result<Option<StoredFile>, redis::RedisError> {
+pub async fn get_bundle(
+    client: &Client,
+    keys: &Keys,
+    id: &str,
+) -> Result<Option<StoredBundle>, redis::RedisError> {
     let mut conn = client.get_multiplexed_async_connection().await?;
 
-    let result: Option<String> = redis::cmd("GETDEL").arg(id).query_async(&mut conn).await?;
+    let result: Option<String> = redis::cmd("GETDEL")
+        .arg(keys.bundle(id))
+        .query_async(&mut conn)
+        .await?;
 
     if let Some(json_str) = result {
-        let stored_file: StoredFile = serde_json::from_str(&json_str).map_err(|e| {
+        let stored_bundle: StoredBundle = serde_json::from_str(&json_str).map_err(|e| {
             redis::RedisError::from((
                 redis::ErrorKind::TypeError,
                 "Deserialization error",
                 e.to_string(),
             ))
         })?;
-        return Ok(Some(stored_file));
+        return Ok(Some(stored_bundle));
     }
 
     Ok(None)
 }
 
-/// Peek at a file without burning it. Returns (StoredFile, ttl_seconds).
-/// For legacy files without created_at, returns created_at=0.
-pub async fn peek_file(
-    client: &Client,
-    id: &str,
-) -> Result<Option<(StoredFile, i64)>, redis::RedisError> {
-    let mut conn = client.get_multiplexed_async_connection().await?;
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    // Use GET (not GETDEL) to preserve the file
-    let result: Option<String> = conn.get(id).await?;
+    #[test]
+    fn test_wait_timeout_is_not_durable() {
+        assert!(matches!(check_replicated(0), Err(StoreError::NotDurable)));
+        assert!(check_replicated(1).is_ok());
+        assert!(check_replicated(2).is_ok());
+    }
 
-    match result {
-        Some(json_str) => {
-            // Get TTL
-            let ttl: i64 = conn.ttl(id).await?;
-
-            let stored: StoredFile = serde_json::from_str(&json_str).map_err(|e| {
-                redis::RedisError::from((
-                    redis::ErrorKind::TypeError,
-                    "Deserialization error",
-                    e.to_string(),
-                ))
-            })?;
-            Ok(Some((stored, ttl)))
+    #[test]
+    fn test_value_over_cap_is_refused() {
+        assert!(within_value_cap(1024, 1024).is_ok());
+        assert!(matches!(
+            within_value_cap(1025, 1024),
+            Err(ReadError::TooLarge(1025))
+        ));
+    }
+}
+
+/// Replica routing against a live Redis at `REDIS_URL`, with two logical
+/// databases standing in for the primary and the replica:
+/// `cargo test --features redis-tests`.
+#[cfg(all(test, feature = "redis-tests"))]
+mod redis_tests {
+    use super::*;
+    use crate::models::FileMetadata;
+    use redis::IntoConnectionInfo;
+    use std::collections::HashSet;
+    use uuid::Uuid;
+
+    const PRIMARY_DB: i64 = 0;
+    const REPLICA_DB: i64 = 1;
+
+    fn client(db: i64) -> Client {
+        let url =
+            std::env::var("REDIS_URL").unwrap_or_else(|_| "redis://127.0.0.1:6379".to_string());
+        let mut info = url.into_connection_info().unwrap();
+        info.redis.db = db;
+        Client::open(info).unwrap()
+    }
+
+    /// Write a file record straight into one backend, returning its id.
+    async fn seed_file(client: &Client, keys: &Keys) -> String {
+        let id = keys.new_id(FILE_ID_PREFIX);
+        let stored = StoredFile {
+            metadata: FileMetadata {
+                original_filename: "a.txt".to_string(),
+                content_type: "text/plain".to_string(),
+                iv: "iv".to_string(),
+            },
+            encrypted_data: "data".to_string(),
+            created_at: 1706900000,
+            encrypted_thumbnail: None,
+            thumbnail_iv: None,
+            crypto: None,
+            content_hash: None,
+        };
+        let mut conn = client.get_multiplexed_async_connection().await.unwrap();
+        let _: () = conn
+            .set_ex(keys.file(&id), serde_json::to_string(&stored).unwrap(), 60)
+            .await
+            .unwrap();
+        id
+    }
+
+    async fn delete(client: &Client, key: String) {
+        let mut conn = client.get_multiplexed_async_connection().await.unwrap();
+        let _: () = conn.del(key).await.unwrap();
+    }
+
+    async fn seed_secret(client: &Client, keys: &Keys, storage: SecretStorage) -> String {
+        let stored = StoredSecret {
+            encrypted_secret: "old".to_string(),
+            created_at: 1706900000,
+            metadata: Some(serde_json::json!({"label": "old"})),
+            tags: vec!["db".to_string()],
+            ..Default::default()
+        };
+        store_secret(client, keys, storage, &stored, 600, None)
+            .await
+            .unwrap()
+            .id
+    }
+
+    fn replacement(
+        encrypted_secret: &str,
+        metadata: Option<serde_json::Value>,
+    ) -> SecretReplaceRequest {
+        SecretReplaceRequest {
+            encrypted_secret: encrypted_secret.to_string(),
+            metadata,
+            payload_length: None,
         }
-        None => Ok(None),
+    }
+
+    async fn replace_keeps_ttl(storage: SecretStorage) {
+        let primary = client(PRIMARY_DB);
+        let keys = Keys::default();
+        let id = seed_secret(&primary, &keys, storage).await;
+
+        let replaced = replace_secret(
+            &primary,
+            &keys,
+            storage,
+            &id,
+            replacement("new", Some(serde_json::json!({"label": "new"}))),
+            1706900100,
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(replaced, Replace::Replaced);
+
+        let mut conn = primary.get_multiplexed_async_connection().await.unwrap();
+        let key = keys.secret(&id);
+        let (value, ttl): (Vec<u8>, i64) = read_with_ttl(&mut conn, storage, &key, 0)
+            .await
+            .unwrap()
+            .unwrap();
+        let stored = format::decode_secret(&value).unwrap().unwrap();
+        assert_eq!(stored.encrypted_secret, "new");
+        assert_eq!(stored.metadata, Some(serde_json::json!({"label": "new"})));
+        assert_eq!(stored.created_at, 1706900000);
+        assert_eq!(stored.updated_at, Some(1706900100));
+        assert_eq!(stored.tags, vec!["db".to_string()]);
+        assert!(ttl > 590 && ttl <= 600, "ttl {}", ttl);
+
+        delete(&primary, key).await;
+    }
+
+    #[tokio::test]
+    async fn test_replace_keeps_ttl() {
+        for format in [StorageFormat::Json, StorageFormat::Bincode] {
+            replace_keeps_ttl(SecretStorage::String { format }).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_replace_keeps_ttl_hash_storage() {
+        replace_keeps_ttl(SecretStorage::Hash { tombstone: 60 }).await;
+    }
+
+    #[tokio::test]
+    async fn test_create_dedup_claim_holds_off_identical_creates() {
+        let primary = client(PRIMARY_DB);
+        let keys = Keys::new(format!("dedup-{}:", Uuid::new_v4()));
+        let storage = SecretStorage::String {
+            format: StorageFormat::Json,
+        };
+        let claim =
+            |hash: &'static str| claim_deduplicated_create(&primary, &keys, storage, hash, 10);
+
+        // Of two identical creates in flight, only the first gets to store
+        assert_eq!(claim("double").await.unwrap(), DedupClaim::Claimed);
+        assert_eq!(claim("double").await.unwrap(), DedupClaim::Pending);
+
+        let id = seed_secret(&primary, &keys, storage).await;
+        record_deduplicated_create(&primary, &keys, "double", &id, "response", 10)
+            .await
+            .unwrap();
+        assert_eq!(
+            claim("double").await.unwrap(),
+            DedupClaim::Recorded("response".to_string())
+        );
+        // Once the secret is gone, a resubmit claims the hash afresh
+        delete(&primary, keys.secret(&id)).await;
+        assert_eq!(claim("double").await.unwrap(), DedupClaim::Claimed);
+
+        // A create that stored nothing lets its retry through
+        assert_eq!(claim("failed").await.unwrap(), DedupClaim::Claimed);
+        release_deduplicated_create(&primary, &keys, "failed")
+            .await
+            .unwrap();
+        assert_eq!(claim("failed").await.unwrap(), DedupClaim::Claimed);
+    }
+
+    #[tokio::test]
+    async fn test_raw_secret_returns_record_without_burning() {
+        let primary = client(PRIMARY_DB);
+        let keys = Keys::default();
+        for storage in [
+            SecretStorage::String {
+                format: StorageFormat::Json,
+            },
+            SecretStorage::String {
+                format: StorageFormat::Bincode,
+            },
+            SecretStorage::Hash { tombstone: 60 },
+        ] {
+            let id = seed_secret(&primary, &keys, storage).await;
+
+            let stored = raw_secret(&primary, &keys, storage, &id, 0)
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(stored.encrypted_secret, "old");
+            assert_eq!(stored.created_at, 1706900000);
+            assert_eq!(stored.metadata, Some(serde_json::json!({"label": "old"})));
+
+            // Still there, with its TTL
+            let ttl = touch_secret(&primary, &keys, storage, &id, Replica::default())
+                .await
+                .unwrap();
+            assert!(ttl.is_some_and(|ttl| ttl > 590), "ttl {:?}", ttl);
+            delete(&primary, keys.secret(&id)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_bincode_storage_reads_json_records() {
+        let primary = client(PRIMARY_DB);
+        let keys = Keys::default();
+        let bincode = SecretStorage::String {
+            format: StorageFormat::Bincode,
+        };
+        // Stored before switching to bincode
+        let id = seed_secret(
+            &primary,
+            &keys,
+            SecretStorage::String {
+                format: StorageFormat::Json,
+            },
+        )
+        .await;
+
+        let stored = raw_secret(&primary, &keys, bincode, &id, 0)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(stored.encrypted_secret, "old");
+
+        // Rewriting the record switches it over
+        let replaced = replace_secret(
+            &primary,
+            &keys,
+            bincode,
+            &id,
+            replacement("new", None),
+            1706900100,
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(replaced, Replace::Replaced);
+        let mut conn = primary.get_multiplexed_async_connection().await.unwrap();
+        let value: Vec<u8> = conn.get(keys.secret(&id)).await.unwrap();
+        assert!(serde_json::from_slice::<serde_json::Value>(&value).is_err());
+
+        let read = ReadOptions {
+            now: 1706900200,
+            ..ReadOptions::default()
+        };
+        let burned = get_secret(&primary, &keys, bincode, &id, read)
+            .await
+            .unwrap();
+        match burned {
+            Some(SecretRead::Found(burned)) => {
+                assert_eq!(burned.encrypted_secret, "new");
+                assert_eq!(burned.created_at, 1706900000);
+            }
+            other => panic!("expected the secret, got {:?}", other),
+        }
+        delete(&primary, keys.peeks(&id)).await;
+    }
+
+    #[tokio::test]
+    async fn test_undecodable_binary_record_fails_reads_without_burning() {
+        let primary = client(PRIMARY_DB);
+        let keys = Keys::default();
+        let storage = SecretStorage::String {
+            format: StorageFormat::Bincode,
+        };
+        let id = seed_secret(&primary, &keys, storage).await;
+        let key = keys.secret(&id);
+        let mut conn = primary.get_multiplexed_async_connection().await.unwrap();
+        let value: Vec<u8> = conn.get(&key).await.unwrap();
+        let _: () = conn
+            .set_ex(&key, &value[..value.len() - 4], 600)
+            .await
+            .unwrap();
+
+        let read = ReadOptions {
+            now: 1706900100,
+            ..ReadOptions::default()
+        };
+        assert!(matches!(
+            get_secret(&primary, &keys, storage, &id, read).await,
+            Err(ReadError::Redis(_))
+        ));
+        assert!(matches!(
+            peek_secret(&primary, &keys, storage, &id, read).await,
+            Err(ReadError::Redis(_))
+        ));
+        assert!(raw_secret(&primary, &keys, storage, &id, 0).await.is_err());
+        // Not taken for a legacy plain string and burned
+        let exists: bool = conn.exists(&key).await.unwrap();
+        assert!(exists);
+        delete(&primary, key).await;
+    }
+
+    #[tokio::test]
+    async fn test_compressed_bincode_file_round_trip() {
+        let primary = client(PRIMARY_DB);
+        let keys = Keys::default();
+        let stored = StoredFile {
+            metadata: FileMetadata {
+                original_filename: "a.txt".to_string(),
+                content_type: "text/plain".to_string(),
+                iv: "iv".to_string(),
+            },
+            encrypted_data: "data".to_string(),
+            created_at: 1706900000,
+            encrypted_thumbnail: Some("thumb".to_string()),
+            thumbnail_iv: Some("tiv".to_string()),
+            crypto: None,
+            content_hash: None,
+        };
+        let id = store_file(
+            &primary,
+            &keys,
+            &stored,
+            60,
+            None,
+            true,
+            StorageFormat::Bincode,
+        )
+        .await
+        .unwrap()
+        .id;
+
+        let (peeked, _) = peek_file(&primary, &keys, &id, Replica::default(), 0, 1024)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(peeked.created_at, 1706900000);
+        // The thumbnail lives at its own key, not in the record
+        assert!(peeked.thumbnail_iv.is_none());
+        assert_eq!(
+            file_thumbnail(&primary, &keys, &id, Replica::default())
+                .await
+                .unwrap(),
+            Some(("thumb".to_string(), "tiv".to_string()))
+        );
+        let burned = get_file(&primary, &keys, &id, 0, 1024)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(burned.encrypted_data, "data");
+        assert_eq!(burned.metadata.original_filename, "a.txt");
+        // and goes with the file
+        assert_eq!(
+            file_thumbnail(&primary, &keys, &id, Replica::default())
+                .await
+                .unwrap(),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_list_secrets_pages_past_limit() {
+        let primary = client(PRIMARY_DB);
+        // A fresh namespace, so only this test's secrets are listed
+        let keys = Keys::new(format!("list-{}:", Uuid::new_v4()));
+        let storage = SecretStorage::String {
+            format: StorageFormat::Json,
+        };
+        let mut seeded = HashSet::new();
+        for _ in 0..5 {
+            seeded.insert(seed_secret(&primary, &keys, storage).await);
+        }
+        let first = seeded.iter().next().unwrap().clone();
+        assert!(acquire_peek_slot(&primary, &keys, &first, 1).await.unwrap());
+
+        let mut listed = HashSet::new();
+        let mut pages = 0;
+        let mut cursor = 0;
+        loop {
+            let (next, secrets) = list_secrets(&primary, &keys, storage, cursor, 2, 0)
+                .await
+                .unwrap();
+            pages += 1;
+            for (id, stored, ttl) in secrets {
+                assert_eq!(stored.created_at, 1706900000);
+                assert_eq!(stored.tags, vec!["db".to_string()]);
+                assert!(ttl > 590, "ttl {}", ttl);
+                assert!(listed.insert(id), "listed twice");
+            }
+            if next == 0 {
+                break;
+            }
+            cursor = next;
+        }
+        assert!(pages > 1);
+        // Every secret once, and the peek slot side key not at all
+        assert_eq!(listed, seeded);
+
+        for id in &seeded {
+            delete(&primary, keys.secret(id)).await;
+        }
+        delete(&primary, keys.active_peeks(&first)).await;
+    }
+
+    #[tokio::test]
+    async fn test_list_owned_secrets_pages_and_prunes() {
+        let primary = client(PRIMARY_DB);
+        let keys = Keys::new(format!("owned-{}:", Uuid::new_v4()));
+        let storage = SecretStorage::String {
+            format: StorageFormat::Json,
+        };
+        let mut seeded = HashSet::new();
+        for _ in 0..5 {
+            let id = seed_secret(&primary, &keys, storage).await;
+            record_owned_secret(&primary, &keys, "ci", &id, 600)
+                .await
+                .unwrap();
+            seeded.insert(id);
+        }
+        // Another key's secret, and ids whose secrets are gone: enough of
+        // them that the set is too big for SSCAN to return in one step
+        let other = seed_secret(&primary, &keys, storage).await;
+        record_owned_secret(&primary, &keys, "other", &other, 600)
+            .await
+            .unwrap();
+        for _ in 0..150 {
+            record_owned_secret(
+                &primary,
+                &keys,
+                "ci",
+                &format!("sps-{}", Uuid::new_v4()),
+                600,
+            )
+            .await
+            .unwrap();
+        }
+
+        let mut listed = HashSet::new();
+        let mut pages = 0;
+        let mut cursor = 0;
+        loop {
+            let (next, secrets) = list_owned_secrets(&primary, &keys, storage, "ci", cursor, 10, 0)
+                .await
+                .unwrap();
+            pages += 1;
+            for secret in secrets {
+                assert_eq!(secret.created_at, 1706900000);
+                assert_eq!(secret.tags, vec!["db".to_string()]);
+                assert!(secret.ttl > 590, "ttl {}", secret.ttl);
+                assert!(listed.insert(secret.id), "listed twice");
+            }
+            if next == 0 {
+                break;
+            }
+            cursor = next;
+        }
+        assert!(pages > 1);
+        assert_eq!(listed, seeded);
+
+        // The gone ids were dropped along the way
+        let mut conn = primary.get_multiplexed_async_connection().await.unwrap();
+        let remaining: usize = conn.scard(keys.owned_secrets("ci")).await.unwrap();
+        assert_eq!(remaining, seeded.len());
+
+        for id in seeded.iter().chain([&other]) {
+            delete(&primary, keys.secret(id)).await;
+        }
+        delete(&primary, keys.owned_secrets("ci")).await;
+        delete(&primary, keys.owned_secrets("other")).await;
+    }
+
+    #[tokio::test]
+    async fn test_burn_counts_distinct_confirmed_readers() {
+        let primary = client(PRIMARY_DB);
+        let keys = Keys::default();
+        let storage = SecretStorage::String {
+            format: StorageFormat::Json,
+        };
+        let stored = StoredSecret {
+            encrypted_secret: "gated".to_string(),
+            created_at: 1706900000,
+            required_confirmations: Some(2),
+            ..Default::default()
+        };
+        let id = store_secret(&primary, &keys, storage, &stored, 600, None)
+            .await
+            .unwrap()
+            .id;
+        let read = ReadOptions {
+            now: 1706900100,
+            confirmation_window: 600,
+            ..ReadOptions::default()
+        };
+
+        // Two tokens peeked by the same reader count once
+        for token in ["a1", "a2"] {
+            issue_confirmation_token(&primary, &keys, &id, token, "10.0.0.1", 600)
+                .await
+                .unwrap();
+            assert_eq!(
+                confirm_secret(&primary, &keys, storage, &id, token, 1706900050, 600)
+                    .await
+                    .unwrap(),
+                Confirm::Counted(1)
+            );
+        }
+        assert!(matches!(
+            get_secret(&primary, &keys, storage, &id, read).await,
+            Ok(Some(SecretRead::Refused(Refusal::ConfirmationsPending {
+                required: 2,
+                collected: 1,
+            })))
+        ));
+
+        issue_confirmation_token(&primary, &keys, &id, "b1", "10.0.0.2", 600)
+            .await
+            .unwrap();
+        confirm_secret(&primary, &keys, storage, &id, "b1", 1706900060, 600)
+            .await
+            .unwrap();
+        assert!(matches!(
+            get_secret(&primary, &keys, storage, &id, read).await,
+            Ok(Some(SecretRead::Found(_)))
+        ));
+        let mut conn = primary.get_multiplexed_async_connection().await.unwrap();
+        let left: i64 = conn
+            .exists(&[keys.confirmations(&id), keys.confirm_tokens(&id)])
+            .await
+            .unwrap();
+        assert_eq!(left, 0);
+        delete(&primary, keys.peeks(&id)).await;
+    }
+
+    #[tokio::test]
+    async fn test_replace_after_burn_or_confirmation() {
+        let primary = client(PRIMARY_DB);
+        let keys = Keys::default();
+        let storage = SecretStorage::String {
+            format: StorageFormat::Json,
+        };
+        let id = seed_secret(&primary, &keys, storage).await;
+
+        // Only a token a peek handed out counts
+        assert_eq!(
+            confirm_secret(&primary, &keys, storage, &id, "made-up", 1706900000, 600)
+                .await
+                .unwrap(),
+            Confirm::UnknownToken
+        );
+        issue_confirmation_token(&primary, &keys, &id, "issued", "reader", 600)
+            .await
+            .unwrap();
+        assert_eq!(
+            confirm_secret(&primary, &keys, storage, &id, "issued", 1706900000, 600)
+                .await
+                .unwrap(),
+            Confirm::Counted(1)
+        );
+        let replaced = replace_secret(
+            &primary,
+            &keys,
+            storage,
+            &id,
+            replacement("new", None),
+            1706900100,
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(replaced, Replace::AlreadyRead);
+
+        // So does a read taken from a `maxReads` secret
+        delete(&primary, keys.confirmations(&id)).await;
+        let mut conn = primary.get_multiplexed_async_connection().await.unwrap();
+        let _: i64 = conn.incr(keys.reads(&id), 1).await.unwrap();
+        let replaced = replace_secret(
+            &primary,
+            &keys,
+            storage,
+            &id,
+            replacement("new", None),
+            1706900100,
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(replaced, Replace::AlreadyRead);
+
+        // A burned secret stays burned rather than being recreated
+        delete(&primary, keys.secret(&id)).await;
+        delete(&primary, keys.reads(&id)).await;
+        delete(&primary, keys.confirm_tokens(&id)).await;
+        let replaced = replace_secret(
+            &primary,
+            &keys,
+            storage,
+            &id,
+            replacement("new", None),
+            1706900100,
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(replaced, Replace::NotFound);
+        let mut conn = primary.get_multiplexed_async_connection().await.unwrap();
+        let exists: bool = conn.exists(keys.secret(&id)).await.unwrap();
+        assert!(!exists);
+    }
+
+    #[tokio::test]
+    async fn test_replace_and_extend_if_unmodified_since() {
+        let primary = client(PRIMARY_DB);
+        let keys = Keys::default();
+        for storage in [
+            SecretStorage::String {
+                format: StorageFormat::Json,
+            },
+            SecretStorage::Hash { tombstone: 60 },
+        ] {
+            let id = seed_secret(&primary, &keys, storage).await;
+            let replace = |since| {
+                replace_secret(
+                    &primary,
+                    &keys,
+                    storage,
+                    &id,
+                    replacement("new", None),
+                    1706900100,
+                    Some(since),
+                )
+            };
+            let extend = |since| extend_secret(&primary, &keys, storage, &id, 900, Some(since));
+
+            // Unchanged since creation, as the client last saw it
+            assert_eq!(extend(1706900000).await.unwrap(), Extend::Extended);
+            assert_eq!(replace(1706900000).await.unwrap(), Replace::Replaced);
+            // The replace above moved it on, so the same precondition is now stale
+            assert_eq!(
+                replace(1706900000).await.unwrap(),
+                Replace::Modified(1706900100)
+            );
+            assert_eq!(
+                extend(1706900000).await.unwrap(),
+                Extend::Modified(1706900100)
+            );
+            assert_eq!(replace(1706900100).await.unwrap(), Replace::Replaced);
+            assert_eq!(extend(1706900100).await.unwrap(), Extend::Extended);
+
+            delete(&primary, keys.secret(&id)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_peek_reads_from_replica() {
+        let (primary, replica) = (client(PRIMARY_DB), client(REPLICA_DB));
+        let keys = Keys::default();
+        let id = seed_file(&replica, &keys).await;
+
+        let routed = Replica {
+            client: Some(&replica),
+            fallback: false,
+        };
+        let (stored, ttl) = peek_file(&primary, &keys, &id, routed, 0, 0)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(stored.encrypted_data, "data");
+        assert!(ttl > 0 && ttl <= 60);
+        // The primary alone never had it
+        assert!(peek_file(&primary, &keys, &id, Replica::default(), 0, 0)
+            .await
+            .unwrap()
+            .is_none());
+
+        delete(&replica, keys.file(&id)).await;
+    }
+
+    #[tokio::test]
+    async fn test_replica_miss_falls_back_to_primary() {
+        let (primary, replica) = (client(PRIMARY_DB), client(REPLICA_DB));
+        let keys = Keys::default();
+        // Created on the primary but not yet replicated
+        let id = seed_file(&primary, &keys).await;
+
+        let strict = Replica {
+            client: Some(&replica),
+            fallback: false,
+        };
+        assert!(peek_file(&primary, &keys, &id, strict, 0, 0)
+            .await
+            .unwrap()
+            .is_none());
+
+        let lenient = Replica {
+            client: Some(&replica),
+            fallback: true,
+        };
+        assert!(peek_file(&primary, &keys, &id, lenient, 0, 0)
+            .await
+            .unwrap()
+            .is_some());
+
+        delete(&primary, keys.file(&id)).await;
+    }
+
+    #[tokio::test]
+    async fn test_oversized_file_is_left_unread() {
+        let primary = client(PRIMARY_DB);
+        let keys = Keys::default();
+        let id = seed_file(&primary, &keys).await;
+
+        assert!(matches!(
+            get_file(&primary, &keys, &id, 16, 0).await,
+            Err(ReadError::TooLarge(_))
+        ));
+        assert!(matches!(
+            peek_file(&primary, &keys, &id, Replica::default(), 16, 0).await,
+            Err(ReadError::TooLarge(_))
+        ));
+        // Refused before GETDEL, so it's still there to burn under a higher cap
+        assert!(get_file(&primary, &keys, &id, 0, 0)
+            .await
+            .unwrap()
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn test_reconcile_keeps_concurrent_slot_changes() {
+        let primary = client(PRIMARY_DB);
+        let keys = Keys::new(format!("reconcile-{}:", Uuid::new_v4()));
+
+        // 5 recorded, but 2 of those files expired unread
+        let mut conn = primary.get_multiplexed_async_connection().await.unwrap();
+        let _: () = conn.set(keys.active_files(), 5).await.unwrap();
+        let snapshot = active_files(&primary, &keys).await.unwrap();
+        // An upload and a burn land while the SCAN runs
+        assert_eq!(
+            reserve_file_slot(&primary, &keys, 10).await.unwrap(),
+            Some(4)
+        );
+        reserve_file_slot(&primary, &keys, 10).await.unwrap();
+        release_file_slot(&primary, &keys).await.unwrap();
+
+        let active = reconcile_active_files(&primary, &keys, snapshot, 3)
+            .await
+            .unwrap();
+        assert_eq!(active, 4);
+        delete(&primary, keys.active_files()).await;
+    }
+
+    #[tokio::test]
+    async fn test_oversized_secret_is_left_unread() {
+        let primary = client(PRIMARY_DB);
+        let keys = Keys::default();
+        let storage = SecretStorage::String {
+            format: StorageFormat::Json,
+        };
+        let id = seed_secret(&primary, &keys, storage).await;
+        let capped = ReadOptions {
+            now: 1706900100,
+            max_value_bytes: 16,
+            ..ReadOptions::default()
+        };
+
+        assert!(matches!(
+            get_secret(&primary, &keys, storage, &id, capped).await,
+            Err(ReadError::TooLarge(_))
+        ));
+        assert!(matches!(
+            peek_secret(&primary, &keys, storage, &id, capped).await,
+            Err(ReadError::TooLarge(_))
+        ));
+        let uncapped = ReadOptions {
+            max_value_bytes: 0,
+            ..capped
+        };
+        assert!(matches!(
+            get_secret(&primary, &keys, storage, &id, uncapped).await,
+            Ok(Some(SecretRead::Found(_)))
+        ));
+        delete(&primary, keys.peeks(&id)).await;
+    }
+
+    #[tokio::test]
+    async fn test_compressed_file_bomb_hits_decompression_limit() {
+        let primary = client(PRIMARY_DB);
+        let keys = Keys::default();
+        let id = keys.new_id(FILE_ID_PREFIX);
+        let bomb = compress::compress(&vec![b' '; 8 * 1024 * 1024]);
+        let mut conn = primary.get_multiplexed_async_connection().await.unwrap();
+        let _: () = conn.set_ex(keys.file(&id), bomb, 60).await.unwrap();
+
+        assert!(matches!(
+            peek_file(&primary, &keys, &id, Replica::default(), 0, 1024 * 1024).await,
+            Err(ReadError::DecompressionLimit(_))
+        ));
+        assert!(matches!(
+            get_file(&primary, &keys, &id, 0, 1024 * 1024).await,
+            Err(ReadError::DecompressionLimit(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_blob_goes_with_its_last_file_however_each_goes() {
+        let primary = client(PRIMARY_DB);
+        let keys = Keys::new(format!("blob-{}:", Uuid::new_v4()));
+        let hash = "ab12";
+        let file = |data: &str| StoredFile {
+            metadata: FileMetadata {
+                original_filename: "a.txt".to_string(),
+                content_type: "text/plain".to_string(),
+                iv: "iv".to_string(),
+            },
+            encrypted_data: data.to_string(),
+            created_at: 1706900000,
+            encrypted_thumbnail: None,
+            thumbnail_iv: None,
+            crypto: None,
+            content_hash: Some(hash.to_string()),
+        };
+        let (primary, keys) = (&primary, &keys);
+        let store = |stored: StoredFile, expiration| async move {
+            store_file(
+                primary,
+                keys,
+                &stored,
+                expiration,
+                None,
+                false,
+                StorageFormat::Json,
+            )
+            .await
+            .unwrap()
+            .id
+        };
+
+        // One file expires without being read, the other lives longer
+        let _expired = store(file("data"), 1).await;
+        let burned = store(file(""), 600).await;
+        let mut conn = primary.get_multiplexed_async_connection().await.unwrap();
+        let ttl: i64 = conn.ttl(keys.blob(hash)).await.unwrap();
+        assert!(ttl > 590, "ttl {}", ttl);
+
+        tokio::time::sleep(Duration::from_millis(2100)).await;
+        let stored = get_file(primary, keys, &burned, 0, 0)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(stored.encrypted_data, "data");
+        // The expired file's reference didn't keep the blob alive
+        let exists: bool = conn.exists(keys.blob(hash)).await.unwrap();
+        assert!(!exists);
+        let exists: bool = conn.exists(keys.blob_refs(hash)).await.unwrap();
+        assert!(!exists);
+    }
+
+    #[tokio::test]
+    async fn test_abandoned_upload_chunks_expire() {
+        let primary = client(PRIMARY_DB);
+        let keys = Keys::default();
+        let id = start_upload(&primary, &keys, "{}", 1).await.unwrap();
+        assert_eq!(
+            put_upload_chunk(&primary, &keys, &id, 0, "abcd", 1024)
+                .await
+                .unwrap(),
+            ChunkWrite::Stored(4)
+        );
+
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        let mut conn = primary.get_multiplexed_async_connection().await.unwrap();
+        let exists: bool = conn.exists(keys.upload_chunks(&id)).await.unwrap();
+        assert!(!exists);
+        assert_eq!(
+            take_upload(&primary, &keys, &id, 1).await.unwrap(),
+            UploadTake::NotFound
+        );
+        assert_eq!(
+            put_upload_chunk(&primary, &keys, &id, 1, "efgh", 1024)
+                .await
+                .unwrap(),
+            ChunkWrite::NotFound
+        );
+    }
+
+    #[tokio::test]
+    async fn test_completed_upload_joins_chunks_and_clears_session() {
+        let primary = client(PRIMARY_DB);
+        let keys = Keys::default();
+        let id = start_upload(&primary, &keys, r#"{"expiration":60}"#, 60)
+            .await
+            .unwrap();
+        // Out of order, and one over the size limit
+        put_upload_chunk(&primary, &keys, &id, 1, "efgh", 8)
+            .await
+            .unwrap();
+        put_upload_chunk(&primary, &keys, &id, 0, "abcd", 8)
+            .await
+            .unwrap();
+        assert_eq!(
+            put_upload_chunk(&primary, &keys, &id, 2, "ij", 8)
+                .await
+                .unwrap(),
+            ChunkWrite::TooLarge
+        );
+
+        // Declaring a chunk that never arrived leaves the session intact
+        assert_eq!(
+            take_upload(&primary, &keys, &id, 3).await.unwrap(),
+            UploadTake::Incomplete
+        );
+        assert_eq!(
+            take_upload(&primary, &keys, &id, 2).await.unwrap(),
+            UploadTake::Complete {
+                session: r#"{"expiration":60}"#.to_string(),
+                data: "abcdefgh".to_string(),
+            }
+        );
+
+        let mut conn = primary.get_multiplexed_async_connection().await.unwrap();
+        let exists: u64 = conn
+            .exists(&[keys.upload(&id), keys.upload_chunks(&id)])
+            .await
+            .unwrap();
+        assert_eq!(exists, 0);
+    }
+
+    #[tokio::test]
+    async fn test_access_log_trims_to_cap_and_counts_dropped() {
+        let client = client(PRIMARY_DB);
+        let keys = Keys::default();
+        let id = keys.new_id(SECRET_ID_PREFIX);
+
+        for i in 0..5 {
+            record_access(&client, &keys, &id, &format!("entry-{}", i), 3, 60)
+                .await
+                .unwrap();
+        }
+
+        let (entries, dropped) = access_log(&client, &keys, &id).await.unwrap();
+        assert_eq!(entries, ["entry-4", "entry-3", "entry-2"]);
+        assert_eq!(dropped, 2);
+
+        let mut conn = client.get_multiplexed_async_connection().await.unwrap();
+        let ttl: i64 = conn.ttl(keys.access_dropped(&id)).await.unwrap();
+        assert!(ttl > 0 && ttl <= 60);
+        let _: () = conn
+            .del(&[keys.access_log(&id), keys.access_dropped(&id)])
+            .await
+            .unwrap();
     }
 }
@@ -0,0 +1,98 @@
+//! Gzip for stored file records (`COMPRESS_STORAGE=1`). Compressed records
+//! are stored as raw gzip bytes; plain records are JSON or marked bincode,
+//! which never start with the gzip magic, so both kinds can be read back
+//! side by side.
+//!
+//! A stored value is untrusted on read: anything with write access to Redis
+//! could plant a small value that inflates to gigabytes. Decompression stops
+//! at a byte limit instead of buffering whatever comes out.
+
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use std::io::{Read, Write};
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Why a stored value couldn't be decompressed.
+#[derive(Debug)]
+pub enum DecompressError {
+    /// It inflates past the limit (`MAX_DECOMPRESSED_BYTES`)
+    TooLarge(u64),
+    /// Not valid gzip
+    Corrupt(String),
+}
+
+pub fn compress(data: &[u8]) -> Vec<u8> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder
+        .write_all(data)
+        .expect("writing to a Vec can't fail");
+    encoder.finish().expect("writing to a Vec can't fail")
+}
+
+/// A stored record, inflated when it's gzip (to at most `max_bytes`, 0 =
+/// unlimited) or as-is otherwise.
+pub fn decode(value: Vec<u8>, max_bytes: u64) -> Result<Vec<u8>, DecompressError> {
+    if value.starts_with(&GZIP_MAGIC) {
+        inflate(&value, max_bytes)
+    } else {
+        Ok(value)
+    }
+}
+
+fn inflate(data: &[u8], max_bytes: u64) -> Result<Vec<u8>, DecompressError> {
+    let limit = if max_bytes == 0 { u64::MAX } else { max_bytes };
+    // Read one byte past the limit, so a value of exactly the limit passes
+    let mut out = Vec::new();
+    GzDecoder::new(data)
+        .take(limit.saturating_add(1))
+        .read_to_end(&mut out)
+        .map_err(|e| DecompressError::Corrupt(e.to_string()))?;
+    if out.len() as u64 > limit {
+        return Err(DecompressError::TooLarge(max_bytes));
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip_and_plain_passthrough() {
+        let json = r#"{"encryptedData":"data"}"#;
+        assert_eq!(
+            decode(compress(json.as_bytes()), 1024).unwrap(),
+            json.as_bytes()
+        );
+        assert_eq!(
+            decode(json.as_bytes().to_vec(), 1024).unwrap(),
+            json.as_bytes()
+        );
+    }
+
+    #[test]
+    fn test_bomb_stops_at_limit() {
+        // 16 MiB of zeros gzips to a few KiB
+        let bomb = compress(&vec![0; 16 * 1024 * 1024]);
+        assert!(bomb.len() < 64 * 1024);
+
+        assert!(matches!(
+            decode(bomb.clone(), 1024 * 1024),
+            Err(DecompressError::TooLarge(_))
+        ));
+        assert_eq!(
+            decode(bomb, 16 * 1024 * 1024).unwrap().len(),
+            16 * 1024 * 1024
+        );
+    }
+
+    #[test]
+    fn test_truncated_gzip_is_corrupt() {
+        let mut value = compress(b"{}");
+        value.truncate(value.len() - 4);
+        assert!(matches!(
+            decode(value, 1024),
+            Err(DecompressError::Corrupt(_))
+        ));
+    }
+}
@@ -0,0 +1,392 @@
+//! Record encodings for secrets and files (`STORAGE_FORMAT`). JSON is
+//! readable in `redis-cli`; bincode drops the field names, which adds up over
+//! many small records.
+//!
+//! Bincode has no field names to skip by either, so the `skip_serializing_if`
+//! fields of `StoredSecret` and `StoredFile` can't go through it as they are.
+//! Binary records are fixed-layout mirrors that hold every field instead,
+//! with free-form secret `metadata` kept as JSON text inside.
+//!
+//! Binary records start with a marker byte that JSON, gzip and legacy
+//! plain-string secrets never start with, so reads accept every encoding
+//! whichever one is configured: switching needs no migration.
+
+use crate::models::{CryptoParams, FileMetadata, StoredFile, StoredSecret};
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+/// Leads a bincode record laid out as the version 1 mirrors below. It's a
+/// UTF-8 continuation byte, so no JSON or base64 text starts with it.
+const BINCODE_V1: u8 = 0xb1;
+
+/// How new secret and file records are encoded (`STORAGE_FORMAT`).
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum StorageFormat {
+    #[default]
+    Json,
+    /// The marker byte, then the record as bincode
+    Bincode,
+}
+
+impl FromStr for StorageFormat {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "json" => Ok(Self::Json),
+            "bincode" => Ok(Self::Bincode),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct BincodeCrypto {
+    algorithm: String,
+    kdf: Option<String>,
+    iterations: Option<u32>,
+    salt: Option<String>,
+}
+
+/// `StoredSecret`, field for field. The conversions destructure both sides
+/// in full, so a new field doesn't compile until it has a place here. Any
+/// change to this layout, even a field added at the end, needs a new marker
+/// byte: bincode has no defaults, so existing records would stop decoding.
+#[derive(Serialize, Deserialize)]
+struct BincodeSecret {
+    encrypted_secret: String,
+    created_at: u64,
+    updated_at: Option<u64>,
+    /// JSON text: bincode can't decode arbitrary values
+    metadata: Option<String>,
+    kind: Option<String>,
+    available_at: Option<u64>,
+    max_age_seconds: Option<u64>,
+    required_confirmations: Option<u32>,
+    tags: Vec<String>,
+    sliding_window: Option<u64>,
+    max_lifetime_seconds: Option<u64>,
+    peek_burn: bool,
+    no_peek: bool,
+    bind_view_context: bool,
+    allowed_cidrs: Vec<String>,
+    max_reads: Option<u32>,
+    crypto: Option<BincodeCrypto>,
+    payload_length: Option<usize>,
+    file_metadata: Option<FileMetadata>,
+}
+
+/// `StoredFile`, field for field, like `BincodeSecret`.
+#[derive(Serialize, Deserialize)]
+struct BincodeFile {
+    metadata: FileMetadata,
+    encrypted_data: String,
+    created_at: u64,
+    encrypted_thumbnail: Option<String>,
+    thumbnail_iv: Option<String>,
+    crypto: Option<BincodeCrypto>,
+    content_hash: Option<String>,
+}
+
+impl From<CryptoParams> for BincodeCrypto {
+    fn from(crypto: CryptoParams) -> Self {
+        let CryptoParams {
+            algorithm,
+            kdf,
+            iterations,
+            salt,
+        } = crypto;
+        BincodeCrypto {
+            algorithm,
+            kdf,
+            iterations,
+            salt,
+        }
+    }
+}
+
+impl From<BincodeCrypto> for CryptoParams {
+    fn from(crypto: BincodeCrypto) -> Self {
+        let BincodeCrypto {
+            algorithm,
+            kdf,
+            iterations,
+            salt,
+        } = crypto;
+        CryptoParams {
+            algorithm,
+            kdf,
+            iterations,
+            salt,
+        }
+    }
+}
+
+impl From<StoredSecret> for BincodeSecret {
+    fn from(stored: StoredSecret) -> Self {
+        let StoredSecret {
+            encrypted_secret,
+            created_at,
+            updated_at,
+            metadata,
+            kind,
+            available_at,
+            max_age_seconds,
+            required_confirmations,
+            tags,
+            sliding_window,
+            max_lifetime_seconds,
+            peek_burn,
+            no_peek,
+            bind_view_context,
+            allowed_cidrs,
+            max_reads,
+            crypto,
+            payload_length,
+            file_metadata,
+        } = stored;
+        BincodeSecret {
+            encrypted_secret,
+            created_at,
+            updated_at,
+            metadata: metadata.map(|metadata| metadata.to_string()),
+            kind,
+            available_at,
+            max_age_seconds,
+            required_confirmations,
+            tags,
+            sliding_window,
+            max_lifetime_seconds,
+            peek_burn,
+            no_peek,
+            bind_view_context,
+            allowed_cidrs,
+            max_reads,
+            crypto: crypto.map(BincodeCrypto::from),
+            payload_length,
+            file_metadata,
+        }
+    }
+}
+
+impl TryFrom<BincodeSecret> for StoredSecret {
+    type Error = serde_json::Error;
+
+    fn try_from(record: BincodeSecret) -> Result<Self, Self::Error> {
+        let BincodeSecret {
+            encrypted_secret,
+            created_at,
+            updated_at,
+            metadata,
+            kind,
+            available_at,
+            max_age_seconds,
+            required_confirmations,
+            tags,
+            sliding_window,
+            max_lifetime_seconds,
+            peek_burn,
+            no_peek,
+            bind_view_context,
+            allowed_cidrs,
+            max_reads,
+            crypto,
+            payload_length,
+            file_metadata,
+        } = record;
+        Ok(StoredSecret {
+            encrypted_secret,
+            created_at,
+            updated_at,
+            metadata: metadata
+                .map(|metadata| serde_json::from_str(&metadata))
+                .transpose()?,
+            kind,
+            available_at,
+            max_age_seconds,
+            required_confirmations,
+            tags,
+            sliding_window,
+            max_lifetime_seconds,
+            peek_burn,
+            no_peek,
+            bind_view_context,
+            allowed_cidrs,
+            max_reads,
+            crypto: crypto.map(CryptoParams::from),
+            payload_length,
+            file_metadata,
+        })
+    }
+}
+
+impl From<StoredFile> for BincodeFile {
+    fn from(stored: StoredFile) -> Self {
+        let StoredFile {
+            metadata,
+            encrypted_data,
+            created_at,
+            encrypted_thumbnail,
+            thumbnail_iv,
+            crypto,
+            content_hash,
+        } = stored;
+        BincodeFile {
+            metadata,
+            encrypted_data,
+            created_at,
+            encrypted_thumbnail,
+            thumbnail_iv,
+            crypto: crypto.map(BincodeCrypto::from),
+            content_hash,
+        }
+    }
+}
+
+impl From<BincodeFile> for StoredFile {
+    fn from(record: BincodeFile) -> Self {
+        let BincodeFile {
+            metadata,
+            encrypted_data,
+            created_at,
+            encrypted_thumbnail,
+            thumbnail_iv,
+            crypto,
+            content_hash,
+        } = record;
+        StoredFile {
+            metadata,
+            encrypted_data,
+            created_at,
+            encrypted_thumbnail,
+            thumbnail_iv,
+            crypto: crypto.map(CryptoParams::from),
+            content_hash,
+        }
+    }
+}
+
+fn to_bincode(record: &impl Serialize) -> Vec<u8> {
+    let mut value = vec![BINCODE_V1];
+    bincode::serialize_into(&mut value, record).expect("writing to a Vec can't fail");
+    value
+}
+
+pub fn encode_secret(format: StorageFormat, stored: &StoredSecret) -> Vec<u8> {
+    match format {
+        StorageFormat::Json => serde_json::to_vec(stored).expect("secret record serializes"),
+        StorageFormat::Bincode => to_bincode(&BincodeSecret::from(stored.clone())),
+    }
+}
+
+/// A secret record in either encoding. None for anything else, which
+/// includes legacy plain-string secrets. No such value starts with the
+/// marker byte, so a binary record that doesn't decode is an error rather
+/// than a plain string.
+pub fn decode_secret(value: &[u8]) -> Result<Option<StoredSecret>, String> {
+    match value.split_first() {
+        Some((&BINCODE_V1, record)) => {
+            let record: BincodeSecret = bincode::deserialize(record).map_err(|e| e.to_string())?;
+            StoredSecret::try_from(record)
+                .map(Some)
+                .map_err(|e| e.to_string())
+        }
+        _ => Ok(serde_json::from_slice(value).ok()),
+    }
+}
+
+pub fn encode_file(format: StorageFormat, stored: &StoredFile) -> Vec<u8> {
+    match format {
+        StorageFormat::Json => serde_json::to_vec(stored).expect("file record serializes"),
+        StorageFormat::Bincode => to_bincode(&BincodeFile::from(stored.clone())),
+    }
+}
+
+/// A file record in either encoding.
+pub fn decode_file(value: &[u8]) -> Result<StoredFile, String> {
+    match value.split_first() {
+        Some((&BINCODE_V1, record)) => bincode::deserialize::<BincodeFile>(record)
+            .map(StoredFile::from)
+            .map_err(|e| e.to_string()),
+        _ => serde_json::from_slice(value).map_err(|e| e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secret() -> StoredSecret {
+        StoredSecret {
+            encrypted_secret: "SGVsbG8gV29ybGQh".to_string(),
+            created_at: 1_700_000_000,
+            metadata: Some(serde_json::json!({"label": "db", "nested": {"n": 1}})),
+            tags: vec!["ops".to_string()],
+            max_reads: Some(3),
+            crypto: Some(CryptoParams {
+                algorithm: "AES-256-GCM".to_string(),
+                kdf: Some("PBKDF2-SHA256".to_string()),
+                iterations: Some(600_000),
+                salt: None,
+            }),
+            payload_length: Some(12),
+            ..StoredSecret::default()
+        }
+    }
+
+    fn file() -> StoredFile {
+        StoredFile {
+            metadata: FileMetadata {
+                original_filename: "note.txt".to_string(),
+                content_type: "text/plain".to_string(),
+                iv: "iv".to_string(),
+            },
+            encrypted_data: "SGVsbG8=".to_string(),
+            created_at: 1_700_000_000,
+            encrypted_thumbnail: None,
+            thumbnail_iv: Some("tiv".to_string()),
+            crypto: None,
+            content_hash: None,
+        }
+    }
+
+    #[test]
+    fn test_secret_round_trips_in_both_formats() {
+        let json = serde_json::to_value(secret()).unwrap();
+        for format in [StorageFormat::Json, StorageFormat::Bincode] {
+            let value = encode_secret(format, &secret());
+            assert_eq!(value[0] == BINCODE_V1, format == StorageFormat::Bincode);
+            let decoded = decode_secret(&value).unwrap().unwrap();
+            assert_eq!(serde_json::to_value(decoded).unwrap(), json);
+        }
+        assert!(
+            encode_secret(StorageFormat::Bincode, &secret()).len()
+                < encode_secret(StorageFormat::Json, &secret()).len()
+        );
+    }
+
+    #[test]
+    fn test_file_round_trips_in_both_formats() {
+        let json = serde_json::to_value(file()).unwrap();
+        for format in [StorageFormat::Json, StorageFormat::Bincode] {
+            let decoded = decode_file(&encode_file(format, &file())).unwrap();
+            assert_eq!(serde_json::to_value(decoded).unwrap(), json);
+        }
+    }
+
+    #[test]
+    fn test_json_and_legacy_values_still_read() {
+        // Written before STORAGE_FORMAT existed
+        let legacy_json = br#"{"encryptedSecret":"abc","createdAt":5,"metadata":null}"#;
+        let stored = decode_secret(legacy_json).unwrap().unwrap();
+        assert_eq!(
+            (stored.encrypted_secret.as_str(), stored.created_at),
+            ("abc", 5)
+        );
+        // Plain-string secrets predate records altogether
+        assert!(decode_secret(b"SGVsbG8=").unwrap().is_none());
+        // A truncated binary record isn't mistaken for anything else
+        let value = encode_secret(StorageFormat::Bincode, &secret());
+        assert!(decode_secret(&value[..value.len() - 4]).is_err());
+    }
+}
@@ -0,0 +1,433 @@
+//! Hash layout for secrets (`HASH_STORAGE=1`). The encrypted payload and the
+//! rest of the record live in separate fields of one hash, so the payload can
+//! expire on its own (HEXPIRE, Redis 7.4+) while the metadata lingers as a
+//! tombstone until the key itself expires.
+//!
+//! Reads hand back the record re-joined as `StoredSecret` JSON, so callers
+//! treat both layouts the same.
+
+use crate::models::StoredSecret;
+use redis::aio::MultiplexedConnection;
+
+const META_FIELD: &str = "meta";
+const PAYLOAD_FIELD: &str = "payload";
+
+/// Create the hash only if the key is free (the hash equivalent of SET NX),
+/// expiring the payload after ARGV[3] seconds and the key after ARGV[4].
+const CREATE_SCRIPT: &str = r#"
+if redis.call('EXISTS', KEYS[1]) == 1 then
+    return false
+end
+redis.call('HSET', KEYS[1], 'meta', ARGV[1], 'payload', ARGV[2])
+redis.call('HEXPIRE', KEYS[1], ARGV[3], 'FIELDS', 1, 'payload')
+redis.call('EXPIRE', KEYS[1], ARGV[4])
+return redis.status_reply('OK')
+"#;
+
+/// Remove the payload and return it with the metadata, leaving the tombstone
+/// for at most ARGV[1] more seconds. Without the cap a burned secret's
+/// tombstone would outlive its unread expiration. A re-read of a burned
+/// secret returns before touching the TTL, so it can't extend it.
+const TAKE_SCRIPT: &str = r#"
+local payload = redis.call('HGET', KEYS[1], 'payload')
+if not payload then
+    return false
+end
+redis.call('HDEL', KEYS[1], 'payload')
+redis.call('EXPIRE', KEYS[1], ARGV[1], 'LT')
+return {redis.call('HGET', KEYS[1], 'meta'), payload}
+"#;
+
+/// `TAKE_SCRIPT` for a multi-confirmation secret: takes the payload only
+/// while the sorted set at KEYS[2] holds at least ARGV[3] confirmations
+/// scored from ARGV[2] on, deleting them and the issued tokens (KEYS[3])
+/// with it. Replies {1 if the threshold was met, confirmations counted,
+/// metadata, payload}.
+const TAKE_CONFIRMED_SCRIPT: &str = r#"
+local collected = redis.call('ZCOUNT', KEYS[2], ARGV[2], '+inf')
+if collected < tonumber(ARGV[3]) then
+    return {0, collected, false, false}
+end
+redis.call('DEL', KEYS[2], KEYS[3])
+local payload = redis.call('HGET', KEYS[1], 'payload')
+if not payload then
+    return {1, collected, false, false}
+end
+redis.call('HDEL', KEYS[1], 'payload')
+redis.call('EXPIRE', KEYS[1], ARGV[1], 'LT')
+return {1, collected, redis.call('HGET', KEYS[1], 'meta'), payload}
+"#;
+
+/// Overwrite both fields, keeping the payload's remaining TTL (HSET drops a
+/// field's TTL). Replies 0 if the payload is gone, 2 if the secret has reader
+/// confirmations at KEYS[2] and 3 if, given ARGV[3] (`If-Unmodified-Since`),
+/// its metadata says it was created or replaced after that, leaving it
+/// untouched.
+const REPLACE_SCRIPT: &str = r#"
+if redis.call('HEXISTS', KEYS[1], 'payload') == 0 then
+    return 0
+end
+if redis.call('EXISTS', KEYS[2]) == 1 then
+    return 2
+end
+if ARGV[3] then
+    local meta = cjson.decode(redis.call('HGET', KEYS[1], 'meta'))
+    if (meta.updatedAt or meta.createdAt) > tonumber(ARGV[3]) then
+        return 3
+    end
+end
+local ttl = redis.call('HPTTL', KEYS[1], 'FIELDS', 1, 'payload')[1]
+redis.call('HSET', KEYS[1], 'meta', ARGV[1], 'payload', ARGV[2])
+if ttl > 0 then
+    redis.call('HPEXPIRE', KEYS[1], ttl, 'FIELDS', 1, 'payload')
+end
+return 1
+"#;
+
+/// Extend the payload to ARGV[1] seconds and the key, tombstone included, to
+/// ARGV[2]. Replies 0 if the payload is gone and 3 if modified after ARGV[3],
+/// as in `REPLACE_SCRIPT`.
+const EXTEND_SCRIPT: &str = r#"
+if redis.call('HEXISTS', KEYS[1], 'payload') == 0 then
+    return 0
+end
+if ARGV[3] then
+    local meta = cjson.decode(redis.call('HGET', KEYS[1], 'meta'))
+    if (meta.updatedAt or meta.createdAt) > tonumber(ARGV[3]) then
+        return 3
+    end
+end
+redis.call('HEXPIRE', KEYS[1], ARGV[1], 'FIELDS', 1, 'payload')
+redis.call('EXPIRE', KEYS[1], ARGV[2])
+return 1
+"#;
+
+/// Split a record into its metadata field (the record with an empty payload)
+/// and its payload field.
+pub fn split(stored: &StoredSecret) -> Result<(String, &str), serde_json::Error> {
+    let mut meta = serde_json::to_value(stored)?;
+    meta["encryptedSecret"] = serde_json::Value::from("");
+    Ok((meta.to_string(), &stored.encrypted_secret))
+}
+
+/// Re-join the fields written by `split` into `StoredSecret` JSON.
+pub fn join(meta: &str, payload: String) -> Option<String> {
+    let mut stored: StoredSecret = serde_json::from_str(meta).ok()?;
+    stored.encrypted_secret = payload;
+    serde_json::to_string(&stored).ok()
+}
+
+/// The create command for `store_new`: replies OK, or nil if the key is taken.
+pub fn create(key: &str, meta: &str, payload: &str, expiration: u64, tombstone: u64) -> redis::Cmd {
+    let mut cmd = redis::cmd("EVAL");
+    cmd.arg(CREATE_SCRIPT)
+        .arg(1)
+        .arg(key)
+        .arg(meta)
+        .arg(payload)
+        .arg(expiration)
+        .arg(expiration + tombstone);
+    cmd
+}
+
+/// The record, or None once the payload has expired or been burned.
+pub async fn read(
+    conn: &mut MultiplexedConnection,
+    key: &str,
+) -> Result<Option<String>, redis::RedisError> {
+    let (meta, payload): (Option<String>, Option<String>) = redis::cmd("HMGET")
+        .arg(key)
+        .arg(META_FIELD)
+        .arg(PAYLOAD_FIELD)
+        .query_async(conn)
+        .await?;
+    Ok(match (meta, payload) {
+        (Some(meta), Some(payload)) => join(&meta, payload),
+        _ => None,
+    })
+}
+
+/// Atomically remove the payload (the burn), returning the record. The
+/// tombstone then expires within `tombstone` seconds.
+pub async fn take(
+    conn: &mut MultiplexedConnection,
+    key: &str,
+    tombstone: u64,
+) -> Result<Option<String>, redis::RedisError> {
+    let taken: Option<(Option<String>, String)> = redis::cmd("EVAL")
+        .arg(TAKE_SCRIPT)
+        .arg(1)
+        .arg(key)
+        .arg(tombstone.max(1))
+        .query_async(conn)
+        .await?;
+    Ok(taken.and_then(|(meta, payload)| join(&meta?, payload)))
+}
+
+/// `take` gated on confirmations (see `TAKE_CONFIRMED_SCRIPT`). Replies
+/// whether the threshold was met, the confirmations counted and the record.
+pub async fn take_confirmed(
+    conn: &mut MultiplexedConnection,
+    key: &str,
+    confirmations_key: &str,
+    tokens_key: &str,
+    window_start: u64,
+    required: u32,
+    tombstone: u64,
+) -> Result<(bool, u64, Option<String>), redis::RedisError> {
+    let (met, collected, meta, payload): (i64, u64, Option<String>, Option<String>) =
+        redis::cmd("EVAL")
+            .arg(TAKE_CONFIRMED_SCRIPT)
+            .arg(3)
+            .arg(key)
+            .arg(confirmations_key)
+            .arg(tokens_key)
+            .arg(tombstone.max(1))
+            .arg(window_start)
+            .arg(required)
+            .query_async(conn)
+            .await?;
+    let record = match (meta, payload) {
+        (Some(meta), Some(payload)) => join(&meta, payload),
+        _ => None,
+    };
+    Ok((met == 1, collected, record))
+}
+
+/// Replace the record in place (see `REPLACE_SCRIPT` for the reply).
+pub async fn replace(
+    conn: &mut MultiplexedConnection,
+    key: &str,
+    confirmations_key: &str,
+    meta: &str,
+    payload: &str,
+    unmodified_since: Option<u64>,
+) -> Result<i64, redis::RedisError> {
+    redis::cmd("EVAL")
+        .arg(REPLACE_SCRIPT)
+        .arg(2)
+        .arg(key)
+        .arg(confirmations_key)
+        .arg(meta)
+        .arg(payload)
+        .arg(unmodified_since)
+        .query_async(conn)
+        .await
+}
+
+/// Reset the payload's TTL to `ttl` seconds, keeping the tombstone after it
+/// (see `EXTEND_SCRIPT` for the reply).
+pub async fn extend(
+    conn: &mut MultiplexedConnection,
+    key: &str,
+    ttl: u64,
+    tombstone: u64,
+    unmodified_since: Option<u64>,
+) -> Result<i64, redis::RedisError> {
+    redis::cmd("EVAL")
+        .arg(EXTEND_SCRIPT)
+        .arg(1)
+        .arg(key)
+        .arg(ttl)
+        .arg(ttl + tombstone)
+        .arg(unmodified_since)
+        .query_async(conn)
+        .await
+}
+
+/// Remaining TTL of the payload field, with TTL's -2/-1 conventions.
+pub async fn payload_ttl(
+    conn: &mut MultiplexedConnection,
+    key: &str,
+) -> Result<i64, redis::RedisError> {
+    let ttls: Vec<i64> = payload_ttl_cmd(key).query_async(conn).await?;
+    Ok(ttls.first().copied().unwrap_or(-2))
+}
+
+/// The HTTL command behind `payload_ttl`, for pipelines; replies with a
+/// one-element array.
+pub fn payload_ttl_cmd(key: &str) -> redis::Cmd {
+    let mut cmd = redis::cmd("HTTL");
+    cmd.arg(key).arg("FIELDS").arg(1).arg(PAYLOAD_FIELD);
+    cmd
+}
+
+/// Whether the payload field is still there. A tombstone has only the
+/// metadata left.
+pub async fn payload_exists(
+    conn: &mut MultiplexedConnection,
+    key: &str,
+) -> Result<bool, redis::RedisError> {
+    redis::cmd("HEXISTS")
+        .arg(key)
+        .arg(PAYLOAD_FIELD)
+        .query_async(conn)
+        .await
+}
+
+/// Size of the payload field in bytes (0 if missing).
+pub async fn payload_len(
+    conn: &mut MultiplexedConnection,
+    key: &str,
+) -> Result<u64, redis::RedisError> {
+    redis::cmd("HSTRLEN")
+        .arg(key)
+        .arg(PAYLOAD_FIELD)
+        .query_async(conn)
+        .await
+}
+
+/// Extend the payload to `ttl` seconds, keeping the tombstone after it.
+pub async fn expire(
+    conn: &mut MultiplexedConnection,
+    key: &str,
+    ttl: i64,
+    tombstone: u64,
+) -> Result<(), redis::RedisError> {
+    redis::pipe()
+        .atomic()
+        .cmd("HEXPIRE")
+        .arg(key)
+        .arg(ttl)
+        .arg("FIELDS")
+        .arg(1)
+        .arg(PAYLOAD_FIELD)
+        .ignore()
+        .expire(key, ttl + tombstone as i64)
+        .ignore()
+        .query_async(conn)
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_join_round_trip() {
+        let stored = StoredSecret {
+            encrypted_secret: "abc".to_string(),
+            created_at: 1706900000,
+            metadata: Some(serde_json::json!({"label": "db"})),
+            ..Default::default()
+        };
+
+        let (meta, payload) = split(&stored).unwrap();
+        assert_eq!(payload, "abc");
+        assert!(!meta.contains("abc"));
+
+        let joined: StoredSecret =
+            serde_json::from_str(&join(&meta, payload.to_string()).unwrap()).unwrap();
+        assert_eq!(joined.encrypted_secret, "abc");
+        assert_eq!(joined.created_at, 1706900000);
+        assert_eq!(joined.metadata, stored.metadata);
+    }
+
+    #[test]
+    fn test_join_rejects_garbage_meta() {
+        assert!(join("not json", "abc".to_string()).is_none());
+    }
+}
+
+/// Round trips against a live Redis 7.4+ at `REDIS_URL`:
+/// `cargo test --features redis-tests`.
+#[cfg(all(test, feature = "redis-tests"))]
+mod redis_tests {
+    use super::*;
+
+    async fn conn() -> MultiplexedConnection {
+        let url =
+            std::env::var("REDIS_URL").unwrap_or_else(|_| "redis://127.0.0.1:6379".to_string());
+        redis::Client::open(url)
+            .unwrap()
+            .get_multiplexed_async_connection()
+            .await
+            .unwrap()
+    }
+
+    fn test_key() -> String {
+        format!("test:hash:{}", uuid::Uuid::new_v4())
+    }
+
+    #[tokio::test]
+    async fn test_hash_round_trip() {
+        let mut conn = conn().await;
+        let key = test_key();
+        let stored = StoredSecret {
+            encrypted_secret: "abc".to_string(),
+            created_at: 1706900000,
+            ..Default::default()
+        };
+        let (meta, payload) = split(&stored).unwrap();
+
+        let created: Option<String> = create(&key, &meta, payload, 60, 60)
+            .query_async(&mut conn)
+            .await
+            .unwrap();
+        assert!(created.is_some());
+        // A second create on the same key is refused, like SET NX
+        let created: Option<String> = create(&key, &meta, payload, 60, 60)
+            .query_async(&mut conn)
+            .await
+            .unwrap();
+        assert!(created.is_none());
+
+        let ttl = payload_ttl(&mut conn, &key).await.unwrap();
+        assert!(ttl > 0 && ttl <= 60);
+
+        let read_back = read(&mut conn, &key).await.unwrap().unwrap();
+        assert!(read_back.contains(r#""encryptedSecret":"abc""#));
+
+        let taken = take(&mut conn, &key, 60).await.unwrap().unwrap();
+        assert!(taken.contains(r#""encryptedSecret":"abc""#));
+
+        // Burned: the payload is gone but the tombstone remains
+        assert!(read(&mut conn, &key).await.unwrap().is_none());
+        assert!(take(&mut conn, &key, 60).await.unwrap().is_none());
+        let tombstone: Option<String> = redis::cmd("HGET")
+            .arg(&key)
+            .arg(META_FIELD)
+            .query_async(&mut conn)
+            .await
+            .unwrap();
+        assert!(tombstone.is_some());
+
+        let _: () = redis::cmd("DEL")
+            .arg(&key)
+            .query_async(&mut conn)
+            .await
+            .unwrap();
+    }
+
+    async fn key_ttl(conn: &mut MultiplexedConnection, key: &str) -> i64 {
+        redis::cmd("TTL").arg(key).query_async(conn).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_burned_tombstone_expires_on_schedule() {
+        let mut conn = conn().await;
+        let key = test_key();
+        let stored = StoredSecret {
+            encrypted_secret: "abc".to_string(),
+            ..Default::default()
+        };
+        let (meta, payload) = split(&stored).unwrap();
+        let _: Option<String> = create(&key, &meta, payload, 3600, 60)
+            .query_async(&mut conn)
+            .await
+            .unwrap();
+        assert!(key_ttl(&mut conn, &key).await > 3600);
+
+        // The burn cuts the tombstone down from the unread expiration
+        assert!(take(&mut conn, &key, 2).await.unwrap().is_some());
+        let ttl = key_ttl(&mut conn, &key).await;
+        assert!(ttl > 0 && ttl <= 2);
+
+        // Re-reads of the burned secret don't extend it
+        assert!(take(&mut conn, &key, 60).await.unwrap().is_none());
+        assert!(key_ttl(&mut conn, &key).await <= 2);
+
+        tokio::time::sleep(std::time::Duration::from_millis(2500)).await;
+        assert_eq!(key_ttl(&mut conn, &key).await, -2);
+    }
+}
@@ -0,0 +1,345 @@
+//! Every Redis key the service reads or writes is built here, so a write and
+//! the read that pairs with it can't drift apart as key types are added.
+
+use uuid::Uuid;
+
+/// Leading part of client-facing ids for each kind of entry.
+pub const SECRET_ID_PREFIX: &str = "sps";
+pub const FILE_ID_PREFIX: &str = "spf";
+pub const BUNDLE_ID_PREFIX: &str = "spb";
+pub const UPLOAD_ID_PREFIX: &str = "spu";
+
+/// Suffix of the sorted set holding a secret's reader confirmations.
+pub const CONFIRMATIONS_SUFFIX: &str = ":confirmations";
+/// Suffix of the hash of confirmation tokens issued to a secret's readers.
+pub const CONFIRM_TOKENS_SUFFIX: &str = ":confirm_tokens";
+/// Suffix of the counter tracking how many times a secret was peeked.
+pub const PEEKS_SUFFIX: &str = ":peeks";
+/// Suffix of the short-lived counter of peeks in flight for an id.
+pub const ACTIVE_PEEKS_SUFFIX: &str = ":active_peeks";
+/// Suffix of the counter of burn reads taken from a `maxReads` secret.
+pub const READS_SUFFIX: &str = ":reads";
+/// Suffix of the list of a secret's most recent reads, newest first.
+pub const ACCESS_LOG_SUFFIX: &str = ":access_log";
+/// Suffix of the count of access log entries trimmed past the cap.
+pub const ACCESS_DROPPED_SUFFIX: &str = ":access_dropped";
+/// Suffix of the hash binding a secret's burn to the client that peeked it.
+pub const VIEW_CONTEXT_SUFFIX: &str = ":view_context";
+/// Suffix of a burned secret's metadata, kept for the `BURN_GRACE_SECONDS` window.
+pub const BURNED_SUFFIX: &str = ":burned";
+/// Suffix of the hash holding a chunked upload's chunks.
+pub const CHUNKS_SUFFIX: &str = ":chunks";
+/// Suffix of a burned file's data, kept for ranged reads while it downloads.
+pub const STAGED_SUFFIX: &str = ":staged";
+/// Suffix of the hash holding a file's encrypted thumbnail and its IV.
+pub const THUMBNAIL_SUFFIX: &str = ":thumbnail";
+
+/// Times an id is regenerated when it contains an `ID_BLOCKLIST` substring.
+const MAX_BLOCKLIST_RETRIES: usize = 10;
+
+/// Builds Redis keys, namespaced under the optional `REDIS_KEY_PREFIX`, and
+/// the ids they are derived from. Ids handed to clients never include the
+/// namespace.
+#[derive(Clone, Debug, Default)]
+pub struct Keys {
+    prefix: String,
+    /// Lowercase substrings generated ids must not contain
+    id_blocklist: Vec<String>,
+}
+
+impl Keys {
+    pub fn new(prefix: impl Into<String>) -> Self {
+        Self {
+            prefix: prefix.into(),
+            id_blocklist: Vec::new(),
+        }
+    }
+
+    pub fn with_id_blocklist(mut self, blocklist: Vec<String>) -> Self {
+        self.id_blocklist = blocklist;
+        self
+    }
+
+    /// A fresh client-facing id such as `sps-<base58 uuid>`, regenerated if
+    /// it would show a blocklisted word in a share link.
+    pub fn new_id(&self, id_prefix: &str) -> String {
+        let body = first_clean(&self.id_blocklist, || {
+            bs58::encode(Uuid::new_v4().as_bytes()).into_string()
+        });
+        format!("{}-{}", id_prefix, body)
+    }
+
+    pub fn secret(&self, id: &str) -> String {
+        format!("{}{}", self.prefix, id)
+    }
+
+    pub fn file(&self, id: &str) -> String {
+        format!("{}{}", self.prefix, id)
+    }
+
+    pub fn bundle(&self, id: &str) -> String {
+        format!("{}{}", self.prefix, id)
+    }
+
+    pub fn confirmations(&self, id: &str) -> String {
+        format!("{}{}{}", self.prefix, id, CONFIRMATIONS_SUFFIX)
+    }
+
+    /// Confirmation tokens handed out by peeks, each mapped to the reader
+    /// it was issued to.
+    pub fn confirm_tokens(&self, id: &str) -> String {
+        format!("{}{}{}", self.prefix, id, CONFIRM_TOKENS_SUFFIX)
+    }
+
+    pub fn peeks(&self, id: &str) -> String {
+        format!("{}{}{}", self.prefix, id, PEEKS_SUFFIX)
+    }
+
+    pub fn active_peeks(&self, id: &str) -> String {
+        format!("{}{}{}", self.prefix, id, ACTIVE_PEEKS_SUFFIX)
+    }
+
+    pub fn reads(&self, id: &str) -> String {
+        format!("{}{}{}", self.prefix, id, READS_SUFFIX)
+    }
+
+    pub fn view_context(&self, id: &str) -> String {
+        format!("{}{}{}", self.prefix, id, VIEW_CONTEXT_SUFFIX)
+    }
+
+    pub fn burned(&self, id: &str) -> String {
+        format!("{}{}{}", self.prefix, id, BURNED_SUFFIX)
+    }
+
+    /// A file's data after `POST /v1/files/:id/stage` burned it, until the
+    /// download is completed or aborted. Only the staging token it returned
+    /// names the key.
+    pub fn staged(&self, id: &str, token: &str) -> String {
+        format!("{}{}:{}{}", self.prefix, id, token, STAGED_SUFFIX)
+    }
+
+    pub fn thumbnail(&self, id: &str) -> String {
+        format!("{}{}{}", self.prefix, id, THUMBNAIL_SUFFIX)
+    }
+
+    pub fn access_log(&self, id: &str) -> String {
+        format!("{}{}{}", self.prefix, id, ACCESS_LOG_SUFFIX)
+    }
+
+    pub fn access_dropped(&self, id: &str) -> String {
+        format!("{}{}{}", self.prefix, id, ACCESS_DROPPED_SUFFIX)
+    }
+
+    /// A chunked upload session's metadata, until it's completed.
+    pub fn upload(&self, id: &str) -> String {
+        format!("{}{}", self.prefix, id)
+    }
+
+    pub fn upload_chunks(&self, id: &str) -> String {
+        format!("{}{}{}", self.prefix, id, CHUNKS_SUFFIX)
+    }
+
+    /// The `EVENTS_STREAM` every secret's lifecycle events are appended to.
+    /// Outside the `sp*` namespace unless named into it.
+    pub fn events_stream(&self, name: &str) -> String {
+        format!("{}{}", self.prefix, name)
+    }
+
+    /// Pub/sub channel carrying a secret's lifecycle events. Channels aren't
+    /// keys, so SCAN and the janitor never see it.
+    pub fn events(&self, id: &str) -> String {
+        format!("{}events:{}", self.prefix, id)
+    }
+
+    /// The create response recorded for a client's `Idempotency-Key`. Outside
+    /// the `sp*` namespace, so SCAN and the janitor never see it.
+    pub fn idempotency(&self, key: &str) -> String {
+        format!("{}idempotency:{}", self.prefix, key)
+    }
+
+    /// The create recorded for a secret's content hash (`DEDUP_CREATES`).
+    /// Outside the `sp*` namespace, so SCAN and the janitor never see it.
+    pub fn create_dedup(&self, hash: &str) -> String {
+        format!("{}dedup:{}", self.prefix, hash)
+    }
+
+    /// Set of the ids of secrets created with the API key labelled `label`.
+    pub fn owned_secrets(&self, label: &str) -> String {
+        format!("{}owned:{}", self.prefix, label)
+    }
+
+    /// A deduplicated file's data, shared by every file with its content
+    /// hash (`DEDUP_FILES`). Outside the `sp*` namespace, so SCAN and the
+    /// janitor never see it.
+    pub fn blob(&self, hash: &str) -> String {
+        format!("{}blob:{}", self.prefix, hash)
+    }
+
+    /// The ids of the files sharing a blob, scored by when each expires, so
+    /// the blob goes with the last of them however each one goes.
+    pub fn blob_refs(&self, hash: &str) -> String {
+        format!("{}blob:{}:refs", self.prefix, hash)
+    }
+
+    /// Approximate count of live files, for the MAX_ACTIVE_FILES cap
+    pub fn active_files(&self) -> String {
+        format!("{}stats:active_files", self.prefix)
+    }
+
+    /// Hash of stored bytes per API key label, for `USAGE_BY_API_KEY`
+    pub fn usage(&self) -> String {
+        format!("{}stats:usage_bytes", self.prefix)
+    }
+
+    /// The operator's emergency cap on new entries' TTLs, when set
+    pub fn emergency_max_ttl(&self) -> String {
+        format!("{}settings:emergency_max_ttl", self.prefix)
+    }
+
+    /// SCAN pattern matching every entry and its side keys.
+    pub fn scan_pattern(&self) -> String {
+        format!("{}sp*", self.prefix)
+    }
+
+    /// SCAN pattern matching secrets (new-style ids) and their side keys.
+    pub fn secret_scan_pattern(&self) -> String {
+        format!("{}{}-*", self.prefix, SECRET_ID_PREFIX)
+    }
+
+    /// A key returned by SCAN, without the namespace.
+    pub fn strip<'a>(&self, key: &'a str) -> &'a str {
+        key.strip_prefix(self.prefix.as_str()).unwrap_or(key)
+    }
+}
+
+/// The first generated id not containing any blocklisted substring
+/// (case-insensitively). After `MAX_BLOCKLIST_RETRIES` the last one is used
+/// anyway: a create must never fail over cosmetics.
+fn first_clean(blocklist: &[String], mut generate: impl FnMut() -> String) -> String {
+    let mut id = generate();
+    for _ in 0..MAX_BLOCKLIST_RETRIES {
+        let lower = id.to_lowercase();
+        if !blocklist.iter().any(|word| lower.contains(word.as_str())) {
+            break;
+        }
+        id = generate();
+    }
+    id
+}
+
+/// The 16 UUID bytes behind an `sps-` id, for compact encodings (QR codes,
+/// NFC tags). None for legacy `sp-` ids and anything else that isn't a
+/// base58-encoded UUID.
+pub fn secret_id_bytes(id: &str) -> Option<[u8; 16]> {
+    let body = id.strip_prefix(SECRET_ID_PREFIX)?.strip_prefix('-')?;
+    let bytes: [u8; 16] = bs58::decode(body).into_vec().ok()?.try_into().ok()?;
+    // Only the canonical spelling maps back to the same id
+    (bs58::encode(bytes).into_string() == body).then_some(bytes)
+}
+
+/// The `sps-` id for bytes from `secret_id_bytes`.
+pub fn secret_id_from_bytes(bytes: [u8; 16]) -> String {
+    format!("{}-{}", SECRET_ID_PREFIX, bs58::encode(bytes).into_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keys_without_prefix() {
+        let keys = Keys::default();
+        assert_eq!(keys.secret("sps-abc"), "sps-abc");
+        assert_eq!(keys.file("spf-abc"), "spf-abc");
+        assert_eq!(keys.bundle("spb-abc"), "spb-abc");
+        assert_eq!(keys.confirmations("sps-abc"), "sps-abc:confirmations");
+        assert_eq!(keys.confirm_tokens("sps-abc"), "sps-abc:confirm_tokens");
+        assert_eq!(keys.peeks("sps-abc"), "sps-abc:peeks");
+        assert_eq!(keys.active_peeks("sps-abc"), "sps-abc:active_peeks");
+        assert_eq!(keys.view_context("sps-abc"), "sps-abc:view_context");
+        assert_eq!(keys.upload_chunks("spu-abc"), "spu-abc:chunks");
+        assert_eq!(keys.idempotency("abc"), "idempotency:abc");
+        assert_eq!(keys.owned_secrets("ci"), "owned:ci");
+        assert_eq!(keys.active_files(), "stats:active_files");
+        assert_eq!(keys.usage(), "stats:usage_bytes");
+        assert_eq!(keys.blob("ab12"), "blob:ab12");
+        assert_eq!(keys.blob_refs("ab12"), "blob:ab12:refs");
+        assert_eq!(keys.emergency_max_ttl(), "settings:emergency_max_ttl");
+        assert_eq!(keys.scan_pattern(), "sp*");
+        assert_eq!(keys.secret_scan_pattern(), "sps-*");
+    }
+
+    #[test]
+    fn test_keys_with_prefix() {
+        let keys = Keys::new("snappwd:");
+        assert_eq!(keys.secret("sps-abc"), "snappwd:sps-abc");
+        assert_eq!(keys.file("spf-abc"), "snappwd:spf-abc");
+        assert_eq!(keys.bundle("spb-abc"), "snappwd:spb-abc");
+        assert_eq!(
+            keys.confirmations("sps-abc"),
+            "snappwd:sps-abc:confirmations"
+        );
+        assert_eq!(keys.peeks("sps-abc"), "snappwd:sps-abc:peeks");
+        assert_eq!(keys.active_peeks("sps-abc"), "snappwd:sps-abc:active_peeks");
+        assert_eq!(keys.active_files(), "snappwd:stats:active_files");
+        assert_eq!(keys.usage(), "snappwd:stats:usage_bytes");
+        assert_eq!(keys.blob("ab12"), "snappwd:blob:ab12");
+        assert_eq!(
+            keys.emergency_max_ttl(),
+            "snappwd:settings:emergency_max_ttl"
+        );
+        assert_eq!(keys.scan_pattern(), "snappwd:sp*");
+        assert_eq!(keys.secret_scan_pattern(), "snappwd:sps-*");
+        assert_eq!(keys.strip("snappwd:sps-abc"), "sps-abc");
+    }
+
+    #[test]
+    fn test_new_id_has_prefix() {
+        let id = Keys::default().new_id(FILE_ID_PREFIX);
+        assert!(id.starts_with("spf-"));
+        assert!(id.len() > "spf-".len());
+    }
+
+    #[test]
+    fn test_blocklisted_id_is_regenerated() {
+        let blocklist = vec!["bad".to_string()];
+        let mut seeded = ["xxBaDxx", "clean1"].into_iter().map(str::to_string);
+        let id = first_clean(&blocklist, || seeded.next().unwrap());
+        assert_eq!(id, "clean1");
+    }
+
+    #[test]
+    fn test_blocklist_retries_are_bounded() {
+        let blocklist = vec!["bad".to_string()];
+        let mut generated = 0;
+        let id = first_clean(&blocklist, || {
+            generated += 1;
+            "bad".to_string()
+        });
+        assert_eq!(id, "bad");
+        assert_eq!(generated, MAX_BLOCKLIST_RETRIES + 1);
+    }
+
+    #[test]
+    fn test_secret_id_bytes_round_trip() {
+        let keys = Keys::default();
+        for _ in 0..100 {
+            let id = keys.new_id(SECRET_ID_PREFIX);
+            let bytes = secret_id_bytes(&id).unwrap();
+            assert_eq!(secret_id_from_bytes(bytes), id);
+        }
+
+        // Leading zero bytes survive as leading '1's
+        let bytes = [0, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14];
+        assert_eq!(secret_id_bytes(&secret_id_from_bytes(bytes)), Some(bytes));
+    }
+
+    #[test]
+    fn test_secret_id_bytes_rejects_non_uuid_ids() {
+        assert_eq!(secret_id_bytes("sp-legacy-id"), None);
+        assert_eq!(secret_id_bytes("spf-7Yx8kN2mPqRs"), None);
+        // Too short to be 16 bytes
+        assert_eq!(secret_id_bytes("sps-7Yx8kN2mPqRs"), None);
+        assert_eq!(secret_id_bytes("sps-"), None);
+    }
+}
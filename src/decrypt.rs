@@ -0,0 +1,76 @@
+//! Server-side decryption (`ALLOW_SERVER_DECRYPT=1`). This is NOT end-to-end
+//! encryption: the caller hands the key to the server, which sees the
+//! plaintext. It exists for trusted internal callers that already hold keys
+//! server-side. The key is used for one request and never stored or logged.
+
+use aes_gcm::{
+    aead::{Aead, KeyInit},
+    Aes128Gcm, Aes256Gcm, Nonce,
+};
+
+/// AES-GCM's standard nonce size; other sizes aren't supported.
+const NONCE_BYTES: usize = 12;
+
+/// Why a payload couldn't be decrypted.
+#[derive(Debug, PartialEq)]
+pub enum DecryptError {
+    /// The key isn't 16 or 32 bytes
+    KeyLength(usize),
+    /// The IV isn't 12 bytes
+    IvLength(usize),
+    /// Wrong key or IV, or the ciphertext was altered
+    Failed,
+}
+
+/// Decrypt AES-GCM `ciphertext` (with its tag appended, as WebCrypto
+/// produces it), picking AES-128 or AES-256 by key length.
+pub fn aes_gcm(key: &[u8], iv: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>, DecryptError> {
+    if iv.len() != NONCE_BYTES {
+        return Err(DecryptError::IvLength(iv.len()));
+    }
+    let nonce = Nonce::from_slice(iv);
+    let plaintext = match key.len() {
+        16 => Aes128Gcm::new_from_slice(key)
+            .map_err(|_| DecryptError::KeyLength(key.len()))?
+            .decrypt(nonce, ciphertext),
+        32 => Aes256Gcm::new_from_slice(key)
+            .map_err(|_| DecryptError::KeyLength(key.len()))?
+            .decrypt(nonce, ciphertext),
+        len => return Err(DecryptError::KeyLength(len)),
+    };
+    plaintext.map_err(|_| DecryptError::Failed)
+}
+
+#[cfg(test)]
+pub(crate) fn encrypt_for_tests(key: &[u8], iv: &[u8], plaintext: &[u8]) -> Vec<u8> {
+    Aes256Gcm::new_from_slice(key)
+        .unwrap()
+        .encrypt(Nonce::from_slice(iv), plaintext)
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decrypt_with_correct_and_wrong_key() {
+        let key = [7u8; 32];
+        let iv = [1u8; 12];
+        let ciphertext = encrypt_for_tests(&key, &iv, b"hello");
+
+        assert_eq!(aes_gcm(&key, &iv, &ciphertext).unwrap(), b"hello");
+        assert_eq!(
+            aes_gcm(&[8u8; 32], &iv, &ciphertext),
+            Err(DecryptError::Failed)
+        );
+        assert_eq!(
+            aes_gcm(&key[..20], &iv, &ciphertext),
+            Err(DecryptError::KeyLength(20))
+        );
+        assert_eq!(
+            aes_gcm(&key, &iv[..8], &ciphertext),
+            Err(DecryptError::IvLength(8))
+        );
+    }
+}
@@ -0,0 +1,259 @@
+//! Lifecycle events for a single secret, streamed to its creator over a
+//! WebSocket (`GET /v1/secrets/:id/events`). Peeks, burns and deletes are
+//! published by the handlers on a per-id Redis channel, so a subscriber on
+//! any instance sees them; expiry comes from keyspace notifications, which
+//! need `notify-keyspace-events` to include `Kx` on the Redis server.
+//!
+//! Pub/sub drops whatever no one is listening for. For consumers that must
+//! see every event, `EVENTS_STREAM` also appends creates, peeks, burns and
+//! deletes of all secrets to one Redis Stream, which consumer groups can
+//! read and acknowledge at their own pace.
+
+use crate::{db, AppState};
+use axum::extract::ws::{Message, WebSocket};
+use futures_util::StreamExt;
+use serde::Serialize;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SecretEvent {
+    Created,
+    Peeked,
+    Burned,
+    Deleted,
+    Expired,
+}
+
+impl SecretEvent {
+    fn as_str(self) -> &'static str {
+        match self {
+            SecretEvent::Created => "created",
+            SecretEvent::Peeked => "peeked",
+            SecretEvent::Burned => "burned",
+            SecretEvent::Deleted => "deleted",
+            SecretEvent::Expired => "expired",
+        }
+    }
+
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "created" => Some(SecretEvent::Created),
+            "peeked" => Some(SecretEvent::Peeked),
+            "burned" => Some(SecretEvent::Burned),
+            "deleted" => Some(SecretEvent::Deleted),
+            "expired" => Some(SecretEvent::Expired),
+            _ => None,
+        }
+    }
+
+    /// Whether the secret is gone after this event, which ends the stream.
+    pub fn is_terminal(self) -> bool {
+        !matches!(self, SecretEvent::Created | SecretEvent::Peeked)
+    }
+}
+
+/// One frame sent to the subscriber.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct EventFrame<'a> {
+    event: SecretEvent,
+    secret_id: &'a str,
+    /// Unix timestamp the event was forwarded at
+    at: u64,
+}
+
+/// Publish `event` for `id` in the background: on the secret's channel when
+/// `SECRET_EVENTS` is on, and onto `EVENTS_STREAM` when that's set. `kind`
+/// is the secret's kind, if it has one and it's known. Best effort: a lost
+/// event only affects its consumers, never the secret.
+pub fn publish(state: &AppState, id: &str, kind: Option<&str>, event: SecretEvent) {
+    // No one can be subscribed to a secret before its create returns
+    let channel = state.config.secret_events && event != SecretEvent::Created;
+    let stream = state.config.events_stream.clone();
+    if !channel && stream.is_none() {
+        return;
+    }
+    let redis = state.redis.clone();
+    let keys = state.keys.clone();
+    let id = id.to_string();
+    let kind = kind.map(str::to_string);
+    let max_len = state.config.events_stream_max_len;
+    let at = state.clock.now();
+    state.tasks.spawn(async move {
+        if channel {
+            if let Err(e) = db::publish_secret_event(&redis, &keys, &id, event.as_str()).await {
+                tracing::warn!("Failed to publish secret event: {}", e);
+            }
+        }
+        if let Some(stream) = stream {
+            if let Err(e) = db::append_secret_event(
+                &redis,
+                &keys,
+                &stream,
+                max_len,
+                db::StreamEvent {
+                    id: &id,
+                    kind: kind.as_deref(),
+                    event: event.as_str(),
+                    at,
+                },
+            )
+            .await
+            {
+                tracing::warn!("Failed to append secret event to stream: {}", e);
+            }
+        }
+    });
+}
+
+/// Whether `notify-keyspace-events` flags make Redis publish expiries on
+/// keyspace channels: `K` plus `x` (or `A`, which includes it).
+pub fn notifies_expiry(flags: &str) -> bool {
+    flags.contains('K') && (flags.contains('x') || flags.contains('A'))
+}
+
+/// The event a pub/sub message stands for: a published lifecycle event, or
+/// the payload key's expiry. Other keyspace operations are ignored.
+fn event_from_message(events_channel: &str, channel: &str, payload: &str) -> Option<SecretEvent> {
+    if channel == events_channel {
+        SecretEvent::parse(payload)
+    } else {
+        (payload == "expired").then_some(SecretEvent::Expired)
+    }
+}
+
+/// Forward `id`'s events to `socket` until a terminal event or until either
+/// side goes away.
+pub async fn stream(state: AppState, id: String, mut socket: WebSocket) {
+    let mut pubsub = match db::subscribe_secret_events(&state.redis, &state.keys, &id).await {
+        Ok(pubsub) => pubsub,
+        Err(e) => {
+            tracing::error!("Failed to subscribe to secret events: {}", e);
+            let _ = socket.send(Message::Close(None)).await;
+            return;
+        }
+    };
+
+    // The secret may have gone between the upgrade and the subscription,
+    // in which case no event is ever coming
+    match db::touch_secret(
+        &state.redis,
+        &state.keys,
+        state.config.secret_storage(),
+        &id,
+        db::Replica::default(),
+    )
+    .await
+    {
+        Ok(Some(_)) => {}
+        Ok(None) => {
+            let _ = socket.send(Message::Close(None)).await;
+            return;
+        }
+        Err(e) => {
+            tracing::error!("Redis error: {}", e);
+            let _ = socket.send(Message::Close(None)).await;
+            return;
+        }
+    }
+
+    let events_channel = state.keys.events(&id);
+    let mut messages = pubsub.on_message();
+    loop {
+        tokio::select! {
+            message = messages.next() => {
+                // None: the pub/sub connection to Redis dropped
+                let Some(message) = message else { break };
+                let Ok(payload) = message.get_payload::<String>() else { continue };
+                let Some(event) =
+                    event_from_message(&events_channel, message.get_channel_name(), &payload)
+                else {
+                    continue;
+                };
+
+                let frame = EventFrame {
+                    event,
+                    secret_id: &id,
+                    at: state.clock.now(),
+                };
+                let text = serde_json::to_string(&frame).expect("event frame serializes");
+                if socket.send(Message::Text(text)).await.is_err() {
+                    return;
+                }
+                if event.is_terminal() {
+                    break;
+                }
+            }
+            incoming = socket.recv() => match incoming {
+                // The client went away; dropping the pub/sub connection unsubscribes
+                None | Some(Err(_)) | Some(Ok(Message::Close(_))) => return,
+                // Subscribers have nothing to say; pings are answered for us
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+
+    let _ = socket.send(Message::Close(None)).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_event_names_round_trip() {
+        for event in [
+            SecretEvent::Created,
+            SecretEvent::Peeked,
+            SecretEvent::Burned,
+            SecretEvent::Deleted,
+            SecretEvent::Expired,
+        ] {
+            assert_eq!(SecretEvent::parse(event.as_str()), Some(event));
+        }
+        assert_eq!(SecretEvent::parse("read"), None);
+        assert!(!SecretEvent::Created.is_terminal());
+        assert!(!SecretEvent::Peeked.is_terminal());
+        assert!(SecretEvent::Burned.is_terminal());
+    }
+
+    #[test]
+    fn test_event_from_message() {
+        let events = "events:sps-abc";
+        let keyspace = "__keyspace@0__:sps-abc";
+        assert_eq!(
+            event_from_message(events, events, "burned"),
+            Some(SecretEvent::Burned)
+        );
+        assert_eq!(
+            event_from_message(events, keyspace, "expired"),
+            Some(SecretEvent::Expired)
+        );
+        // A GETDEL shows up as `del` on the keyspace channel; the burn is
+        // reported through the events channel instead
+        assert_eq!(event_from_message(events, keyspace, "del"), None);
+        assert_eq!(event_from_message(events, events, "bogus"), None);
+    }
+
+    #[test]
+    fn test_notifies_expiry() {
+        assert!(notifies_expiry("Kx"));
+        assert!(notifies_expiry("KEA"));
+        assert!(!notifies_expiry("Ex"));
+        assert!(!notifies_expiry("K"));
+        assert!(!notifies_expiry(""));
+    }
+
+    #[test]
+    fn test_frame_shape() {
+        let frame = EventFrame {
+            event: SecretEvent::Burned,
+            secret_id: "sps-abc",
+            at: 1700000000,
+        };
+        assert_eq!(
+            serde_json::to_value(&frame).unwrap(),
+            serde_json::json!({"event": "burned", "secretId": "sps-abc", "at": 1700000000})
+        );
+    }
+}
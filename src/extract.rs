@@ -0,0 +1,434 @@
+use crate::{config::PolicyProfile, models::ErrorResponse, AppState};
+use axum::{
+    async_trait,
+    body::Bytes,
+    extract::{ConnectInfo, FromRequest, FromRequestParts, Request},
+    http::{header, request::Parts, HeaderMap, StatusCode, Uri},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::de::DeserializeOwned;
+use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr};
+
+/// JSON body extractor that reports rejections as a JSON `ErrorResponse`
+/// instead of axum's plaintext bodies. Invalid fields are named by their
+/// path (e.g. `metadata.iv is required`), since request bodies nest
+/// required fields.
+pub struct ApiJson<T>(pub T);
+
+#[async_trait]
+impl<T, S> FromRequest<S> for ApiJson<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = Response;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        if !has_json_content_type(req.headers()) {
+            return Err((
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                Json(ErrorResponse::with_code(
+                    "UNSUPPORTED_MEDIA_TYPE",
+                    "Expected request with `Content-Type: application/json`",
+                )),
+            )
+                .into_response());
+        }
+
+        let bytes = buffer_body(req, state).await?;
+        parse_json(&bytes)
+            .map(Self)
+            .map_err(|error| (StatusCode::BAD_REQUEST, Json(error)).into_response())
+    }
+}
+
+/// `ApiJson`, or an `application/x-www-form-urlencoded` body when
+/// `ACCEPT_FORM_BODIES` is set, for legacy integrations that can't send
+/// JSON. Form fields are mapped onto the JSON body (see `form_to_json`) and
+/// then validated exactly like JSON.
+pub struct ApiJsonOrForm<T>(pub T);
+
+#[async_trait]
+impl<T> FromRequest<AppState> for ApiJsonOrForm<T>
+where
+    T: DeserializeOwned,
+{
+    type Rejection = Response;
+
+    async fn from_request(req: Request, state: &AppState) -> Result<Self, Self::Rejection> {
+        if !(state.config.accept_form_bodies && has_form_content_type(req.headers())) {
+            let ApiJson(value) = ApiJson::from_request(req, state).await?;
+            return Ok(Self(value));
+        }
+
+        let bytes = buffer_body(req, state).await?;
+        form_to_json(&bytes)
+            .and_then(|body| parse_json(body.to_string().as_bytes()))
+            .map(Self)
+            .map_err(|error| (StatusCode::BAD_REQUEST, Json(error)).into_response())
+    }
+}
+
+async fn buffer_body<S: Send + Sync>(req: Request, state: &S) -> Result<Bytes, Response> {
+    Bytes::from_request(req, state).await.map_err(|rejection| {
+        // Exceeding DefaultBodyLimit surfaces as a 413 from the body buffering step
+        if rejection.status() == StatusCode::PAYLOAD_TOO_LARGE {
+            return (
+                StatusCode::PAYLOAD_TOO_LARGE,
+                Json(ErrorResponse::with_code(
+                    "PAYLOAD_TOO_LARGE",
+                    "Request body too large",
+                )),
+            )
+                .into_response();
+        }
+        rejection.into_response()
+    })
+}
+
+/// `application/json` or an `application/*+json` type, as axum's `Json` accepts.
+fn has_json_content_type(headers: &HeaderMap) -> bool {
+    let Some(content_type) = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+    else {
+        return false;
+    };
+    let mime = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    mime == "application/json" || (mime.starts_with("application/") && mime.ends_with("+json"))
+}
+
+fn has_form_content_type(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split(';').next())
+        .is_some_and(|mime| {
+            mime.trim()
+                .eq_ignore_ascii_case("application/x-www-form-urlencoded")
+        })
+}
+
+/// Form fields as the equivalent JSON body: `expiration` as a number,
+/// `metadata` as the JSON document it holds, anything else as a string.
+/// Values that don't convert are passed through as strings so the usual
+/// field errors name them.
+fn form_to_json(bytes: &[u8]) -> Result<serde_json::Value, ErrorResponse> {
+    let mut fields = serde_json::Map::new();
+    for (name, value) in form_urlencoded::parse(bytes) {
+        let value = match name.as_ref() {
+            "expiration" => value
+                .parse::<u64>()
+                .map(serde_json::Value::from)
+                .unwrap_or_else(|_| serde_json::Value::from(value.as_ref())),
+            "metadata" => serde_json::from_str(&value).map_err(|_| {
+                ErrorResponse::with_code("INVALID_FIELD", "metadata: not a JSON document")
+            })?,
+            _ => serde_json::Value::from(value.as_ref()),
+        };
+        fields.insert(name.into_owned(), value);
+    }
+    Ok(serde_json::Value::Object(fields))
+}
+
+fn parse_json<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, ErrorResponse> {
+    let mut deserializer = serde_json::Deserializer::from_slice(bytes);
+    let value = serde_path_to_error::deserialize(&mut deserializer).map_err(|e| {
+        if e.inner().is_data() {
+            ErrorResponse::with_code(
+                "INVALID_FIELD",
+                field_message(&e.path().to_string(), e.inner()),
+            )
+        } else {
+            ErrorResponse::with_code("INVALID_JSON", "Request body is not valid JSON")
+        }
+    })?;
+    deserializer
+        .end()
+        .map_err(|_| ErrorResponse::with_code("INVALID_JSON", "Request body is not valid JSON"))?;
+    Ok(value)
+}
+
+/// Describe a data error at `path` (`.` for the top level), e.g.
+/// `metadata.contentType is required`.
+fn field_message(path: &str, error: &serde_json::Error) -> String {
+    let message = error.to_string();
+    // serde_json appends the position, which means nothing to API clients
+    let message = message.split(" at line ").next().unwrap_or_default();
+    let qualify = |field: &str| match path {
+        "." => field.to_string(),
+        _ => format!("{}.{}", path, field),
+    };
+
+    match message
+        .strip_prefix("missing field `")
+        .and_then(|rest| rest.strip_suffix('`'))
+    {
+        Some(field) => format!("{} is required", qualify(field)),
+        None if path == "." => message.to_string(),
+        None => format!("{}: {}", path, message),
+    }
+}
+
+/// The client's IP address: the peer address, or the entry appended to
+/// `X-Forwarded-For` by the fronting proxy when `TRUST_PROXY_HEADERS` is set.
+/// None when neither is available (e.g. in-process tests).
+pub struct ClientIp(pub Option<IpAddr>);
+
+#[async_trait]
+impl FromRequestParts<AppState> for ClientIp {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let peer = parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| *addr);
+
+        Ok(Self(resolve_client_ip(
+            &parts.headers,
+            peer,
+            state.config.trust_proxy_headers,
+        )))
+    }
+}
+
+/// The caller's policy profile, chosen by its `X-Api-Key` header from
+/// `POLICY_PROFILES`. Callers without a known key get the default profile.
+pub struct Policy(pub PolicyProfile);
+
+#[async_trait]
+impl FromRequestParts<AppState> for Policy {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let api_key = parts
+            .headers
+            .get("x-api-key")
+            .and_then(|v| v.to_str().ok())
+            .map(str::trim);
+        Ok(Self(state.config.policy_profiles.for_key(api_key)))
+    }
+}
+
+/// Whether the request reached us over https: the request's own scheme, or
+/// the last `X-Forwarded-Proto` entry (the one our proxy set) when
+/// `trust_proxy_headers` is set.
+pub fn is_https(headers: &HeaderMap, uri: &Uri, trust_proxy_headers: bool) -> bool {
+    if trust_proxy_headers {
+        let forwarded = headers
+            .get("x-forwarded-proto")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.rsplit(',').next())
+            .map(|v| v.trim().eq_ignore_ascii_case("https"));
+        if let Some(forwarded) = forwarded {
+            return forwarded;
+        }
+    }
+
+    uri.scheme_str() == Some("https")
+}
+
+pub fn resolve_client_ip(
+    headers: &HeaderMap,
+    peer: Option<SocketAddr>,
+    trust_proxy_headers: bool,
+) -> Option<IpAddr> {
+    if trust_proxy_headers {
+        // The last entry is the one our proxy appended; earlier ones are client-controlled
+        let forwarded = headers
+            .get("x-forwarded-for")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.rsplit(',').next())
+            .and_then(|v| v.trim().parse().ok());
+        if forwarded.is_some() {
+            return forwarded;
+        }
+    }
+
+    peer.map(|addr| addr.ip())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::models::{FileRequest, SecretRequest};
+    use axum::{body::Body, http::HeaderValue, routing::post, Router};
+    use tower::ServiceExt; // for `oneshot`
+
+    fn file_request_error(body: &str) -> String {
+        parse_json::<FileRequest>(body.as_bytes())
+            .map(|_| ())
+            .unwrap_err()
+            .error
+    }
+
+    #[test]
+    fn test_file_request_missing_iv() {
+        let body = r#"{"metadata": {"originalFilename": "a.pdf", "contentType": "application/pdf"}, "encryptedData": "abc", "expiration": 3600}"#;
+        assert_eq!(file_request_error(body), "metadata.iv is required");
+    }
+
+    #[test]
+    fn test_file_request_missing_content_type() {
+        let body = r#"{"metadata": {"originalFilename": "a.pdf", "iv": "xyz"}, "encryptedData": "abc", "expiration": 3600}"#;
+        assert_eq!(file_request_error(body), "metadata.contentType is required");
+    }
+
+    #[test]
+    fn test_file_request_valid() {
+        let body = r#"{"metadata": {"originalFilename": "a.pdf", "contentType": "application/pdf", "iv": "xyz"}, "encryptedData": "abc", "expiration": 3600}"#;
+        let request: FileRequest = parse_json(body.as_bytes()).unwrap();
+        assert_eq!(request.metadata.iv, "xyz");
+    }
+
+    #[test]
+    fn test_invalid_json_is_not_a_field_error() {
+        let error = parse_json::<FileRequest>(b"{not json")
+            .map(|_| ())
+            .unwrap_err();
+        assert_eq!(error.code, Some("INVALID_JSON"));
+    }
+
+    #[test]
+    fn test_form_and_json_create_are_equivalent() {
+        let form = "encryptedSecret=U2FsdGVkX1%2Bvuppp%3D%3D&expiration=3600&metadata=%7B%22label%22%3A%22db%22%7D";
+        let json = r#"{"encryptedSecret":"U2FsdGVkX1+vuppp==","expiration":3600,"metadata":{"label":"db"}}"#;
+
+        let from_form: SecretRequest = parse_json(
+            form_to_json(form.as_bytes())
+                .unwrap()
+                .to_string()
+                .as_bytes(),
+        )
+        .unwrap();
+        let from_json: SecretRequest = parse_json(json.as_bytes()).unwrap();
+        assert_eq!(
+            serde_json::to_value(from_form.into_stored(1706900000)).unwrap(),
+            serde_json::to_value(from_json.into_stored(1706900000)).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_form_field_errors_name_the_field() {
+        let error =
+            form_to_json(b"encryptedSecret=abc&expiration=3600&metadata=%7Bnope").unwrap_err();
+        assert_eq!(error.code, Some("INVALID_FIELD"));
+
+        let body = form_to_json(b"encryptedSecret=abc&expiration=soon").unwrap();
+        let error = parse_json::<SecretRequest>(body.to_string().as_bytes())
+            .map(|_| ())
+            .unwrap_err();
+        assert!(error.error.starts_with("expiration:"));
+    }
+
+    async fn create_status(accept_form_bodies: bool, content_type: &str, body: &str) -> StatusCode {
+        let state = AppState::new(
+            redis::Client::open("redis://127.0.0.1/").unwrap(),
+            Config {
+                accept_form_bodies,
+                ..Config::default()
+            },
+        );
+        let app = Router::new()
+            .route(
+                "/",
+                post(|ApiJsonOrForm(_): ApiJsonOrForm<SecretRequest>| async {}),
+            )
+            .with_state(state);
+        let req = Request::builder()
+            .method("POST")
+            .uri("/")
+            .header("content-type", content_type)
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        app.oneshot(req).await.unwrap().status()
+    }
+
+    #[tokio::test]
+    async fn test_form_bodies_accepted_only_when_enabled() {
+        let form = "encryptedSecret=abc&expiration=3600";
+        let form_type = "application/x-www-form-urlencoded";
+        assert_eq!(create_status(true, form_type, form).await, StatusCode::OK);
+        assert_eq!(
+            create_status(false, form_type, form).await,
+            StatusCode::UNSUPPORTED_MEDIA_TYPE
+        );
+
+        // JSON stays the primary path either way; other types are still refused
+        let json = r#"{"encryptedSecret":"abc","expiration":3600}"#;
+        assert_eq!(
+            create_status(true, "application/json", json).await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            create_status(true, "text/plain", form).await,
+            StatusCode::UNSUPPORTED_MEDIA_TYPE
+        );
+    }
+
+    #[test]
+    fn test_resolve_client_ip_uses_peer_by_default() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", HeaderValue::from_static("198.51.100.1"));
+        let peer: SocketAddr = "203.0.113.7:4000".parse().unwrap();
+
+        let ip = resolve_client_ip(&headers, Some(peer), false);
+        assert_eq!(ip, Some("203.0.113.7".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_is_https_trusts_forwarded_proto_only_from_proxy() {
+        let uri: Uri = "/v1/secrets".parse().unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-proto", HeaderValue::from_static("http, https"));
+
+        assert!(is_https(&headers, &uri, true));
+        assert!(!is_https(&headers, &uri, false));
+        assert!(!is_https(&HeaderMap::new(), &uri, true));
+        assert!(is_https(
+            &HeaderMap::new(),
+            &"https://api.snappwd.example/v1/secrets".parse().unwrap(),
+            false
+        ));
+    }
+
+    #[test]
+    fn test_resolve_client_ip_trusts_last_forwarded_entry() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "x-forwarded-for",
+            HeaderValue::from_static("10.0.0.1, 198.51.100.1"),
+        );
+        let peer: SocketAddr = "203.0.113.7:4000".parse().unwrap();
+
+        let ip = resolve_client_ip(&headers, Some(peer), true);
+        assert_eq!(ip, Some("198.51.100.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_resolve_client_ip_falls_back_to_peer() {
+        let headers = HeaderMap::new();
+        let peer: SocketAddr = "203.0.113.7:4000".parse().unwrap();
+
+        assert_eq!(
+            resolve_client_ip(&headers, Some(peer), true),
+            Some("203.0.113.7".parse().unwrap())
+        );
+        assert_eq!(resolve_client_ip(&headers, None, true), None);
+    }
+}
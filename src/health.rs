@@ -0,0 +1,147 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How long an `INFO memory` reply is reused between readiness probes.
+const MEMORY_INFO_TTL: Duration = Duration::from_secs(5);
+
+/// Redis memory figures from `INFO memory`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MemoryUsage {
+    pub used: u64,
+    /// 0 when Redis runs without a `maxmemory` limit
+    pub max: u64,
+}
+
+impl MemoryUsage {
+    /// Parse `used_memory` and `maxmemory` out of an `INFO memory` reply.
+    pub fn parse(info: &str) -> Option<Self> {
+        let mut used = None;
+        let mut max = None;
+        for line in info.lines() {
+            match line.trim().split_once(':') {
+                Some(("used_memory", v)) => used = v.parse().ok(),
+                Some(("maxmemory", v)) => max = v.parse().ok(),
+                _ => {}
+            }
+        }
+        Some(Self {
+            used: used?,
+            max: max?,
+        })
+    }
+
+    /// Whether usage is at or above `high_water_percent` of `maxmemory`.
+    /// Never true without a `maxmemory` limit.
+    pub fn is_under_pressure(&self, high_water_percent: u8) -> bool {
+        self.max > 0 && self.used * 100 >= self.max * u64::from(high_water_percent)
+    }
+}
+
+/// Whether a Redis `maxmemory-policy` can evict SnapPwd keys before their
+/// TTL, silently breaking one-time links. Every key we write has a TTL, so
+/// `volatile-*` policies are as unsafe as `allkeys-*`; only `noeviction` is safe.
+pub fn policy_evicts_keys(policy: &str) -> bool {
+    let policy = policy.trim().to_ascii_lowercase();
+    policy.starts_with("allkeys-") || policy.starts_with("volatile-")
+}
+
+/// `redis_version` from an `INFO server` reply, as (major, minor).
+pub fn redis_version(info: &str) -> Option<(u32, u32)> {
+    let version = info
+        .lines()
+        .find_map(|line| line.trim().strip_prefix("redis_version:"))?;
+    let mut parts = version.split('.');
+    let major = parts.next()?.parse().ok()?;
+    let minor = parts.next()?.parse().ok()?;
+    Some((major, minor))
+}
+
+/// Whether a Redis version has hash-field TTLs (HEXPIRE), needed by `HASH_STORAGE`.
+pub fn supports_hash_field_ttl(version: (u32, u32)) -> bool {
+    version >= (7, 4)
+}
+
+/// Caches the last memory reading so frequent probes don't hammer Redis.
+#[derive(Default)]
+pub struct MemoryInfoCache {
+    last: Mutex<Option<(Instant, MemoryUsage)>>,
+}
+
+impl MemoryInfoCache {
+    pub fn get(&self) -> Option<MemoryUsage> {
+        let last = *self.last.lock().unwrap();
+        last.filter(|(at, _)| at.elapsed() < MEMORY_INFO_TTL)
+            .map(|(_, usage)| usage)
+    }
+
+    pub fn set(&self, usage: MemoryUsage) {
+        *self.last.lock().unwrap() = Some((Instant::now(), usage));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info(used: u64, max: u64) -> String {
+        format!(
+            "# Memory\r\nused_memory:{}\r\nused_memory_human:1.00M\r\nmaxmemory:{}\r\nmaxmemory_policy:noeviction\r\n",
+            used, max
+        )
+    }
+
+    #[test]
+    fn test_parse_memory_info() {
+        let usage = MemoryUsage::parse(&info(1024, 4096)).unwrap();
+        assert_eq!(
+            usage,
+            MemoryUsage {
+                used: 1024,
+                max: 4096
+            }
+        );
+        assert!(MemoryUsage::parse("# Memory\r\n").is_none());
+    }
+
+    #[test]
+    fn test_memory_pressure_threshold() {
+        let below = MemoryUsage::parse(&info(89, 100)).unwrap();
+        let above = MemoryUsage::parse(&info(95, 100)).unwrap();
+        assert!(!below.is_under_pressure(90));
+        assert!(above.is_under_pressure(90));
+    }
+
+    #[test]
+    fn test_no_maxmemory_is_never_under_pressure() {
+        let unlimited = MemoryUsage::parse(&info(10_000_000, 0)).unwrap();
+        assert!(!unlimited.is_under_pressure(90));
+    }
+
+    #[test]
+    fn test_eviction_policy_check() {
+        assert!(policy_evicts_keys("allkeys-lru"));
+        assert!(policy_evicts_keys("allkeys-random"));
+        assert!(policy_evicts_keys("volatile-ttl"));
+        assert!(!policy_evicts_keys("noeviction"));
+    }
+
+    #[test]
+    fn test_hash_field_ttl_version_check() {
+        let info = "# Server\r\nredis_version:7.4.1\r\nredis_mode:standalone\r\n";
+        assert_eq!(redis_version(info), Some((7, 4)));
+        assert!(supports_hash_field_ttl((7, 4)));
+        assert!(supports_hash_field_ttl((8, 0)));
+        assert!(!supports_hash_field_ttl((7, 2)));
+        assert!(redis_version("# Server\r\n").is_none());
+    }
+
+    #[test]
+    fn test_cache_returns_recent_reading() {
+        let cache = MemoryInfoCache::default();
+        assert!(cache.get().is_none());
+
+        let usage = MemoryUsage { used: 1, max: 2 };
+        cache.set(usage);
+        assert_eq!(cache.get(), Some(usage));
+    }
+}
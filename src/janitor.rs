@@ -0,0 +1,299 @@
+//! Background sweep over SnapPwd keys. Approximate counters drift as keys
+//! expire without decrementing them, so the janitor periodically recounts the
+//! real keys, resets the counters and logs anything that looks wrong.
+
+use crate::{db, AppState};
+use std::collections::HashSet;
+use std::time::Duration;
+
+/// Pause between SCAN batches so a sweep doesn't hammer Redis.
+const BATCH_PAUSE: Duration = Duration::from_millis(10);
+/// Counter drift above which a reset is logged as a warning rather than debug.
+const DRIFT_WARN_THRESHOLD: u64 = 100;
+
+/// Keys seen during one sweep, by kind.
+#[derive(Debug, Default)]
+pub struct KeyCounts {
+    pub secrets: u64,
+    pub files: u64,
+    pub bundles: u64,
+    secret_ids: HashSet<String>,
+    confirmation_parents: HashSet<String>,
+    upload_ids: HashSet<String>,
+    chunk_parents: HashSet<String>,
+}
+
+impl KeyCounts {
+    pub fn record(&mut self, key: String) {
+        if let Some(parent) = key.strip_suffix(db::CONFIRMATIONS_SUFFIX) {
+            self.confirmation_parents.insert(parent.to_string());
+        } else if let Some(parent) = key.strip_suffix(db::CHUNKS_SUFFIX) {
+            self.chunk_parents.insert(parent.to_string());
+        } else if key.ends_with(db::CONFIRM_TOKENS_SUFFIX)
+            || key.ends_with(db::PEEKS_SUFFIX)
+            || key.ends_with(db::ACTIVE_PEEKS_SUFFIX)
+            || key.ends_with(db::READS_SUFFIX)
+            || key.ends_with(db::ACCESS_LOG_SUFFIX)
+            || key.ends_with(db::ACCESS_DROPPED_SUFFIX)
+            || key.ends_with(db::VIEW_CONTEXT_SUFFIX)
+            || key.ends_with(db::BURNED_SUFFIX)
+            || key.ends_with(db::STAGED_SUFFIX)
+            || key.ends_with(db::THUMBNAIL_SUFFIX)
+        {
+            // Confirmation tokens, peek and read counters, access logs, view
+            // contexts, burn records, staged downloads and thumbnails are
+            // side keys, not entries
+        } else if key.starts_with("spu-") {
+            self.upload_ids.insert(key);
+        } else if key.starts_with("spf-") {
+            self.files += 1;
+        } else if key.starts_with("spb-") {
+            self.bundles += 1;
+        } else if key.starts_with("sps-") || key.starts_with("sp-") {
+            self.secrets += 1;
+            self.secret_ids.insert(key);
+        }
+    }
+
+    /// Confirmation sets whose secret no longer exists. They share the
+    /// secret's TTL, so these indicate a burn path that skipped cleanup.
+    pub fn orphaned_confirmations(&self) -> usize {
+        self.confirmation_parents
+            .difference(&self.secret_ids)
+            .count()
+    }
+
+    /// Upload chunks whose session is gone. The chunks share the session's
+    /// TTL, but a chunk write racing the session's expiry can outlive it.
+    pub fn orphaned_chunks(&self) -> Vec<String> {
+        self.chunk_parents
+            .difference(&self.upload_ids)
+            .cloned()
+            .collect()
+    }
+}
+
+/// Absolute difference between a counter's recorded value and a recount.
+pub fn drift(recorded: Option<i64>, actual: u64) -> u64 {
+    recorded.unwrap_or(0).abs_diff(actual as i64)
+}
+
+/// Sweep every `interval` until the process exits.
+pub async fn run(state: AppState, interval: Duration) {
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        interval.tick().await;
+        if let Err(e) = sweep(&state).await {
+            tracing::warn!("Janitor sweep failed: {}", e);
+        }
+    }
+}
+
+/// Delete secrets past their `maxAgeSeconds` every `interval` until the
+/// process exits, instead of leaving them to their TTL.
+pub async fn run_max_age(state: AppState, interval: Duration) {
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        interval.tick().await;
+        match sweep_max_age(&state).await {
+            Ok(removed) => tracing::debug!("Removed {} secrets past their max age", removed),
+            Err(e) => tracing::warn!("Max-age sweep failed: {:?}", e),
+        }
+    }
+}
+
+/// Secrets scanned per batch by the max-age sweep.
+const MAX_AGE_BATCH: usize = 500;
+
+/// One incremental pass over every secret, deleting those whose read window
+/// has closed. Returns how many were deleted.
+async fn sweep_max_age(state: &AppState) -> Result<u64, db::ReadError> {
+    let mut removed = 0;
+    let mut cursor = 0;
+    loop {
+        let (next, secrets) = db::list_secrets(
+            &state.redis,
+            &state.keys,
+            state.config.secret_storage(),
+            cursor,
+            MAX_AGE_BATCH,
+            state.config.max_stored_value_bytes,
+        )
+        .await?;
+        let now = state.clock.now();
+        for (id, stored, _) in secrets {
+            if stored.is_past_max_age(now)
+                && db::delete_secret(&state.redis, &state.keys, &id).await?
+            {
+                removed += 1;
+            }
+        }
+        if next == 0 {
+            return Ok(removed);
+        }
+        cursor = next;
+        tokio::time::sleep(BATCH_PAUSE).await;
+    }
+}
+
+async fn sweep(state: &AppState) -> Result<(), redis::RedisError> {
+    let keys = &state.keys;
+    let active_files = if state.config.max_active_files > 0 {
+        Some(db::active_files(&state.redis, keys).await?)
+    } else {
+        None
+    };
+    let mut counts = KeyCounts::default();
+    db::scan_keys(&state.redis, &keys.scan_pattern(), BATCH_PAUSE, |key| {
+        counts.record(keys.strip(&key).to_string())
+    })
+    .await?;
+
+    tracing::debug!(
+        "Janitor counted {} secrets, {} files, {} bundles",
+        counts.secrets,
+        counts.files,
+        counts.bundles
+    );
+    state.metrics.set_stored_secrets(counts.secrets);
+
+    if let Some(recorded) = active_files {
+        db::reconcile_active_files(&state.redis, keys, recorded, counts.files).await?;
+        let drift = drift(Some(recorded), counts.files);
+        if drift > DRIFT_WARN_THRESHOLD {
+            tracing::warn!(
+                "Active file counter was off by {} (recorded {}, actual {})",
+                drift,
+                recorded,
+                counts.files
+            );
+        }
+    }
+
+    // Ids are bearer credentials, so only the count is logged
+    let orphans = counts.orphaned_confirmations();
+    if orphans > 0 {
+        tracing::warn!("Found {} orphaned confirmation sets", orphans);
+    }
+
+    let abandoned = counts.orphaned_chunks();
+    if !abandoned.is_empty() {
+        let removed = db::sweep_upload_chunks(&state.redis, keys, &abandoned).await?;
+        tracing::debug!("Removed chunks of {} abandoned uploads", removed);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reconcile_counts_seeded_keys() {
+        let mut counts = KeyCounts::default();
+        for key in [
+            "sps-a",
+            "sps-a:confirmations",
+            "sps-a:confirm_tokens",
+            "sps-b:confirmations",
+            "sp-legacy",
+            "sp-legacy:peeks",
+            "spf-x",
+            "spf-y",
+            "spf-y:active_peeks",
+            "spf-y:thumbnail",
+            "spb-z",
+            "spu-open",
+            "spu-open:chunks",
+            "spu-gone:chunks",
+        ] {
+            counts.record(key.to_string());
+        }
+
+        assert_eq!(counts.secrets, 2);
+        assert_eq!(counts.files, 2);
+        assert_eq!(counts.bundles, 1);
+        // sps-b's secret is gone but its confirmations remain
+        assert_eq!(counts.orphaned_confirmations(), 1);
+        // Upload sessions aren't files until completed
+        assert_eq!(counts.orphaned_chunks(), vec!["spu-gone".to_string()]);
+    }
+
+    #[test]
+    fn test_drift() {
+        assert_eq!(drift(Some(250), 100), 150);
+        assert_eq!(drift(Some(-3), 2), 5);
+        assert_eq!(drift(None, 7), 7);
+    }
+}
+
+/// Sweeps against a live Redis at `REDIS_URL`: `cargo test --features redis-tests`.
+#[cfg(all(test, feature = "redis-tests"))]
+mod redis_tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use crate::config::Config;
+    use crate::models::StoredSecret;
+    use redis::Client;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_max_age_sweep_removes_only_closed_windows() {
+        let url =
+            std::env::var("REDIS_URL").unwrap_or_else(|_| "redis://127.0.0.1:6379".to_string());
+        let mut state = AppState::new(
+            Client::open(url).unwrap(),
+            Config {
+                // A fresh namespace, so only this test's secrets are swept
+                redis_key_prefix: format!("max-age-{}:", uuid::Uuid::new_v4()),
+                ..Config::default()
+            },
+        );
+        state.clock = Arc::new(MockClock::new(1706900000 + 600));
+
+        let store = |created_at: u64| {
+            let state = state.clone();
+            async move {
+                let stored = StoredSecret {
+                    encrypted_secret: "abc".to_string(),
+                    created_at,
+                    max_age_seconds: Some(300),
+                    ..Default::default()
+                };
+                db::store_secret(
+                    &state.redis,
+                    &state.keys,
+                    state.config.secret_storage(),
+                    &stored,
+                    3600,
+                    None,
+                )
+                .await
+                .unwrap()
+                .id
+            }
+        };
+        // Readable until 1706900300, before the mock clock's now
+        let closed = store(1706900000).await;
+        // Readable until 1706900800
+        let fresh = store(1706900500).await;
+
+        assert_eq!(sweep_max_age(&state).await.unwrap(), 1);
+
+        let storage = state.config.secret_storage();
+        let raw = |id: String| {
+            let state = state.clone();
+            async move {
+                db::raw_secret(&state.redis, &state.keys, storage, &id, 0)
+                    .await
+                    .unwrap()
+            }
+        };
+        assert!(raw(closed).await.is_none());
+        assert!(raw(fresh.clone()).await.is_some());
+        db::delete_secret(&state.redis, &state.keys, &fresh)
+            .await
+            .unwrap();
+    }
+}
@@ -0,0 +1,453 @@
+use rand::Rng;
+use std::collections::HashMap;
+use std::future::Future;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tower_service::Service;
+
+/// Caps the number of simultaneous in-flight operations per key: the client
+/// IP by default. Entries are removed once a key has nothing in flight, so
+/// the map only ever holds currently active keys.
+#[derive(Debug)]
+pub struct ConcurrencyLimiter<K: Eq + Hash + Clone = IpAddr> {
+    max: usize,
+    in_flight: Mutex<HashMap<K, usize>>,
+}
+
+/// Held for the duration of an operation; releases its slot on drop.
+#[derive(Debug)]
+pub struct ConcurrencyPermit<K: Eq + Hash + Clone = IpAddr> {
+    limiter: Arc<ConcurrencyLimiter<K>>,
+    key: K,
+}
+
+impl<K: Eq + Hash + Clone> ConcurrencyLimiter<K> {
+    pub fn new(max: usize) -> Self {
+        Self {
+            max,
+            in_flight: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.max > 0
+    }
+
+    /// Take a slot for `key`, or None if it already has `max` operations in flight.
+    pub fn try_acquire(self: &Arc<Self>, key: K) -> Option<ConcurrencyPermit<K>> {
+        let mut in_flight = self.in_flight.lock().unwrap_or_else(|e| e.into_inner());
+        let count = in_flight.entry(key.clone()).or_insert(0);
+        if *count >= self.max {
+            return None;
+        }
+        *count += 1;
+
+        Some(ConcurrencyPermit {
+            limiter: Arc::clone(self),
+            key,
+        })
+    }
+}
+
+impl<K: Eq + Hash + Clone> Drop for ConcurrencyPermit<K> {
+    fn drop(&mut self) {
+        let mut in_flight = self
+            .limiter
+            .in_flight
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        if let Some(count) = in_flight.get_mut(&self.key) {
+            *count -= 1;
+            if *count == 0 {
+                in_flight.remove(&self.key);
+            }
+        }
+    }
+}
+
+/// Caps on open event subscriptions, counted in this process: per secret id
+/// (`MAX_SUBSCRIBERS_PER_ID`) and in total (`MAX_SUBSCRIBERS`). 0 disables
+/// either.
+#[derive(Debug)]
+pub struct SubscriberLimits {
+    per_id: Arc<ConcurrencyLimiter<String>>,
+    total: Arc<ConcurrencyLimiter<()>>,
+}
+
+/// Held for as long as a subscription stays open.
+#[derive(Debug)]
+pub struct SubscriberPermit {
+    _per_id: Option<ConcurrencyPermit<String>>,
+    _total: Option<ConcurrencyPermit<()>>,
+}
+
+impl SubscriberLimits {
+    pub fn new(max_per_id: usize, max_total: usize) -> Self {
+        Self {
+            per_id: Arc::new(ConcurrencyLimiter::new(max_per_id)),
+            total: Arc::new(ConcurrencyLimiter::new(max_total)),
+        }
+    }
+
+    /// A subscription slot for `id`, or None if either cap is reached.
+    pub fn try_subscribe(&self, id: &str) -> Option<SubscriberPermit> {
+        let per_id = if self.per_id.is_enabled() {
+            Some(self.per_id.try_acquire(id.to_string())?)
+        } else {
+            None
+        };
+        let total = if self.total.is_enabled() {
+            Some(self.total.try_acquire(())?)
+        } else {
+            None
+        };
+        Some(SubscriberPermit {
+            _per_id: per_id,
+            _total: total,
+        })
+    }
+}
+
+/// Service-wide token bucket (`GLOBAL_RPS`): refills at `rate` tokens per
+/// second up to `burst`, regardless of which client is asking.
+#[derive(Debug)]
+pub struct RequestBudget {
+    rate: u32,
+    burst: u32,
+    bucket: Mutex<Bucket>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl RequestBudget {
+    /// A budget of `rate` requests per second; `burst` of 0 means one
+    /// second's worth.
+    pub fn new(rate: u32, burst: u32) -> Self {
+        let burst = if burst == 0 { rate } else { burst };
+        Self {
+            rate,
+            burst,
+            bucket: Mutex::new(Bucket {
+                tokens: f64::from(burst),
+                refilled_at: Instant::now(),
+            }),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.rate > 0
+    }
+
+    /// Take a token, or return how long until the next one is available.
+    pub fn try_acquire(&self) -> Result<(), Duration> {
+        self.try_acquire_at(Instant::now())
+    }
+
+    fn try_acquire_at(&self, now: Instant) -> Result<(), Duration> {
+        let mut bucket = self.bucket.lock().unwrap_or_else(|e| e.into_inner());
+        let elapsed = now.saturating_duration_since(bucket.refilled_at);
+        bucket.tokens = (bucket.tokens + elapsed.as_secs_f64() * f64::from(self.rate))
+            .min(f64::from(self.burst));
+        bucket.refilled_at = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (1.0 - bucket.tokens) / f64::from(self.rate),
+            ))
+        }
+    }
+}
+
+/// `Retry-After` seconds for a 429: `base` rounded up, spread at random by
+/// up to `jitter` of itself either way (`RETRY_AFTER_JITTER`) and never
+/// under a second, so clients throttled together don't all retry together.
+pub fn retry_after_seconds(base: Duration, jitter: f64) -> u64 {
+    let base = base.as_secs_f64().ceil().max(1.0) as u64;
+    let spread = (base as f64 * jitter).ceil() as u64;
+    if spread == 0 {
+        return base;
+    }
+    rand::thread_rng().gen_range(base.saturating_sub(spread).max(1)..=base + spread)
+}
+
+/// Caps open connections (`MAX_CONNECTIONS`) by wrapping the make-service
+/// given to `axum::serve`. Each accepted connection waits for a slot before
+/// it's served and holds it until it closes. While every slot is taken the
+/// accept loop stalls, so new clients queue in the listen backlog (and time
+/// out there) instead of being served.
+#[derive(Clone, Debug)]
+pub struct ConnectionLimit<M> {
+    inner: M,
+    /// None = unlimited
+    slots: Option<Arc<Semaphore>>,
+}
+
+impl<M> ConnectionLimit<M> {
+    /// At most `max` connections at once; 0 means unlimited.
+    pub fn new(inner: M, max: usize) -> Self {
+        Self {
+            inner,
+            slots: (max > 0).then(|| Arc::new(Semaphore::new(max))),
+        }
+    }
+}
+
+impl<M, T> Service<T> for ConnectionLimit<M>
+where
+    M: Service<T>,
+    M::Future: Send + 'static,
+{
+    type Response = LimitedConnection<M::Response>;
+    type Error = M::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, target: T) -> Self::Future {
+        let service = self.inner.call(target);
+        let slots = self.slots.clone();
+        Box::pin(async move {
+            let slot = match slots {
+                Some(slots) => Some(Arc::new(
+                    slots
+                        .acquire_owned()
+                        .await
+                        .expect("semaphore is never closed"),
+                )),
+                None => None,
+            };
+            Ok(LimitedConnection {
+                service: service.await?,
+                _slot: slot,
+            })
+        })
+    }
+}
+
+/// The service for one connection. Its slot is released once the
+/// connection and every per-request clone of this are dropped.
+#[derive(Clone, Debug)]
+pub struct LimitedConnection<S> {
+    service: S,
+    _slot: Option<Arc<OwnedSemaphorePermit>>,
+}
+
+impl<S, R> Service<R> for LimitedConnection<S>
+where
+    S: Service<R>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: R) -> Self::Future {
+        self.service.call(req)
+    }
+}
+
+/// How long a reading of the emergency TTL cap is trusted. Creates on other
+/// instances see a change made through the admin endpoint this late.
+const EMERGENCY_MAX_TTL_CACHE: Duration = Duration::from_secs(5);
+
+/// Caches the last reading of the emergency TTL cap (None when unset), so a
+/// create doesn't cost a Redis round trip just to check it.
+#[derive(Default)]
+pub struct EmergencyMaxTtlCache {
+    last: Mutex<Option<(Instant, Option<u64>)>>,
+}
+
+impl EmergencyMaxTtlCache {
+    /// The cached cap, unless the reading is stale (outer None).
+    pub fn get(&self) -> Option<Option<u64>> {
+        let last = *self.last.lock().unwrap();
+        last.filter(|(at, _)| at.elapsed() < EMERGENCY_MAX_TTL_CACHE)
+            .map(|(_, cap)| cap)
+    }
+
+    pub fn set(&self, cap: Option<u64>) {
+        *self.last.lock().unwrap() = Some((Instant::now(), cap));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_limiter_rejects_beyond_max() {
+        let limiter = Arc::new(ConcurrencyLimiter::new(2));
+        let ip: IpAddr = "203.0.113.7".parse().unwrap();
+
+        let first = limiter.try_acquire(ip);
+        let second = limiter.try_acquire(ip);
+        assert!(first.is_some());
+        assert!(second.is_some());
+        assert!(limiter.try_acquire(ip).is_none());
+
+        // Other clients are unaffected
+        let other: IpAddr = "203.0.113.8".parse().unwrap();
+        assert!(limiter.try_acquire(other).is_some());
+    }
+
+    #[test]
+    fn test_emergency_max_ttl_cache_remembers_unset_too() {
+        let cache = EmergencyMaxTtlCache::default();
+        assert_eq!(cache.get(), None);
+        cache.set(None);
+        assert_eq!(cache.get(), Some(None));
+        cache.set(Some(3600));
+        assert_eq!(cache.get(), Some(Some(3600)));
+    }
+
+    #[test]
+    fn test_retry_after_jitter_varies_within_bounds() {
+        let samples: Vec<u64> = (0..200)
+            .map(|_| retry_after_seconds(Duration::from_secs(10), 0.5))
+            .collect();
+        assert!(
+            samples.iter().all(|s| (5..=15).contains(s)),
+            "{:?}",
+            samples
+        );
+        assert!(samples.iter().any(|s| *s != samples[0]));
+
+        assert_eq!(retry_after_seconds(Duration::from_millis(2500), 0.0), 3);
+        // Never below a second, however wide the spread
+        assert!((0..50).all(|_| retry_after_seconds(Duration::ZERO, 1.0) >= 1));
+    }
+
+    #[test]
+    fn test_limiter_releases_on_drop() {
+        let limiter = Arc::new(ConcurrencyLimiter::new(1));
+        let ip: IpAddr = "203.0.113.7".parse().unwrap();
+
+        let permit = limiter.try_acquire(ip);
+        assert!(permit.is_some());
+        assert!(limiter.try_acquire(ip).is_none());
+
+        drop(permit);
+        assert!(limiter.try_acquire(ip).is_some());
+        assert!(limiter.in_flight.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_subscriber_caps_per_id_and_in_total() {
+        let limits = SubscriberLimits::new(2, 3);
+
+        let first = limits.try_subscribe("sps-a");
+        let second = limits.try_subscribe("sps-a");
+        assert!(first.is_some() && second.is_some());
+        assert!(limits.try_subscribe("sps-a").is_none());
+
+        // Another id has its own per-id slots, until the total runs out
+        let other = limits.try_subscribe("sps-b");
+        assert!(other.is_some());
+        assert!(limits.try_subscribe("sps-c").is_none());
+
+        // Closing a subscription frees its slots
+        drop(first);
+        assert!(limits.try_subscribe("sps-a").is_some());
+
+        let unlimited = SubscriberLimits::new(0, 0);
+        let held: Vec<_> = (0..100).map(|_| unlimited.try_subscribe("sps-a")).collect();
+        assert!(held.iter().all(Option::is_some));
+    }
+
+    #[test]
+    fn test_budget_sheds_once_burst_is_spent() {
+        let budget = RequestBudget::new(10, 3);
+        let now = Instant::now();
+
+        for _ in 0..3 {
+            assert!(budget.try_acquire_at(now).is_ok());
+        }
+        let wait = budget.try_acquire_at(now).unwrap_err();
+        assert!(wait > Duration::ZERO && wait <= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn test_budget_refills_over_time() {
+        let budget = RequestBudget::new(10, 2);
+        let now = Instant::now();
+        assert!(budget.try_acquire_at(now).is_ok());
+        assert!(budget.try_acquire_at(now).is_ok());
+        assert!(budget.try_acquire_at(now).is_err());
+
+        // 100ms at 10 rps earns one token back, never more than the burst
+        let later = now + Duration::from_millis(100);
+        assert!(budget.try_acquire_at(later).is_ok());
+        assert!(budget.try_acquire_at(later).is_err());
+
+        let much_later = now + Duration::from_secs(60);
+        assert!(budget.try_acquire_at(much_later).is_ok());
+        assert!(budget.try_acquire_at(much_later).is_ok());
+        assert!(budget.try_acquire_at(much_later).is_err());
+    }
+
+    #[test]
+    fn test_budget_burst_defaults_to_rate() {
+        let budget = RequestBudget::new(5, 0);
+        assert!(budget.is_enabled());
+        let now = Instant::now();
+        assert_eq!(
+            (0..10)
+                .filter(|_| budget.try_acquire_at(now).is_ok())
+                .count(),
+            5
+        );
+        assert!(!RequestBudget::new(0, 0).is_enabled());
+    }
+
+    #[tokio::test]
+    async fn test_connection_limit_waits_for_a_free_slot() {
+        use std::convert::Infallible;
+        use tower::{service_fn, ServiceExt};
+
+        let make = service_fn(|_: ()| async { Ok::<_, Infallible>(()) });
+        let mut limit = ConnectionLimit::new(make, 1);
+
+        let first = limit.ready().await.unwrap().call(()).await.unwrap();
+        let second = limit.ready().await.unwrap().call(());
+        tokio::pin!(second);
+        // The only slot is held by the first connection
+        assert!(tokio::time::timeout(Duration::from_millis(50), &mut second)
+            .await
+            .is_err());
+
+        drop(first);
+        assert!(tokio::time::timeout(Duration::from_millis(50), &mut second)
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_connection_limit_zero_is_unlimited() {
+        use std::convert::Infallible;
+        use tower::{service_fn, ServiceExt};
+
+        let make = service_fn(|_: ()| async { Ok::<_, Infallible>(()) });
+        let mut limit = ConnectionLimit::new(make, 0);
+        let mut connections = Vec::new();
+        for _ in 0..100 {
+            connections.push(limit.ready().await.unwrap().call(()).await.unwrap());
+        }
+    }
+}
@@ -1,73 +1,384 @@
-use axum::{
-    extract::DefaultBodyLimit,
-    routing::{get, post},
-    Router,
-};
+use axum::middleware::{from_fn, from_fn_with_state};
 use redis::Client;
 use std::env;
+use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Duration;
 use tower_http::cors::CorsLayer;
 use tower_http::trace::TraceLayer;
 
+mod clock;
+mod config;
 mod db;
+mod decrypt;
+mod events;
+mod extract;
 mod handlers;
+mod health;
+mod janitor;
+mod limits;
+mod manage;
+mod metrics;
+mod middleware;
 mod models;
+mod routes;
+mod tasks;
+mod tcp;
+mod telemetry;
+#[cfg(unix)]
+mod unix;
+
+use clock::{Clock, SystemClock};
+use config::Config;
+use db::Keys;
+use health::MemoryInfoCache;
+use limits::{
+    ConcurrencyLimiter, ConnectionLimit, EmergencyMaxTtlCache, RequestBudget, SubscriberLimits,
+};
+use metrics::Metrics;
+use tasks::BackgroundTasks;
 
 #[derive(Clone)]
 pub struct AppState {
     pub redis: Arc<Client>,
-    pub max_file_size_bytes: usize,
+    /// Read replica (`REDIS_REPLICA_URL`), if configured
+    pub replica: Option<Arc<Client>>,
+    pub config: Arc<Config>,
+    pub clock: Arc<dyn Clock>,
+    pub upload_limiter: Arc<ConcurrencyLimiter>,
+    /// Open event subscriptions (`MAX_SUBSCRIBERS_PER_ID`, `MAX_SUBSCRIBERS`)
+    pub subscriber_limits: Arc<SubscriberLimits>,
+    /// Service-wide request budget (`GLOBAL_RPS`)
+    pub request_budget: Arc<RequestBudget>,
+    pub metrics: Arc<Metrics>,
+    pub memory_info: Arc<MemoryInfoCache>,
+    pub emergency_max_ttl: Arc<EmergencyMaxTtlCache>,
+    pub keys: Arc<Keys>,
+    pub tasks: Arc<BackgroundTasks>,
+    /// Unix timestamp the process started at, for uptime
+    pub started_at: u64,
+}
+
+impl AppState {
+    pub fn new(redis: Client, config: Config) -> Self {
+        Self {
+            redis: Arc::new(redis),
+            replica: None,
+            clock: Arc::new(SystemClock),
+            upload_limiter: Arc::new(ConcurrencyLimiter::new(
+                config.max_concurrent_uploads_per_ip,
+            )),
+            subscriber_limits: Arc::new(SubscriberLimits::new(
+                config.max_subscribers_per_id,
+                config.max_subscribers,
+            )),
+            request_budget: Arc::new(RequestBudget::new(config.global_rps, config.global_burst)),
+            keys: Arc::new(
+                Keys::new(config.redis_key_prefix.clone())
+                    .with_id_blocklist(config.id_blocklist.clone()),
+            ),
+            config: Arc::new(config),
+            metrics: Arc::new(Metrics::default()),
+            memory_info: Arc::new(MemoryInfoCache::default()),
+            emergency_max_ttl: Arc::new(EmergencyMaxTtlCache::default()),
+            tasks: Arc::new(BackgroundTasks::default()),
+            started_at: SystemClock.now(),
+        }
+    }
+
+    pub fn with_replica(mut self, replica: Client) -> Self {
+        self.replica = Some(Arc::new(replica));
+        self
+    }
+
+    /// Routing for lookups that tolerate replication lag.
+    pub fn replica(&self) -> db::Replica<'_> {
+        db::Replica {
+            client: self.replica.as_deref(),
+            fallback: self.config.replica_fallback,
+        }
+    }
+
+    /// Client for read-only scans: the replica when there is one.
+    pub fn reader(&self) -> &Client {
+        self.replica.as_deref().unwrap_or(&self.redis)
+    }
 }
 
 #[tokio::main]
 async fn main() {
-    // Initialize tracing
-    tracing_subscriber::fmt::init();
+    let _telemetry = telemetry::init();
 
     let redis_url = env::var("REDIS_URL").unwrap_or_else(|_| "redis://127.0.0.1:6379".to_string());
 
-    // Configurable max file size (MB) - default 2MB
-    let max_file_size_mb: usize = env::var("MAX_FILE_SIZE_MB")
-        .ok()
-        .and_then(|v| v.parse().ok())
-        .unwrap_or(2);
-    let max_file_size_bytes = max_file_size_mb * 1024 * 1024;
+    let config = Config::from_env();
+    let max_file_size_bytes = config.max_file_size_bytes;
+    // A typo must not leave the create endpoints open
+    if config.api_keys.is_empty() && env::var("API_KEYS").is_ok_and(|v| !v.trim().is_empty()) {
+        tracing::error!("API_KEYS is malformed (expected label:key,label:key); refusing to start");
+        return;
+    }
+    // Nor a typo lift a limit
+    if malformed::<config::SizeTtlCurve>("SIZE_TTL_CURVE") {
+        tracing::error!(
+            "SIZE_TTL_CURVE is malformed (expected size_bytes:max_seconds,...); refusing to start"
+        );
+        return;
+    }
+    if malformed::<config::SecretKinds>("SECRET_KINDS") {
+        tracing::error!(
+            "SECRET_KINDS is malformed (expected kind[:default_expiration[:max_payload_length]],...); refusing to start"
+        );
+        return;
+    }
+    if malformed::<config::PolicyProfiles>("POLICY_PROFILES") {
+        tracing::error!(
+            "POLICY_PROFILES is malformed (expected key[:min_expiration[:max_expiration[:max_payload_length[:type|type]]]],...); refusing to start"
+        );
+        return;
+    }
+    // The redirect target must not come from the request's own Host header
+    if config.force_https == config::ForceHttps::Redirect && config.public_base_url.is_none() {
+        tracing::error!("FORCE_HTTPS=redirect requires PUBLIC_BASE_URL; refusing to start");
+        return;
+    }
 
     tracing::info!("Connecting to Redis at {}", redis_url);
-    tracing::info!("Max file size configured to {} MB", max_file_size_mb);
+    tracing::info!(
+        "Max file size configured to {} MB",
+        max_file_size_bytes / 1024 / 1024
+    );
+    if let Some(min) = config.min_response_time {
+        tracing::info!("Padding GET responses to at least {:?}", min);
+    }
 
     let client = match db::get_redis_client(&redis_url).await {
-        Ok(c) => Arc::new(c),
+        Ok(c) => c,
         Err(e) => {
             tracing::error!("Failed to connect to Redis: {}", e);
             return;
         }
     };
 
-    let state = AppState {
-        redis: client,
-        max_file_size_bytes,
+    match db::maxmemory_policy(&client).await {
+        Ok(Some(policy)) if health::policy_evicts_keys(&policy) => {
+            tracing::warn!(
+                "Redis maxmemory-policy is {}: keys can be evicted before they expire, breaking one-time links. Use noeviction.",
+                policy
+            );
+            if config.strict_eviction_check {
+                tracing::error!("Refusing to start (STRICT_EVICTION_CHECK is set)");
+                return;
+            }
+        }
+        Ok(_) => {}
+        // Managed Redis often disables CONFIG; don't block startup on that
+        Err(e) => tracing::warn!("Could not check Redis maxmemory-policy: {}", e),
+    }
+
+    if config.hash_storage {
+        // Without HEXPIRE, hash-stored payloads would never expire on their own
+        let version = db::server_info(&client)
+            .await
+            .ok()
+            .and_then(|info| health::redis_version(&info));
+        match version {
+            Some(version) if health::supports_hash_field_ttl(version) => {
+                tracing::info!("Storing secrets as hashes");
+            }
+            Some((major, minor)) => {
+                tracing::error!(
+                    "HASH_STORAGE needs Redis 7.4+ for HEXPIRE, server is {}.{}",
+                    major,
+                    minor
+                );
+                return;
+            }
+            None => {
+                tracing::error!("HASH_STORAGE is set but the Redis version could not be read");
+                return;
+            }
+        }
+    }
+
+    if config.secret_events {
+        match db::keyspace_events(&client).await {
+            Ok(Some(flags)) if events::notifies_expiry(&flags) => {}
+            Ok(_) => tracing::warn!(
+                "SECRET_EVENTS is set but Redis notify-keyspace-events lacks Kx: subscribers won't see expiries"
+            ),
+            Err(e) => tracing::warn!("Could not check Redis notify-keyspace-events: {}", e),
+        }
+    }
+
+    let replica = match &config.redis_replica_url {
+        Some(url) => match db::get_redis_client(url).await {
+            Ok(replica) => {
+                tracing::info!("Serving read-only lookups from replica at {}", url);
+                Some(replica)
+            }
+            Err(e) => {
+                tracing::error!("Failed to connect to Redis replica: {}", e);
+                return;
+            }
+        },
+        None => None,
+    };
+
+    let mut state = AppState::new(client, config);
+    if let Some(replica) = replica {
+        state = state.with_replica(replica);
+    }
+    clock::check_clock(state.clock.as_ref());
+
+    if state.config.diagnostic_errors {
+        tracing::warn!(
+            "DIAGNOSTIC_ERRORS is set: 404 bodies explain why secrets are missing. Never enable this in production"
+        );
+    }
+
+    if state.config.global_rps > 0 {
+        tracing::info!("Global request budget {} rps", state.config.global_rps);
+    }
+    if state.config.max_active_files > 0 {
+        tracing::info!("Active files capped at {}", state.config.max_active_files);
+        if state.config.janitor_interval.is_none() {
+            tracing::warn!(
+                "MAX_ACTIVE_FILES is set without JANITOR_INTERVAL_SECONDS: files that expire unread keep their slots"
+            );
+        }
+    }
+    if let Some(interval) = state.config.janitor_interval {
+        state
+            .tasks
+            .spawn_until_shutdown(janitor::run(state.clone(), interval));
+    }
+    if let Some(interval) = state.config.max_age_sweep_interval {
+        state
+            .tasks
+            .spawn_until_shutdown(janitor::run_max_age(state.clone(), interval));
+    }
+
+    let tasks = state.tasks.clone();
+    let shutdown_grace = state.config.shutdown_grace;
+    let trailing_slash = state.config.trailing_slash;
+    let max_connections = state.config.max_connections;
+    let compression = state
+        .config
+        .compress_responses
+        .then_some(state.config.compression_min_bytes);
+    let tcp_tuning = tcp::Tuning {
+        nodelay: state.config.tcp_nodelay,
+        keepalive: state.config.tcp_keepalive,
     };
 
-    // Calculate body limit safely (max_file_size_bytes * 1.5 for base64 + JSON overhead)
-    // Or just be generous with the transport limit since we validate logically in the handler.
-    // Let's go with 2x to be safe, minimum 10MB.
-    let body_limit = std::cmp::max(10 * 1024 * 1024, max_file_size_bytes * 2);
-
-    let app = Router::new()
-        .route("/openapi.yaml", get(handlers::openapi))
-        .route("/v1/secrets", post(handlers::create_secret))
-        .route("/v1/secrets/:id", get(handlers::get_secret))
-        .route("/v1/files", post(handlers::create_file))
-        .route("/v1/files/:id", get(handlers::get_file))
-        .layer(DefaultBodyLimit::max(body_limit))
+    let app = routes::router(&state)
+        .layer(from_fn_with_state(state.clone(), middleware::limit_headers))
+        .layer(from_fn_with_state(state.clone(), middleware::global_budget))
+        .layer(from_fn_with_state(state.clone(), middleware::force_https))
+        .layer(from_fn_with_state(state.clone(), middleware::response_case))
+        .layer(from_fn(middleware::problem_json))
+        .layer(from_fn_with_state(state.clone(), middleware::envelope))
         .with_state(state)
         .layer(CorsLayer::permissive()) // Allow all CORS for now, can be tightened
-        .layer(TraceLayer::new_for_http());
+        .layer(TraceLayer::new_for_http().make_span_with(telemetry::request_span));
+    let app = middleware::compression(app, compression);
+    let app = middleware::trailing_slash(app, trailing_slash);
+
+    #[cfg(unix)]
+    if let Some(path) = env::var_os("LISTEN_SOCKET") {
+        let path = std::path::PathBuf::from(path);
+        let listener = match unix::bind(&path) {
+            Ok(listener) => listener,
+            Err(e) => {
+                tracing::error!("Failed to bind {}: {}", path.display(), e);
+                return;
+            }
+        };
+        tracing::info!("listening on unix:{}", path.display());
+
+        tokio::select! {
+            result = unix::serve(listener, app, max_connections) => {
+                if let Err(e) = result {
+                    tracing::error!("Unix socket server error: {}", e);
+                }
+            }
+            _ = shutdown_signal() => tracing::info!("Shutting down"),
+        }
+        unix::cleanup(&path);
+        drain(&tasks, shutdown_grace).await;
+        return;
+    }
 
     let port = env::var("PORT").unwrap_or_else(|_| "8080".to_string());
-    let addr = format!("0.0.0.0:{}", port);
-    let listener = tokio::net::TcpListener::bind(&addr).await.unwrap();
+    let addr: SocketAddr = match format!("0.0.0.0:{}", port).parse() {
+        Ok(addr) => addr,
+        Err(e) => {
+            tracing::error!("Invalid PORT {}: {}", port, e);
+            return;
+        }
+    };
+    let listener = match tcp::bind(addr, tcp_tuning) {
+        Ok(listener) => listener,
+        Err(e) => {
+            tracing::error!("Failed to bind {}: {}", addr, e);
+            return;
+        }
+    };
     tracing::info!("listening on {}", listener.local_addr().unwrap());
-    axum::serve(listener, app).await.unwrap();
+    if max_connections > 0 {
+        tracing::info!("Connections capped at {}", max_connections);
+    }
+    axum::serve(
+        listener,
+        ConnectionLimit::new(
+            app.into_make_service_with_connect_info::<SocketAddr>(),
+            max_connections,
+        ),
+    )
+    .tcp_nodelay(tcp_tuning.nodelay)
+    .with_graceful_shutdown(shutdown_signal())
+    .await
+    .unwrap();
+    tracing::info!("Shutting down");
+    drain(&tasks, shutdown_grace).await;
+}
+
+/// Let background tasks finish (or cancel them) before the process exits.
+async fn drain(tasks: &BackgroundTasks, grace: Duration) {
+    if !tasks.shutdown(grace).await {
+        tracing::warn!("Cancelled background tasks still running after {:?}", grace);
+    }
+}
+
+/// Whether the setting `name` is present but doesn't parse, for settings
+/// whose silent fallback would drop a limit the operator asked for.
+fn malformed<T: std::str::FromStr>(name: &str) -> bool {
+    env::var(name).is_ok_and(|value| value.parse::<T>().is_err())
+}
+
+/// Resolves on Ctrl+C or SIGTERM.
+#[cfg(unix)]
+async fn shutdown_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut terminate = match signal(SignalKind::terminate()) {
+        Ok(terminate) => terminate,
+        Err(e) => {
+            tracing::warn!("Failed to install SIGTERM handler: {}", e);
+            let _ = tokio::signal::ctrl_c().await;
+            return;
+        }
+    };
+
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate.recv() => {}
+    }
+}
+
+/// Resolves on Ctrl+C.
+#[cfg(not(unix))]
+async fn shutdown_signal() {
+    let _ = tokio::signal::ctrl_c().await;
 }
@@ -0,0 +1,139 @@
+//! Signed management links. Instead of an owner token stored next to the
+//! secret, creation hands out a URL carrying the id, an expiry and an
+//! HMAC-SHA256 over both. Whoever holds it can inspect, delete or extend
+//! that one secret until the expiry; nothing extra is kept in Redis.
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Why a management link was refused.
+#[derive(Debug, PartialEq)]
+pub enum LinkError {
+    /// The signature matches no configured key (tampered id, expiry or signature)
+    BadSignature,
+    /// Correctly signed, but past its expiry
+    Expired,
+}
+
+fn mac(key: &str, id: &str, expires_at: u64) -> HmacSha256 {
+    let mut mac =
+        HmacSha256::new_from_slice(key.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(format!("{}.{}", id, expires_at).as_bytes());
+    mac
+}
+
+/// Base64url signature of `id` and `expires_at` (unix seconds) under `key`.
+pub fn sign(key: &str, id: &str, expires_at: u64) -> String {
+    URL_SAFE_NO_PAD.encode(mac(key, id, expires_at).finalize().into_bytes())
+}
+
+/// Check a link against every key in `keys` (the current one, then the
+/// previous one while it's being rotated out). The signature is checked
+/// first, so a link with an edited expiry is reported as tampered.
+pub fn verify(
+    keys: &[&str],
+    id: &str,
+    expires_at: u64,
+    signature: &str,
+    now: u64,
+) -> Result<(), LinkError> {
+    let signature = URL_SAFE_NO_PAD
+        .decode(signature)
+        .map_err(|_| LinkError::BadSignature)?;
+    if !keys
+        .iter()
+        .any(|key| mac(key, id, expires_at).verify_slice(&signature).is_ok())
+    {
+        return Err(LinkError::BadSignature);
+    }
+    if now >= expires_at {
+        return Err(LinkError::Expired);
+    }
+
+    Ok(())
+}
+
+/// Path and query of the management link for `id`, relative to the API.
+pub fn url(key: &str, id: &str, expires_at: u64) -> String {
+    format!(
+        "/v1/secrets/{}/manage?expires={}&sig={}",
+        id,
+        expires_at,
+        sign(key, id, expires_at)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: u64 = 1706900000;
+
+    #[test]
+    fn test_valid_link_verifies() {
+        let sig = sign("k1", "sps-abc", NOW + 60);
+        assert_eq!(verify(&["k1"], "sps-abc", NOW + 60, &sig, NOW), Ok(()));
+    }
+
+    #[test]
+    fn test_expired_link_rejected() {
+        let sig = sign("k1", "sps-abc", NOW);
+        assert_eq!(
+            verify(&["k1"], "sps-abc", NOW, &sig, NOW),
+            Err(LinkError::Expired)
+        );
+    }
+
+    #[test]
+    fn test_tampered_link_rejected() {
+        let sig = sign("k1", "sps-abc", NOW + 60);
+        // Another id, a pushed-out expiry, or an edited signature
+        assert_eq!(
+            verify(&["k1"], "sps-abd", NOW + 60, &sig, NOW),
+            Err(LinkError::BadSignature)
+        );
+        assert_eq!(
+            verify(&["k1"], "sps-abc", NOW + 3600, &sig, NOW),
+            Err(LinkError::BadSignature)
+        );
+        let mut edited = sig.into_bytes();
+        edited[0] = if edited[0] == b'A' { b'B' } else { b'A' };
+        let edited = String::from_utf8(edited).unwrap();
+        assert_eq!(
+            verify(&["k1"], "sps-abc", NOW + 60, &edited, NOW),
+            Err(LinkError::BadSignature)
+        );
+        assert_eq!(
+            verify(&["k1"], "sps-abc", NOW + 60, "not base64!", NOW),
+            Err(LinkError::BadSignature)
+        );
+    }
+
+    #[test]
+    fn test_previous_key_verifies_during_rotation() {
+        let sig = sign("old", "sps-abc", NOW + 60);
+        assert_eq!(
+            verify(&["new", "old"], "sps-abc", NOW + 60, &sig, NOW),
+            Ok(())
+        );
+        assert_eq!(
+            verify(&["new"], "sps-abc", NOW + 60, &sig, NOW),
+            Err(LinkError::BadSignature)
+        );
+    }
+
+    #[test]
+    fn test_url_carries_expiry_and_signature() {
+        assert_eq!(
+            url("k1", "sps-abc", NOW),
+            format!(
+                "/v1/secrets/sps-abc/manage?expires={}&sig={}",
+                NOW,
+                sign("k1", "sps-abc", NOW)
+            )
+        );
+    }
+}
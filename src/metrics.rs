@@ -0,0 +1,232 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+
+/// Upper bounds (bytes) for the payload size histogram: 1KB, 16KB, 256KB, 1MB, 8MB
+const PAYLOAD_BYTES_BUCKETS: [u64; 5] = [1024, 16 * 1024, 256 * 1024, 1024 * 1024, 8 * 1024 * 1024];
+
+/// Endpoint a stored payload came through, used as the `endpoint` label.
+#[derive(Clone, Copy, Debug)]
+pub enum PayloadKind {
+    Secret,
+    File,
+    Bundle,
+}
+
+impl PayloadKind {
+    fn label(self) -> &'static str {
+        match self {
+            PayloadKind::Secret => "secrets",
+            PayloadKind::File => "files",
+            PayloadKind::Bundle => "bundles",
+        }
+    }
+}
+
+/// Fixed-bucket histogram with lock-free observation.
+#[derive(Debug)]
+pub struct Histogram {
+    bounds: &'static [u64],
+    /// Per-bucket (non-cumulative) counts; the last slot is the +Inf bucket
+    buckets: Vec<AtomicU64>,
+    sum: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Histogram {
+    pub fn new(bounds: &'static [u64]) -> Self {
+        Self {
+            bounds,
+            buckets: (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect(),
+            sum: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, value: u64) {
+        let index = self
+            .bounds
+            .iter()
+            .position(|&bound| value <= bound)
+            .unwrap_or(self.bounds.len());
+        self.buckets[index].fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(value, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    /// Append this histogram in Prometheus text format, with cumulative buckets.
+    fn render(&self, name: &str, labels: &str, out: &mut String) {
+        let mut cumulative = 0;
+        for (i, bucket) in self.buckets.iter().enumerate() {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let le = match self.bounds.get(i) {
+                Some(bound) => bound.to_string(),
+                None => "+Inf".to_string(),
+            };
+            let _ = writeln!(
+                out,
+                "{}_bucket{{{},le=\"{}\"}} {}",
+                name, labels, le, cumulative
+            );
+        }
+        let _ = writeln!(
+            out,
+            "{}_sum{{{}}} {}",
+            name,
+            labels,
+            self.sum.load(Ordering::Relaxed)
+        );
+        let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, self.count());
+    }
+}
+
+/// Process-local metrics exposed on `GET /metrics`.
+#[derive(Debug)]
+pub struct Metrics {
+    secret_payload_bytes: Histogram,
+    file_payload_bytes: Histogram,
+    bundle_payload_bytes: Histogram,
+    /// Decoded bytes stored per endpoint, as reported in `X-Storage-Bytes`
+    stored_bytes: [AtomicU64; 3],
+    /// Secrets counted by the last janitor sweep (-1 = not swept yet)
+    stored_secrets: AtomicI64,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {
+            secret_payload_bytes: Histogram::new(&PAYLOAD_BYTES_BUCKETS),
+            file_payload_bytes: Histogram::new(&PAYLOAD_BYTES_BUCKETS),
+            bundle_payload_bytes: Histogram::new(&PAYLOAD_BYTES_BUCKETS),
+            stored_bytes: Default::default(),
+            stored_secrets: AtomicI64::new(-1),
+        }
+    }
+}
+
+impl Metrics {
+    pub fn payload_bytes(&self, kind: PayloadKind) -> &Histogram {
+        match kind {
+            PayloadKind::Secret => &self.secret_payload_bytes,
+            PayloadKind::File => &self.file_payload_bytes,
+            PayloadKind::Bundle => &self.bundle_payload_bytes,
+        }
+    }
+
+    /// Record the decoded size of a successfully stored payload.
+    pub fn observe_payload(&self, kind: PayloadKind, bytes: usize) {
+        self.payload_bytes(kind).observe(bytes as u64);
+        self.stored_bytes[kind as usize].fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Total decoded bytes stored through an endpoint since startup.
+    pub fn stored_bytes(&self, kind: PayloadKind) -> u64 {
+        self.stored_bytes[kind as usize].load(Ordering::Relaxed)
+    }
+
+    pub fn set_stored_secrets(&self, count: u64) {
+        self.stored_secrets.store(count as i64, Ordering::Relaxed);
+    }
+
+    /// Approximate number of stored secrets, as of the last janitor sweep.
+    pub fn stored_secrets(&self) -> Option<u64> {
+        u64::try_from(self.stored_secrets.load(Ordering::Relaxed)).ok()
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        out.push_str("# HELP snappwd_payload_bytes Decoded size of stored payloads.\n");
+        out.push_str("# TYPE snappwd_payload_bytes histogram\n");
+        for kind in [PayloadKind::Secret, PayloadKind::File, PayloadKind::Bundle] {
+            let labels = format!("endpoint=\"{}\"", kind.label());
+            self.payload_bytes(kind)
+                .render("snappwd_payload_bytes", &labels, &mut out);
+        }
+        out.push_str("# HELP snappwd_stored_bytes_total Decoded bytes stored, for billing.\n");
+        out.push_str("# TYPE snappwd_stored_bytes_total counter\n");
+        for kind in [PayloadKind::Secret, PayloadKind::File, PayloadKind::Bundle] {
+            let _ = writeln!(
+                out,
+                "snappwd_stored_bytes_total{{endpoint=\"{}\"}} {}",
+                kind.label(),
+                self.stored_bytes(kind)
+            );
+        }
+        if let Some(count) = self.stored_secrets() {
+            out.push_str(
+                "# HELP snappwd_stored_secrets Secrets counted by the last janitor sweep.\n",
+            );
+            out.push_str("# TYPE snappwd_stored_secrets gauge\n");
+            let _ = writeln!(out, "snappwd_stored_secrets {}", count);
+        }
+        out
+    }
+}
+
+/// Decoded byte length of base64 data, without allocating.
+pub fn decoded_len(encoded: &str) -> usize {
+    let padding = encoded.bytes().rev().take_while(|&b| b == b'=').count();
+    (encoded.len() * 3 / 4).saturating_sub(padding)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histogram_observes_created_secret() {
+        let metrics = Metrics::default();
+        metrics.observe_payload(PayloadKind::Secret, 2000);
+
+        assert_eq!(metrics.payload_bytes(PayloadKind::Secret).count(), 1);
+        assert_eq!(metrics.payload_bytes(PayloadKind::File).count(), 0);
+
+        let rendered = metrics.render();
+        // 2000 bytes falls in the 16KB bucket (and every larger one), not the 1KB one
+        assert!(
+            rendered.contains(r#"snappwd_payload_bytes_bucket{endpoint="secrets",le="1024"} 0"#)
+        );
+        assert!(
+            rendered.contains(r#"snappwd_payload_bytes_bucket{endpoint="secrets",le="16384"} 1"#)
+        );
+        assert!(
+            rendered.contains(r#"snappwd_payload_bytes_bucket{endpoint="secrets",le="+Inf"} 1"#)
+        );
+        assert!(rendered.contains(r#"snappwd_payload_bytes_sum{endpoint="secrets"} 2000"#));
+        assert!(rendered.contains(r#"snappwd_payload_bytes_count{endpoint="secrets"} 1"#));
+        assert!(rendered.contains(r#"snappwd_stored_bytes_total{endpoint="secrets"} 2000"#));
+        assert!(rendered.contains(r#"snappwd_stored_bytes_total{endpoint="files"} 0"#));
+    }
+
+    #[test]
+    fn test_histogram_overflow_bucket() {
+        let histogram = Histogram::new(&PAYLOAD_BYTES_BUCKETS);
+        histogram.observe(16 * 1024 * 1024);
+
+        let mut out = String::new();
+        histogram.render("h", "endpoint=\"files\"", &mut out);
+        assert!(out.contains(r#"h_bucket{endpoint="files",le="8388608"} 0"#));
+        assert!(out.contains(r#"h_bucket{endpoint="files",le="+Inf"} 1"#));
+    }
+
+    #[test]
+    fn test_decoded_len() {
+        assert_eq!(decoded_len("SGVsbG8gV29ybGQh"), 12); // "Hello World!"
+        assert_eq!(decoded_len("SGVsbG8="), 5); // "Hello"
+        assert_eq!(decoded_len(""), 0);
+    }
+
+    #[test]
+    fn test_stored_secrets_gauge_after_sweep() {
+        let metrics = Metrics::default();
+        assert_eq!(metrics.stored_secrets(), None);
+        assert!(!metrics.render().contains("snappwd_stored_secrets"));
+
+        metrics.set_stored_secrets(42);
+        assert_eq!(metrics.stored_secrets(), Some(42));
+        assert!(metrics.render().contains("snappwd_stored_secrets 42\n"));
+    }
+}
@@ -0,0 +1,1476 @@
+use crate::{
+    config::{ApiKeys, Config, ForceHttps, ResponseCase, TrailingSlash},
+    db,
+    extract::{self, ClientIp},
+    handlers, limits,
+    models::{ErrorResponse, ProblemDetails},
+    AppState,
+};
+use axum::{
+    body::{self, Body},
+    extract::{Request, State},
+    http::{header, HeaderMap, HeaderValue, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json, Router,
+};
+use std::time::{Duration, Instant};
+use tower_http::{
+    compression::{
+        predicate::{DefaultPredicate, Predicate, SizeAbove},
+        CompressionLayer,
+    },
+    normalize_path::NormalizePath,
+};
+use tracing::Instrument;
+
+/// Pads responses to at least `MIN_RESPONSE_MS` so hits and misses on the
+/// GET endpoints take roughly the same time, flattening timing-based id
+/// enumeration signals.
+pub async fn min_response_time(
+    State(state): State<AppState>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let start = Instant::now();
+    let response = next.run(req).await;
+
+    if let Some(min) = state.config.min_response_time {
+        let elapsed = start.elapsed();
+        if elapsed < min {
+            tokio::time::sleep(min - elapsed).await;
+        }
+    }
+
+    response
+}
+
+/// Cuts a request off with 408 once it has run for `limit`. Applied per
+/// route, since a file transfer legitimately takes far longer than a secret
+/// read.
+pub async fn timeout(State(limit): State<Duration>, req: Request, next: Next) -> Response {
+    match tokio::time::timeout(limit, next.run(req)).await {
+        Ok(response) => response,
+        Err(_) => (
+            StatusCode::REQUEST_TIMEOUT,
+            Json(ErrorResponse::with_code(
+                "REQUEST_TIMEOUT",
+                "Request took too long",
+            )),
+        )
+            .into_response(),
+    }
+}
+
+/// Sets `X-Content-Type-Options: nosniff` on file downloads. Payloads are
+/// ciphertext the server can't inspect, so browsers must never guess a type
+/// from their bytes.
+pub async fn nosniff(req: Request, next: Next) -> Response {
+    let mut response = next.run(req).await;
+    response.headers_mut().insert(
+        header::X_CONTENT_TYPE_OPTIONS,
+        HeaderValue::from_static("nosniff"),
+    );
+    response
+}
+
+/// How long clients are told to wait after hitting a concurrency cap, where
+/// there's no refill time to report.
+const CONCURRENCY_RETRY_AFTER: Duration = Duration::from_secs(1);
+
+/// A 429 with a jittered `Retry-After` of about `wait`. Every rate-limited
+/// path responds through this, so they all spread their retries.
+pub fn too_many_requests(
+    config: &Config,
+    code: &'static str,
+    message: &str,
+    wait: Option<Duration>,
+) -> Response {
+    let retry_after = limits::retry_after_seconds(
+        wait.unwrap_or(CONCURRENCY_RETRY_AFTER),
+        config.retry_after_jitter,
+    );
+    (
+        StatusCode::TOO_MANY_REQUESTS,
+        [(header::RETRY_AFTER, retry_after)],
+        Json(ErrorResponse::with_code(code, message)),
+    )
+        .into_response()
+}
+
+/// Limits simultaneous in-flight uploads per client IP. Applied before the
+/// body is buffered so parallel uploads can't pile up in memory.
+pub async fn upload_concurrency(
+    State(state): State<AppState>,
+    ClientIp(ip): ClientIp,
+    req: Request,
+    next: Next,
+) -> Response {
+    let _permit = match ip {
+        Some(ip) if state.upload_limiter.is_enabled() => {
+            match state.upload_limiter.try_acquire(ip) {
+                Some(permit) => Some(permit),
+                None => {
+                    return too_many_requests(
+                        &state.config,
+                        "TOO_MANY_UPLOADS",
+                        "Too many concurrent uploads",
+                        None,
+                    );
+                }
+            }
+        }
+        _ => None,
+    };
+
+    next.run(req).await
+}
+
+/// Paths that stay reachable when the global budget is spent, so probes and
+/// scrapes can still see an overloaded instance.
+const BUDGET_EXEMPT_PATHS: &[&str] = &["/readyz", "/metrics"];
+
+/// Sheds requests with 429 once the service-wide `GLOBAL_RPS` budget is
+/// spent, whichever clients the load comes from. Health and metrics routes
+/// bypass it.
+pub async fn global_budget(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    let budget = &state.request_budget;
+    if budget.is_enabled() && !BUDGET_EXEMPT_PATHS.contains(&req.uri().path()) {
+        if let Err(wait) = budget.try_acquire() {
+            return too_many_requests(
+                &state.config,
+                "OVERLOADED",
+                "Service is overloaded, retry later",
+                Some(wait),
+            );
+        }
+    }
+
+    next.run(req).await
+}
+
+/// Rejects requests carrying more than `MAX_HEADER_COUNT` headers, or more
+/// than `MAX_HEADER_BYTES` of header names and values, with 431 before any
+/// extractor or handler looks at them.
+pub async fn limit_headers(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    let headers = req.headers();
+    let max_count = state.config.max_header_count;
+    let max_bytes = state.config.max_header_bytes;
+    let too_many = max_count > 0 && headers.len() > max_count;
+    let too_large = max_bytes > 0
+        && headers
+            .iter()
+            .map(|(name, value)| name.as_str().len() + value.len())
+            .sum::<usize>()
+            > max_bytes;
+    if too_many || too_large {
+        return (
+            StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+            Json(ErrorResponse::with_code(
+                "HEADERS_TOO_LARGE",
+                "Request headers too large",
+            )),
+        )
+            .into_response();
+    }
+
+    next.run(req).await
+}
+
+/// With `FORCE_HTTPS`, keeps plain-HTTP requests from being served: safe
+/// methods are redirected to `PUBLIC_BASE_URL` in `redirect` mode,
+/// everything else is rejected. `/readyz` is exempt so load balancer probes
+/// keep working.
+pub async fn force_https(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    let mode = state.config.force_https;
+    if mode == ForceHttps::Off
+        || req.uri().path() == "/readyz"
+        || extract::is_https(req.headers(), req.uri(), state.config.trust_proxy_headers)
+    {
+        return next.run(req).await;
+    }
+
+    let safe = req.method() == Method::GET || req.method() == Method::HEAD;
+    if mode == ForceHttps::Redirect && safe {
+        let path = req
+            .uri()
+            .path_and_query()
+            .map_or("/", |pq| pq.as_str())
+            .to_string();
+        // Never the Host header, which would make this an open redirect;
+        // startup refuses `redirect` without `PUBLIC_BASE_URL`
+        let location = state
+            .config
+            .public_base_url
+            .as_deref()
+            .and_then(|base| HeaderValue::try_from(format!("{}{}", base, path)).ok());
+        if let Some(location) = location {
+            return (
+                StatusCode::MOVED_PERMANENTLY,
+                [(header::LOCATION, location)],
+            )
+                .into_response();
+        }
+    }
+
+    (
+        StatusCode::BAD_REQUEST,
+        Json(ErrorResponse::with_code(
+            "HTTPS_REQUIRED",
+            "Requests must be made over https",
+        )),
+    )
+        .into_response()
+}
+
+/// Server-side Origin check for create endpoints when `ENFORCE_ORIGIN` is
+/// set. Unlike CORS this rejects the request outright, including from
+/// non-browser clients that send no Origin or Referer at all.
+pub async fn enforce_origin(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    if state.config.enforce_origin {
+        let allowed = request_origin(req.headers())
+            .is_some_and(|origin| state.config.allowed_origins.contains(&origin));
+        if !allowed {
+            return (
+                StatusCode::FORBIDDEN,
+                Json(ErrorResponse::with_code(
+                    "ORIGIN_NOT_ALLOWED",
+                    "Request origin not allowed",
+                )),
+            )
+                .into_response();
+        }
+    }
+
+    next.run(req).await
+}
+
+/// Rejects creates without a non-empty `User-Agent` when
+/// `REQUIRE_USER_AGENT` is set: a mild deterrent, since many abusive
+/// scripts don't send one.
+pub async fn require_user_agent(
+    State(state): State<AppState>,
+    req: Request,
+    next: Next,
+) -> Response {
+    if state.config.require_user_agent {
+        let has_user_agent = req
+            .headers()
+            .get(header::USER_AGENT)
+            .is_some_and(|v| v.as_bytes().iter().any(|b| !b.is_ascii_whitespace()));
+        if !has_user_agent {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(ErrorResponse::with_code(
+                    "USER_AGENT_REQUIRED",
+                    "User-Agent header required",
+                )),
+            )
+                .into_response();
+        }
+    }
+
+    next.run(req).await
+}
+
+/// Rejects creates whose `Content-Type` isn't the canonical
+/// `application/json` (charset, if any, UTF-8) when `STRICT_CONTENT_TYPE` is
+/// set, or the form type when `ACCEPT_FORM_BODIES` negotiates it. Without it
+/// the body extractors also take `application/*+json` and any charset.
+pub async fn strict_content_type(
+    State(state): State<AppState>,
+    req: Request,
+    next: Next,
+) -> Response {
+    if state.config.strict_content_type
+        && !is_canonical_content_type(req.headers(), state.config.accept_form_bodies)
+    {
+        return (
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            Json(ErrorResponse::with_code(
+                "UNSUPPORTED_MEDIA_TYPE",
+                "Expected request with `Content-Type: application/json`",
+            )),
+        )
+            .into_response();
+    }
+
+    next.run(req).await
+}
+
+fn is_canonical_content_type(headers: &HeaderMap, accept_form: bool) -> bool {
+    let Some(content_type) = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+    else {
+        return false;
+    };
+    let mut parts = content_type.split(';').map(str::trim);
+    let mime = parts.next().unwrap_or_default();
+    if accept_form && mime.eq_ignore_ascii_case("application/x-www-form-urlencoded") {
+        return true;
+    }
+    mime.eq_ignore_ascii_case("application/json")
+        && parts.all(|param| {
+            param.split_once('=').is_some_and(|(name, value)| {
+                name.trim().eq_ignore_ascii_case("charset")
+                    && value.trim().trim_matches('"').eq_ignore_ascii_case("utf-8")
+            })
+        })
+}
+
+/// The request's origin from `Origin`, falling back to the scheme and
+/// authority of `Referer`.
+fn request_origin(headers: &HeaderMap) -> Option<String> {
+    if let Some(origin) = headers.get(header::ORIGIN).and_then(|v| v.to_str().ok()) {
+        return Some(origin.trim_end_matches('/').to_string());
+    }
+
+    let referer = headers.get(header::REFERER)?.to_str().ok()?;
+    let (scheme, rest) = referer.split_once("://")?;
+    let authority = rest.split(['/', '?', '#']).next()?;
+    Some(format!("{}://{}", scheme, authority))
+}
+
+/// With `ENVELOPE_RESPONSES`, wraps JSON bodies as `{"data": ..., "error": null}`
+/// on success and `{"data": null, "error": ...}` otherwise, for clients that
+/// need a consistent top-level object. Status codes and non-JSON bodies
+/// (OpenAPI spec, metrics, QR codes) pass through unchanged.
+pub async fn envelope(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    let response = next.run(req).await;
+    if !state.config.envelope_responses {
+        return response;
+    }
+
+    let is_json = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("application/json"));
+    if !is_json {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let payload: serde_json::Value = match body::to_bytes(body, usize::MAX).await {
+        Ok(bytes) => serde_json::from_slice(&bytes).unwrap_or(serde_json::Value::Null),
+        Err(e) => {
+            tracing::error!("Failed to buffer response for envelope: {}", e);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+
+    let wrapped = if parts.status.is_success() {
+        serde_json::json!({ "data": payload, "error": null })
+    } else {
+        serde_json::json!({ "data": null, "error": payload })
+    };
+
+    parts.headers.remove(header::CONTENT_LENGTH);
+    Response::from_parts(parts, Body::from(wrapped.to_string()))
+}
+
+/// With `RESPONSE_CASE=snake`, renames the keys of JSON response bodies
+/// from camelCase to snake_case. Client-supplied `metadata` is left as
+/// stored, since its keys aren't ours to rename. Bodies are buffered up to
+/// the largest request body accepted, as no response echoes back more.
+pub async fn response_case(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    let response = next.run(req).await;
+    if state.config.response_case == ResponseCase::Camel {
+        return response;
+    }
+
+    let is_json = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("application/json"));
+    if !is_json {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let limit = state
+        .config
+        .json_body_limit()
+        .max(state.config.upload_body_limit());
+    let payload: serde_json::Value = match body::to_bytes(body, limit).await {
+        Ok(bytes) => serde_json::from_slice(&bytes).unwrap_or(serde_json::Value::Null),
+        Err(e) => {
+            tracing::error!("Failed to buffer response for casing: {}", e);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+
+    parts.headers.remove(header::CONTENT_LENGTH);
+    Response::from_parts(parts, Body::from(snake_case_keys(payload).to_string()))
+}
+
+fn snake_case_keys(value: serde_json::Value) -> serde_json::Value {
+    match value {
+        serde_json::Value::Object(map) => map
+            .into_iter()
+            .map(|(key, value)| {
+                let value = if key == "metadata" {
+                    value
+                } else {
+                    snake_case_keys(value)
+                };
+                (to_snake_case(&key), value)
+            })
+            .collect(),
+        serde_json::Value::Array(items) => items.into_iter().map(snake_case_keys).collect(),
+        other => other,
+    }
+}
+
+fn to_snake_case(key: &str) -> String {
+    let mut out = String::with_capacity(key.len() + 4);
+    for c in key.chars() {
+        if c.is_ascii_uppercase() {
+            if !out.is_empty() {
+                out.push('_');
+            }
+            out.push(c.to_ascii_lowercase());
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+/// Rewrites JSON error bodies as RFC 7807 `application/problem+json` for
+/// requests whose `Accept` asks for it. Everyone else keeps `ErrorResponse`.
+pub async fn problem_json(req: Request, next: Next) -> Response {
+    let wants_problem = req
+        .headers()
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .any(|v| v.contains("application/problem+json"));
+    let instance = req.uri().path().to_string();
+    let response = next.run(req).await;
+    if !wants_problem || response.status().is_success() {
+        return response;
+    }
+
+    let is_json = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("application/json"));
+    if !is_json {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let error: serde_json::Value = match body::to_bytes(body, usize::MAX).await {
+        Ok(bytes) => serde_json::from_slice(&bytes).unwrap_or(serde_json::Value::Null),
+        Err(e) => {
+            tracing::error!("Failed to buffer response for problem+json: {}", e);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+
+    let title = parts.status.canonical_reason().unwrap_or("Error");
+    let problem = ProblemDetails::new(
+        parts.status.as_u16(),
+        title,
+        error["error"].as_str().unwrap_or(title).to_string(),
+        error["code"].as_str().map(str::to_string),
+        instance,
+    );
+    parts.headers.remove(header::CONTENT_LENGTH);
+    parts.headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/problem+json"),
+    );
+    Response::from_parts(
+        parts,
+        Body::from(serde_json::to_string(&problem).expect("problem details serialize")),
+    )
+}
+
+/// Label of the `API_KEYS` entry a request authenticated with, in the
+/// request's extensions for handlers that want to attribute their work.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ApiKeyLabel(pub String);
+
+/// With `API_KEYS`, requires one of the configured keys in `X-Api-Key`. The
+/// matched key's label goes in the request extensions, on an `api_key` span
+/// around the handler and in the audit log; the key itself is never logged.
+pub async fn require_api_key(
+    State(state): State<AppState>,
+    ClientIp(ip): ClientIp,
+    mut req: Request,
+    next: Next,
+) -> Response {
+    if state.config.api_keys.is_empty() {
+        return next.run(req).await;
+    }
+
+    let method = req.method().clone();
+    let path = req.uri().path().to_string();
+    let given = req
+        .headers()
+        .get("x-api-key")
+        .and_then(|v| v.to_str().ok())
+        .map(str::trim);
+    let Some(label) = given.and_then(|given| match_api_key(&state.config.api_keys, given)) else {
+        tracing::warn!(target: "audit", "api {} {} from {:?}: no valid API key", method, path, ip);
+        return (
+            StatusCode::UNAUTHORIZED,
+            Json(ErrorResponse::with_code(
+                "INVALID_API_KEY",
+                "A valid X-Api-Key is required",
+            )),
+        )
+            .into_response();
+    };
+
+    let label = label.to_string();
+    req.extensions_mut().insert(ApiKeyLabel(label.clone()));
+    let span = tracing::info_span!("api_key", label = %label);
+    let response = next.run(req).instrument(span).await;
+    if state.config.usage_by_api_key {
+        if let Some(bytes) = storage_bytes(&response) {
+            record_usage(&state, &label, bytes).await;
+        }
+    }
+    tracing::info!(
+        target: "audit",
+        "api {} {} by {} from {:?}: {}",
+        method,
+        path,
+        label,
+        ip,
+        response.status()
+    );
+    response
+}
+
+/// The `X-Storage-Bytes` a successful create reported.
+fn storage_bytes(response: &Response) -> Option<u64> {
+    if !response.status().is_success() {
+        return None;
+    }
+    response
+        .headers()
+        .get(handlers::STORAGE_BYTES_HEADER)?
+        .to_str()
+        .ok()?
+        .parse()
+        .ok()
+}
+
+/// Add a create's stored bytes to its key's total. A failed write is logged
+/// rather than failing a create that already succeeded.
+async fn record_usage(state: &AppState, label: &str, bytes: u64) {
+    if let Err(e) = db::record_usage(&state.redis, &state.keys, label, bytes).await {
+        tracing::warn!("Failed to record usage for {}: {}", label, e);
+    }
+}
+
+/// The label of the configured key equal to `given`. Every key is compared,
+/// in constant time, so timing reveals neither which key matched nor how
+/// much of a guess was right.
+fn match_api_key<'a>(keys: &'a ApiKeys, given: &str) -> Option<&'a str> {
+    keys.iter().fold(None, |matched, (label, key)| {
+        let equal = constant_time_eq(key.as_bytes(), given.as_bytes());
+        matched.or(equal.then_some(label))
+    })
+}
+
+/// Guards `/v1/admin/*` with `ADMIN_API_KEY` (sent as a Bearer token).
+/// Fails closed: with no key configured every admin call is rejected. Every
+/// call is audit-logged with its caller and outcome, never its payload.
+pub async fn require_admin_key(
+    State(state): State<AppState>,
+    ClientIp(ip): ClientIp,
+    req: Request,
+    next: Next,
+) -> Response {
+    let method = req.method().clone();
+    let path = req.uri().path().to_string();
+
+    let authorized = match (&state.config.admin_api_key, bearer_token(req.headers())) {
+        (Some(expected), Some(given)) => constant_time_eq(expected.as_bytes(), given.as_bytes()),
+        _ => false,
+    };
+    if !authorized {
+        tracing::warn!(target: "audit", "admin {} {} from {:?}: unauthorized", method, path, ip);
+        return (
+            StatusCode::UNAUTHORIZED,
+            Json(ErrorResponse::with_code(
+                "UNAUTHORIZED",
+                "Admin API key required",
+            )),
+        )
+            .into_response();
+    }
+
+    let response = next.run(req).await;
+    tracing::info!(
+        target: "audit",
+        "admin {} {} from {:?}: {}",
+        method,
+        path,
+        ip,
+        response.status()
+    );
+    response
+}
+
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+}
+
+/// Compare without short-circuiting so response timing doesn't reveal how
+/// much of a guessed key was right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Apply `TRAILING_SLASH` to the finished router. Paths must be normalized
+/// before routing, which a `Router::layer` runs after, so under `merge` the
+/// router is wrapped instead.
+pub fn trailing_slash(app: Router, mode: TrailingSlash) -> Router {
+    match mode {
+        TrailingSlash::Merge => {
+            Router::new().fallback_service(NormalizePath::trim_trailing_slash(app))
+        }
+        TrailingSlash::Strict => app,
+    }
+}
+
+/// Gzip responses for clients that accept it (`COMPRESS_RESPONSES`), except
+/// bodies under `min_bytes` (`COMPRESSION_MIN_BYTES`): small JSON gains
+/// nothing and the gzip framing can even make it bigger.
+pub fn compression(app: Router, min_bytes: Option<u16>) -> Router {
+    match min_bytes {
+        Some(min_bytes) => app.layer(
+            CompressionLayer::new()
+                .compress_when(DefaultPredicate::new().and(SizeAbove::new(min_bytes))),
+        ),
+        None => app,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::Config, handlers};
+    use axum::{
+        body::Body,
+        extract::ConnectInfo,
+        http::{Request, StatusCode},
+        middleware::{from_fn, from_fn_with_state},
+        routing::{get, post},
+        Router,
+    };
+    use redis::Client;
+    use std::net::SocketAddr;
+    use std::time::Duration;
+    use tower::ServiceExt; // for `oneshot`
+
+    fn dummy_state(config: Config) -> AppState {
+        AppState::new(Client::open("redis://127.0.0.1/").unwrap(), config)
+    }
+
+    #[tokio::test]
+    async fn test_min_response_time_pads_fast_404() {
+        let state = dummy_state(Config {
+            min_response_time: Some(Duration::from_millis(100)),
+            ..Config::default()
+        });
+        let app = Router::new()
+            .route(
+                "/v1/secrets/:id",
+                get(handlers::get_secret)
+                    .layer(from_fn_with_state(state.clone(), min_response_time)),
+            )
+            .with_state(state);
+
+        // Invalid prefix returns 404 without touching Redis
+        let req = Request::builder()
+            .method("GET")
+            .uri("/v1/secrets/invalid-id")
+            .body(Body::empty())
+            .unwrap();
+
+        let start = Instant::now();
+        let response = app.oneshot(req).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert!(start.elapsed() >= Duration::from_millis(100));
+    }
+
+    async fn status_with_headers(config: Config, headers: &[(String, String)]) -> StatusCode {
+        async fn ok() -> StatusCode {
+            StatusCode::OK
+        }
+
+        let state = dummy_state(config);
+        let app = Router::new()
+            .route("/", get(ok))
+            .layer(from_fn_with_state(state.clone(), limit_headers))
+            .with_state(state);
+
+        let mut req = Request::builder().uri("/");
+        for (name, value) in headers {
+            req = req.header(name, value);
+        }
+        let response = app.oneshot(req.body(Body::empty()).unwrap()).await.unwrap();
+        response.status()
+    }
+
+    fn numbered_headers(count: usize) -> Vec<(String, String)> {
+        (0..count)
+            .map(|i| (format!("x-header-{}", i), "v".to_string()))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_header_count_limit() {
+        let config = || Config {
+            max_header_count: 8,
+            ..Config::default()
+        };
+        assert_eq!(
+            status_with_headers(config(), &numbered_headers(8)).await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            status_with_headers(config(), &numbered_headers(9)).await,
+            StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE
+        );
+        // Off unless configured
+        assert_eq!(
+            status_with_headers(Config::default(), &numbered_headers(200)).await,
+            StatusCode::OK
+        );
+    }
+
+    #[tokio::test]
+    async fn test_header_bytes_limit() {
+        let config = || Config {
+            max_header_bytes: 1024,
+            ..Config::default()
+        };
+        let header = |len| vec![("x-large".to_string(), "a".repeat(len))];
+        assert_eq!(
+            status_with_headers(config(), &header(512)).await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            status_with_headers(config(), &header(2048)).await,
+            StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE
+        );
+    }
+
+    async fn create_with_user_agent(require: bool, user_agent: Option<&str>) -> StatusCode {
+        async fn created() -> StatusCode {
+            StatusCode::OK
+        }
+
+        let state = dummy_state(Config {
+            require_user_agent: require,
+            ..Config::default()
+        });
+        let app = Router::new()
+            .route(
+                "/v1/secrets",
+                post(created).layer(from_fn_with_state(state.clone(), require_user_agent)),
+            )
+            .with_state(state);
+
+        let mut req = Request::builder().method("POST").uri("/v1/secrets");
+        if let Some(user_agent) = user_agent {
+            req = req.header(header::USER_AGENT, user_agent);
+        }
+        let response = app.oneshot(req.body(Body::empty()).unwrap()).await.unwrap();
+        response.status()
+    }
+
+    #[tokio::test]
+    async fn test_user_agent_required_when_enabled() {
+        assert_eq!(
+            create_with_user_agent(true, Some("curl/8.5.0")).await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            create_with_user_agent(true, None).await,
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            create_with_user_agent(true, Some(" ")).await,
+            StatusCode::BAD_REQUEST
+        );
+        // Off by default, so headless clients without a UA keep working
+        assert_eq!(create_with_user_agent(false, None).await, StatusCode::OK);
+    }
+
+    async fn create_with_content_type(config: Config, content_type: Option<&str>) -> StatusCode {
+        let state = dummy_state(config);
+        let app = Router::new()
+            .route(
+                "/v1/secrets",
+                post(handlers::create_secret)
+                    .layer(from_fn_with_state(state.clone(), strict_content_type)),
+            )
+            .with_state(state);
+
+        let mut req = Request::builder().method("POST").uri("/v1/secrets");
+        if let Some(content_type) = content_type {
+            req = req.header("content-type", content_type);
+        }
+        // Invalid expiration, so a request that gets past the check fails
+        // with 400 before touching Redis
+        let body = Body::from(r#"{"encryptedSecret":"abc","expiration":1}"#);
+        app.oneshot(req.body(body).unwrap()).await.unwrap().status()
+    }
+
+    #[tokio::test]
+    async fn test_strict_content_type_rejects_missing_or_wrong_type() {
+        let strict = || Config {
+            strict_content_type: true,
+            ..Config::default()
+        };
+        for content_type in [None, Some("text/plain"), Some("application/vnd.api+json")] {
+            assert_eq!(
+                create_with_content_type(strict(), content_type).await,
+                StatusCode::UNSUPPORTED_MEDIA_TYPE
+            );
+        }
+        assert_eq!(
+            create_with_content_type(strict(), Some("application/json; charset=latin1")).await,
+            StatusCode::UNSUPPORTED_MEDIA_TYPE
+        );
+        for content_type in ["application/json", "Application/JSON; charset=\"UTF-8\""] {
+            assert_eq!(
+                create_with_content_type(strict(), Some(content_type)).await,
+                StatusCode::BAD_REQUEST
+            );
+        }
+
+        // Form bodies pass only when they're negotiated
+        let form = Some("application/x-www-form-urlencoded");
+        assert_eq!(
+            create_with_content_type(strict(), form).await,
+            StatusCode::UNSUPPORTED_MEDIA_TYPE
+        );
+        let with_forms = Config {
+            accept_form_bodies: true,
+            ..strict()
+        };
+        assert_eq!(
+            create_with_content_type(with_forms, form).await,
+            StatusCode::BAD_REQUEST
+        );
+
+        // Off by default: `+json` types are still parsed as JSON
+        assert_eq!(
+            create_with_content_type(Config::default(), Some("application/vnd.api+json")).await,
+            StatusCode::BAD_REQUEST
+        );
+    }
+
+    async fn create_with_headers(headers: &[(&str, &str)]) -> StatusCode {
+        let state = dummy_state(Config {
+            enforce_origin: true,
+            allowed_origins: vec!["https://snappwd.io".to_string()],
+            ..Config::default()
+        });
+
+        async fn created() -> StatusCode {
+            StatusCode::OK
+        }
+
+        let app = Router::new()
+            .route(
+                "/v1/secrets",
+                post(created).layer(from_fn_with_state(state.clone(), enforce_origin)),
+            )
+            .with_state(state);
+
+        let mut req = Request::builder().method("POST").uri("/v1/secrets");
+        for (name, value) in headers {
+            req = req.header(*name, *value);
+        }
+        app.oneshot(req.body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+            .status()
+    }
+
+    #[tokio::test]
+    async fn test_enforce_origin_allows_listed_origin() {
+        assert_eq!(
+            create_with_headers(&[("origin", "https://snappwd.io")]).await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            create_with_headers(&[("referer", "https://snappwd.io/share?x=1")]).await,
+            StatusCode::OK
+        );
+    }
+
+    #[tokio::test]
+    async fn test_enforce_origin_rejects_other_origin() {
+        assert_eq!(
+            create_with_headers(&[("origin", "https://evil.example")]).await,
+            StatusCode::FORBIDDEN
+        );
+    }
+
+    #[tokio::test]
+    async fn test_enforce_origin_rejects_missing_header() {
+        assert_eq!(create_with_headers(&[]).await, StatusCode::FORBIDDEN);
+    }
+
+    async fn enveloped_body(envelope_responses: bool, uri: &str) -> serde_json::Value {
+        use http_body_util::BodyExt;
+
+        let state = dummy_state(Config {
+            envelope_responses,
+            ..Config::default()
+        });
+
+        async fn created() -> Json<serde_json::Value> {
+            Json(serde_json::json!({ "secretId": "sps-abc" }))
+        }
+
+        async fn rejected() -> (StatusCode, Json<ErrorResponse>) {
+            (
+                StatusCode::BAD_REQUEST,
+                Json(ErrorResponse::new("Invalid expiration time")),
+            )
+        }
+
+        let app = Router::new()
+            .route("/v1/secrets", post(created))
+            .route("/v1/files", post(rejected))
+            .layer(from_fn_with_state(state.clone(), envelope))
+            .with_state(state);
+
+        let req = Request::builder()
+            .method("POST")
+            .uri(uri)
+            .body(Body::empty())
+            .unwrap();
+        let response = app.oneshot(req).await.unwrap();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_envelope_wraps_success_and_error() {
+        assert_eq!(
+            enveloped_body(true, "/v1/secrets").await,
+            serde_json::json!({ "data": { "secretId": "sps-abc" }, "error": null })
+        );
+        assert_eq!(
+            enveloped_body(true, "/v1/files").await,
+            serde_json::json!({ "data": null, "error": { "error": "Invalid expiration time" } })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_envelope_disabled_returns_bare_bodies() {
+        assert_eq!(
+            enveloped_body(false, "/v1/secrets").await,
+            serde_json::json!({ "secretId": "sps-abc" })
+        );
+        assert_eq!(
+            enveloped_body(false, "/v1/files").await,
+            serde_json::json!({ "error": "Invalid expiration time" })
+        );
+    }
+
+    async fn cased_body(case: ResponseCase) -> serde_json::Value {
+        use http_body_util::BodyExt;
+
+        let state = dummy_state(Config {
+            response_case: case,
+            ..Config::default()
+        });
+
+        async fn peeked() -> Json<serde_json::Value> {
+            Json(serde_json::json!({
+                "encryptedSecret": "abc",
+                "expiresAt": 1700000000,
+                "metadata": { "fileName": "a.txt" },
+                "tags": [{ "tagName": "x" }],
+            }))
+        }
+
+        let app = Router::new()
+            .route("/v1/secrets/:id/peek", get(peeked))
+            .layer(from_fn_with_state(state.clone(), response_case))
+            .with_state(state);
+
+        let req = Request::builder()
+            .uri("/v1/secrets/sps-abc/peek")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.oneshot(req).await.unwrap();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_response_case_follows_config() {
+        assert_eq!(
+            cased_body(ResponseCase::Camel).await,
+            serde_json::json!({
+                "encryptedSecret": "abc",
+                "expiresAt": 1700000000,
+                "metadata": { "fileName": "a.txt" },
+                "tags": [{ "tagName": "x" }],
+            })
+        );
+        assert_eq!(
+            cased_body(ResponseCase::Snake).await,
+            serde_json::json!({
+                "encrypted_secret": "abc",
+                "expires_at": 1700000000,
+                "metadata": { "fileName": "a.txt" },
+                "tags": [{ "tag_name": "x" }],
+            })
+        );
+    }
+
+    #[test]
+    fn test_to_snake_case() {
+        assert_eq!(to_snake_case("secretId"), "secret_id");
+        assert_eq!(to_snake_case("payloadSha256"), "payload_sha256");
+        assert_eq!(to_snake_case("error"), "error");
+        assert_eq!(to_snake_case("already_snake"), "already_snake");
+    }
+
+    async fn https_response(
+        mode: ForceHttps,
+        method: &str,
+        forwarded_proto: Option<&str>,
+    ) -> Response {
+        let state = dummy_state(Config {
+            force_https: mode,
+            trust_proxy_headers: true,
+            public_base_url: Some("https://snappwd.example".to_string()),
+            ..Config::default()
+        });
+        let app = Router::new()
+            .route(
+                "/v1/secrets/:id",
+                get(|| async { "ok" }).post(|| async { "ok" }),
+            )
+            .layer(from_fn_with_state(state.clone(), force_https))
+            .with_state(state);
+
+        let mut req = Request::builder()
+            .method(method)
+            .uri("/v1/secrets/sps-abc?peek=true")
+            .header(header::HOST, "api.snappwd.example");
+        if let Some(proto) = forwarded_proto {
+            req = req.header("x-forwarded-proto", proto);
+        }
+        app.oneshot(req.body(Body::empty()).unwrap()).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_force_https_redirects_or_rejects_plain_http() {
+        let redirected = https_response(ForceHttps::Redirect, "GET", Some("http")).await;
+        assert_eq!(redirected.status(), StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(
+            redirected.headers()[header::LOCATION],
+            "https://snappwd.example/v1/secrets/sps-abc?peek=true"
+        );
+
+        // A body sent in the clear can't be made safe by redirecting it
+        let posted = https_response(ForceHttps::Redirect, "POST", Some("http")).await;
+        assert_eq!(posted.status(), StatusCode::BAD_REQUEST);
+
+        let rejected = https_response(ForceHttps::Reject, "GET", None).await;
+        assert_eq!(rejected.status(), StatusCode::BAD_REQUEST);
+
+        // Off serves plain http as before
+        let served = https_response(ForceHttps::Off, "GET", Some("http")).await;
+        assert_eq!(served.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_force_https_passes_https() {
+        for mode in [ForceHttps::Redirect, ForceHttps::Reject] {
+            let response = https_response(mode, "POST", Some("https")).await;
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+    }
+
+    /// Status and the label the handler saw, for a create sent with `api_key`
+    async fn api_key_response(api_keys: &str, api_key: Option<&str>) -> (StatusCode, String) {
+        use axum::Extension;
+        use http_body_util::BodyExt;
+
+        let state = dummy_state(Config {
+            api_keys: api_keys.parse().unwrap(),
+            ..Config::default()
+        });
+
+        async fn create(label: Option<Extension<ApiKeyLabel>>) -> String {
+            label
+                .map(|Extension(ApiKeyLabel(label))| label)
+                .unwrap_or_default()
+        }
+
+        let app = Router::new()
+            .route(
+                "/v1/secrets",
+                post(create).layer(from_fn_with_state(state.clone(), require_api_key)),
+            )
+            .with_state(state);
+
+        let mut req = Request::builder().method("POST").uri("/v1/secrets");
+        if let Some(api_key) = api_key {
+            req = req.header("x-api-key", api_key);
+        }
+        let response = app.oneshot(req.body(Body::empty()).unwrap()).await.unwrap();
+        let status = response.status();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_any_configured_api_key_authenticates_with_its_label() {
+        // `web` is mid-rotation, so both of its keys work
+        let keys = "web:old-key, web:new-key, ci:ci-key";
+        for (api_key, label) in [("old-key", "web"), ("new-key", "web"), ("ci-key", "ci")] {
+            assert_eq!(
+                api_key_response(keys, Some(api_key)).await,
+                (StatusCode::OK, label.to_string())
+            );
+        }
+
+        assert_eq!(
+            api_key_response(keys, Some("ci-key2")).await.0,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            api_key_response(keys, None).await.0,
+            StatusCode::UNAUTHORIZED
+        );
+        // Without API_KEYS creates stay open
+        assert_eq!(
+            api_key_response("", None).await,
+            (StatusCode::OK, String::new())
+        );
+    }
+
+    #[test]
+    fn test_api_keys_parse() {
+        let keys: ApiKeys = "web:a:b,ci:c".parse().unwrap();
+        assert_eq!(match_api_key(&keys, "a:b"), Some("web"));
+        assert_eq!(match_api_key(&keys, "c"), Some("ci"));
+        assert_eq!(match_api_key(&keys, "a"), None);
+        // The labels are all the config's Debug output shows
+        assert_eq!(format!("{:?}", keys), r#"["web", "ci"]"#);
+
+        assert!("web:a,nolabel".parse::<ApiKeys>().is_err());
+        assert!(":key".parse::<ApiKeys>().is_err());
+        assert!("web:".parse::<ApiKeys>().is_err());
+    }
+
+    async fn admin_status(admin_api_key: Option<&str>, authorization: Option<&str>) -> StatusCode {
+        let state = dummy_state(Config {
+            admin_api_key: admin_api_key.map(str::to_string),
+            ..Config::default()
+        });
+
+        async fn admin() -> StatusCode {
+            StatusCode::OK
+        }
+
+        let app = Router::new()
+            .route(
+                "/v1/admin/expiry-histogram",
+                get(admin).layer(from_fn_with_state(state.clone(), require_admin_key)),
+            )
+            .with_state(state);
+
+        let mut req = Request::builder().uri("/v1/admin/expiry-histogram");
+        if let Some(authorization) = authorization {
+            req = req.header("authorization", authorization);
+        }
+        app.oneshot(req.body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+            .status()
+    }
+
+    #[tokio::test]
+    async fn test_admin_key_accepted() {
+        assert_eq!(
+            admin_status(Some("s3cret"), Some("Bearer s3cret")).await,
+            StatusCode::OK
+        );
+    }
+
+    #[tokio::test]
+    async fn test_admin_routes_fail_closed_without_key() {
+        assert_eq!(admin_status(None, None).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            admin_status(None, Some("Bearer ")).await,
+            StatusCode::UNAUTHORIZED
+        );
+    }
+
+    #[tokio::test]
+    async fn test_admin_key_rejects_wrong_or_missing_token() {
+        assert_eq!(
+            admin_status(Some("s3cret"), Some("Bearer s3cre")).await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            admin_status(Some("s3cret"), Some("s3cret")).await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            admin_status(Some("s3cret"), None).await,
+            StatusCode::UNAUTHORIZED
+        );
+    }
+
+    async fn status_with_trailing_slash(mode: TrailingSlash, uri: &str) -> StatusCode {
+        async fn created() -> StatusCode {
+            StatusCode::OK
+        }
+
+        let app = trailing_slash(Router::new().route("/v1/secrets", post(created)), mode);
+        let req = Request::builder()
+            .method("POST")
+            .uri(uri)
+            .body(Body::empty())
+            .unwrap();
+        app.oneshot(req).await.unwrap().status()
+    }
+
+    #[tokio::test]
+    async fn test_trailing_slash_merged() {
+        assert_eq!(
+            status_with_trailing_slash(TrailingSlash::Merge, "/v1/secrets").await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            status_with_trailing_slash(TrailingSlash::Merge, "/v1/secrets/").await,
+            StatusCode::OK
+        );
+    }
+
+    #[tokio::test]
+    async fn test_trailing_slash_strict() {
+        assert_eq!(
+            status_with_trailing_slash(TrailingSlash::Strict, "/v1/secrets").await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            status_with_trailing_slash(TrailingSlash::Strict, "/v1/secrets/").await,
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[tokio::test]
+    async fn test_upload_concurrency_rejects_excess_from_same_ip() {
+        let state = dummy_state(Config {
+            max_concurrent_uploads_per_ip: 2,
+            ..Config::default()
+        });
+
+        // Stand-in for a slow upload so all requests overlap
+        async fn slow_upload() -> StatusCode {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            StatusCode::OK
+        }
+
+        let app = Router::new()
+            .route(
+                "/v1/files",
+                post(slow_upload).layer(from_fn_with_state(state.clone(), upload_concurrency)),
+            )
+            .with_state(state);
+
+        let peer: SocketAddr = "203.0.113.7:4000".parse().unwrap();
+        let send = || {
+            let mut req = Request::builder()
+                .method("POST")
+                .uri("/v1/files")
+                .body(Body::empty())
+                .unwrap();
+            req.extensions_mut().insert(ConnectInfo(peer));
+            app.clone().oneshot(req)
+        };
+
+        let (a, b, c) = tokio::join!(send(), send(), send());
+        let mut statuses = [
+            a.unwrap().status(),
+            b.unwrap().status(),
+            c.unwrap().status(),
+        ];
+        statuses.sort();
+
+        assert_eq!(
+            statuses,
+            [
+                StatusCode::OK,
+                StatusCode::OK,
+                StatusCode::TOO_MANY_REQUESTS
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_global_budget_sheds_flood_but_not_readyz() {
+        let state = dummy_state(Config {
+            global_rps: 1,
+            global_burst: 5,
+            ..Config::default()
+        });
+
+        async fn ok() -> StatusCode {
+            StatusCode::OK
+        }
+
+        let app = Router::new()
+            .route("/v1/secrets", post(ok))
+            .route("/readyz", get(ok))
+            .route("/status", get(ok))
+            .layer(from_fn_with_state(state.clone(), global_budget))
+            .with_state(state);
+
+        let send = |method: &str, uri: &str| {
+            let req = Request::builder()
+                .method(method)
+                .uri(uri)
+                .body(Body::empty())
+                .unwrap();
+            app.clone().oneshot(req)
+        };
+
+        let mut shed = Vec::new();
+        for _ in 0..20 {
+            let response = send("POST", "/v1/secrets").await.unwrap();
+            if response.status() == StatusCode::TOO_MANY_REQUESTS {
+                shed.push(response);
+            }
+        }
+        assert_eq!(shed.len(), 15);
+        // About a second until the next token, spread by RETRY_AFTER_JITTER
+        for response in &shed {
+            let retry_after: u64 = response.headers()[header::RETRY_AFTER]
+                .to_str()
+                .unwrap()
+                .parse()
+                .unwrap();
+            assert!((1..=2).contains(&retry_after), "{}", retry_after);
+        }
+
+        for _ in 0..5 {
+            let response = send("GET", "/readyz").await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+        // The public status page is shed like any other route
+        let response = send("GET", "/status").await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[tokio::test]
+    async fn test_compression_skips_small_bodies() {
+        async fn small() -> String {
+            "x".repeat(100)
+        }
+        async fn large() -> String {
+            "x".repeat(4096)
+        }
+
+        let app = compression(
+            Router::new()
+                .route("/small", get(small))
+                .route("/large", get(large)),
+            Some(1024),
+        );
+
+        let send = |uri: &str| {
+            let req = Request::builder()
+                .uri(uri)
+                .header(header::ACCEPT_ENCODING, "gzip")
+                .body(Body::empty())
+                .unwrap();
+            app.clone().oneshot(req)
+        };
+
+        let response = send("/small").await.unwrap();
+        assert!(response.headers().get(header::CONTENT_ENCODING).is_none());
+        let response = send("/large").await.unwrap();
+        assert_eq!(response.headers()[header::CONTENT_ENCODING], "gzip");
+    }
+
+    #[tokio::test]
+    async fn test_problem_json_on_request() {
+        let state = dummy_state(Config::default());
+        let app = Router::new()
+            .route("/v1/secrets/:id", get(handlers::get_secret))
+            .layer(from_fn(problem_json))
+            .with_state(state);
+
+        let send = |uri: &str, accept: &str| {
+            let req = Request::builder()
+                .uri(uri)
+                .header(header::ACCEPT, accept)
+                .extension(ConnectInfo(SocketAddr::from(([203, 0, 113, 7], 1234))))
+                .body(Body::empty())
+                .unwrap();
+            app.clone().oneshot(req)
+        };
+        let problem = |response: Response| async move {
+            assert_eq!(
+                response.headers()[header::CONTENT_TYPE],
+                "application/problem+json"
+            );
+            let body = body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap()
+        };
+
+        // Unknown prefix: a 404 without a code
+        let response = send("/v1/secrets/xx-abc", "application/problem+json")
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let body = problem(response).await;
+        assert_eq!(body["type"], "about:blank");
+        assert_eq!(body["title"], "Not Found");
+        assert_eq!(body["status"], 404);
+        assert_eq!(body["detail"], "Secret not found");
+        assert_eq!(body["instance"], "/v1/secrets/xx-abc");
+
+        // Malformed id: a coded 400
+        let response = send("/v1/secrets/sps-0OIl", "application/problem+json, */*")
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = problem(response).await;
+        assert_eq!(body["type"], "urn:snappwd:problem:malformed-id");
+        assert_eq!(body["title"], "Bad Request");
+        assert_eq!(body["status"], 400);
+        assert_eq!(body["code"], "MALFORMED_ID");
+
+        // Plain JSON clients keep ErrorResponse
+        let response = send("/v1/secrets/sps-0OIl", "application/json")
+            .await
+            .unwrap();
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "application/json");
+    }
+}
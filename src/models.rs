@@ -1,23 +1,330 @@
+use ipnet::IpNet;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
 
-#[derive(Deserialize, Serialize, Debug)]
+/// How a payload was encrypted, so any compatible client can decrypt it.
+/// The service checks the names and ranges but never uses the values.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct CryptoParams {
+    /// Cipher, e.g. `AES-256-GCM`
+    pub algorithm: String,
+    /// Key derivation function for passphrase-protected payloads
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kdf: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub iterations: Option<u32>,
+    /// Base64 KDF salt
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub salt: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Default)]
 pub struct SecretRequest {
     #[serde(rename = "encryptedSecret")]
     pub encrypted_secret: String,
+    /// Seconds until expiry; may be omitted when `kind` has a default
+    #[serde(default)]
     pub expiration: u64,
     #[serde(default)]
     pub metadata: Option<serde_json::Value>,
+    /// Category such as `password` or `note`, checked against `SECRET_KINDS`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kind: Option<String>,
+    /// Why the sender is sharing the secret. Only ever written to the audit
+    /// log; it is not stored, so recipients never see it.
+    #[serde(
+        rename = "shareReason",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub share_reason: Option<String>,
+    /// Unix timestamp before which the secret cannot be read (embargo)
+    #[serde(
+        rename = "availableAt",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub available_at: Option<u64>,
+    /// Seconds after creation the secret can still be read. It stays peekable
+    /// until it expires, so readers can see the window has closed.
+    #[serde(
+        rename = "maxAgeSeconds",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub max_age_seconds: Option<u64>,
+    /// Number of distinct reader confirmations (peeks with `confirm`) required before burn
+    #[serde(
+        rename = "requiredConfirmations",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub required_confirmations: Option<u32>,
+    /// Short labels for organizing secrets (peek-visible)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    /// Reset the TTL to `expiration` on every peek, up to `maxLifetimeSeconds`
+    #[serde(
+        rename = "slidingExpiration",
+        default,
+        skip_serializing_if = "std::ops::Not::not"
+    )]
+    pub sliding_expiration: bool,
+    /// Absolute lifetime ceiling (from creation) for sliding secrets
+    #[serde(
+        rename = "maxLifetimeSeconds",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub max_lifetime_seconds: Option<u64>,
+    /// Wait for a Redis replica to acknowledge the write before responding
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub durable: bool,
+    /// The first peek returns the payload along with the metadata and burns the secret
+    #[serde(
+        rename = "peekBurn",
+        default,
+        skip_serializing_if = "std::ops::Not::not"
+    )]
+    pub peek_burn: bool,
+    /// Refuse every peek; the secret can only be burn-read
+    #[serde(rename = "noPeek", default, skip_serializing_if = "std::ops::Not::not")]
+    pub no_peek: bool,
+    /// A peek binds the burn to the peeking client: it gets an
+    /// `X-View-Context` token the burn must present
+    #[serde(
+        rename = "bindViewContext",
+        default,
+        skip_serializing_if = "std::ops::Not::not"
+    )]
+    pub bind_view_context: bool,
+    /// Networks (CIDRs or single IPs) readers must connect from
+    #[serde(
+        rename = "allowedCidrs",
+        default,
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub allowed_cidrs: Vec<String>,
+    /// Number of burn reads allowed; the last one deletes the secret (burn-after-N)
+    #[serde(rename = "maxReads", default, skip_serializing_if = "Option::is_none")]
+    pub max_reads: Option<u32>,
+    /// Decryption parameters, returned with the payload on peek and burn
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub crypto: Option<CryptoParams>,
+    /// Client-reported zxcvbn score (0-4) of the passphrase, checked against
+    /// `MIN_PASSWORD_STRENGTH` and not stored
+    #[serde(
+        rename = "passwordStrength",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub password_strength: Option<u8>,
+    /// Decoded payload size the client sent, checked against what arrived
+    /// (400 `SIZE_MISMATCH`) and not stored
+    #[serde(
+        rename = "payloadBytes",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub payload_bytes: Option<u64>,
 }
 
-/// Internal storage format for secrets (JSON in Redis)
-#[derive(Deserialize, Serialize, Debug)]
+impl SecretRequest {
+    /// Build the stored record for this request, created at `created_at`.
+    pub fn into_stored(self, created_at: u64) -> StoredSecret {
+        StoredSecret {
+            encrypted_secret: self.encrypted_secret,
+            created_at,
+            updated_at: None,
+            metadata: self.metadata,
+            kind: self.kind,
+            available_at: self.available_at,
+            max_age_seconds: self.max_age_seconds,
+            required_confirmations: self.required_confirmations,
+            tags: self.tags,
+            sliding_window: self.sliding_expiration.then_some(self.expiration),
+            max_lifetime_seconds: self.max_lifetime_seconds,
+            peek_burn: self.peek_burn,
+            no_peek: self.no_peek,
+            bind_view_context: self.bind_view_context,
+            allowed_cidrs: self.allowed_cidrs,
+            max_reads: self.max_reads,
+            crypto: self.crypto,
+            payload_length: None,
+            file_metadata: None,
+        }
+    }
+}
+
+/// Request for PUT /v1/secrets/:id: the new content for an unread secret
+#[derive(Deserialize, Debug)]
+pub struct SecretReplaceRequest {
+    #[serde(rename = "encryptedSecret")]
+    pub encrypted_secret: String,
+    #[serde(default)]
+    pub metadata: Option<serde_json::Value>,
+    /// Set by the server when it pads `encrypted_secret` (`PAD_PAYLOAD_BUCKETS`)
+    #[serde(skip)]
+    pub payload_length: Option<usize>,
+}
+
+/// Internal storage format for secrets (JSON or bincode in Redis, see
+/// `STORAGE_FORMAT`)
+#[derive(Deserialize, Serialize, Debug, Default, Clone)]
 pub struct StoredSecret {
     #[serde(rename = "encryptedSecret")]
     pub encrypted_secret: String,
     #[serde(rename = "createdAt")]
     pub created_at: u64,
+    /// When the payload was last replaced in place, if ever
+    #[serde(rename = "updatedAt", default, skip_serializing_if = "Option::is_none")]
+    pub updated_at: Option<u64>,
     #[serde(default)]
     pub metadata: Option<serde_json::Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kind: Option<String>,
+    #[serde(
+        rename = "availableAt",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub available_at: Option<u64>,
+    #[serde(
+        rename = "maxAgeSeconds",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub max_age_seconds: Option<u64>,
+    #[serde(
+        rename = "requiredConfirmations",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub required_confirmations: Option<u32>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    /// For sliding secrets, the TTL (seconds) each peek resets to
+    #[serde(
+        rename = "slidingWindow",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub sliding_window: Option<u64>,
+    #[serde(
+        rename = "maxLifetimeSeconds",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub max_lifetime_seconds: Option<u64>,
+    #[serde(
+        rename = "peekBurn",
+        default,
+        skip_serializing_if = "std::ops::Not::not"
+    )]
+    pub peek_burn: bool,
+    #[serde(rename = "noPeek", default, skip_serializing_if = "std::ops::Not::not")]
+    pub no_peek: bool,
+    #[serde(
+        rename = "bindViewContext",
+        default,
+        skip_serializing_if = "std::ops::Not::not"
+    )]
+    pub bind_view_context: bool,
+    /// Normalized CIDRs (validated at create time)
+    #[serde(
+        rename = "allowedCidrs",
+        default,
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub allowed_cidrs: Vec<String>,
+    #[serde(rename = "maxReads", default, skip_serializing_if = "Option::is_none")]
+    pub max_reads: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub crypto: Option<CryptoParams>,
+    /// True length of `encrypted_secret` when it's stored padded
+    /// (`PAD_PAYLOAD_BUCKETS`); reads trim the padding off
+    #[serde(
+        rename = "payloadLength",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub payload_length: Option<usize>,
+    /// Set when this record is a small file stored as a secret (`UNIFY_SMALL_FILES`)
+    #[serde(
+        rename = "fileMetadata",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub file_metadata: Option<FileMetadata>,
+}
+
+impl StoredSecret {
+    /// Trim the padding off a padded payload (`PAD_PAYLOAD_BUCKETS`), giving
+    /// back exactly what was stored.
+    pub fn unpad(&mut self) {
+        if let Some(len) = self.payload_length.take() {
+            if self.encrypted_secret.is_char_boundary(len) {
+                self.encrypted_secret.truncate(len);
+            }
+        }
+    }
+
+    /// Whether a reader at `ip` may access the secret. Unpinned secrets are
+    /// readable from anywhere; pinned ones need a known IP inside a network.
+    pub fn allows_ip(&self, ip: Option<IpAddr>) -> bool {
+        if self.allowed_cidrs.is_empty() {
+            return true;
+        }
+        let Some(ip) = ip.map(|ip| ip.to_canonical()) else {
+            return false;
+        };
+        self.allowed_cidrs
+            .iter()
+            .filter_map(|cidr| cidr.parse::<IpNet>().ok())
+            .any(|net| net.contains(&ip))
+    }
+
+    /// When the record last changed: its last in-place replace, or creation.
+    pub fn last_modified(&self) -> u64 {
+        self.updated_at.unwrap_or(self.created_at)
+    }
+
+    /// Whether the secret may be read at `now` (i.e. any embargo has passed).
+    pub fn is_available(&self, now: u64) -> bool {
+        !matches!(self.available_at, Some(available_at) if now < available_at)
+    }
+
+    /// When the `maxAgeSeconds` read window closes, if the secret has one.
+    pub fn readable_until(&self) -> Option<u64> {
+        self.max_age_seconds
+            .map(|max_age| self.created_at.saturating_add(max_age))
+    }
+
+    /// Whether the read window has closed at `now`.
+    pub fn is_past_max_age(&self, now: u64) -> bool {
+        self.readable_until()
+            .is_some_and(|readable_until| now >= readable_until)
+    }
+
+    /// Whether enough distinct confirmations have been collected to release the payload.
+    pub fn confirmations_met(&self, collected: u64) -> bool {
+        self.required_confirmations
+            .is_none_or(|required| collected >= u64::from(required))
+    }
+
+    /// TTL a peek at `now` should extend a sliding secret to: the sliding
+    /// window, clamped so the secret never outlives `created_at +
+    /// max_lifetime_seconds`. None for non-sliding secrets.
+    pub fn sliding_ttl(&self, now: u64) -> Option<u64> {
+        let window = self.sliding_window?;
+        let remaining = match self.max_lifetime_seconds {
+            Some(max_lifetime) => (self.created_at + max_lifetime).saturating_sub(now),
+            None => window,
+        };
+        Some(window.min(remaining))
+    }
 }
 
 /// Query params for GET /v1/secrets/{id}
@@ -25,29 +332,196 @@ pub struct StoredSecret {
 pub struct GetSecretParams {
     #[serde(default)]
     pub peek: bool,
+    /// Reader token recorded as a confirmation when peeking a multi-confirmation secret
+    #[serde(default)]
+    pub confirm: Option<String>,
 }
 
 /// Response for peek=true
-#[derive(Serialize, Debug)]
+#[derive(Serialize, Debug, Default)]
 pub struct SecretPeekResponse {
     #[serde(rename = "createdAt")]
     pub created_at: u64,
     #[serde(rename = "ttlSeconds")]
     pub ttl_seconds: i64,
+    /// RFC 3339 expiry time, null if the secret never expires
+    #[serde(rename = "expiresAt")]
+    pub expires_at: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub metadata: Option<serde_json::Value>,
+    /// True when `metadata` was left out for being over `MAX_PEEK_METADATA_BYTES`
+    #[serde(
+        rename = "metadataTruncated",
+        skip_serializing_if = "std::ops::Not::not"
+    )]
+    pub metadata_truncated: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub kind: Option<String>,
+    #[serde(
+        rename = "requiredConfirmations",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub required_confirmations: Option<u32>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub crypto: Option<CryptoParams>,
+    /// Unix timestamp the `maxAgeSeconds` read window closes at
+    #[serde(rename = "readableUntil", skip_serializing_if = "Option::is_none")]
+    pub readable_until: Option<u64>,
+    /// Only for `peekBurn` secrets, whose first peek releases the payload
+    #[serde(rename = "encryptedSecret", skip_serializing_if = "Option::is_none")]
+    pub encrypted_secret: Option<String>,
+    /// True when this peek burned the secret
+    #[serde(rename = "finalRead", skip_serializing_if = "Option::is_none")]
+    pub final_read: Option<bool>,
 }
 
-#[derive(Serialize, Debug)]
+impl SecretPeekResponse {
+    /// Keep only allowlisted metadata keys (`PEEK_METADATA_ALLOWLIST`). The
+    /// stored record is unaffected. Non-object metadata has no keys to allow,
+    /// so it is hidden entirely; an empty allowlist shows everything.
+    pub fn with_metadata_allowlist(mut self, allowlist: &[String]) -> Self {
+        self.metadata = allowlisted(self.metadata, allowlist);
+        self
+    }
+
+    /// Leave out metadata over `max_bytes` of JSON (`MAX_PEEK_METADATA_BYTES`,
+    /// 0 = no limit), flagging it as truncated; a burn still returns it. A
+    /// `peekBurn` peek is that burn, so it keeps its metadata.
+    pub fn with_metadata_limit(mut self, max_bytes: usize) -> Self {
+        if self.final_read.is_none() {
+            (self.metadata, self.metadata_truncated) = capped(self.metadata, max_bytes);
+        }
+        self
+    }
+
+    pub fn new(stored: StoredSecret, ttl_seconds: i64, now: u64) -> Self {
+        let peek_burn = stored.peek_burn;
+        let readable_until = stored.readable_until();
+        Self {
+            created_at: stored.created_at,
+            ttl_seconds,
+            expires_at: expires_at(ttl_seconds, now),
+            metadata: stored.metadata,
+            metadata_truncated: false,
+            kind: stored.kind,
+            required_confirmations: stored.required_confirmations,
+            tags: stored.tags,
+            crypto: stored.crypto,
+            readable_until,
+            encrypted_secret: peek_burn.then_some(stored.encrypted_secret),
+            final_read: peek_burn.then_some(true),
+        }
+    }
+}
+
+/// Metadata with only the allowlisted keys (`PEEK_METADATA_ALLOWLIST`).
+fn allowlisted(
+    metadata: Option<serde_json::Value>,
+    allowlist: &[String],
+) -> Option<serde_json::Value> {
+    if allowlist.is_empty() {
+        return metadata;
+    }
+    match metadata {
+        Some(serde_json::Value::Object(mut fields)) => {
+            fields.retain(|key, _| allowlist.contains(key));
+            Some(serde_json::Value::Object(fields))
+        }
+        _ => None,
+    }
+}
+
+/// Metadata within `max_bytes` of JSON (0 = no limit), and whether it was
+/// left out for being over.
+fn capped(
+    metadata: Option<serde_json::Value>,
+    max_bytes: usize,
+) -> (Option<serde_json::Value>, bool) {
+    match metadata {
+        Some(value) if max_bytes > 0 && value.to_string().len() > max_bytes => (None, true),
+        metadata => (metadata, false),
+    }
+}
+
+/// What a peek shows of a secret burned within the last
+/// `BURN_GRACE_SECONDS`: when it was created and read, never the payload.
+/// Stored as-is under the secret's burn record key.
+#[derive(Deserialize, Serialize, Debug, Default)]
+pub struct BurnedSecretPeek {
+    /// Always false: the payload is gone
+    pub found: bool,
+    #[serde(rename = "createdAt")]
+    pub created_at: u64,
+    #[serde(rename = "burnedAt")]
+    pub burned_at: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<serde_json::Value>,
+    /// Set on the way out, like `SecretPeekResponse::metadata_truncated`
+    #[serde(
+        rename = "metadataTruncated",
+        default,
+        skip_serializing_if = "std::ops::Not::not"
+    )]
+    pub metadata_truncated: bool,
+}
+
+impl BurnedSecretPeek {
+    pub fn with_metadata_allowlist(mut self, allowlist: &[String]) -> Self {
+        self.metadata = allowlisted(self.metadata, allowlist);
+        self
+    }
+
+    pub fn with_metadata_limit(mut self, max_bytes: usize) -> Self {
+        (self.metadata, self.metadata_truncated) = capped(self.metadata, max_bytes);
+        self
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug)]
 pub struct SecretResponse {
     #[serde(rename = "secretId")]
     pub secret_id: String,
+    /// Expiration in seconds as requested
+    pub expiration: u64,
+    /// TTL read back from Redis once stored, after granularity rounding and
+    /// any emergency cap, so clients can display the truth
+    #[serde(rename = "effectiveTtlSeconds")]
+    pub effective_ttl_seconds: u64,
+    /// Fresh random value clients can bind into their own signatures (`RESPONSE_NONCE`)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nonce: Option<String>,
+    /// Hex SHA-256 of the payload as received (`RESPONSE_CHECKSUM`)
+    #[serde(rename = "payloadSha256", skip_serializing_if = "Option::is_none")]
+    pub payload_sha256: Option<String>,
+    /// Signed link to inspect, delete or extend the secret (`MANAGEMENT_SIGNING_KEY`)
+    #[serde(rename = "managementUrl", skip_serializing_if = "Option::is_none")]
+    pub management_url: Option<String>,
 }
 
 #[derive(Serialize, Debug)]
 pub struct EncryptedSecretResponse {
     #[serde(rename = "encryptedSecret")]
     pub encrypted_secret: String,
+    /// Unix timestamp at which the read removed the secret
+    #[serde(rename = "burnedAt", skip_serializing_if = "Option::is_none")]
+    pub burned_at: Option<u64>,
+    /// True when this read deleted the secret, so clients can show it is now destroyed
+    #[serde(rename = "finalRead", skip_serializing_if = "Option::is_none")]
+    pub final_read: Option<bool>,
+    /// How many times the secret was peeked before this read, so clients can
+    /// warn about an over-shared link
+    #[serde(rename = "peekCount", skip_serializing_if = "Option::is_none")]
+    pub peek_count: Option<u64>,
+    /// Burn reads left after this one, for `maxReads` secrets
+    #[serde(rename = "readsRemaining", skip_serializing_if = "Option::is_none")]
+    pub reads_remaining: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub crypto: Option<CryptoParams>,
+    /// The creator's `metadata.mac` over the ciphertext, for the reader to verify
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mac: Option<String>,
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
@@ -65,21 +539,210 @@ pub struct FileRequest {
     #[serde(rename = "encryptedData")]
     pub encrypted_data: String, // Base64
     pub expiration: u64,
+    /// Wait for a Redis replica to acknowledge the write before responding
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub durable: bool,
+    /// Small client-encrypted preview of an image, readable without burning the file
+    #[serde(
+        rename = "encryptedThumbnail",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub encrypted_thumbnail: Option<String>,
+    #[serde(
+        rename = "thumbnailIv",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub thumbnail_iv: Option<String>,
+    /// Decryption parameters, returned with the file on peek and burn
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub crypto: Option<CryptoParams>,
+    /// Client-reported zxcvbn score (0-4) of the passphrase, not stored
+    #[serde(
+        rename = "passwordStrength",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub password_strength: Option<u8>,
+    /// Decoded payload size the client sent, checked against what arrived
+    /// (400 `SIZE_MISMATCH`) and not stored
+    #[serde(
+        rename = "payloadBytes",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub payload_bytes: Option<u64>,
 }
 
 #[derive(Serialize, Debug)]
 pub struct FileResponse {
     #[serde(rename = "fileId")]
     pub file_id: String,
+    /// Expiration in seconds as requested
+    pub expiration: u64,
+    /// TTL read back from Redis once stored, after granularity rounding and
+    /// any emergency cap, so clients can display the truth
+    #[serde(rename = "effectiveTtlSeconds")]
+    pub effective_ttl_seconds: u64,
+    /// Fresh random value clients can bind into their own signatures (`RESPONSE_NONCE`)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nonce: Option<String>,
+    /// Hex SHA-256 of the payload as received (`RESPONSE_CHECKSUM`)
+    #[serde(rename = "payloadSha256", skip_serializing_if = "Option::is_none")]
+    pub payload_sha256: Option<String>,
+    /// Reference for POST /v1/files/ref to share this data again (`DEDUP_FILES`)
+    #[serde(rename = "contentHash", skip_serializing_if = "Option::is_none")]
+    pub content_hash: Option<String>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct StoredFile {
     pub metadata: FileMetadata,
     #[serde(rename = "encryptedData")]
     pub encrypted_data: String,
     #[serde(rename = "createdAt", default)]
     pub created_at: u64,
+    /// Only set on the way in: `db::store_file` keeps the thumbnail at its
+    /// own key, so records come back without it
+    #[serde(
+        rename = "encryptedThumbnail",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub encrypted_thumbnail: Option<String>,
+    #[serde(
+        rename = "thumbnailIv",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub thumbnail_iv: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub crypto: Option<CryptoParams>,
+    /// Hex SHA-256 of the data, when it's kept in a shared blob (`DEDUP_FILES`)
+    #[serde(
+        rename = "contentHash",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub content_hash: Option<String>,
+}
+
+/// Body of POST /v1/files/ref: a new file sharing an already stored blob's
+/// data, so it isn't uploaded again (`DEDUP_FILES`)
+#[derive(Deserialize, Debug)]
+pub struct FileRefRequest {
+    /// The `contentHash` a create returned for the data
+    #[serde(rename = "contentHash")]
+    pub content_hash: String,
+    pub metadata: FileMetadata,
+    pub expiration: u64,
+}
+
+/// Body of POST /v1/admin/files/:id/decrypt
+#[derive(Deserialize)]
+pub struct DecryptRequest {
+    /// Base64 AES-GCM key (16 or 32 bytes). Used once, never stored or logged.
+    pub key: String,
+}
+
+/// Body of POST /v1/files/uploads: everything about a file except its data,
+/// which follows in chunks. Kept as the session record until completion.
+#[derive(Deserialize, Serialize, Debug)]
+pub struct UploadStartRequest {
+    pub metadata: FileMetadata,
+    pub expiration: u64,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub durable: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub crypto: Option<CryptoParams>,
+    #[serde(
+        rename = "passwordStrength",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub password_strength: Option<u8>,
+    /// Decoded size of the whole file, checked once every chunk is in
+    #[serde(
+        rename = "payloadBytes",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub payload_bytes: Option<u64>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct UploadStartResponse {
+    #[serde(rename = "uploadId")]
+    pub upload_id: String,
+    /// Seconds left to send every chunk and complete the upload
+    #[serde(rename = "expiresIn")]
+    pub expires_in: u64,
+}
+
+/// Body of PUT /v1/files/uploads/:id/chunks/:index
+#[derive(Deserialize, Debug)]
+pub struct UploadChunkRequest {
+    /// The next slice of the encrypted data's base64
+    pub data: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct UploadChunkResponse {
+    /// Characters received across all chunks so far
+    #[serde(rename = "receivedBytes")]
+    pub received_bytes: u64,
+}
+
+/// Body of POST /v1/files/uploads/:id/complete
+#[derive(Deserialize, Debug)]
+pub struct UploadCompleteRequest {
+    /// How many chunks were sent, indexed from 0
+    #[serde(rename = "chunkCount")]
+    pub chunk_count: u32,
+}
+
+/// Response for GET /v1/files/:id/thumbnail
+#[derive(Serialize, Debug)]
+pub struct ThumbnailResponse {
+    #[serde(rename = "encryptedThumbnail")]
+    pub encrypted_thumbnail: String,
+    #[serde(rename = "thumbnailIv")]
+    pub thumbnail_iv: String,
+}
+
+/// One file within a bundle
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct BundlePart {
+    pub metadata: FileMetadata,
+    #[serde(rename = "encryptedData")]
+    pub encrypted_data: String, // Base64
+}
+
+/// Request for POST /v1/files/bundle: several files shared under one id
+#[derive(Deserialize, Serialize, Debug)]
+pub struct BundleRequest {
+    pub files: Vec<BundlePart>,
+    pub expiration: u64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct BundleResponse {
+    #[serde(rename = "bundleId")]
+    pub bundle_id: String,
+    /// Effective expiration in seconds, after any granularity rounding
+    pub expiration: u64,
+    /// Fresh random value clients can bind into their own signatures (`RESPONSE_NONCE`)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nonce: Option<String>,
+}
+
+/// Internal storage format for bundles (JSON in Redis)
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StoredBundle {
+    pub files: Vec<BundlePart>,
+    #[serde(rename = "createdAt", default)]
+    pub created_at: u64,
 }
 
 /// Query params for GET /v1/files/{id}
@@ -89,6 +752,104 @@ pub struct GetFileParams {
     pub peek: bool,
 }
 
+/// Query params for GET /v1/files/check
+#[derive(Deserialize, Debug)]
+pub struct FileCheckParams {
+    /// Decoded (not base64) size of the file to upload
+    #[serde(rename = "sizeBytes")]
+    pub size_bytes: usize,
+    #[serde(default)]
+    pub filename: Option<String>,
+}
+
+/// Response for GET /v1/files/check
+#[derive(Serialize, Debug)]
+pub struct FileCheckResponse {
+    pub allowed: bool,
+    #[serde(rename = "maxExpirationSeconds")]
+    pub max_expiration_seconds: u64,
+    /// Why the upload would be rejected (null when allowed)
+    pub reason: Option<String>,
+}
+
+/// Response for GET /v1/secrets/:id/touch
+#[derive(Serialize, Debug)]
+pub struct TouchResponse {
+    pub exists: bool,
+    /// Remaining TTL (-1 if it never expires), null when the secret doesn't exist
+    #[serde(rename = "ttlSeconds")]
+    pub ttl_seconds: Option<i64>,
+}
+
+/// Request for POST /v1/secrets/exists
+#[derive(Deserialize, Debug)]
+pub struct SecretExistsRequest {
+    pub ids: Vec<String>,
+}
+
+/// One id's entry in the POST /v1/secrets/exists response
+#[derive(Serialize, Debug, PartialEq)]
+pub struct SecretExistence {
+    pub exists: bool,
+    /// Remaining TTL (-1 if it never expires), null when the secret doesn't exist
+    #[serde(rename = "ttlSeconds")]
+    pub ttl_seconds: Option<i64>,
+    /// The id isn't well-formed, so it was never looked up
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub malformed: bool,
+}
+
+/// One read of a secret in its access log (`MAX_ACCESS_LOG_ENTRIES`)
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct AccessLogEntry {
+    /// Unix timestamp of the read
+    pub at: u64,
+    /// `peek` or `read`
+    pub action: String,
+}
+
+/// Response for GET /v1/secrets/:id/manage
+#[derive(Serialize, Debug)]
+pub struct ManageResponse {
+    pub exists: bool,
+    /// Remaining TTL (-1 if it never expires), null when the secret doesn't exist
+    #[serde(rename = "ttlSeconds")]
+    pub ttl_seconds: Option<i64>,
+    /// Most recent reads, newest first (omitted when access logs are disabled)
+    #[serde(rename = "accessLog", skip_serializing_if = "Option::is_none")]
+    pub access_log: Option<Vec<AccessLogEntry>>,
+    /// Older reads trimmed from `accessLog` past the cap
+    #[serde(rename = "accessLogDropped", skip_serializing_if = "Option::is_none")]
+    pub access_log_dropped: Option<u64>,
+}
+
+/// Query of a signed management link, `/v1/secrets/{id}/manage`
+#[derive(Deserialize, Debug)]
+pub struct ManageParams {
+    /// Unix timestamp after which the link stops working
+    pub expires: u64,
+    /// Base64url HMAC over the id and `expires`
+    pub sig: String,
+}
+
+/// Request for POST /v1/secrets/:id/manage/extend
+#[derive(Deserialize, Debug)]
+pub struct ExtendRequest {
+    /// New TTL in seconds, counted from now
+    pub expiration: u64,
+}
+
+/// Response for POST /v1/secrets/:id/manage/extend
+#[derive(Serialize, Debug)]
+pub struct ExtendResponse {
+    /// Effective TTL in seconds, after any granularity rounding
+    #[serde(rename = "ttlSeconds")]
+    pub ttl_seconds: u64,
+    /// Link re-signed to the new expiry; the old one stops at the original expiry
+    #[serde(rename = "managementUrl")]
+    pub management_url: String,
+}
+
 /// Response for file peek=true
 #[derive(Serialize, Debug)]
 pub struct FilePeekResponse {
@@ -96,27 +857,290 @@ pub struct FilePeekResponse {
     pub created_at: u64,
     #[serde(rename = "ttlSeconds")]
     pub ttl_seconds: i64,
+    /// RFC 3339 expiry time, null if the file never expires
+    #[serde(rename = "expiresAt")]
+    pub expires_at: Option<String>,
     pub metadata: FileMetadata,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub crypto: Option<CryptoParams>,
+}
+
+/// Response for `POST /v1/files/:id/stage`: everything but the data, which
+/// is read in ranges from `GET /v1/files/:id/stage`
+#[derive(Serialize, Debug)]
+pub struct StagedFileResponse {
+    #[serde(rename = "createdAt")]
+    pub created_at: u64,
+    pub metadata: FileMetadata,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub crypto: Option<CryptoParams>,
+    /// Length of the staged `encryptedData` text, in bytes
+    #[serde(rename = "sizeBytes")]
+    pub size_bytes: usize,
+    /// Seconds the staged data stays readable
+    #[serde(rename = "ttlSeconds")]
+    pub ttl_seconds: u64,
+    /// Sent as `X-Staging-Token` on every later call for this download
+    #[serde(rename = "stagingToken")]
+    pub staging_token: String,
+}
+
+/// The `expiresAt` of a peek: `now + ttl` as an RFC 3339 (ISO-8601) UTC
+/// timestamp. A TTL of -1 (no expiry) has none; -2 means the key expired
+/// between the read and the TTL lookup, so it expires now.
+pub fn expires_at(ttl_seconds: i64, now: u64) -> Option<String> {
+    let timestamp = match ttl_seconds {
+        -2 => now as i64,
+        ttl if ttl < 0 => return None,
+        ttl => now as i64 + ttl,
+    };
+    OffsetDateTime::from_unix_timestamp(timestamp)
+        .ok()?
+        .format(&Rfc3339)
+        .ok()
+}
+
+/// Response for GET /
+#[derive(Serialize, Debug)]
+pub struct ServiceDescriptor {
+    pub name: &'static str,
+    pub version: &'static str,
+    pub docs: &'static str,
+}
+
+/// Entry in the response for GET /v1/endpoints
+#[derive(Serialize, Debug)]
+pub struct EndpointDescriptor {
+    pub method: crate::routes::Method,
+    pub path: &'static str,
+    pub description: &'static str,
+    #[serde(rename = "requiresAuth")]
+    pub requires_auth: bool,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ReadinessResponse {
+    /// `ok`, `degraded` (Redis near `maxmemory`) or `unavailable`
+    pub status: &'static str,
+}
+
+/// Query params for GET /v1/secrets
+#[derive(Deserialize, Debug, Default)]
+pub struct ListSecretsParams {
+    /// Keys to examine for this page (default 50, at most 100)
+    pub limit: Option<usize>,
+    /// `nextCursor` of the previous page; omitted for the first
+    pub cursor: Option<u64>,
+}
+
+/// Body for PUT /v1/admin/secrets/:id: a secret record exported from
+/// another instance, stored as-is under that id
+#[derive(Deserialize, Debug)]
+pub struct SecretImportRequest {
+    pub record: StoredSecret,
+    /// Seconds until the imported secret expires; an overwrite resets the
+    /// TTL to this too
+    pub expiration: u64,
+}
+
+/// Query params for PUT /v1/admin/secrets/:id
+#[derive(Deserialize, Debug, Default)]
+pub struct ImportParams {
+    /// Replace a secret already stored under the id (`ALLOW_IMPORT_OVERWRITE`)
+    #[serde(default)]
+    pub overwrite: bool,
+}
+
+/// A listed secret: enough to pick it out, never the payload or metadata
+#[derive(Serialize, Debug)]
+pub struct SecretListItem {
+    pub id: String,
+    #[serde(rename = "createdAt")]
+    pub created_at: u64,
+    #[serde(rename = "ttlSeconds")]
+    pub ttl_seconds: i64,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+}
+
+/// Response for GET /v1/secrets
+#[derive(Serialize, Debug)]
+pub struct SecretList {
+    pub items: Vec<SecretListItem>,
+    /// Pass as `cursor` for the next page; null once the listing is complete
+    #[serde(rename = "nextCursor")]
+    pub next_cursor: Option<String>,
+}
+
+/// Response for GET /v1/admin/usage
+#[derive(Serialize, Debug)]
+pub struct UsageReport {
+    /// Decoded bytes stored by each API key label (`USAGE_BY_API_KEY`)
+    #[serde(rename = "bytesByApiKey")]
+    pub bytes_by_api_key: BTreeMap<String, u64>,
+}
+
+/// Body for PUT /v1/admin/emergency-max-ttl, and the response of each
+/// method on it
+#[derive(Serialize, Deserialize, Debug)]
+pub struct EmergencyMaxTtl {
+    /// Cap on new secrets', files' and bundles' TTLs; null when unset
+    #[serde(rename = "maxTtlSeconds")]
+    pub max_ttl_seconds: Option<u64>,
+}
+
+/// Response for GET /v1/admin/expiry-histogram: live entries by remaining TTL
+#[derive(Serialize, Debug, Default, PartialEq)]
+pub struct ExpiryHistogram {
+    #[serde(rename = "under1m")]
+    pub under_1m: u64,
+    #[serde(rename = "under1h")]
+    pub under_1h: u64,
+    #[serde(rename = "under1d")]
+    pub under_1d: u64,
+    #[serde(rename = "over1d")]
+    pub over_1d: u64,
+}
+
+impl ExpiryHistogram {
+    /// Count an entry with `ttl` seconds left. Keys that are already gone
+    /// (-2) or have no TTL (-1, which SnapPwd never writes) are skipped.
+    pub fn record(&mut self, ttl: i64) {
+        match ttl {
+            ..=-1 => {}
+            0..=59 => self.under_1m += 1,
+            60..=3599 => self.under_1h += 1,
+            3600..=86399 => self.under_1d += 1,
+            _ => self.over_1d += 1,
+        }
+    }
 }
 
 #[derive(Serialize, Debug)]
 pub struct ErrorResponse {
     pub error: String,
+    /// Machine-readable error code, for errors clients are expected to branch on
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub code: Option<&'static str>,
+    /// Why the request failed, only with `DIAGNOSTIC_ERRORS`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub debug: Option<serde_json::Value>,
+    /// Index of the bundle part the error is about
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub part: Option<usize>,
+}
+
+impl ErrorResponse {
+    pub fn new(error: impl Into<String>) -> Self {
+        Self {
+            error: error.into(),
+            code: None,
+            debug: None,
+            part: None,
+        }
+    }
+
+    pub fn with_code(code: &'static str, error: impl Into<String>) -> Self {
+        Self {
+            error: error.into(),
+            code: Some(code),
+            debug: None,
+            part: None,
+        }
+    }
+
+    pub fn with_debug(mut self, debug: serde_json::Value) -> Self {
+        self.debug = Some(debug);
+        self
+    }
+
+    /// Attribute the error to bundle part `index`, in the message too.
+    pub fn for_part(mut self, index: usize) -> Self {
+        self.error = format!("files[{}]: {}", index, self.error);
+        self.part = Some(index);
+        self
+    }
+}
+
+/// RFC 7807 error body, sent in place of `ErrorResponse` to clients that
+/// ask for `application/problem+json`.
+#[derive(Serialize, Debug)]
+pub struct ProblemDetails {
+    /// `urn:snappwd:problem:<code>` for coded errors, `about:blank` otherwise
+    #[serde(rename = "type")]
+    pub problem_type: String,
+    /// The HTTP status' reason phrase
+    pub title: String,
+    pub status: u16,
+    /// The `ErrorResponse` message
+    pub detail: String,
+    /// Path of the request that failed
+    pub instance: String,
+    /// The `ErrorResponse` code, kept so clients can still branch on it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub code: Option<String>,
+}
+
+impl ProblemDetails {
+    pub fn new(
+        status: u16,
+        title: &str,
+        detail: String,
+        code: Option<String>,
+        instance: String,
+    ) -> Self {
+        let problem_type = match &code {
+            Some(code) => format!(
+                "urn:snappwd:problem:{}",
+                code.to_ascii_lowercase().replace('_', "-")
+            ),
+            None => "about:blank".to_string(),
+        };
+        Self {
+            problem_type,
+            title: title.to_string(),
+            status,
+            detail,
+            instance,
+            code,
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_expiry_histogram_buckets() {
+        let mut histogram = ExpiryHistogram::default();
+        for ttl in [-2, -1, 0, 59, 60, 3599, 3600, 86399, 86400, 2592000] {
+            histogram.record(ttl);
+        }
+        assert_eq!(
+            histogram,
+            ExpiryHistogram {
+                under_1m: 2,
+                under_1h: 2,
+                under_1d: 2,
+                over_1d: 2,
+            }
+        );
+    }
+
     #[test]
     fn test_secret_request_serialization() {
         let req = SecretRequest {
             encrypted_secret: "abc".to_string(),
             expiration: 3600,
             metadata: None,
+            ..Default::default()
         };
         let json = serde_json::to_string(&req).unwrap();
-        assert_eq!(json, r#"{"encryptedSecret":"abc","expiration":3600,"metadata":null}"#);
+        assert_eq!(
+            json,
+            r#"{"encryptedSecret":"abc","expiration":3600,"metadata":null}"#
+        );
     }
 
     #[test]
@@ -125,6 +1149,7 @@ mod tests {
             encrypted_secret: "abc".to_string(),
             expiration: 3600,
             metadata: Some(serde_json::json!({"label": "test"})),
+            ..Default::default()
         };
         let json = serde_json::to_string(&req).unwrap();
         assert!(json.contains(r#""encryptedSecret":"abc""#));
@@ -157,11 +1182,321 @@ mod tests {
             encrypted_secret: "secret123".to_string(),
             created_at: 1706900000,
             metadata: Some(serde_json::json!({"label": "test"})),
+            ..Default::default()
         };
         let json = serde_json::to_string(&stored).unwrap();
         assert!(json.contains(r#""encryptedSecret":"secret123""#));
         assert!(json.contains(r#""createdAt":1706900000"#));
         assert!(json.contains(r#""metadata":{"label":"test"}"#));
+        assert!(!json.contains("availableAt"));
+    }
+
+    #[test]
+    fn test_secret_request_deserialization_with_available_at() {
+        let json = r#"{"encryptedSecret":"abc","expiration":3600,"availableAt":1706903600}"#;
+        let req: SecretRequest = serde_json::from_str(json).unwrap();
+        assert_eq!(req.available_at, Some(1706903600));
+    }
+
+    #[test]
+    fn test_max_age_read_window() {
+        let json = r#"{"encryptedSecret":"abc","expiration":3600,"maxAgeSeconds":600}"#;
+        let req: SecretRequest = serde_json::from_str(json).unwrap();
+        let stored = req.into_stored(1706900000);
+
+        assert_eq!(stored.readable_until(), Some(1706900600));
+        assert!(!stored.is_past_max_age(1706900599));
+        assert!(stored.is_past_max_age(1706900600));
+        assert!(!StoredSecret::default().is_past_max_age(u64::MAX));
+    }
+
+    #[test]
+    fn test_crypto_params_stored_and_returned_on_peek() {
+        let json = r#"{"encryptedSecret":"abc","expiration":3600,"crypto":{"algorithm":"AES-256-GCM","kdf":"PBKDF2-SHA256","iterations":600000,"salt":"c2FsdHNhbHQ="}}"#;
+        let req: SecretRequest = serde_json::from_str(json).unwrap();
+        let stored = req.into_stored(1706900000);
+        let crypto = stored.crypto.clone().unwrap();
+        assert_eq!(crypto.algorithm, "AES-256-GCM");
+        assert_eq!(crypto.iterations, Some(600000));
+
+        let stored: StoredSecret =
+            serde_json::from_str(&serde_json::to_string(&stored).unwrap()).unwrap();
+        let peek = serde_json::to_value(SecretPeekResponse::new(stored, 60, 1706900000)).unwrap();
+        assert_eq!(
+            peek["crypto"],
+            serde_json::json!({
+                "algorithm": "AES-256-GCM",
+                "kdf": "PBKDF2-SHA256",
+                "iterations": 600000,
+                "salt": "c2FsdHNhbHQ="
+            })
+        );
+    }
+
+    #[test]
+    fn test_stored_secret_embargo_before_window() {
+        let stored = StoredSecret {
+            encrypted_secret: "secret123".to_string(),
+            created_at: 1706900000,
+            metadata: None,
+            available_at: Some(1706903600),
+            ..Default::default()
+        };
+        assert!(!stored.is_available(1706900000));
+        assert!(!stored.is_available(1706903599));
+    }
+
+    #[test]
+    fn test_stored_secret_embargo_after_window() {
+        let stored = StoredSecret {
+            encrypted_secret: "secret123".to_string(),
+            created_at: 1706900000,
+            metadata: None,
+            available_at: Some(1706903600),
+            ..Default::default()
+        };
+        assert!(stored.is_available(1706903600));
+        assert!(stored.is_available(1706990000));
+    }
+
+    #[test]
+    fn test_stored_secret_without_embargo_is_available() {
+        let json = r#"{"encryptedSecret":"secret123","createdAt":1706900000}"#;
+        let stored: StoredSecret = serde_json::from_str(json).unwrap();
+        assert!(stored.available_at.is_none());
+        assert!(stored.is_available(0));
+    }
+
+    #[test]
+    fn test_single_confirmation_insufficient() {
+        let stored = StoredSecret {
+            encrypted_secret: "secret123".to_string(),
+            created_at: 1706900000,
+            required_confirmations: Some(2),
+            ..Default::default()
+        };
+        assert!(!stored.confirmations_met(0));
+        assert!(!stored.confirmations_met(1));
+    }
+
+    #[test]
+    fn test_dual_confirmation_success() {
+        let stored = StoredSecret {
+            encrypted_secret: "secret123".to_string(),
+            created_at: 1706900000,
+            required_confirmations: Some(2),
+            ..Default::default()
+        };
+        assert!(stored.confirmations_met(2));
+        assert!(stored.confirmations_met(3));
+    }
+
+    #[test]
+    fn test_confirmations_not_required_by_default() {
+        let json = r#"{"encryptedSecret":"secret123","createdAt":1706900000}"#;
+        let stored: StoredSecret = serde_json::from_str(json).unwrap();
+        assert!(stored.required_confirmations.is_none());
+        assert!(stored.confirmations_met(0));
+    }
+
+    #[test]
+    fn test_tags_are_peek_visible() {
+        let json = r#"{"encryptedSecret":"abc","expiration":3600,"tags":["prod","db"]}"#;
+        let req: SecretRequest = serde_json::from_str(json).unwrap();
+        let stored = req.into_stored(1706900000);
+
+        let resp = SecretPeekResponse::new(stored, 298, 1706900000);
+        let json = serde_json::to_string(&resp).unwrap();
+        assert!(json.contains(r#""tags":["prod","db"]"#));
+    }
+
+    #[test]
+    fn test_share_reason_is_not_stored() {
+        let json =
+            r#"{"encryptedSecret":"abc","expiration":3600,"shareReason":"rotating db creds"}"#;
+        let req: SecretRequest = serde_json::from_str(json).unwrap();
+        assert_eq!(req.share_reason.as_deref(), Some("rotating db creds"));
+
+        let stored = req.into_stored(1706900000);
+        let stored_json = serde_json::to_string(&stored).unwrap();
+        assert!(!stored_json.contains("rotating db creds"));
+
+        let stored: StoredSecret = serde_json::from_str(&stored_json).unwrap();
+        let peek =
+            serde_json::to_string(&SecretPeekResponse::new(stored, 298, 1706900000)).unwrap();
+        assert!(!peek.contains("shareReason"));
+        assert!(!peek.contains("rotating db creds"));
+    }
+
+    #[test]
+    fn test_kind_is_peek_visible() {
+        let json = r#"{"encryptedSecret":"abc","expiration":3600,"kind":"password"}"#;
+        let req: SecretRequest = serde_json::from_str(json).unwrap();
+        let stored = req.into_stored(1706900000);
+
+        let resp = SecretPeekResponse::new(stored, 298, 1706900000);
+        let json = serde_json::to_string(&resp).unwrap();
+        assert!(json.contains(r#""kind":"password""#));
+    }
+
+    #[test]
+    fn test_peek_burn_peek_includes_payload() {
+        let json = r#"{"encryptedSecret":"abc","expiration":3600,"peekBurn":true,"metadata":{"label":"db"}}"#;
+        let req: SecretRequest = serde_json::from_str(json).unwrap();
+        let stored = req.into_stored(1706900000);
+        assert!(stored.peek_burn);
+
+        let json =
+            serde_json::to_string(&SecretPeekResponse::new(stored, 298, 1706900000)).unwrap();
+        assert!(json.contains(r#""encryptedSecret":"abc""#));
+        assert!(json.contains(r#""finalRead":true"#));
+        assert!(json.contains(r#""metadata":{"label":"db"}"#));
+    }
+
+    #[test]
+    fn test_peek_metadata_allowlist_strips_other_keys() {
+        let json = r#"{"encryptedSecret":"abc","expiration":3600,"metadata":{"label":"db","owner":"alice","ticket":"OPS-1"}}"#;
+        let req: SecretRequest = serde_json::from_str(json).unwrap();
+        let stored = req.into_stored(1706900000);
+        let allowlist = vec!["label".to_string(), "ticket".to_string()];
+
+        let peek =
+            SecretPeekResponse::new(stored, 298, 1706900000).with_metadata_allowlist(&allowlist);
+        assert_eq!(
+            peek.metadata,
+            Some(serde_json::json!({"label": "db", "ticket": "OPS-1"}))
+        );
+    }
+
+    #[test]
+    fn test_peek_metadata_without_allowlist_shows_everything() {
+        let stored = StoredSecret {
+            metadata: Some(serde_json::json!({"label": "db", "owner": "alice"})),
+            ..Default::default()
+        };
+        let peek = SecretPeekResponse::new(stored, 298, 1706900000).with_metadata_allowlist(&[]);
+        assert_eq!(
+            peek.metadata,
+            Some(serde_json::json!({"label": "db", "owner": "alice"}))
+        );
+
+        // Scalar metadata has no keys to allow
+        let stored = StoredSecret {
+            metadata: Some(serde_json::json!("db")),
+            ..Default::default()
+        };
+        let peek = SecretPeekResponse::new(stored, 298, 1706900000)
+            .with_metadata_allowlist(&["label".to_string()]);
+        assert!(peek.metadata.is_none());
+    }
+
+    #[test]
+    fn test_peek_metadata_limit() {
+        let stored = || StoredSecret {
+            metadata: Some(serde_json::json!({"label": "db"})),
+            ..Default::default()
+        };
+        // {"label":"db"} is 14 bytes
+        let peek = SecretPeekResponse::new(stored(), 298, 1706900000).with_metadata_limit(14);
+        assert_eq!(peek.metadata, Some(serde_json::json!({"label": "db"})));
+        assert!(!serde_json::to_string(&peek)
+            .unwrap()
+            .contains("metadataTruncated"));
+
+        let peek = SecretPeekResponse::new(stored(), 298, 1706900000).with_metadata_limit(13);
+        assert!(peek.metadata.is_none());
+        let json = serde_json::to_string(&peek).unwrap();
+        assert!(json.contains(r#""metadataTruncated":true"#));
+        assert!(!json.contains("label"));
+
+        // A peekBurn peek is the burn, so nothing is held back
+        let peek_burn = StoredSecret {
+            peek_burn: true,
+            ..stored()
+        };
+        let peek = SecretPeekResponse::new(peek_burn, 0, 1706900000).with_metadata_limit(13);
+        assert_eq!(peek.metadata, Some(serde_json::json!({"label": "db"})));
+        assert!(!peek.metadata_truncated);
+    }
+
+    #[test]
+    fn test_plain_peek_omits_payload() {
+        let json = r#"{"encryptedSecret":"abc","expiration":3600}"#;
+        let req: SecretRequest = serde_json::from_str(json).unwrap();
+        let stored = req.into_stored(1706900000);
+        assert!(!serde_json::to_string(&stored).unwrap().contains("peekBurn"));
+
+        let json =
+            serde_json::to_string(&SecretPeekResponse::new(stored, 298, 1706900000)).unwrap();
+        assert!(!json.contains("encryptedSecret"));
+        assert!(!json.contains("finalRead"));
+    }
+
+    fn pinned_secret() -> StoredSecret {
+        StoredSecret {
+            encrypted_secret: "abc".to_string(),
+            allowed_cidrs: vec!["203.0.113.0/24".to_string()],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_pinned_secret_allows_reader_in_range() {
+        let stored = pinned_secret();
+        assert!(stored.allows_ip(Some("203.0.113.7".parse().unwrap())));
+        // IPv4-mapped IPv6 peers (dual-stack sockets) match their IPv4 network
+        assert!(stored.allows_ip(Some("::ffff:203.0.113.7".parse().unwrap())));
+    }
+
+    #[test]
+    fn test_pinned_secret_refuses_reader_out_of_range() {
+        let stored = pinned_secret();
+        assert!(!stored.allows_ip(Some("198.51.100.1".parse().unwrap())));
+        assert!(!stored.allows_ip(None));
+    }
+
+    #[test]
+    fn test_unpinned_secret_allows_any_reader() {
+        let stored = StoredSecret::default();
+        assert!(stored.allows_ip(None));
+        assert!(stored.allows_ip(Some("198.51.100.1".parse().unwrap())));
+    }
+
+    fn sliding_secret() -> StoredSecret {
+        let json = r#"{"encryptedSecret":"abc","expiration":600,"slidingExpiration":true,"maxLifetimeSeconds":1500}"#;
+        let req: SecretRequest = serde_json::from_str(json).unwrap();
+        req.into_stored(1706900000)
+    }
+
+    #[test]
+    fn test_sliding_peeks_extend_ttl() {
+        let stored = sliding_secret();
+        assert_eq!(stored.sliding_window, Some(600));
+
+        // Each peek resets the TTL to the full window
+        assert_eq!(stored.sliding_ttl(1706900300), Some(600));
+        assert_eq!(stored.sliding_ttl(1706900800), Some(600));
+    }
+
+    #[test]
+    fn test_sliding_ttl_capped_by_max_lifetime() {
+        let stored = sliding_secret();
+
+        // 1300s in, only 200s of the 1500s lifetime remain
+        assert_eq!(stored.sliding_ttl(1706901300), Some(200));
+        assert_eq!(stored.sliding_ttl(1706901500), Some(0));
+        assert_eq!(stored.sliding_ttl(1706909999), Some(0));
+    }
+
+    #[test]
+    fn test_non_sliding_secret_has_no_sliding_ttl() {
+        let json = r#"{"encryptedSecret":"abc","expiration":600}"#;
+        let req: SecretRequest = serde_json::from_str(json).unwrap();
+        let stored = req.into_stored(1706900000);
+        assert!(stored.sliding_ttl(1706900300).is_none());
+
+        let json = serde_json::to_string(&stored).unwrap();
+        assert!(!json.contains("slidingWindow"));
     }
 
     #[test]
@@ -182,6 +1517,7 @@ mod tests {
             created_at: 1706900000,
             ttl_seconds: 298,
             metadata: Some(serde_json::json!({"label": "test"})),
+            ..Default::default()
         };
         let json = serde_json::to_string(&resp).unwrap();
         assert!(json.contains(r#""createdAt":1706900000"#));
@@ -189,12 +1525,65 @@ mod tests {
         assert!(json.contains(r#""metadata":{"label":"test"}"#));
     }
 
+    #[test]
+    fn test_burned_secret_peek_round_trips_without_payload() {
+        let record = BurnedSecretPeek {
+            found: false,
+            created_at: 1706900000,
+            burned_at: 1706900300,
+            metadata: Some(serde_json::json!({"label": "test", "owner": "ops"})),
+            metadata_truncated: false,
+        };
+        let json = serde_json::to_string(&record).unwrap();
+        assert!(json.contains(r#""found":false"#));
+        assert!(json.contains(r#""burnedAt":1706900300"#));
+        assert!(!json.contains("encryptedSecret"));
+
+        let read: BurnedSecretPeek = serde_json::from_str(&json).unwrap();
+        let read = read.with_metadata_allowlist(&["label".to_string()]);
+        assert_eq!(read.created_at, 1706900000);
+        assert_eq!(read.metadata, Some(serde_json::json!({"label": "test"})));
+    }
+
+    #[test]
+    fn test_encrypted_secret_response_final_read() {
+        let resp = EncryptedSecretResponse {
+            encrypted_secret: "secret123".to_string(),
+            burned_at: Some(1706900000),
+            final_read: Some(true),
+            peek_count: Some(2),
+            reads_remaining: None,
+            crypto: None,
+            mac: None,
+        };
+        let json = serde_json::to_string(&resp).unwrap();
+        assert!(json.contains(r#""burnedAt":1706900000"#));
+        assert!(json.contains(r#""finalRead":true"#));
+        assert!(json.contains(r#""peekCount":2"#));
+    }
+
+    #[test]
+    fn test_encrypted_secret_response_omits_burn_receipt() {
+        let resp = EncryptedSecretResponse {
+            encrypted_secret: "secret123".to_string(),
+            burned_at: None,
+            final_read: None,
+            peek_count: None,
+            reads_remaining: None,
+            crypto: None,
+            mac: None,
+        };
+        let json = serde_json::to_string(&resp).unwrap();
+        assert_eq!(json, r#"{"encryptedSecret":"secret123"}"#);
+    }
+
     #[test]
     fn test_secret_peek_response_no_metadata() {
         let resp = SecretPeekResponse {
             created_at: 1706900000,
             ttl_seconds: 298,
             metadata: None,
+            ..Default::default()
         };
         let json = serde_json::to_string(&resp).unwrap();
         assert!(!json.contains("metadata"));
@@ -210,6 +1599,12 @@ mod tests {
             },
             encrypted_data: "data123".to_string(),
             expiration: 3600,
+            durable: false,
+            encrypted_thumbnail: None,
+            thumbnail_iv: None,
+            crypto: None,
+            password_strength: None,
+            payload_bytes: None,
         };
         let json = serde_json::to_string(&req).unwrap();
         // Check for presence of fields rather than exact string due to order
@@ -224,6 +1619,32 @@ mod tests {
         assert!(!params.peek);
     }
 
+    #[test]
+    fn test_expires_at_adds_ttl_to_now() {
+        assert_eq!(
+            expires_at(298, 1706900000).as_deref(),
+            Some("2024-02-02T18:58:18Z")
+        );
+    }
+
+    #[test]
+    fn test_expires_at_without_expiry_is_null() {
+        assert_eq!(expires_at(-1, 1706900000), None);
+
+        let stored = StoredSecret::default();
+        let json = serde_json::to_string(&SecretPeekResponse::new(stored, -1, 1706900000)).unwrap();
+        assert!(json.contains(r#""ttlSeconds":-1"#));
+        assert!(json.contains(r#""expiresAt":null"#));
+    }
+
+    #[test]
+    fn test_expires_at_missing_key_is_now() {
+        assert_eq!(
+            expires_at(-2, 1706900000).as_deref(),
+            Some("2024-02-02T18:53:20Z")
+        );
+    }
+
     #[test]
     fn test_get_file_params_peek_true() {
         let params: GetFileParams = serde_json::from_str(r#"{"peek":true}"#).unwrap();
@@ -235,19 +1656,62 @@ mod tests {
         let resp = FilePeekResponse {
             created_at: 1706900000,
             ttl_seconds: 298,
+            expires_at: expires_at(298, 1706900000),
             metadata: FileMetadata {
                 original_filename: "test.pdf".to_string(),
                 content_type: "application/pdf".to_string(),
                 iv: "abc123".to_string(),
             },
+            crypto: None,
         };
         let json = serde_json::to_string(&resp).unwrap();
         assert!(json.contains(r#""createdAt":1706900000"#));
         assert!(json.contains(r#""ttlSeconds":298"#));
+        assert!(json.contains(r#""expiresAt":"2024-02-02T18:58:18Z""#));
         assert!(json.contains(r#""originalFilename":"test.pdf""#));
         assert!(json.contains(r#""contentType":"application/pdf""#));
     }
 
+    #[test]
+    fn test_bundle_request_with_two_files() {
+        let json = r#"{
+            "files": [
+                {"metadata":{"originalFilename":"a.txt","contentType":"text/plain","iv":"iv1"},"encryptedData":"data1"},
+                {"metadata":{"originalFilename":"b.pdf","contentType":"application/pdf","iv":"iv2"},"encryptedData":"data2"}
+            ],
+            "expiration": 3600
+        }"#;
+        let req: BundleRequest = serde_json::from_str(json).unwrap();
+        assert_eq!(req.files.len(), 2);
+        assert_eq!(req.files[1].metadata.original_filename, "b.pdf");
+
+        // Parts survive the round trip through storage in order
+        let stored = StoredBundle {
+            files: req.files,
+            created_at: 1706900000,
+        };
+        let json = serde_json::to_string(&stored).unwrap();
+        let stored: StoredBundle = serde_json::from_str(&json).unwrap();
+        assert_eq!(stored.files.len(), 2);
+        assert_eq!(stored.files[0].encrypted_data, "data1");
+        assert_eq!(stored.files[1].encrypted_data, "data2");
+        assert_eq!(stored.created_at, 1706900000);
+    }
+
+    #[test]
+    fn test_error_response_without_code() {
+        let json = serde_json::to_string(&ErrorResponse::new("Secret not found")).unwrap();
+        assert_eq!(json, r#"{"error":"Secret not found"}"#);
+    }
+
+    #[test]
+    fn test_error_response_with_code() {
+        let resp = ErrorResponse::with_code("PAYLOAD_TOO_LARGE", "Request body too large");
+        let json = serde_json::to_string(&resp).unwrap();
+        assert!(json.contains(r#""error":"Request body too large""#));
+        assert!(json.contains(r#""code":"PAYLOAD_TOO_LARGE""#));
+    }
+
     #[test]
     fn test_stored_file_with_created_at() {
         let stored = StoredFile {
@@ -258,9 +1722,14 @@ mod tests {
             },
             encrypted_data: "encrypted123".to_string(),
             created_at: 1706900000,
+            encrypted_thumbnail: None,
+            thumbnail_iv: None,
+            crypto: None,
+            content_hash: None,
         };
         let json = serde_json::to_string(&stored).unwrap();
         assert!(json.contains(r#""createdAt":1706900000"#));
+        assert!(!json.contains("thumbnail"));
     }
 
     #[test]
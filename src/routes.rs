@@ -0,0 +1,792 @@
+//! The route table. The router and `GET /v1/endpoints` are both built from
+//! it, so the discovery list can't drift from what is actually served.
+
+use crate::{config::Config, handlers, middleware, AppState};
+use axum::{
+    extract::DefaultBodyLimit,
+    middleware::{from_fn, from_fn_with_state},
+    routing::{on, MethodFilter, MethodRouter},
+    Router,
+};
+use serde::Serialize;
+use std::time::Duration;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum Method {
+    Get,
+    Post,
+    Put,
+    Delete,
+}
+
+impl Method {
+    fn filter(self) -> MethodFilter {
+        match self {
+            Method::Get => MethodFilter::GET,
+            Method::Post => MethodFilter::POST,
+            Method::Put => MethodFilter::PUT,
+            Method::Delete => MethodFilter::DELETE,
+        }
+    }
+}
+
+/// Per-route middleware. Guards are applied in the order listed, so the last
+/// one runs first.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Guard {
+    MinResponseTime,
+    UploadConcurrency,
+    EnforceOrigin,
+    AdminKey,
+    ApiKey,
+    NoSniff,
+    UserAgent,
+    StrictContentType,
+}
+
+impl Guard {
+    fn apply(self, handler: MethodRouter<AppState>, state: &AppState) -> MethodRouter<AppState> {
+        let state = state.clone();
+        match self {
+            Guard::MinResponseTime => {
+                handler.layer(from_fn_with_state(state, middleware::min_response_time))
+            }
+            Guard::UploadConcurrency => {
+                handler.layer(from_fn_with_state(state, middleware::upload_concurrency))
+            }
+            Guard::EnforceOrigin => {
+                handler.layer(from_fn_with_state(state, middleware::enforce_origin))
+            }
+            Guard::AdminKey => {
+                handler.layer(from_fn_with_state(state, middleware::require_admin_key))
+            }
+            Guard::ApiKey => handler.layer(from_fn_with_state(state, middleware::require_api_key)),
+            Guard::NoSniff => handler.layer(from_fn(middleware::nosniff)),
+            Guard::UserAgent => {
+                handler.layer(from_fn_with_state(state, middleware::require_user_agent))
+            }
+            Guard::StrictContentType => {
+                handler.layer(from_fn_with_state(state, middleware::strict_content_type))
+            }
+        }
+    }
+}
+
+/// Which request body limit a route gets. Routes are grouped into one
+/// sub-router per limit, so small JSON endpoints stay tight while uploads
+/// get room.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BodyLimit {
+    /// `MAX_JSON_BODY_BYTES` when set: secrets, and routes that take no body
+    Json,
+    /// Derived from `MAX_FILE_SIZE_MB`: file and bundle uploads
+    Upload,
+}
+
+impl BodyLimit {
+    fn bytes(self, config: &Config) -> usize {
+        match self {
+            BodyLimit::Json => config.json_body_limit(),
+            BodyLimit::Upload => config.upload_body_limit(),
+        }
+    }
+}
+
+pub struct Route {
+    pub method: Method,
+    pub path: &'static str,
+    pub description: &'static str,
+    pub guards: &'static [Guard],
+    pub body: BodyLimit,
+    handler: fn(MethodFilter) -> MethodRouter<AppState>,
+}
+
+impl Route {
+    /// Whether callers need `ADMIN_API_KEY`.
+    pub fn requires_auth(&self) -> bool {
+        self.guards.contains(&Guard::AdminKey)
+    }
+
+    /// `FILE_TIMEOUT_MS` for file routes and `SECRET_TIMEOUT_MS` for secret
+    /// routes, each falling back to `REQUEST_TIMEOUT_MS`. Burning reads get
+    /// none: see `BURNING_READS`.
+    fn timeout(&self, config: &Config) -> Option<Duration> {
+        if BURNING_READS.contains(&(self.method, self.path)) {
+            return None;
+        }
+        let specific = if self.path.starts_with("/v1/files") {
+            config.file_timeout
+        } else if self.path.starts_with("/v1/secrets") {
+            config.secret_timeout
+        } else {
+            None
+        };
+        specific.or(config.request_timeout)
+    }
+}
+
+/// Reads that burn what they return. A timeout would drop one after the
+/// burn but before the reply, losing the entry for good, so they always run
+/// to completion.
+const BURNING_READS: &[(Method, &str)] = &[
+    (Method::Get, "/v1/secrets/:id"),
+    (Method::Get, "/v1/secrets/compact/:code"),
+    (Method::Get, "/v1/files/:id"),
+    (Method::Get, "/v1/files/:id/bundle"),
+    (Method::Post, "/v1/files/:id/stage"),
+];
+
+pub static ROUTES: &[Route] = &[
+    Route {
+        method: Method::Get,
+        path: "/",
+        description: "Service descriptor, or a redirect to ROOT_REDIRECT",
+        guards: &[],
+        body: BodyLimit::Json,
+        handler: |method| on(method, handlers::root),
+    },
+    Route {
+        method: Method::Get,
+        path: "/openapi.yaml",
+        description: "OpenAPI specification",
+        guards: &[],
+        body: BodyLimit::Json,
+        handler: |method| on(method, handlers::openapi),
+    },
+    Route {
+        method: Method::Get,
+        path: "/metrics",
+        description: "Prometheus metrics",
+        guards: &[],
+        body: BodyLimit::Json,
+        handler: |method| on(method, handlers::metrics),
+    },
+    Route {
+        method: Method::Get,
+        path: "/readyz",
+        description: "Readiness probe",
+        guards: &[],
+        body: BodyLimit::Json,
+        handler: |method| on(method, handlers::readyz),
+    },
+    Route {
+        method: Method::Get,
+        path: "/status",
+        description: "HTML status page, when ENABLE_STATUS_PAGE is set",
+        guards: &[],
+        body: BodyLimit::Json,
+        handler: |method| on(method, handlers::status_page),
+    },
+    Route {
+        method: Method::Get,
+        path: "/v1/endpoints",
+        description: "This list of endpoints",
+        guards: &[],
+        body: BodyLimit::Json,
+        handler: |method| on(method, handlers::endpoints),
+    },
+    Route {
+        method: Method::Get,
+        path: "/v1/features",
+        description: "Optional features and whether this deployment has them enabled",
+        guards: &[],
+        body: BodyLimit::Json,
+        handler: |method| on(method, handlers::features),
+    },
+    Route {
+        method: Method::Post,
+        path: "/v1/secrets",
+        description: "Store an encrypted secret",
+        guards: &[
+            Guard::StrictContentType,
+            Guard::UserAgent,
+            Guard::EnforceOrigin,
+            Guard::ApiKey,
+        ],
+        body: BodyLimit::Json,
+        handler: |method| on(method, handlers::create_secret),
+    },
+    Route {
+        method: Method::Get,
+        path: "/v1/secrets",
+        description: "A page of the caller's secrets' ids, creation times, TTLs and tags",
+        guards: &[Guard::ApiKey],
+        body: BodyLimit::Json,
+        handler: |method| on(method, handlers::list_secrets),
+    },
+    Route {
+        method: Method::Get,
+        path: "/v1/secrets/:id",
+        description: "Read and burn a secret, or peek at its metadata with ?peek=true",
+        guards: &[Guard::MinResponseTime],
+        body: BodyLimit::Json,
+        handler: |method| on(method, handlers::get_secret),
+    },
+    Route {
+        method: Method::Put,
+        path: "/v1/secrets/:id",
+        description:
+            "Replace an unread secret's payload and metadata via its signed management link",
+        guards: &[Guard::EnforceOrigin, Guard::ApiKey],
+        body: BodyLimit::Json,
+        handler: |method| on(method, handlers::replace_secret),
+    },
+    Route {
+        method: Method::Get,
+        path: "/v1/secrets/:id/qr",
+        description: "SVG QR code of a secret's share link",
+        guards: &[],
+        body: BodyLimit::Json,
+        handler: |method| on(method, handlers::get_secret_qr),
+    },
+    Route {
+        method: Method::Get,
+        path: "/v1/secrets/:id/compact",
+        description: "The 16 raw bytes behind a secret id, for NFC tags and dense QR codes",
+        guards: &[],
+        body: BodyLimit::Json,
+        handler: |method| on(method, handlers::get_secret_compact_id),
+    },
+    Route {
+        method: Method::Get,
+        path: "/v1/secrets/compact/:code",
+        description: "Read and burn (or peek at) a secret by its base64url compact id",
+        guards: &[Guard::MinResponseTime],
+        body: BodyLimit::Json,
+        handler: |method| on(method, handlers::get_secret_compact),
+    },
+    Route {
+        method: Method::Get,
+        path: "/v1/secrets/:id/touch",
+        description: "Whether a secret exists and its TTL, without reading it",
+        guards: &[],
+        body: BodyLimit::Json,
+        handler: |method| on(method, handlers::touch_secret),
+    },
+    Route {
+        method: Method::Post,
+        path: "/v1/secrets/exists",
+        description: "Whether each of several secrets exists and its TTL, without reading them",
+        guards: &[Guard::ApiKey],
+        body: BodyLimit::Json,
+        handler: |method| on(method, handlers::secrets_exist),
+    },
+    Route {
+        method: Method::Get,
+        path: "/v1/secrets/:id/manage",
+        description: "Whether a secret exists and its TTL, via its signed management link",
+        guards: &[],
+        body: BodyLimit::Json,
+        handler: |method| on(method, handlers::manage_secret),
+    },
+    Route {
+        method: Method::Delete,
+        path: "/v1/secrets/:id/manage",
+        description: "Delete an unread secret via its signed management link",
+        guards: &[Guard::ApiKey],
+        body: BodyLimit::Json,
+        handler: |method| on(method, handlers::delete_managed_secret),
+    },
+    Route {
+        method: Method::Get,
+        path: "/v1/secrets/:id/raw-record",
+        description: "A secret's full stored record for backup, via its signed management link",
+        guards: &[Guard::NoSniff],
+        body: BodyLimit::Json,
+        handler: |method| on(method, handlers::raw_secret_record),
+    },
+    Route {
+        method: Method::Post,
+        path: "/v1/secrets/:id/manage/extend",
+        description: "Reset a secret's TTL via its signed management link",
+        guards: &[Guard::ApiKey],
+        body: BodyLimit::Json,
+        handler: |method| on(method, handlers::extend_managed_secret),
+    },
+    Route {
+        method: Method::Get,
+        path: "/v1/secrets/:id/events",
+        description:
+            "WebSocket of a secret's peek, burn and expiry events, via its management link",
+        guards: &[],
+        body: BodyLimit::Json,
+        handler: |method| on(method, handlers::secret_events),
+    },
+    Route {
+        method: Method::Post,
+        path: "/v1/files",
+        description: "Store an encrypted file",
+        guards: &[
+            Guard::StrictContentType,
+            Guard::UploadConcurrency,
+            Guard::UserAgent,
+            Guard::EnforceOrigin,
+            Guard::ApiKey,
+        ],
+        body: BodyLimit::Upload,
+        handler: |method| on(method, handlers::create_file),
+    },
+    Route {
+        method: Method::Get,
+        path: "/v1/files/check",
+        description: "Whether a file of a given size and name would be accepted",
+        guards: &[],
+        body: BodyLimit::Json,
+        handler: |method| on(method, handlers::check_file),
+    },
+    Route {
+        method: Method::Get,
+        path: "/v1/files/:id",
+        description: "Read and burn a file, or peek at its metadata with ?peek=true",
+        guards: &[Guard::MinResponseTime, Guard::NoSniff],
+        body: BodyLimit::Json,
+        handler: |method| on(method, handlers::get_file),
+    },
+    Route {
+        method: Method::Get,
+        path: "/v1/files/:id/thumbnail",
+        description: "A file's encrypted preview thumbnail, without burning the file",
+        guards: &[Guard::NoSniff],
+        body: BodyLimit::Json,
+        handler: |method| on(method, handlers::get_file_thumbnail),
+    },
+    Route {
+        method: Method::Post,
+        path: "/v1/files/:id/stage",
+        description: "Burn a file into a short-lived staging key for ranged download",
+        guards: &[Guard::MinResponseTime, Guard::ApiKey],
+        body: BodyLimit::Json,
+        handler: |method| on(method, handlers::stage_file),
+    },
+    Route {
+        method: Method::Get,
+        path: "/v1/files/:id/stage",
+        description: "A staged file's data, whole or as one byte range",
+        guards: &[Guard::NoSniff],
+        body: BodyLimit::Json,
+        handler: |method| on(method, handlers::get_staged_file),
+    },
+    Route {
+        method: Method::Post,
+        path: "/v1/files/:id/stage/complete",
+        description: "Finish a staged download, deleting the staged data",
+        guards: &[Guard::ApiKey],
+        body: BodyLimit::Json,
+        handler: |method| on(method, handlers::complete_staged_file),
+    },
+    Route {
+        method: Method::Post,
+        path: "/v1/files/:id/stage/abort",
+        description: "Abandon a staged download, deleting the staged data",
+        guards: &[Guard::ApiKey],
+        body: BodyLimit::Json,
+        handler: |method| on(method, handlers::abort_staged_file),
+    },
+    Route {
+        method: Method::Post,
+        path: "/v1/files/bundle",
+        description: "Store several encrypted files under one id",
+        guards: &[
+            Guard::StrictContentType,
+            Guard::UploadConcurrency,
+            Guard::UserAgent,
+            Guard::EnforceOrigin,
+            Guard::ApiKey,
+        ],
+        body: BodyLimit::Upload,
+        handler: |method| on(method, handlers::create_bundle),
+    },
+    Route {
+        method: Method::Get,
+        path: "/v1/files/:id/bundle",
+        description: "Read and burn every file in a bundle",
+        guards: &[Guard::MinResponseTime, Guard::NoSniff],
+        body: BodyLimit::Json,
+        handler: |method| on(method, handlers::get_bundle),
+    },
+    Route {
+        method: Method::Post,
+        path: "/v1/files/ref",
+        description: "Store a file sharing an already stored blob's data (DEDUP_FILES)",
+        guards: &[
+            Guard::StrictContentType,
+            Guard::UserAgent,
+            Guard::EnforceOrigin,
+            Guard::ApiKey,
+        ],
+        body: BodyLimit::Json,
+        handler: |method| on(method, handlers::create_file_ref),
+    },
+    Route {
+        method: Method::Post,
+        path: "/v1/files/uploads",
+        description: "Start a chunked file upload",
+        guards: &[
+            Guard::StrictContentType,
+            Guard::UserAgent,
+            Guard::EnforceOrigin,
+            Guard::ApiKey,
+        ],
+        body: BodyLimit::Json,
+        handler: |method| on(method, handlers::start_upload),
+    },
+    Route {
+        method: Method::Put,
+        path: "/v1/files/uploads/:id/chunks/:index",
+        description: "Send one chunk of a chunked upload",
+        guards: &[
+            Guard::StrictContentType,
+            Guard::UploadConcurrency,
+            Guard::UserAgent,
+            Guard::EnforceOrigin,
+            Guard::ApiKey,
+        ],
+        body: BodyLimit::Upload,
+        handler: |method| on(method, handlers::put_upload_chunk),
+    },
+    Route {
+        method: Method::Post,
+        path: "/v1/files/uploads/:id/complete",
+        description: "Store a chunked upload's chunks as a file",
+        guards: &[
+            Guard::StrictContentType,
+            Guard::UserAgent,
+            Guard::EnforceOrigin,
+            Guard::ApiKey,
+        ],
+        body: BodyLimit::Json,
+        handler: |method| on(method, handlers::complete_upload),
+    },
+    Route {
+        method: Method::Post,
+        path: "/v1/admin/files/:id/decrypt",
+        description:
+            "A file's plaintext, decrypted server-side with a supplied key (ALLOW_SERVER_DECRYPT)",
+        guards: &[Guard::NoSniff, Guard::AdminKey],
+        body: BodyLimit::Json,
+        handler: |method| on(method, handlers::decrypt_file),
+    },
+    Route {
+        method: Method::Put,
+        path: "/v1/admin/secrets/:id",
+        description: "Import a secret from another instance under its existing id",
+        guards: &[Guard::AdminKey],
+        body: BodyLimit::Json,
+        handler: |method| on(method, handlers::import_secret),
+    },
+    Route {
+        method: Method::Get,
+        path: "/v1/admin/usage",
+        description: "Stored bytes per API key label (USAGE_BY_API_KEY)",
+        guards: &[Guard::AdminKey],
+        body: BodyLimit::Json,
+        handler: |method| on(method, handlers::usage),
+    },
+    Route {
+        method: Method::Get,
+        path: "/v1/admin/emergency-max-ttl",
+        description: "The emergency cap on new entries' TTLs, if set",
+        guards: &[Guard::AdminKey],
+        body: BodyLimit::Json,
+        handler: |method| on(method, handlers::get_emergency_max_ttl),
+    },
+    Route {
+        method: Method::Put,
+        path: "/v1/admin/emergency-max-ttl",
+        description: "Cap every new secret's, file's and bundle's TTL",
+        guards: &[Guard::AdminKey],
+        body: BodyLimit::Json,
+        handler: |method| on(method, handlers::set_emergency_max_ttl),
+    },
+    Route {
+        method: Method::Delete,
+        path: "/v1/admin/emergency-max-ttl",
+        description: "Lift the emergency TTL cap",
+        guards: &[Guard::AdminKey],
+        body: BodyLimit::Json,
+        handler: |method| on(method, handlers::clear_emergency_max_ttl),
+    },
+    Route {
+        method: Method::Get,
+        path: "/v1/admin/expiry-histogram",
+        description: "Counts of stored entries by remaining TTL",
+        guards: &[Guard::AdminKey],
+        body: BodyLimit::Json,
+        handler: |method| on(method, handlers::expiry_histogram),
+    },
+];
+
+/// A router serving every entry in `ROUTES`, with its guards, timeout and
+/// body limit applied.
+pub fn router(state: &AppState) -> Router<AppState> {
+    [BodyLimit::Json, BodyLimit::Upload]
+        .into_iter()
+        .map(|limit| {
+            ROUTES
+                .iter()
+                .filter(|route| route.body == limit)
+                .fold(Router::new(), |router, route| {
+                    let handler = route
+                        .guards
+                        .iter()
+                        .fold((route.handler)(route.method.filter()), |handler, guard| {
+                            guard.apply(handler, state)
+                        });
+                    // Outermost, so the guards' own waits count against it
+                    let handler = match route.timeout(&state.config) {
+                        Some(limit) => {
+                            handler.layer(from_fn_with_state(limit, middleware::timeout))
+                        }
+                        None => handler,
+                    };
+                    router.route(route.path, handler)
+                })
+                .layer(DefaultBodyLimit::max(limit.bytes(&state.config)))
+        })
+        .fold(Router::new(), Router::merge)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, http::Request, http::StatusCode};
+    use http_body_util::BodyExt;
+    use std::collections::HashSet;
+    use tower::ServiceExt; // for `oneshot`
+
+    #[tokio::test]
+    async fn test_every_route_is_listed() {
+        let state = AppState::new(
+            redis::Client::open("redis://127.0.0.1/").unwrap(),
+            Config::default(),
+        );
+        let app = router(&state).with_state(state);
+
+        let req = Request::builder()
+            .uri("/v1/endpoints")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.oneshot(req).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let listed: Vec<serde_json::Value> = serde_json::from_slice(&body).unwrap();
+        let listed: HashSet<(String, String, bool)> = listed
+            .iter()
+            .map(|endpoint| {
+                (
+                    endpoint["method"].as_str().unwrap().to_string(),
+                    endpoint["path"].as_str().unwrap().to_string(),
+                    endpoint["requiresAuth"].as_bool().unwrap(),
+                )
+            })
+            .collect();
+
+        let registered: HashSet<(String, String, bool)> = ROUTES
+            .iter()
+            .map(|route| {
+                (
+                    serde_json::to_value(route.method)
+                        .unwrap()
+                        .as_str()
+                        .unwrap()
+                        .to_string(),
+                    route.path.to_string(),
+                    route.requires_auth(),
+                )
+            })
+            .collect();
+        assert_eq!(listed, registered);
+        assert!(listed.contains(&(
+            "GET".to_string(),
+            "/v1/admin/expiry-histogram".to_string(),
+            true
+        )));
+    }
+
+    #[test]
+    fn test_mutating_routes_require_a_key() {
+        for route in ROUTES {
+            if route.method == Method::Get {
+                continue;
+            }
+            assert!(
+                route.guards.contains(&Guard::ApiKey) || route.guards.contains(&Guard::AdminKey),
+                "{:?} {} takes no key",
+                route.method,
+                route.path
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_features_reflect_config() {
+        let state = AppState::new(
+            redis::Client::open("redis://127.0.0.1/").unwrap(),
+            Config {
+                secret_events: true,
+                ..Config::default()
+            },
+        );
+        let app = router(&state).with_state(state);
+
+        let req = Request::builder()
+            .uri("/v1/features")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.oneshot(req).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let features: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(features["secretEvents"], true);
+        assert_eq!(features["managementLinks"], false);
+        assert_eq!(features["peek"], true);
+        // No cap can be read without Redis, so none is in force
+        assert_eq!(features["emergencyMaxTtl"], false);
+    }
+
+    #[tokio::test]
+    async fn test_timeout_cuts_slow_requests_off() {
+        use axum::routing::get;
+
+        async fn slow() -> StatusCode {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            StatusCode::OK
+        }
+        async fn fast() -> StatusCode {
+            StatusCode::OK
+        }
+
+        let app = Router::new()
+            .route("/slow", get(slow))
+            .route("/fast", get(fast))
+            .layer(from_fn_with_state(
+                Duration::from_millis(50),
+                middleware::timeout,
+            ));
+        let status = |uri: &'static str| {
+            let app = app.clone();
+            async move {
+                let req = Request::builder().uri(uri).body(Body::empty()).unwrap();
+                app.oneshot(req).await.unwrap().status()
+            }
+        };
+        assert_eq!(status("/slow").await, StatusCode::REQUEST_TIMEOUT);
+        assert_eq!(status("/fast").await, StatusCode::OK);
+    }
+
+    #[test]
+    fn test_timeouts_are_per_endpoint() {
+        let config = Config {
+            request_timeout: Some(Duration::from_secs(5)),
+            secret_timeout: Some(Duration::from_millis(100)),
+            file_timeout: Some(Duration::from_millis(80)),
+            ..Config::default()
+        };
+        let route = |method, path| {
+            ROUTES
+                .iter()
+                .find(|route| route.method == method && route.path == path)
+                .unwrap()
+                .timeout(&config)
+        };
+        // Each group gets its own timeout rather than REQUEST_TIMEOUT_MS
+        assert_eq!(
+            route(Method::Get, "/v1/secrets/:id/touch"),
+            Some(Duration::from_millis(100))
+        );
+        assert_eq!(
+            route(Method::Get, "/v1/files/:id/thumbnail"),
+            Some(Duration::from_millis(80))
+        );
+        assert_eq!(route(Method::Get, "/readyz"), Some(Duration::from_secs(5)));
+
+        // Burning reads are never cut off between the burn and the reply
+        for (method, path) in BURNING_READS {
+            assert_eq!(route(*method, path), None, "{path}");
+        }
+        // Staging burns, but the ranged reads of a staged file don't
+        assert_eq!(
+            route(Method::Get, "/v1/files/:id/stage"),
+            Some(Duration::from_millis(80))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_file_downloads_are_nosniff() {
+        let state = AppState::new(
+            redis::Client::open("redis://127.0.0.1/").unwrap(),
+            Config::default(),
+        );
+        let app = router(&state).with_state(state);
+
+        // Malformed ids are rejected before Redis, so no server is needed
+        for uri in ["/v1/files/spf-0OIl", "/v1/files/spb-0OIl/bundle"] {
+            let req = Request::builder().uri(uri).body(Body::empty()).unwrap();
+            let response = app.clone().oneshot(req).await.unwrap();
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+            assert_eq!(
+                response.headers()["x-content-type-options"],
+                "nosniff",
+                "{uri}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_get_on_bundle_create_route_is_not_a_file_read() {
+        let state = AppState::new(
+            redis::Client::open("redis://127.0.0.1/").unwrap(),
+            Config::default(),
+        );
+        let app = router(&state).with_state(state);
+
+        let req = Request::builder()
+            .uri("/v1/files/bundle")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.oneshot(req).await.unwrap();
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+    }
+
+    #[tokio::test]
+    async fn test_body_limit_by_route_group() {
+        let state = AppState::new(
+            redis::Client::open("redis://127.0.0.1/").unwrap(),
+            Config {
+                max_json_body_bytes: 1024,
+                ..Config::default()
+            },
+        );
+        let app = router(&state).with_state(state);
+
+        // Same 4KB (not valid JSON) body to both: the secrets route refuses to
+        // read it, the upload route reads it and only then rejects it as JSON
+        let post = |uri: &str| {
+            Request::builder()
+                .method("POST")
+                .uri(uri)
+                .header("content-type", "application/json")
+                .body(Body::from("x".repeat(4096)))
+                .unwrap()
+        };
+        let response = app.clone().oneshot(post("/v1/secrets")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        let response = app.oneshot(post("/v1/files")).await.unwrap();
+        assert_ne!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert!(response.status().is_client_error());
+
+        // Unset, secrets get the upload limit like every other route
+        let state = AppState::new(
+            redis::Client::open("redis://127.0.0.1/").unwrap(),
+            Config::default(),
+        );
+        let app = router(&state).with_state(state);
+        let response = app.oneshot(post("/v1/secrets")).await.unwrap();
+        assert_ne!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert!(response.status().is_client_error());
+    }
+}
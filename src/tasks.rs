@@ -0,0 +1,115 @@
+//! Background work spawned outside request handling. Everything goes through
+//! `BackgroundTasks` so graceful shutdown can stop long-running loops and
+//! give short tasks (e.g. notifications) a bounded time to finish.
+
+use std::future::Future;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+
+#[derive(Clone, Debug, Default)]
+pub struct BackgroundTasks {
+    tracker: TaskTracker,
+    /// Cancelled as soon as shutdown starts; stops `spawn_until_shutdown` tasks
+    shutdown: CancellationToken,
+    /// Cancelled when the shutdown deadline passes; stops everything else
+    deadline: CancellationToken,
+}
+
+impl BackgroundTasks {
+    /// Spawn short work that shutdown waits for, up to its deadline.
+    pub fn spawn<F>(&self, task: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let deadline = self.deadline.clone();
+        self.tracker.spawn(async move {
+            tokio::select! {
+                _ = task => {}
+                _ = deadline.cancelled() => {}
+            }
+        });
+    }
+
+    /// Spawn long-running work (e.g. the janitor loop) that is cancelled as
+    /// soon as shutdown starts.
+    pub fn spawn_until_shutdown<F>(&self, task: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let shutdown = self.shutdown.clone();
+        self.tracker.spawn(async move {
+            tokio::select! {
+                _ = task => {}
+                _ = shutdown.cancelled() => {}
+            }
+        });
+    }
+
+    /// Cancel long-running tasks and wait up to `grace` for the rest, then
+    /// cancel whatever is still running. Returns false if anything had to be
+    /// cancelled at the deadline.
+    pub async fn shutdown(&self, grace: Duration) -> bool {
+        self.shutdown.cancel();
+        self.tracker.close();
+
+        if tokio::time::timeout(grace, self.tracker.wait())
+            .await
+            .is_ok()
+        {
+            return true;
+        }
+
+        self.deadline.cancel();
+        self.tracker.wait().await;
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    fn flagged(delay: Duration) -> (Arc<AtomicBool>, impl Future<Output = ()>) {
+        let done = Arc::new(AtomicBool::new(false));
+        let flag = done.clone();
+        (done, async move {
+            tokio::time::sleep(delay).await;
+            flag.store(true, Ordering::SeqCst);
+        })
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_awaits_short_tasks() {
+        let tasks = BackgroundTasks::default();
+        let (done, task) = flagged(Duration::from_millis(50));
+        tasks.spawn(task);
+
+        assert!(tasks.shutdown(Duration::from_secs(5)).await);
+        assert!(done.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_cancels_tasks_past_deadline() {
+        let tasks = BackgroundTasks::default();
+        let (done, task) = flagged(Duration::from_secs(3600));
+        tasks.spawn(task);
+
+        assert!(!tasks.shutdown(Duration::from_millis(50)).await);
+        assert!(!done.load(Ordering::SeqCst));
+        assert!(tasks.tracker.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_stops_long_running_tasks_immediately() {
+        let tasks = BackgroundTasks::default();
+        let (done, task) = flagged(Duration::from_secs(3600));
+        tasks.spawn_until_shutdown(task);
+
+        // Cancelled at once, so the grace period isn't used up
+        assert!(tasks.shutdown(Duration::from_secs(5)).await);
+        assert!(!done.load(Ordering::SeqCst));
+    }
+}
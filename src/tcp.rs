@@ -0,0 +1,70 @@
+//! TCP listener setup. The socket is built with socket2 so its options can
+//! be set before it starts listening; connections accepted from it inherit
+//! them on Linux and the BSDs.
+
+use socket2::{Domain, Socket, TcpKeepalive, Type};
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::TcpListener;
+
+const BACKLOG: i32 = 1024;
+
+/// Socket options for the TCP listener.
+#[derive(Clone, Copy, Debug)]
+pub struct Tuning {
+    /// Disable Nagle's algorithm, so small responses aren't held back
+    /// waiting for more data to coalesce
+    pub nodelay: bool,
+    /// Idle time before keepalive probes are sent (None = no keepalive)
+    pub keepalive: Option<Duration>,
+}
+
+/// Bind a listener on `addr` with `tuning` applied.
+pub fn bind(addr: SocketAddr, tuning: Tuning) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    socket.set_reuse_address(true)?;
+    socket.set_nodelay(tuning.nodelay)?;
+    if let Some(idle) = tuning.keepalive {
+        // Probe at the idle interval too, so a dead peer is noticed within a
+        // few multiples of it rather than the OS default of minutes
+        socket.set_tcp_keepalive(&TcpKeepalive::new().with_time(idle).with_interval(idle))?;
+    }
+    socket.bind(&addr.into())?;
+    socket.listen(BACKLOG)?;
+    socket.set_nonblocking(true)?;
+    TcpListener::from_std(socket.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use socket2::SockRef;
+
+    #[tokio::test]
+    async fn test_listener_is_tuned() {
+        let listener = bind(
+            "127.0.0.1:0".parse().unwrap(),
+            Tuning {
+                nodelay: true,
+                keepalive: Some(Duration::from_secs(30)),
+            },
+        )
+        .unwrap();
+        let socket = SockRef::from(&listener);
+        assert!(socket.nodelay().unwrap());
+        assert!(socket.keepalive().unwrap());
+
+        let listener = bind(
+            "127.0.0.1:0".parse().unwrap(),
+            Tuning {
+                nodelay: false,
+                keepalive: None,
+            },
+        )
+        .unwrap();
+        let socket = SockRef::from(&listener);
+        assert!(!socket.nodelay().unwrap());
+        assert!(!socket.keepalive().unwrap());
+    }
+}
@@ -0,0 +1,176 @@
+//! Logging and distributed tracing. Every request span joins the trace named
+//! by the caller's W3C `traceparent`/`tracestate` headers, and logs carry its
+//! `trace_id`. With `OTEL_EXPORTER_OTLP_ENDPOINT` set, spans are also
+//! exported over OTLP/HTTP (the standard `OTEL_EXPORTER_OTLP_*` variables
+//! apply), so SnapPwd shows up in the callers' traces.
+
+use axum::http::{HeaderMap, Request};
+use opentelemetry::{
+    propagation::{Extractor, TextMapPropagator},
+    trace::{TraceContextExt, TracerProvider as _},
+    Context, KeyValue,
+};
+use opentelemetry_sdk::{propagation::TraceContextPropagator, trace::TracerProvider, Resource};
+use std::env;
+use tracing::{field, Span};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::{
+    filter::LevelFilter, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter,
+};
+
+const SERVICE_NAME: &str = "snappwd-service";
+
+/// Flushes exported spans when dropped, at the end of `main`.
+pub struct Telemetry(Option<TracerProvider>);
+
+impl Drop for Telemetry {
+    fn drop(&mut self) {
+        if let Some(provider) = self.0.take() {
+            if let Err(e) = provider.shutdown() {
+                eprintln!("Failed to flush traces: {}", e);
+            }
+        }
+    }
+}
+
+/// Install the global subscriber: `RUST_LOG`-filtered logs (ERROR by
+/// default, as before), plus an OTLP exporter when
+/// `OTEL_EXPORTER_OTLP_ENDPOINT` is set.
+pub fn init() -> Telemetry {
+    let filter = EnvFilter::builder()
+        .with_default_directive(LevelFilter::ERROR.into())
+        .from_env_lossy();
+    let (provider, exporter_error) = match env::var("OTEL_EXPORTER_OTLP_ENDPOINT") {
+        Ok(endpoint) if !endpoint.trim().is_empty() => match otlp_provider() {
+            Ok(provider) => (Some(provider), None),
+            Err(e) => (None, Some(e)),
+        },
+        _ => (None, None),
+    };
+    let otel = provider
+        .as_ref()
+        .map(|provider| tracing_opentelemetry::layer().with_tracer(provider.tracer(SERVICE_NAME)));
+
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(tracing_subscriber::fmt::layer())
+        .with(otel)
+        .init();
+
+    if provider.is_some() {
+        tracing::info!("Exporting traces over OTLP");
+    }
+    if let Some(e) = exporter_error {
+        tracing::error!(
+            "Failed to set up the OTLP exporter, traces won't be exported: {}",
+            e
+        );
+    }
+    Telemetry(provider)
+}
+
+fn otlp_provider() -> Result<TracerProvider, opentelemetry::trace::TraceError> {
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_http()
+        .build()?;
+    let service_name = env::var("OTEL_SERVICE_NAME").unwrap_or_else(|_| SERVICE_NAME.to_string());
+    Ok(TracerProvider::builder()
+        .with_batch_exporter(exporter, opentelemetry_sdk::runtime::Tokio)
+        .with_resource(Resource::new([KeyValue::new("service.name", service_name)]))
+        .build())
+}
+
+/// The span each request runs in (`TraceLayer::make_span_with`), as a child
+/// of the caller's span when it sent a valid `traceparent`. Only the path is
+/// recorded: query strings carry management link signatures.
+pub fn request_span<B>(request: &Request<B>) -> Span {
+    let span = tracing::info_span!(
+        "request",
+        method = %request.method(),
+        path = %request.uri().path(),
+        version = ?request.version(),
+        trace_id = field::Empty,
+    );
+    let parent = remote_context(request.headers());
+    let remote = parent.span().span_context().clone();
+    if remote.is_valid() {
+        span.record("trace_id", field::display(remote.trace_id()));
+        span.set_parent(parent);
+    }
+    span
+}
+
+/// The trace context in W3C `traceparent`/`tracestate` headers. Invalid or
+/// missing headers give an empty context.
+fn remote_context(headers: &HeaderMap) -> Context {
+    TraceContextPropagator::new().extract(&HeaderExtractor(headers))
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|name| name.as_str()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+
+    const TRACEPARENT: &str = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+
+    fn request(traceparent: Option<&str>) -> Request<Body> {
+        let mut builder = Request::builder().uri("/v1/secrets");
+        if let Some(traceparent) = traceparent {
+            builder = builder.header("traceparent", traceparent);
+        }
+        builder.body(Body::empty()).unwrap()
+    }
+
+    #[test]
+    fn test_traceparent_is_attached_to_request_span() {
+        let provider = TracerProvider::builder().build();
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+
+        tracing::subscriber::with_default(subscriber, || {
+            let span = request_span(&request(Some(TRACEPARENT)));
+            let context = span.context();
+            let span_context = context.span().span_context().clone();
+            assert_eq!(
+                span_context.trace_id().to_string(),
+                "4bf92f3577b34da6a3ce929d0e0e4736"
+            );
+            // A new span within the caller's trace, not the caller's own span
+            assert_ne!(span_context.span_id().to_string(), "00f067aa0ba902b7");
+
+            // Without a (valid) traceparent, the request starts its own trace
+            for traceparent in [None, Some("00-not-a-trace-01")] {
+                let span = request_span(&request(traceparent));
+                let trace_id = span.context().span().span_context().trace_id();
+                assert_ne!(trace_id.to_string(), "4bf92f3577b34da6a3ce929d0e0e4736");
+            }
+        });
+    }
+
+    #[test]
+    fn test_remote_context_parses_traceparent() {
+        let mut headers = HeaderMap::new();
+        headers.insert("traceparent", TRACEPARENT.parse().unwrap());
+        headers.insert("tracestate", "vendor=abc".parse().unwrap());
+
+        let context = remote_context(&headers);
+        let remote = context.span().span_context().clone();
+        assert!(remote.is_valid());
+        assert!(remote.is_remote());
+        assert!(remote.is_sampled());
+        assert_eq!(remote.span_id().to_string(), "00f067aa0ba902b7");
+        assert_eq!(remote.trace_state().get("vendor"), Some("abc"));
+    }
+}
@@ -0,0 +1,137 @@
+//! Serving over a Unix domain socket, for sidecar deployments that shouldn't
+//! expose a TCP port. axum 0.7's `serve` only accepts TCP listeners, so this
+//! drives hyper directly with the same router.
+
+use axum::Router;
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::conn::auto,
+    service::TowerToHyperService,
+};
+use std::io;
+use std::os::unix::fs::FileTypeExt;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::net::UnixListener;
+use tokio::sync::Semaphore;
+
+/// Bind the socket, replacing a stale socket file left by a previous run.
+/// Anything else at `path` (a regular file, or a socket a live process still
+/// accepts on) is left alone and the bind fails.
+pub fn bind(path: &Path) -> io::Result<UnixListener> {
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if !meta.file_type().is_socket() => {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} exists and is not a socket", path.display()),
+            ));
+        }
+        Ok(_) => match std::os::unix::net::UnixStream::connect(path) {
+            Ok(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    format!("{} is in use by another process", path.display()),
+                ));
+            }
+            Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+                std::fs::remove_file(path)?;
+            }
+            Err(e) => return Err(e),
+        },
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    UnixListener::bind(path)
+}
+
+/// Accept connections forever, serving each with `app`. With
+/// `max_connections` (0 = unlimited) open, stops accepting until one closes.
+pub async fn serve(listener: UnixListener, app: Router, max_connections: usize) -> io::Result<()> {
+    let slots = (max_connections > 0).then(|| Arc::new(Semaphore::new(max_connections)));
+    loop {
+        let slot = match &slots {
+            Some(slots) => Some(
+                Arc::clone(slots)
+                    .acquire_owned()
+                    .await
+                    .expect("semaphore is never closed"),
+            ),
+            None => None,
+        };
+        let (socket, _) = listener.accept().await?;
+        let service = TowerToHyperService::new(app.clone());
+
+        tokio::spawn(async move {
+            if let Err(e) = auto::Builder::new(TokioExecutor::new())
+                .serve_connection_with_upgrades(TokioIo::new(socket), service)
+                .await
+            {
+                tracing::debug!("Unix socket connection error: {}", e);
+            }
+            drop(slot);
+        });
+    }
+}
+
+/// Remove the socket file on shutdown.
+pub fn cleanup(path: &Path) {
+    if let Err(e) = std::fs::remove_file(path) {
+        tracing::warn!("Failed to remove socket {}: {}", path.display(), e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Bytes, http::Request, routing::get};
+    use http_body_util::Empty;
+
+    #[tokio::test]
+    async fn test_request_over_unix_socket() {
+        let path = std::env::temp_dir().join(format!("snappwd-{}.sock", uuid::Uuid::new_v4()));
+        let listener = bind(&path).unwrap();
+        let app = Router::new().route("/", get(|| async { "ok" }));
+        let server = tokio::spawn(serve(listener, app, 0));
+
+        let stream = tokio::net::UnixStream::connect(&path).await.unwrap();
+        let (mut sender, conn) = hyper::client::conn::http1::handshake(TokioIo::new(stream))
+            .await
+            .unwrap();
+        tokio::spawn(conn);
+
+        let req = Request::builder()
+            .uri("/")
+            .header("host", "localhost")
+            .body(Empty::<Bytes>::new())
+            .unwrap();
+        let response = sender.send_request(req).await.unwrap();
+        assert_eq!(response.status(), 200);
+
+        server.abort();
+        cleanup(&path);
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn test_bind_replaces_only_stale_sockets() {
+        let path = std::env::temp_dir().join(format!("snappwd-{}.sock", uuid::Uuid::new_v4()));
+
+        // A live listener keeps its socket
+        let live = bind(&path).unwrap();
+        assert_eq!(bind(&path).unwrap_err().kind(), io::ErrorKind::AddrInUse);
+
+        // Once it's gone the leftover file is stale and gets replaced
+        drop(live);
+        let _rebound = bind(&path).unwrap();
+        cleanup(&path);
+
+        // Never deletes a file that isn't a socket
+        std::fs::write(&path, "data").unwrap();
+        assert_eq!(
+            bind(&path).unwrap_err().kind(),
+            io::ErrorKind::AlreadyExists
+        );
+        assert!(path.exists());
+        std::fs::remove_file(&path).unwrap();
+    }
+}